@@ -0,0 +1,123 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use flate2::{write::ZlibEncoder, Compression};
+use std::io::Write;
+use tokio::{
+    io::{duplex, split, AsyncReadExt, AsyncWriteExt, DuplexStream, WriteHalf},
+    runtime::Runtime,
+};
+use vnc::{PixelFormat, VncClient, VncConnector, VncEncoding, VncEvent};
+
+const WIDTH: u16 = 1024;
+const HEIGHT: u16 = 64;
+
+/// Accept the client without authentication, then drain whatever it sends
+async fn server(stream: DuplexStream) -> WriteHalf<DuplexStream> {
+    let (mut reader, mut writer) = split(stream);
+    writer.write_all(b"RFB 003.008\n").await.unwrap();
+    let mut version = [0; 12];
+    reader.read_exact(&mut version).await.unwrap();
+    // SecurityType::None
+    writer.write_all(&[1, 1]).await.unwrap();
+    reader.read_u8().await.unwrap();
+    writer.write_u32(0).await.unwrap();
+    reader.read_u8().await.unwrap();
+
+    writer.write_u16(WIDTH).await.unwrap();
+    writer.write_u16(HEIGHT).await.unwrap();
+    writer
+        .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+        .await
+        .unwrap();
+    writer.write_u32(5).await.unwrap();
+    writer.write_all(b"bench").await.unwrap();
+
+    tokio::spawn(async move {
+        let mut buf = [0; 1024];
+        while let Ok(n) = reader.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+    writer
+}
+
+/// A framebuffer update of a single 2 colors Tight rect, looking like text
+fn mono_update() -> Vec<u8> {
+    let bitmap: Vec<u8> = (0..(WIDTH as usize / 8 * HEIGHT as usize))
+        .map(|i| (i * 37 % 251) as u8)
+        .collect();
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&bitmap).unwrap();
+    // the servers keep the stream open with a sync flush
+    encoder.flush().unwrap();
+    let compressed = encoder.get_ref().clone();
+
+    let mut update = vec![0, 0, 0, 1];
+    for v in [0, 0, WIDTH, HEIGHT] {
+        update.extend_from_slice(&v.to_be_bytes());
+    }
+    update.extend_from_slice(&(VncEncoding::Tight as i32).to_be_bytes());
+    // reset stream 0, explicit palette filter of black & white
+    update.extend_from_slice(&[0x41, 1, 1, 0, 0, 0, 255, 255, 255]);
+    let mut len = compressed.len();
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            update.push(byte);
+            break;
+        }
+        update.push(byte | 0x80);
+    }
+    update.extend_from_slice(&compressed);
+    update
+}
+
+async fn next_update(vnc: &VncClient) {
+    loop {
+        if let VncEvent::UpdateComplete = vnc.recv_event().await.unwrap() {
+            return;
+        }
+    }
+}
+
+fn tight_mono(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let (vnc, mut server) = rt.block_on(async {
+        let (client, server_stream) = duplex(1 << 20);
+        let (vnc, server) = tokio::join!(
+            async {
+                VncConnector::new(client)
+                    .set_auth_method(|_| async move { Ok(String::new().into()) })
+                    .add_encoding(VncEncoding::Tight)
+                    .set_pixel_format(PixelFormat::bgra())
+                    .build()
+                    .unwrap()
+                    .try_start()
+                    .await
+                    .unwrap()
+                    .finish()
+                    .unwrap()
+            },
+            server(server_stream)
+        );
+        (vnc, server)
+    });
+    let update = mono_update();
+
+    let mut group = c.benchmark_group("tight");
+    group.throughput(Throughput::Elements(WIDTH as u64 * HEIGHT as u64));
+    group.bench_function("mono", |b| {
+        b.iter(|| {
+            rt.block_on(async {
+                server.write_all(&update).await.unwrap();
+                next_update(&vnc).await;
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, tight_mono);
+criterion_main!(benches);
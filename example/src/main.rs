@@ -106,13 +106,18 @@ impl CanvasUtils {
             VncEvent::Bell => {
                 tracing::warn!("Bell event got, but ignore it");
             }
-            VncEvent::SetPixelFormat(_) => unreachable!(),
+            VncEvent::SetPixelFormat(pf) => {
+                tracing::warn!("Pixel format {:?} got, but ignore it", pf);
+            }
             VncEvent::Copy(dst, src) => {
                 self.copy(dst, src)?;
             }
             VncEvent::JpegImage(_rect, _data) => {
                 tracing::warn!("Jpeg event got, but ignore it");
             }
+            VncEvent::PngImage(_rect, _data) => {
+                tracing::warn!("Png event got, but ignore it");
+            }
             VncEvent::SetCursor(rect, data) => {
                 if rect.width != 0 {
                     self.draw(rect, data)?;
@@ -121,7 +126,9 @@ impl CanvasUtils {
             VncEvent::Text(string) => {
                 tracing::info!("Got clipboard message {}", string);
             }
-            _ => unreachable!(),
+            VncEvent::UpdateComplete => {}
+            // more events are added over time, skip the ones not rendered here
+            _ => {}
         }
         Ok(())
     }
@@ -143,7 +150,7 @@ async fn main() -> Result<()> {
 
     let tcp = TcpStream::connect("127.0.0.1:5900").await?;
     let vnc = VncConnector::new(tcp)
-        .set_auth_method(async move { Ok("123".to_string()) })
+        .set_auth_method(|_| async move { Ok("123".into()) })
         .add_encoding(vnc::VncEncoding::Tight)
         .add_encoding(vnc::VncEncoding::Zrle)
         .add_encoding(vnc::VncEncoding::CopyRect)
@@ -154,19 +161,16 @@ async fn main() -> Result<()> {
         .try_start()
         .await?
         .finish()?;
-    let (vnc_event_sender, mut vnc_event_receiver) = tokio::sync::mpsc::channel(100);
-    let (x11_event_sender, x11_event_receiver) = tokio::sync::mpsc::channel(100);
-    tokio::spawn(async move { vnc.run(vnc_event_sender, x11_event_receiver).await.unwrap() });
 
     let mut canvas = CanvasUtils::new()?;
 
-    while let Some(event) = vnc_event_receiver.recv().await {
+    while let Ok(event) = vnc.recv_event().await {
         canvas.hande_vnc_event(event)?;
-        while let Ok(e) = vnc_event_receiver.try_recv() {
+        while let Some(e) = vnc.poll_event().await? {
             canvas.hande_vnc_event(e)?;
         }
         canvas.flush()?;
-        let _ = x11_event_sender.send(X11Event::Refresh).await;
+        let _ = vnc.input(X11Event::Refresh).await;
     }
     canvas.close();
     Ok(())
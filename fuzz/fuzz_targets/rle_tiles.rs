@@ -0,0 +1,77 @@
+//! Feed a single ZRLE or TRLE rect with arbitrary tile data to the client
+//!
+//! The first byte selects the encoding, the next two are the rect size,
+//! and the rest are the tiles (compressed by the harness in case of ZRLE)
+//!
+//! Malformed tiles must end the session with an error rather than a panic
+
+#![no_main]
+
+use std::io::Write;
+
+use libfuzzer_sys::fuzz_target;
+use tokio::io::AsyncWriteExt;
+use vnc::{PixelFormat, VncConnector, VncEncoding};
+
+const WIDTH: u16 = 256;
+const HEIGHT: u16 = 256;
+
+fn server_stream(data: &[u8]) -> Option<Vec<u8>> {
+    let [kind, width, height, tiles @ ..] = data else {
+        return None;
+    };
+    let mut stream = Vec::new();
+    // version & security type None & security result
+    stream.extend_from_slice(b"RFB 003.008\n");
+    stream.extend_from_slice(&[1, 1, 0, 0, 0, 0]);
+    // server init with the rgba pixel format and an empty name
+    stream.extend_from_slice(&WIDTH.to_be_bytes());
+    stream.extend_from_slice(&HEIGHT.to_be_bytes());
+    stream.extend_from_slice(&[32, 24, 0, 1, 0, 255, 0, 255, 0, 255, 0, 8, 16, 0, 0, 0]);
+    stream.extend_from_slice(&0_u32.to_be_bytes());
+    // a framebuffer update with a single rect
+    stream.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+    stream.extend_from_slice(&(*width as u16).to_be_bytes());
+    stream.extend_from_slice(&(*height as u16).to_be_bytes());
+    if kind & 1 == 0 {
+        stream.extend_from_slice(&(VncEncoding::Zrle as i32).to_be_bytes());
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(tiles).ok()?;
+        let compressed = encoder.flush_finish().ok()?;
+        stream.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+        stream.extend_from_slice(&compressed);
+    } else {
+        stream.extend_from_slice(&(VncEncoding::Trle as i32).to_be_bytes());
+        stream.extend_from_slice(tiles);
+    }
+    Some(stream)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some(stream) = server_stream(data) else {
+        return;
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async move {
+        let (client, mut server) = tokio::io::duplex(stream.len() + 4096);
+        server.write_all(&stream).await.unwrap();
+        let Ok(vnc) = VncConnector::new(client)
+            .set_auth_method(|_| async move { Ok(String::new().into()) })
+            .add_encoding(VncEncoding::Zrle)
+            .add_encoding(VncEncoding::Trle)
+            .set_pixel_format(PixelFormat::rgba())
+            .build()
+        else {
+            return;
+        };
+        let Ok(vnc) = vnc.try_start().await.and_then(|vnc| vnc.finish()) else {
+            return;
+        };
+        // close the server side once the data is consumed
+        drop(server);
+        while vnc.recv_event().await.is_ok() {}
+    });
+});
@@ -3,30 +3,59 @@ use crate::{VncError, VncVersion};
 use anyhow::Result;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+/// Security types defined by the [RFB protocol](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.2)
+///
 #[allow(dead_code)]
+#[non_exhaustive]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-pub(super) enum SecurityType {
+pub enum SecurityType {
     Invalid = 0,
     None = 1,
     VncAuth = 2,
+    /// RealVNC RSA-AES, the whole session is encrypted
+    ///
+    /// Requires the `ra2` feature
+    ///
     RA2 = 5,
+    /// RealVNC RSA-AES, only the credentials are encrypted
+    ///
+    /// Requires the `ra2` feature
+    ///
     RA2ne = 6,
     Tight = 16,
     Ultra = 17,
     Tls = 18,
     VeNCrypt = 19,
+    /// The SASL auth of libvirt/QEMU & gtk-vnc
+    ///
+    /// Requires the `sasl` feature, only the SCRAM mechanisms are supported
+    ///
     GtkVncSasl = 20,
     Md5Hash = 21,
     ColinDeanXvp = 22,
+    /// Apple Remote Desktop, the Diffie-Hellman auth of the macOS Screen Sharing
+    ///
+    /// Requires the `apple` feature, see `VncConnector::set_username`
+    ///
+    Ard = 30,
+    /// The newer macOS auth, which is not implemented
+    ///
+    MacOsX = 35,
+    /// [SecurityType::RA2] with AES-256 & SHA-256
+    ///
+    RA256 = 129,
+    /// [SecurityType::RA2ne] with AES-256 & SHA-256
+    ///
+    RAne256 = 130,
 }
 
 impl TryFrom<u8> for SecurityType {
     type Error = VncError;
     fn try_from(num: u8) -> Result<Self, Self::Error> {
         match num {
-            0 | 1 | 2 | 5 | 6 | 16 | 17 | 18 | 19 | 20 | 21 | 22 => {
-                Ok(unsafe { std::mem::transmute(num) })
+            0 | 1 | 2 | 5 | 6 | 16 | 17 | 18 | 19 | 20 | 21 | 22 | 30 | 35 | 129 | 130 => {
+                Ok(unsafe { std::mem::transmute::<u8, SecurityType>(num) })
             }
             invalid => Err(VncError::InvalidSecurityTyep(invalid)),
         }
@@ -40,6 +69,24 @@ impl From<SecurityType> for u8 {
 }
 
 impl SecurityType {
+    /// Whether the connector can authenticate with the type, in the enabled features
+    ///
+    pub fn is_implemented(&self) -> bool {
+        matches!(
+            self,
+            SecurityType::None | SecurityType::VncAuth | SecurityType::VeNCrypt
+        ) || (cfg!(feature = "apple") && *self == SecurityType::Ard)
+            || (cfg!(feature = "ra2")
+                && matches!(
+                    self,
+                    SecurityType::RA2
+                        | SecurityType::RA2ne
+                        | SecurityType::RA256
+                        | SecurityType::RAne256
+                ))
+            || (cfg!(feature = "sasl") && *self == SecurityType::GtkVncSasl)
+    }
+
     pub(super) async fn read<S>(reader: &mut S, version: &VncVersion) -> Result<Vec<Self>>
     where
         S: AsyncRead + Unpin,
@@ -74,9 +121,18 @@ impl SecurityType {
                 }
                 let mut sec_types = vec![];
                 for _ in 0..num {
-                    sec_types.push(reader.read_u8().await?.try_into()?);
+                    // e.g. the macOS servers offer some undocumented types
+                    match reader.read_u8().await?.try_into() {
+                        Ok(sec_type) => sec_types.push(sec_type),
+                        Err(e) => tracing::trace!("Skip {}", e),
+                    }
                 }
                 tracing::trace!("Server supported security type: {:?}", sec_types);
+                if sec_types.is_empty() {
+                    return Err(
+                        VncError::Custom("No known security type offered".to_string()).into(),
+                    );
+                }
                 Ok(sec_types)
             }
         }
@@ -112,41 +168,32 @@ impl From<AuthResult> for u32 {
 
 pub(super) struct AuthHelper {
     challenge: [u8; 16],
-    key: [u8; 8],
 }
 
 impl AuthHelper {
-    pub(super) async fn read<S>(reader: &mut S, credential: &str) -> Result<Self>
+    pub(super) async fn read<S>(reader: &mut S) -> Result<Self>
     where
         S: AsyncRead + Unpin,
     {
         let mut challenge = [0; 16];
         reader.read_exact(&mut challenge).await?;
+        Ok(Self { challenge })
+    }
 
-        let credential_len = credential.len();
-        let mut key = [0u8; 8];
-        for (i, key_i) in key.iter_mut().enumerate() {
-            let c = if i < credential_len {
-                credential.as_bytes()[i]
-            } else {
-                0
-            };
-            let mut cs = 0u8;
-            for j in 0..8 {
-                cs |= ((c >> j) & 1) << (7 - j)
-            }
-            *key_i = cs;
-        }
+    pub(super) fn challenge(&self) -> [u8; 16] {
+        self.challenge
+    }
 
-        Ok(Self { challenge, key })
+    /// The response computed from the password with DES
+    pub(super) fn encrypt(&self, credential: &[u8]) -> [u8; 16] {
+        security::vnc_auth_response(&self.challenge, credential)
     }
 
-    pub(super) async fn write<S>(&self, writer: &mut S) -> Result<()>
+    pub(super) async fn write<S>(&self, writer: &mut S, response: &[u8; 16]) -> Result<()>
     where
         S: AsyncWrite + Unpin,
     {
-        let encrypted = security::des::encrypt(&self.challenge, &self.key);
-        writer.write_all(&encrypted).await?;
+        writer.write_all(response).await?;
         Ok(())
     }
 
@@ -158,3 +205,208 @@ impl AuthHelper {
         Ok(result.into())
     }
 }
+
+/// The subtypes of [SecurityType::VeNCrypt] that can be used
+///
+/// The anonymous TLS ones (TLSNone 257, TLSVnc 258, TLSPlain 259 & TLSSasl 264) are left out,
+///
+/// since rustls doesn't implement the anonymous Diffie-Hellman
+///
+// the x509 ones are only chosen with a tls config
+#[cfg_attr(not(all(feature = "tls", feature = "sasl")), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub(super) enum VeNCryptSubtype {
+    Plain = 256,
+    X509None = 260,
+    X509Vnc = 261,
+    X509Plain = 262,
+    X509Sasl = 263,
+}
+
+impl VeNCryptSubtype {
+    /// Whether the stream is upgraded to TLS before the authentication
+    pub(super) fn needs_tls(&self) -> bool {
+        !matches!(self, VeNCryptSubtype::Plain)
+    }
+
+    /// Whether a username & a password are sent
+    pub(super) fn is_plain(&self) -> bool {
+        matches!(self, VeNCryptSubtype::Plain | VeNCryptSubtype::X509Plain)
+    }
+
+    /// Negotiate the version of [SecurityType::VeNCrypt], return the subtypes offered by the server
+    pub(super) async fn read<S>(stream: &mut S) -> Result<Vec<u32>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let major = stream.read_u8().await?;
+        let minor = stream.read_u8().await?;
+        if (major, minor) < (0, 2) {
+            return Err(VncError::Custom(format!(
+                "VeNCrypt version {}.{} is not supported",
+                major, minor
+            ))
+            .into());
+        }
+        stream.write_all(&[0, 2]).await?;
+        if stream.read_u8().await? != 0 {
+            return Err(VncError::Custom("VeNCrypt version 0.2 is rejected".to_string()).into());
+        }
+        let num = stream.read_u8().await?;
+        let mut subtypes = Vec::with_capacity(num as usize);
+        for _ in 0..num {
+            subtypes.push(stream.read_u32().await?);
+        }
+        tracing::trace!("Server supported VeNCrypt subtypes: {:?}", subtypes);
+        Ok(subtypes)
+    }
+
+    /// Whether [SecurityType::GtkVncSasl] follows the TLS handshake
+    pub(super) fn is_sasl(&self) -> bool {
+        matches!(self, VeNCryptSubtype::X509Sasl)
+    }
+
+    /// Write the credentials of the plain subtypes, which are protected by TLS if any
+    pub(super) async fn write_plain<S>(
+        writer: &mut S,
+        username: &str,
+        password: &[u8],
+    ) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        writer.write_u32(username.len() as u32).await?;
+        writer.write_u32(password.len() as u32).await?;
+        writer.write_all(username.as_bytes()).await?;
+        writer.write_all(password).await?;
+        Ok(())
+    }
+}
+
+/// A step of [SecurityType::GtkVncSasl], the data from the server & whether it is done
+#[cfg(feature = "sasl")]
+pub(super) struct SaslStep {
+    pub(super) data: Vec<u8>,
+    pub(super) complete: bool,
+}
+
+#[cfg(feature = "sasl")]
+impl SaslStep {
+    /// The comma separated mechanisms offered by the server
+    pub(super) async fn read_mechanisms<S>(reader: &mut S) -> Result<Vec<String>>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let len = reader.read_u32().await? as usize;
+        if len > 4096 {
+            return Err(VncError::Custom("SASL mechanism list is too long".to_string()).into());
+        }
+        let mut mechanisms = vec![0; len];
+        reader.read_exact(&mut mechanisms).await?;
+        let mechanisms = String::from_utf8_lossy(&mechanisms);
+        tracing::trace!("Server supported SASL mechanisms: {}", mechanisms);
+        Ok(mechanisms.split(',').map(str::to_owned).collect())
+    }
+
+    /// Start with `mechanism` & the initial response
+    pub(super) async fn start<S>(writer: &mut S, mechanism: &str, data: &[u8]) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        writer.write_u32(mechanism.len() as u32).await?;
+        writer.write_all(mechanism.as_bytes()).await?;
+        Self::write(writer, data).await
+    }
+
+    /// The data of the client is nul terminated, or nothing if empty
+    pub(super) async fn write<S>(writer: &mut S, data: &[u8]) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        if data.is_empty() {
+            writer.write_u32(0).await?;
+        } else {
+            writer.write_u32(data.len() as u32 + 1).await?;
+            writer.write_all(data).await?;
+            writer.write_u8(0).await?;
+        }
+        writer.flush().await?;
+        Ok(())
+    }
+
+    pub(super) async fn read<S>(reader: &mut S) -> Result<Self>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let len = reader.read_u32().await? as usize;
+        if len > 1 << 16 {
+            return Err(VncError::Custom("SASL data is too long".to_string()).into());
+        }
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data).await?;
+        if data.last() == Some(&0) {
+            data.pop();
+        }
+        let complete = reader.read_u8().await? != 0;
+        Ok(Self { data, complete })
+    }
+}
+
+/// The Diffie-Hellman parameters sent by the server for [SecurityType::Ard]
+#[cfg(feature = "apple")]
+pub(super) struct ArdHelper {
+    generator: u16,
+    prime: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+#[cfg(feature = "apple")]
+impl ArdHelper {
+    pub(super) async fn read<S>(reader: &mut S) -> Result<Self>
+    where
+        S: AsyncRead + Unpin,
+    {
+        // +--------------+--------------+-------------------+
+        // | No. of bytes | Type [Value] | Description       |
+        // +--------------+--------------+-------------------+
+        // | 2            | U16          | generator         |
+        // | 2            | U16          | key-length        |
+        // | key-length   | U8 array     | prime-modulus     |
+        // | key-length   | U8 array     | server-public-key |
+        // +--------------+--------------+-------------------+
+        let generator = reader.read_u16().await?;
+        let key_len = reader.read_u16().await? as usize;
+        let mut prime = vec![0; key_len];
+        reader.read_exact(&mut prime).await?;
+        let mut server_key = vec![0; key_len];
+        reader.read_exact(&mut server_key).await?;
+        Ok(Self {
+            generator,
+            prime,
+            server_key,
+        })
+    }
+
+    /// Write the encrypted credentials followed by the public key of the client
+    pub(super) async fn write<S>(
+        &self,
+        writer: &mut S,
+        username: &str,
+        password: &[u8],
+    ) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let (credentials, public_key) = security::ard_response(
+            self.generator,
+            &self.prime,
+            &self.server_key,
+            username,
+            password,
+        )?;
+        writer.write_all(&credentials).await?;
+        writer.write_all(&public_key).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+pub(super) type BellFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+type BellCallback = Box<dyn Fn() -> BellFuture + Send + Sync>;
+
+/// Run the callback given to [crate::VncConnector::set_bell_handler] upon the bells
+///
+/// The bells within `debounce` since the latest run are swallowed
+///
+pub(super) struct BellNotifier {
+    callback: BellCallback,
+    debounce: Duration,
+    last: Option<Instant>,
+}
+
+impl BellNotifier {
+    pub(super) fn new<F, Fut>(debounce: Duration, callback: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            callback: Box::new(move || Box::pin(callback())),
+            debounce,
+            last: None,
+        }
+    }
+
+    /// The future to be spawned for a bell at `now`, unless debounced
+    ///
+    /// Every bell is run without a clock, i.e. `now` is `None`
+    ///
+    pub(super) fn ring(&mut self, now: Option<Instant>) -> Option<BellFuture> {
+        if let Some(now) = now {
+            if matches!(self.last, Some(last) if now.duration_since(last) < self.debounce) {
+                return None;
+            }
+            self.last = Some(now);
+        }
+        Some((self.callback)())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[tokio::test]
+    async fn test_bell_debounce() {
+        let rings = Arc::new(AtomicUsize::new(0));
+        let counter = rings.clone();
+        let mut notifier = BellNotifier::new(Duration::from_millis(100), move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let start = Instant::now();
+        for offset in [0, 30, 99, 100, 150, 250] {
+            if let Some(future) = notifier.ring(Some(start + Duration::from_millis(offset))) {
+                future.await;
+            }
+        }
+        // rung at 0, 100 & 250
+        assert_eq!(rings.load(Ordering::Relaxed), 3);
+
+        // not debounced without a clock
+        for _ in 0..2 {
+            if let Some(future) = notifier.ring(None) {
+                future.await;
+            }
+        }
+        assert_eq!(rings.load(Ordering::Relaxed), 5);
+    }
+}
@@ -1,24 +1,64 @@
 use anyhow::{Ok, Result};
 
-use std::vec;
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::Duration,
+    vec,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use tokio::task::{AbortHandle, JoinSet};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    sync::mpsc::{Receiver, Sender},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, BufReader},
+    sync::{
+        mpsc::{channel, error::TryRecvError, Receiver, Sender},
+        oneshot, watch, Mutex,
+    },
 };
-use tracing::{info, trace};
+use tracing::{error, info, trace, warn};
 
-use crate::{codec, PixelFormat, Rect, VncEncoding, VncEvent, X11Event};
+use crate::{
+    codec,
+    proto::messages::{read_screen, ClientMsg, ServerMsg},
+    ByteOrder, ContentMode, CursorState, DecodeStrictness, DeltaCompression, EchoedInput,
+    ExtendedDesktopSize, ExtensionEvent, JpegPolicy, LockKeys, Magnifier, MessageLength,
+    NamePolicy, PixelFormat, PointerPolicy, ProtocolLogging, Rect, ResizeStatus, Screen,
+    ScreenLayout, Transform, UnknownMessagePolicy, VncEncoding, VncError, VncEvent, X11Event,
+};
 
-use super::messages::{ClientMsg, ServerMsg};
+#[cfg(feature = "bell")]
+use super::bell::BellNotifier;
+#[cfg(feature = "trace")]
+use super::trace::{Counted, SharedTracer, Tracer};
+use super::{
+    delta::DeltaEncoder,
+    echo::EchoTracker,
+    framebuffer::{self, Framebuffer},
+    handoff::{DetachedSession, STATEFUL_ENCODINGS},
+    layout::KeyboardLayout,
+    magnifier::Lens,
+    protocol_log::ProtocolLog,
+    report::{self, DebugReport, FrameTiming},
+    session::SessionInfo,
+    transport::{self, Timed, Transport},
+    writer::{self, Flushed, Outgoing},
+};
 
 struct ImageRect {
     rect: Rect,
     encoding: VncEncoding,
 }
 
-impl From<[u8; 12]> for ImageRect {
-    fn from(buf: [u8; 12]) -> Self {
-        Self {
+impl TryFrom<[u8; 12]> for ImageRect {
+    type Error = VncError;
+    fn try_from(buf: [u8; 12]) -> Result<Self, Self::Error> {
+        std::result::Result::Ok(Self {
             rect: Rect {
                 x: (buf[0] as u16) << 8 | buf[1] as u16,
                 y: (buf[2] as u16) << 8 | buf[3] as u16,
@@ -29,241 +69,4528 @@ impl From<[u8; 12]> for ImageRect {
                 | (buf[9] as u32) << 16
                 | (buf[10] as u32) << 8
                 | (buf[11] as u32))
-                .into(),
-        }
+                .try_into()?,
+        })
     }
 }
 
 impl ImageRect {
     async fn read<S>(reader: &mut S) -> Result<Self>
     where
-        S: AsyncRead + Unpin,
+        S: AsyncBufRead + Unpin,
     {
         let mut rect_buf = [0_u8; 12];
-        reader.read_exact(&mut rect_buf).await?;
-        Ok(rect_buf.into())
+        // most headers have been buffered along with the previous rect
+        if let Some(head) = reader.fill_buf().await?.get(..12) {
+            rect_buf.copy_from_slice(head);
+            reader.consume(12);
+        } else {
+            reader.read_exact(&mut rect_buf).await?;
+        }
+        Ok(rect_buf.try_into()?)
     }
 }
 
-/// The instance of a connected vnc client
-pub struct VncClient<S>
+/// What the client handle passes to the protocol engine, in order
+enum Input {
+    Event(X11Event),
+    /// Answered with the waiter of the messages queued before
+    Flush(oneshot::Sender<Flushed>),
+    /// Answered at the next message boundary, after which the engine stops
+    Detach(oneshot::Sender<Detached>),
+    /// Answered with the waiter of the shutdown, at the next message boundary if set
+    Close(bool, oneshot::Sender<Flushed>),
+}
+
+/// The state taken out of the engine by [VncClient::detach], i.e. the pixel format,
+///
+/// the resolution and the bytes read from the server but not handled yet
+///
+type Detached = Result<(PixelFormat, (u16, u16), Vec<u8>)>;
+
+/// Summary of the input recorded into the debug report, without the content of key events
+fn describe_input(event: &X11Event) -> String {
+    match event {
+        X11Event::KeyEvent(key) => format!("KeyEvent(down: {})", key.down),
+        X11Event::ExtendedKeyEvent { down, .. } => format!("ExtendedKeyEvent(down: {})", down),
+        X11Event::CopyText(text) => format!("CopyText({} bytes)", text.len()),
+        event => format!("{:?}", event),
+    }
+}
+
+/// Summary of the server message recorded into the debug report, without the clipboard content
+fn describe_server_msg(msg: &ServerMsg) -> String {
+    match msg {
+        ServerMsg::ServerCutText(text) => format!("ServerCutText({} bytes)", text.len()),
+        ServerMsg::SetColorMapEntries(first_color, colors) => {
+            format!(
+                "SetColorMapEntries({}, {} colors)",
+                first_color,
+                colors.len()
+            )
+        }
+        msg => format!("{:?}", msg),
+    }
+}
+
+/// The gii version implemented
+const GII_VERSION: u16 = 1;
+
+/// Find the next band of `rows` rows starting from `start` which is not covered by the `viewport`
+///
+/// Returns the band and where to start the next time
+fn next_prefetch_band(
+    screen: (u16, u16),
+    viewport: &Rect,
+    start: u16,
+    rows: u16,
+) -> Option<(Rect, u16)> {
+    let (width, height) = screen;
+    let covered = |band: &Rect| {
+        viewport.x == 0
+            && viewport.width >= width
+            && viewport.y <= band.y
+            && viewport.y as u32 + viewport.height as u32 >= band.y as u32 + band.height as u32
+    };
+    let mut y = if start < height { start } else { 0 };
+    for _ in 0..height.div_ceil(rows) {
+        let band = Rect {
+            x: 0,
+            y,
+            width,
+            height: rows.min(height - y),
+        };
+        y = if height - y > rows { y + rows } else { 0 };
+        if !covered(&band) {
+            return Some((band, y));
+        }
+    }
+    None
+}
+
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn displacement(dst: &Rect, src: &Rect) -> (i32, i32) {
+    (dst.x as i32 - src.x as i32, dst.y as i32 - src.y as i32)
+}
+
+async fn flush_copies(copies: &mut Vec<(Rect, Rect)>, sender: &Sender<VncEvent>) -> Result<()> {
+    match copies.len() {
+        0 => (),
+        1 => {
+            let (dst, src) = copies.pop().unwrap();
+            sender.send(VncEvent::Copy(dst, src)).await?;
+        }
+        _ => {
+            sender
+                .send(VncEvent::CopyBatch(std::mem::take(copies)))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Preallocate the decoders of the enabled encodings for a full screen update
+///
+/// So that the first update after a resize does not stall on the allocations
+///
+fn warm_up(
+    encodings: &[VncEncoding],
+    format: &PixelFormat,
+    screen: (u16, u16),
+    zlib: &mut codec::ZlibDecoder,
+    zrle: &mut codec::ZrleDecoder,
+    tight: &mut codec::TightDecoder,
+) {
+    let (width, height) = screen;
+    for encoding in encodings {
+        match encoding {
+            VncEncoding::Zlib => zlib.reserve(format, width, height),
+            VncEncoding::Zrle => zrle.reserve(),
+            VncEncoding::Tight | VncEncoding::TightPng => tight.reserve(width, height),
+            _ => (),
+        }
+    }
+}
+
+type EventFilter = Box<dyn Fn(&VncEvent) -> bool + Send>;
+
+struct Subscriber {
+    filter: EventFilter,
+    sender: Sender<VncEvent>,
+}
+
+type Subscribers = Arc<std::sync::Mutex<Vec<Subscriber>>>;
+
+type WakeFn = Box<dyn Fn() + Send>;
+
+/// Tell a non-async event loop that the events are ready, see [VncClient::register_waker]
+#[derive(Default)]
+struct Readiness {
+    waker: std::sync::Mutex<Option<WakeFn>>,
+    // whether the waker has been called since the latest drain
+    woken: AtomicBool,
+}
+
+impl Readiness {
+    fn wake(&self) {
+        if let Some(waker) = &*self.waker.lock().unwrap() {
+            if !self.woken.swap(true, Ordering::AcqRel) {
+                waker();
+            }
+        }
+    }
+}
+
+/// The output channel, which wakes the event loop registered if any
+struct EventOutput {
+    sender: Sender<VncEvent>,
+    readiness: Arc<Readiness>,
+}
+
+impl EventOutput {
+    async fn send(&self, event: VncEvent) -> Result<()> {
+        self.sender.send(event).await?;
+        self.readiness.wake();
+        Ok(())
+    }
+}
+
+/// Forward the events generated by the engine to the subscribers and the output channel
+///
+/// The images inside of the magnified region are followed by a [VncEvent::Magnified]
+///
+/// The events are mapped to the local display by the `transform`, except the magnified ones
+///
+/// then composed into the `framebuffer` and collected into [VncEvent::FrameDelta] if set
+async fn dispatch(
+    mut events: Receiver<VncEvent>,
+    output: &EventOutput,
+    subscribers: &Subscribers,
+    lens: &SharedLens,
+    transform: Transform,
+    mut framebuffer: Option<Framebuffer>,
+    mut delta: Option<DeltaEncoder>,
+) {
+    // the remote resolution
+    let mut screen = (0, 0);
+    while let Some(event) = events.recv().await {
+        let magnified = match (&event, *lens.lock().unwrap()) {
+            (VncEvent::RawImage(rect, pixels), Some(lens)) => lens.magnify(rect, pixels),
+            _ => None,
+        };
+        let mut event = transform.event(event, &mut screen);
+        let mut repainted = Vec::new();
+        if let Some(framebuffer) = framebuffer.as_mut() {
+            if let VncEvent::PaletteUpdated(range, _) = &event {
+                repainted = framebuffer.repaint(range);
+            }
+            event = framebuffer.apply(event);
+        }
+        let events = std::iter::once(event)
+            .chain(repainted.into_iter().map(VncEvent::Damage))
+            .flat_map(|event| {
+                let (finished, event) = match delta.as_mut() {
+                    Some(delta) => delta.apply(event),
+                    None => (None, Some(event)),
+                };
+                finished.into_iter().chain(event)
+            });
+        for event in events.chain(magnified) {
+            subscribers.lock().unwrap().retain(|subscriber| {
+                if (subscriber.filter)(&event) {
+                    // drop the event rather than blocking the engine if the subscriber lags
+                    !matches!(
+                        subscriber.sender.try_send(event.clone()),
+                        Err(tokio::sync::mpsc::error::TrySendError::Closed(_))
+                    )
+                } else {
+                    !subscriber.sender.is_closed()
+                }
+            });
+            if output.send(event).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+type SharedLens = Arc<std::sync::Mutex<Option<Lens>>>;
+
+/// The state shared by the tasks of the engine
+struct EngineState {
+    stop: watch::Sender<bool>,
+    error: Arc<std::sync::Mutex<Option<anyhow::Error>>>,
+    report: Arc<std::sync::Mutex<DebugReport>>,
+    reason: transport::SharedReason,
+    readiness: Arc<Readiness>,
+}
+
+impl EngineState {
+    /// Record the first error, and stop the reader & the writer
+    fn fail(&self, e: anyhow::Error) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            let e = transport::with_reason(e, &self.reason);
+            error!("Vnc engine stopped with error: {:?}", e);
+            self.report.lock().unwrap().last_error = Some(format!("{:?}", e));
+            *error = Some(e);
+        }
+        self.stop.send_replace(true);
+    }
+
+    async fn stopped(&self) {
+        let mut stop = self.stop.subscribe();
+        let _ = stop.wait_for(|stopped| *stopped).await;
+    }
+}
+
+/// Fails the engine if a task is dropped before finishing, i.e. aborted or panicked
+struct TaskGuard {
+    name: &'static str,
+    engine: Arc<EngineState>,
+    finished: bool,
+}
+
+impl TaskGuard {
+    fn new(name: &'static str, engine: Arc<EngineState>) -> Self {
+        Self {
+            name,
+            engine,
+            finished: false,
+        }
+    }
+
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            let e = VncError::Custom(format!("The {} task of the engine is aborted", self.name));
+            self.engine.fail(e.into());
+            self.engine.readiness.wake();
+        }
+    }
+}
+
+/// Run the reader or the writer until it finishes or the engine fails
+///
+/// `keep` is dropped after the error has been recorded
+///
+async fn supervise<T>(
+    name: &'static str,
+    engine: Arc<EngineState>,
+    task: impl Future<Output = Result<()>>,
+    keep: T,
+) {
+    let guard = TaskGuard::new(name, engine.clone());
+    let result = tokio::select! {
+        result = task => result,
+        _ = engine.stopped() => Ok(()),
+    };
+    if let Err(e) = result {
+        engine.fail(e);
+    }
+    drop(keep);
+    guard.finish();
+}
+
+/// The state maintained by the engine which can be watched by the client
+struct Watchers {
+    screen: watch::Sender<Screen>,
+    pixel_format: watch::Sender<Option<PixelFormat>>,
+    pointer: watch::Sender<(u16, u16)>,
+    cursor: watch::Sender<CursorState>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn<F>(task: F)
 where
-    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future<Output = ()> + Send + 'static,
 {
-    stream: S,
-    shared: bool,
-    pixel_format: Option<PixelFormat>,
-    name: String,
-    encodings: Vec<VncEncoding>,
-    screen: (u16, u16),
+    tokio::spawn(task);
 }
 
-impl<S> VncClient<S>
+#[cfg(target_arch = "wasm32")]
+fn spawn<F>(task: F)
 where
-    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future<Output = ()> + 'static,
 {
-    pub(super) fn new(
-        stream: S,
-        shared: bool,
-        pixel_format: Option<PixelFormat>,
-        encodings: Vec<VncEncoding>,
-    ) -> Self {
-        Self {
-            stream,
-            shared,
-            pixel_format,
-            name: String::new(),
-            encodings,
-            screen: (0, 0),
-        }
+    wasm_bindgen_futures::spawn_local(task);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_local<F>(task: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    tokio::task::spawn_local(task);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn spawn_local<F>(task: F)
+where
+    F: Future<Output = ()> + 'static,
+{
+    wasm_bindgen_futures::spawn_local(task);
+}
+
+/// The tasks of the engine spawned by [super::connector::VncState::try_start_in]
+///
+/// Once any of them fails, panics or is aborted, the others stop as well
+///
+/// and the client reports the error as if the connection had failed
+///
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug)]
+pub struct VncTasks {
+    reader: AbortHandle,
+    writer: AbortHandle,
+    housekeeping: AbortHandle,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl VncTasks {
+    /// The task reading the server messages and handling the inputs
+    ///
+    pub fn reader(&self) -> &AbortHandle {
+        &self.reader
     }
 
+    /// The task writing the client messages
     ///
-    /// Run the vnc engine
+    pub fn writer(&self) -> &AbortHandle {
+        &self.writer
+    }
+
+    /// The task dispatching the events to the client and the subscribers
     ///
-    /// Which will poll the data from the server and send output via `sender`
+    pub fn housekeeping(&self) -> &AbortHandle {
+        &self.housekeeping
+    }
+
+    /// Abort all of the tasks, the client is disconnected
     ///
-    /// Also poll the user input from the `recv`
+    pub fn abort(&self) {
+        self.reader.abort();
+        self.writer.abort();
+        self.housekeeping.abort();
+    }
+
+    /// Whether all of the tasks have finished
     ///
-    pub async fn run(
-        mut self,
-        sender: Sender<VncEvent>,
-        mut recv: Receiver<X11Event>,
-    ) -> Result<()> {
-        trace!("client init msg");
-        self.send_client_init().await?;
-        trace!("server init msg");
-        self.read_server_init(&sender).await?;
-        trace!("client encodings: {:?}", self.encodings);
-        self.send_client_encoding().await?;
-        trace!("Require the first frame");
-        ClientMsg::FramebufferUpdateRequest(
-            Rect {
-                x: 0,
-                y: 0,
-                width: self.screen.0,
-                height: self.screen.1,
-            },
-            0,
-        )
-        .write(&mut self.stream)
-        .await?;
+    pub fn is_finished(&self) -> bool {
+        self.reader.is_finished() && self.writer.is_finished() && self.housekeeping.is_finished()
+    }
+}
 
-        trace!("Start main loop");
-        let mut raw_decoder = codec::RawDecoder::new();
-        let mut zrle_decoder = codec::ZrleDecoder::new();
-        let mut tight_decoder = codec::TightDecoder::new();
-        let mut trle_decoder = codec::TrleDecoder::new();
-        let mut cursor = codec::CursorDecoder::new();
-        let pf = self.pixel_format.as_ref().unwrap();
-        loop {
-            tokio::select! {
-                server_msg = ServerMsg::read(&mut self.stream) => {
-                    let server_msg = server_msg?;
-                    trace!("Server message got: {:?}", server_msg);
-                    match server_msg {
-                        ServerMsg::FramebufferUpdate(rect_num) => {
-                            for _ in 0..rect_num {
-                                let rect = ImageRect::read(&mut self.stream).await?;
-                                trace!("Encoding: {:?}", rect.encoding);
+/// The instance of a connected vnc client
+///
+/// The protocol engine runs in a background task once connected,
+///
+/// use [VncClient::input] to send commands to the server
+///
+/// and [VncClient::recv_event] or [VncClient::poll_event] to get the output
+///
+pub struct VncClient {
+    input: Sender<Input>,
+    pub(super) output: Mutex<Receiver<VncEvent>>,
+    readiness: Arc<Readiness>,
+    subscribers: Subscribers,
+    pub(super) error: Arc<std::sync::Mutex<Option<anyhow::Error>>>,
+    screen: watch::Receiver<Screen>,
+    pixel_format: watch::Receiver<Option<PixelFormat>>,
+    pointer: watch::Receiver<(u16, u16)>,
+    cursor: watch::Receiver<CursorState>,
+    report: Arc<std::sync::Mutex<DebugReport>>,
+    protocol_log: Arc<ProtocolLog>,
+    session: SessionInfo,
+}
 
-                                match rect.encoding {
-                                    VncEncoding::Raw => {
-                                        raw_decoder.decode(pf, &rect.rect, &mut self.stream, &sender).await?;
-                                    }
-                                    VncEncoding::CopyRect => {
-                                        let source_x = self.stream.read_u16().await?;
-                                        let source_y = self.stream.read_u16().await?;
-                                        let mut src_rect = rect.rect;
-                                        src_rect.x = source_x;
-                                        src_rect.y = source_y;
-                                        sender.send(VncEvent::Copy(rect.rect, src_rect)).await?;
-                                    }
-                                    VncEncoding::Tight => {
-                                        tight_decoder.decode(pf, &rect.rect, &mut self.stream, &sender).await?;
-                                    }
-                                    VncEncoding::Trle => {
-                                        trle_decoder.decode(pf, &rect.rect, &mut self.stream, &sender).await?;
-                                    }
-                                    VncEncoding::Zrle => {
-                                        zrle_decoder.decode(pf, &rect.rect, &mut self.stream, &sender).await?;
-                                    }
-                                    VncEncoding::CursorPseudo => {
-                                        cursor.decode(pf, &rect.rect, &mut self.stream, &sender).await?;
-                                    }
-                                    VncEncoding::DesktopSizePseudo => {
-                                        sender.send(VncEvent::SetResolution((rect.rect.width, rect.rect.height).into())).await?;
-                                    }
-                                }
-                            }
-                        }
-                        // SetColorMapEntries,
-                        ServerMsg::Bell => {
-                            sender.send(VncEvent::Bell).await?;
-                        }
-                        ServerMsg::ServerCutText(text) => {
-                            sender.send(VncEvent::Text(text)).await?;
-                        }
-                    }
-                }
-                x11_event = recv.recv() => {
-                    if let Some(x11_event) = x11_event {
-                        match x11_event {
-                            X11Event::Refresh => {
-                                ClientMsg::FramebufferUpdateRequest(
-                                    Rect {
-                                        x: 0,
-                                        y: 0,
-                                        width: self.screen.0,
-                                        height: self.screen.1,
-                                    },
-                                    1,
-                                )
-                                .write(&mut self.stream)
-                                .await?;
-                            },
-                            X11Event::KeyEvent(key) => {
-                                ClientMsg::KeyEvent(key.keycode, key.down).write(&mut self.stream).await?;
-                            },
-                            X11Event::PointerEvent(mouse) => {
-                                ClientMsg::PointerEvent(mouse.position_x, mouse.position_y, mouse.bottons).write(&mut self.stream).await?;
-                            },
-                            X11Event::CopyText(text) => {
-                                ClientMsg::ClientCutText(text).write(&mut self.stream).await?;
-                            },
-                        }
-                    }
-                }
-            }
+impl VncClient {
+    pub(super) async fn new<S>(stream: S, mut config: ClientConfig) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        #[cfg(not(target_arch = "wasm32"))]
+        let runtime = config.decode_runtime.take();
+        let (client, reader, writer, housekeeping) = Self::connect(stream, config).await?;
+        let engine = async {
+            tokio::join!(reader, writer, housekeeping);
+        };
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(runtime) = runtime {
+            runtime.spawn(engine);
+            return Ok(client);
         }
+        spawn(engine);
+        Ok(client)
     }
 
-    async fn send_client_init(&mut self) -> Result<()> {
-        info!("Send shared flag: {}", self.shared);
-        self.stream.write_u8(self.shared as u8).await?;
-        Ok(())
+    /// Same as [VncClient::new] but the engine is spawned onto the current thread
+    pub(super) async fn new_local<S>(stream: S, config: ClientConfig) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        #[cfg(not(target_arch = "wasm32"))]
+        if config.decode_runtime.is_some() {
+            warn!("The local engine can't be moved to the decode runtime, decoding inline");
+        }
+        let (client, reader, writer, housekeeping) = Self::connect(stream, config).await?;
+        spawn_local(async {
+            tokio::join!(reader, writer, housekeeping);
+        });
+        Ok(client)
     }
 
-    async fn read_server_init(&mut self, sender: &Sender<VncEvent>) -> Result<()> {
-        // +--------------+--------------+------------------------------+
-        // | No. of bytes | Type [Value] | Description                  |
-        // +--------------+--------------+------------------------------+
-        // | 2            | U16          | framebuffer-width in pixels  |
-        // | 2            | U16          | framebuffer-height in pixels |
-        // | 16           | PIXEL_FORMAT | server-pixel-format          |
-        // | 4            | U32          | name-length                  |
-        // | name-length  | U8 array     | name-string                  |
-        // +--------------+--------------+------------------------------+
+    /// Same as [VncClient::new] but the tasks of the engine are spawned onto `set` separately
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(super) async fn new_in<S>(
+        stream: S,
+        mut config: ClientConfig,
+        set: &mut JoinSet<()>,
+    ) -> Result<(Self, VncTasks)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let runtime = config.decode_runtime.take();
+        let (client, reader, writer, housekeeping) = Self::connect(stream, config).await?;
+        let runtime = runtime.unwrap_or_else(tokio::runtime::Handle::current);
+        let tasks = VncTasks {
+            reader: set.spawn_on(reader, &runtime),
+            writer: set.spawn_on(writer, &runtime),
+            housekeeping: set.spawn_on(housekeeping, &runtime),
+        };
+        Ok((client, tasks))
+    }
 
-        let screen_width = self.stream.read_u16().await?;
-        let screen_height = self.stream.read_u16().await?;
-        let mut send_our_pf = false;
+    /// Initialize the session, returns the client and the tasks of the engine to be spawned,
+    ///
+    /// i.e. the reader, the writer and the housekeeping
+    ///
+    async fn connect<S>(
+        stream: S,
+        config: ClientConfig,
+    ) -> Result<(
+        Self,
+        impl Future<Output = ()>,
+        impl Future<Output = ()>,
+        impl Future<Output = ()>,
+    )>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
+        let (screen_sender, screen) = watch::channel(Screen::default());
+        let (pixel_format_sender, pixel_format_receiver) = watch::channel(None);
+        let (pointer_sender, pointer) = watch::channel((0, 0));
+        let (cursor_sender, cursor) = watch::channel(CursorState::default());
+        let report = Arc::new(std::sync::Mutex::new(DebugReport::default()));
+        let protocol_log = Arc::new(ProtocolLog::default());
+        let (stream, reason) = Transport::new(stream);
+        let (reader, writer) = tokio::io::split(stream);
+        let reader = Timed::new(reader, config.read_timeout);
+        let read_armed = reader.armed();
+        // the headers & the small fields are served from the buffer instead of one syscall each,
+        // while the reads larger than the buffer still go to the stream directly
+        let reader = BufReader::with_capacity(READ_BUFFER, reader);
+        #[cfg(feature = "trace")]
+        let mut config = config;
+        #[cfg(feature = "trace")]
+        let tracer = config
+            .trace
+            .take()
+            .map(|tracer| Arc::new(std::sync::Mutex::new(tracer)));
+        #[cfg(feature = "trace")]
+        let reader = Counted::new(reader, tracer.as_ref());
+        let (outgoing, writer) = writer::writer(
+            writer,
+            config.outgoing_queue,
+            config.pointer_policy,
+            report.clone(),
+            protocol_log.clone(),
+            #[cfg(feature = "trace")]
+            tracer.clone(),
+        );
+        let mut writer = Box::pin(writer);
+        let mut inner = VncInner::new(
+            reader,
+            outgoing,
+            config,
+            Watchers {
+                screen: screen_sender,
+                pixel_format: pixel_format_sender,
+                pointer: pointer_sender,
+                cursor: cursor_sender,
+            },
+            report.clone(),
+            protocol_log.clone(),
+            read_armed,
+        );
+        #[cfg(feature = "trace")]
+        {
+            inner.tracer = tracer;
+        }
+        let (input_sender, input_receiver) = channel(100);
+        let (event_sender, event_receiver) = channel(100);
+        let (output_sender, output_receiver) = channel(100);
+        let subscribers = Subscribers::default();
+        let readiness = Arc::new(Readiness::default());
+        let error = Arc::new(std::sync::Mutex::new(None));
 
-        sender
-            .send(VncEvent::SetResolution(
-                (screen_width, screen_height).into(),
-            ))
-            .await?;
-        self.screen = (screen_width, screen_height);
+        // the writer has to make progress while initializing
+        let session = tokio::select! {
+            session = inner.init(&event_sender) => session,
+            result = &mut writer => result.and(Err(VncError::ClientNotRunning.into())),
+        };
+        // the servers close the stream after ClientInit to deny the access
+        let session = session.map_err(|e| {
+            transport::displaced(&reason);
+            transport::with_reason(e, &reason)
+        })?;
 
-        let pixel_format = PixelFormat::read(&mut self.stream).await?;
-        if self.pixel_format.is_none() {
-            sender.send(VncEvent::SetPixelFormat(pixel_format)).await?;
-            self.pixel_format = Some(pixel_format);
-        } else {
-            send_our_pf = true;
-        }
+        let engine_subscribers = subscribers.clone();
+        let engine_lens = inner.lens.clone();
+        let transform = inner.config.transform;
+        let bpp = inner.pixel_format.unwrap().output().bits_per_pixel as usize / 8;
+        let framebuffer = inner
+            .config
+            .framebuffer
+            .take()
+            .map(|memory| Framebuffer::new(memory, bpp));
+        let delta = inner.config.frame_delta.map(DeltaEncoder::new);
+        let output = EventOutput {
+            sender: output_sender,
+            readiness: readiness.clone(),
+        };
+        let engine = Arc::new(EngineState {
+            stop: watch::channel(false).0,
+            error: error.clone(),
+            report: report.clone(),
+            reason,
+            readiness: readiness.clone(),
+        });
 
-        let name_len = self.stream.read_u32().await?;
-        let mut name_buf = vec![0_u8; name_len as usize];
-        self.stream.read_exact(&mut name_buf).await?;
-        self.name = String::from_utf8(name_buf)?;
+        // the engine stops if either the reader or the writer fails
+        let events = event_sender.clone();
+        let reader = supervise(
+            "reader",
+            engine.clone(),
+            inner.run(event_sender, input_receiver),
+            events,
+        );
+        let writer = supervise("writer", engine.clone(), writer, ());
+        // while the housekeeping dispatches the events left until the reader is gone
+        let housekeeping = async move {
+            let guard = TaskGuard::new("housekeeping", engine.clone());
+            dispatch(
+                event_receiver,
+                &output,
+                &engine_subscribers,
+                &engine_lens,
+                transform,
+                framebuffer,
+                delta,
+            )
+            .await;
+            // the output channel is closed after the error has been recorded
+            drop(output);
+            engine.readiness.wake();
+            guard.finish();
+        };
 
-        if send_our_pf {
-            info!(
-                "Send customized pixel format {:#?}",
-                self.pixel_format.as_ref().unwrap()
-            );
-            ClientMsg::SetPixelFormat(*self.pixel_format.as_ref().unwrap())
-                .write(&mut self.stream)
-                .await?;
+        let client = Self {
+            input: input_sender,
+            output: Mutex::new(output_receiver),
+            readiness,
+            subscribers,
+            error,
+            screen,
+            pixel_format: pixel_format_receiver,
+            pointer,
+            cursor,
+            report,
+            protocol_log,
+            session,
+        };
+        Ok((client, reader, writer, housekeeping))
+    }
+
+    /// Information collected while connecting
+    ///
+    pub fn session_info(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    /// Log the messages exchanged with the server, and the rects of the updates if [ProtocolLogging::Rects]
+    ///
+    /// The lines are logged at the info level, at most 100 lines per second, the rest are counted only
+    ///
+    /// So that it can be turned on for a while to catch a desync, without a build logging at the trace level
+    ///
+    pub fn set_protocol_logging(&self, level: ProtocolLogging) {
+        info!("Protocol logging: {:?}", level);
+        self.protocol_log.set_level(level);
+    }
+
+    /// Record the version string read by the connector before the session is initialized
+    pub(super) fn set_raw_server_version(&mut self, version: String) {
+        self.session.raw_server_version = version;
+    }
+
+    /// Send a command to the vnc server
+    ///
+    pub async fn input(&self, event: X11Event) -> Result<()> {
+        if self.input.send(Input::Event(event)).await.is_err() {
+            return Err(self.take_error());
         }
         Ok(())
     }
 
-    async fn send_client_encoding(&mut self) -> Result<()> {
-        ClientMsg::SetEncodings(self.encodings.clone())
-            .write(&mut self.stream)
-            .await?;
+    /// Wait until the messages generated by the previous inputs have been written to the server
+    ///
+    /// e.g. to make sure a key sequence is sent before dropping the client,
+    ///
+    /// which doesn't tell whether the server has processed them
+    ///
+    pub async fn flush_input(&self) -> Result<()> {
+        let (done, flushed) = oneshot::channel();
+        if self.input.send(Input::Flush(done)).await.is_err() {
+            return Err(self.take_error());
+        }
+        let std::result::Result::Ok(flushed) = flushed.await else {
+            return Err(self.take_error());
+        };
+        if flushed.wait().await.is_err() {
+            return Err(self.take_error());
+        }
         Ok(())
     }
-}
 
-impl<S> Drop for VncClient<S>
-where
-    S: AsyncRead + AsyncWrite + Unpin,
-{
-    fn drop(&mut self) {
-        trace!("Client closed");
+    /// Detach the session, to be resumed by [super::VncConnector::resume] in another process
+    ///
+    /// The engine stops at a message boundary once the queued messages are written,
+    ///
+    /// leaving the connection open, so the application must keep its own handle of the socket,
+    ///
+    /// e.g. a duplicate of the fd taken before the stream is given to the connector,
+    ///
+    /// which is passed to the new process along with [DetachedSession::to_bytes]
+    ///
+    /// ```no_run
+    /// use vnc::{DetachedSession, VncClient, VncConnector};
+    /// use tokio::net::TcpStream;
+    /// use anyhow::Result;
+    ///
+    /// // `socket` is a duplicate of the stream given to the connector of `vnc`
+    /// async fn hand_over(vnc: VncClient, socket: std::net::TcpStream) -> Result<VncClient> {
+    ///     let state = vnc.detach().await?.to_bytes();
+    ///     // in the new process, with the fd of `socket` and the `state` passed over
+    ///     socket.set_nonblocking(true)?;
+    ///     VncConnector::new(TcpStream::from_std(socket)?)
+    ///         .set_auth_method(|_| async move { Ok("password".into()) })
+    ///         .add_encoding(vnc::VncEncoding::Raw)
+    ///         .resume(DetachedSession::from_bytes(&state)?)
+    ///         .await
+    /// }
+    /// ```
+    ///
+    /// It fails if the session is over TLS or encrypted, or the zlib based encodings are enabled,
+    ///
+    /// whose state can't be taken over, and the engine keeps running in that case
+    ///
+    /// Otherwise the client is stopped as if disconnected,
+    ///
+    /// the events not received yet are dropped, and the state of the extensions,
+    ///
+    /// e.g. the continuous updates and the color map, are not carried over
+    ///
+    pub async fn detach(&self) -> Result<DetachedSession> {
+        let (done, detached) = oneshot::channel();
+        if self.input.send(Input::Detach(done)).await.is_err() {
+            return Err(self.take_error());
+        }
+        let std::result::Result::Ok(detached) = detached.await else {
+            return Err(self.take_error());
+        };
+        let (pixel_format, screen, pending) = detached?;
+        Ok(DetachedSession {
+            raw_server_version: self.session.raw_server_version.clone(),
+            server_pixel_format: self.session.server_pixel_format,
+            pixel_format,
+            screen,
+            name_bytes: self.session.name_bytes.clone(),
+            pending,
+        })
+    }
+
+    /// Close the session gracefully
+    ///
+    /// The inputs sent before are written to the server, e.g. the releases of the keys pressed,
+    ///
+    /// then the write half of the stream is shut down and the engine stops as if disconnected
+    ///
+    /// With `wait_update`, the FramebufferUpdate being decoded is finished and its events are delivered,
+    ///
+    /// otherwise the rest of it is dropped after the rect being decoded
+    ///
+    pub async fn close(&self, wait_update: bool) -> Result<()> {
+        let (done, closed) = oneshot::channel();
+        if self
+            .input
+            .send(Input::Close(wait_update, done))
+            .await
+            .is_err()
+        {
+            return Err(self.take_error());
+        }
+        let std::result::Result::Ok(closed) = closed.await else {
+            return Err(self.take_error());
+        };
+        if closed.wait().await.is_err() {
+            return Err(self.take_error());
+        }
+        Ok(())
+    }
+
+    /// Press and release `keysym`
+    ///
+    pub async fn tap_key(&self, keysym: u32) -> Result<()> {
+        self.input(X11Event::KeyEvent((keysym, true).into()))
+            .await?;
+        self.input(X11Event::KeyEvent((keysym, false).into())).await
+    }
+
+    /// Move the remote pointer to the absolute position `(x, y)` without pressing or releasing any button
+    ///
+    /// A [VncEvent::CursorPosition] is generated as well if `notify` is set,
+    ///
+    /// which is useful to let the local cursor follow a presenter in shared sessions
+    ///
+    pub async fn warp_pointer(&self, x: u16, y: u16, notify: bool) -> Result<()> {
+        self.input(X11Event::WarpPointer { x, y, notify }).await
+    }
+
+    /// Tell the engine whether the local window has the focus
+    ///
+    /// On the loss, the keys & the pointer buttons pressed are released,
+    ///
+    /// whose releases would be delivered to another window otherwise
+    ///
+    pub async fn notify_focus(&self, focused: bool) -> Result<()> {
+        let event = match focused {
+            true => X11Event::FocusIn(None),
+            false => X11Event::FocusOut,
+        };
+        self.input(event).await
+    }
+
+    /// Tell the engine that the local window gains the focus with the lock keys being `locks`
+    ///
+    /// The lock keys of the guest are toggled to match them, which requires the
+    ///
+    /// [VncEncoding::QemuLedStatePseudo] and a [VncEvent::LedState] received, otherwise nothing is toggled
+    ///
+    pub async fn notify_focus_with_locks(&self, locks: LockKeys) -> Result<()> {
+        self.input(X11Event::FocusIn(Some(locks))).await
+    }
+
+    /// Only require updates of `viewport` when refreshing, `None` for the whole screen
+    ///
+    /// Which saves bandwidth if only part of the remote desktop is displayed
+    ///
+    /// See [super::VncConnector::set_prefetch_rate] to keep the rest of the screen roughly up to date
+    ///
+    pub async fn set_viewport(&self, viewport: Option<Rect>) -> Result<()> {
+        self.input(X11Event::SetViewport(viewport)).await
+    }
+
+    /// Magnify the region around `magnifier.focus`, `None` to stop
+    ///
+    /// The images inside of the region are followed by a [VncEvent::Magnified],
+    ///
+    /// which is scaled by `magnifier.zoom` with the nearest neighbor to fit `magnifier.size`
+    ///
+    /// The region is refreshed on every [X11Event::Refresh] while the rest of the screen is left to
+    ///
+    /// [super::VncConnector::set_prefetch_rate], just like [VncClient::set_viewport]
+    ///
+    /// Note that the jpeg & png rects are not magnified
+    ///
+    pub async fn set_magnifier(&self, magnifier: Option<Magnifier>) -> Result<()> {
+        self.input(X11Event::SetMagnifier(magnifier)).await
+    }
+
+    /// Tune the encodings for crisp text, i.e. lossless with the highest compression
+    ///
+    /// The encodings are informed to the server again, see [ContentMode::Text]
+    ///
+    pub async fn prefer_text_mode(&self) -> Result<()> {
+        self.input(X11Event::SetContentMode(ContentMode::Text))
+            .await
+    }
+
+    /// Tune the encodings for motion, i.e. jpeg with the lowest compression
+    ///
+    /// The encodings are informed to the server again, see [ContentMode::Video]
+    ///
+    pub async fn prefer_video_mode(&self) -> Result<()> {
+        self.input(X11Event::SetContentMode(ContentMode::Video))
+            .await
+    }
+
+    /// Let the server send the updates of `rect` as soon as they happen,
+    ///
+    /// instead of waiting for a [X11Event::Refresh] after each update
+    ///
+    /// Requires [VncEncoding::ContinuousUpdatesPseudo] to be added to the connector,
+    ///
+    /// the request is held until the server confirms the support with a [VncEvent::EndOfContinuousUpdates]
+    ///
+    /// and is ignored if the server never does, so keep refreshing in that case
+    ///
+    pub async fn enable_continuous_updates(&self, rect: Rect) -> Result<()> {
+        self.input(X11Event::SetContinuousUpdates(Some(rect))).await
+    }
+
+    /// Stop the continuous updates, which is confirmed by a [VncEvent::EndOfContinuousUpdates]
+    ///
+    pub async fn disable_continuous_updates(&self) -> Result<()> {
+        self.input(X11Event::SetContinuousUpdates(None)).await
+    }
+
+    /// Request the server to resize the remote desktop, e.g. to match the local window
+    ///
+    /// An empty `screens` means a single screen covering the whole desktop
+    ///
+    /// Requires [VncEncoding::ExtendedDesktopSizePseudo] to be added to the connector,
+    ///
+    /// the request is ignored until the server sends a [VncEvent::ExtendedDesktopSize],
+    ///
+    /// which is also the answer telling whether the resize succeeded
+    ///
+    pub async fn set_desktop_size(
+        &self,
+        width: u16,
+        height: u16,
+        screens: Vec<ScreenLayout>,
+    ) -> Result<()> {
+        self.input(X11Event::SetDesktopSize(width, height, screens))
+            .await
+    }
+
+    /// Wait for the next event generated by the engine
+    ///
+    /// Returns the error which stopped the engine once all the events are consumed
+    ///
+    pub async fn recv_event(&self) -> Result<VncEvent> {
+        match self.output.lock().await.recv().await {
+            Some(event) => Ok(self.delivered(event)),
+            None => Err(self.take_error()),
+        }
+    }
+
+    /// Get an event generated by the engine without waiting
+    ///
+    /// Returns `Ok(None)` if there is no event pending
+    ///
+    pub async fn poll_event(&self) -> Result<Option<VncEvent>> {
+        match self.output.lock().await.try_recv() {
+            std::result::Result::Ok(event) => Ok(Some(self.delivered(event))),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(self.take_error()),
+        }
+    }
+
+    /// Call `waker` once some events are ready to be taken by [VncClient::drain_events]
+    ///
+    /// For the event loops which are not async, e.g. the GTK main loop or the Win32 message pump,
+    ///
+    /// the `waker` is supposed to post a message to the loop rather than to drain the events itself
+    ///
+    /// It is not called again until the events are drained, and once right away in case some are pending
+    ///
+    pub fn register_waker<F>(&self, waker: F)
+    where
+        F: Fn() + Send + 'static,
+    {
+        *self.readiness.waker.lock().unwrap() = Some(Box::new(waker));
+        self.readiness.woken.store(false, Ordering::Release);
+        self.readiness.wake();
+    }
+
+    /// Move the events pending into `events` without waiting, return how many have been moved
+    ///
+    /// Shouldn't be mixed with [VncClient::recv_event], which holds the events while waiting
+    ///
+    /// Fails once the engine stops and all the events have been drained
+    ///
+    pub fn drain_events(&self, events: &mut Vec<VncEvent>) -> Result<usize> {
+        // the events coming from now on wake again
+        self.readiness.woken.store(false, Ordering::Release);
+        let std::result::Result::Ok(mut output) = self.output.try_lock() else {
+            return Ok(0);
+        };
+        let mut drained = 0;
+        loop {
+            match output.try_recv() {
+                std::result::Result::Ok(event) => events.push(self.delivered(event)),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) if drained == 0 => return Err(self.take_error()),
+                Err(TryRecvError::Disconnected) => {
+                    // come back for the error
+                    self.readiness.wake();
+                    break;
+                }
+            }
+            drained += 1;
+        }
+        Ok(drained)
+    }
+
+    /// Wait for the next event which satisfies `predicate`
+    ///
+    /// Note that all the events before it are consumed and discarded
+    ///
+    /// So it is intended for tools which are only interested in some kinds of events
+    ///
+    pub async fn next_event_matching<P>(&self, mut predicate: P) -> Result<VncEvent>
+    where
+        P: FnMut(&VncEvent) -> bool,
+    {
+        loop {
+            let event = self.recv_event().await?;
+            if predicate(&event) {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Wait for the next [VncEvent::SetResolution], discarding the other events
+    ///
+    pub async fn next_resize(&self) -> Result<Screen> {
+        match self
+            .next_event_matching(|e| matches!(e, VncEvent::SetResolution(_)))
+            .await?
+        {
+            VncEvent::SetResolution(screen) => Ok(screen),
+            event => Err(VncError::Custom(format!("Unexpected event {:?}", event)).into()),
+        }
+    }
+
+    /// Wait for the next [VncEvent::Text], discarding the other events
+    ///
+    pub async fn next_clipboard(&self) -> Result<String> {
+        match self
+            .next_event_matching(|e| matches!(e, VncEvent::Text(_)))
+            .await?
+        {
+            VncEvent::Text(text) => Ok(text),
+            event => Err(VncError::Custom(format!("Unexpected event {:?}", event)).into()),
+        }
+    }
+
+    /// Subscribe a copy of the events which satisfy `filter`
+    ///
+    /// ```no_compile
+    /// let mut texts = vnc.subscribe_filtered(|e| matches!(e, VncEvent::Text(_)));
+    /// while let Some(VncEvent::Text(text)) = texts.recv().await {
+    ///     println!("Clipboard: {}", text);
+    /// }
+    /// ```
+    ///
+    /// The events are still delivered to [VncClient::recv_event] as well,
+    ///
+    /// which should keep being consumed, otherwise the engine will be blocked
+    ///
+    /// Events are dropped for a subscriber which doesn't keep up with the engine
+    ///
+    pub fn subscribe_filtered<P>(&self, filter: P) -> Receiver<VncEvent>
+    where
+        P: Fn(&VncEvent) -> bool + Send + 'static,
+    {
+        let (sender, receiver) = channel(100);
+        self.subscribers.lock().unwrap().push(Subscriber {
+            filter: Box::new(filter),
+            sender,
+        });
+        receiver
+    }
+
+    /// Watch the current resolution of the remote desktop
+    ///
+    /// Which is `0x0` until the server informs it
+    ///
+    pub fn watch_screen(&self) -> watch::Receiver<Screen> {
+        self.screen.clone()
+    }
+
+    /// Watch the pixel format that the image data is in
+    ///
+    /// Which is `None` until negotiated with the server
+    ///
+    pub fn watch_pixel_format(&self) -> watch::Receiver<Option<PixelFormat>> {
+        self.pixel_format.clone()
+    }
+
+    /// Watch the position of the remote pointer
+    ///
+    /// Which is updated by the pointer events sent to the server,
+    ///
+    /// and by the server as well if [VncEncoding::PointerPosPseudo] is set
+    ///
+    pub fn watch_pointer(&self) -> watch::Receiver<(u16, u16)> {
+        self.pointer.clone()
+    }
+
+    /// Watch the shape & the position of the remote cursor,
+    ///
+    /// to render it on a layer above the framebuffer
+    ///
+    /// Which stays at [CursorState::default] unless [super::VncConnector::set_cursor_layer] is enabled
+    ///
+    pub fn watch_cursor(&self) -> watch::Receiver<CursorState> {
+        self.cursor.clone()
+    }
+
+    /// A snapshot of the negotiated settings, per-encoding counters,
+    ///
+    /// the latest protocol events and the error which stopped the engine
+    ///
+    /// ```no_compile
+    /// println!("{}", vnc.debug_report());
+    /// ```
+    ///
+    /// The content of key events is never recorded
+    ///
+    pub fn debug_report(&self) -> DebugReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    pub(super) fn delivered(&self, event: VncEvent) -> VncEvent {
+        if let (VncEvent::UpdateComplete, Some(now)) = (&event, report::now()) {
+            self.report.lock().unwrap().frame_delivered(now);
+        }
+        event
+    }
+
+    fn take_error(&self) -> anyhow::Error {
+        self.error
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| VncError::ClientNotRunning.into())
+    }
+}
+
+/// Options of the engine, which are set up by [super::VncConnector]
+pub(super) struct ClientConfig {
+    pub(super) shared: bool,
+    pub(super) pixel_format: Option<PixelFormat>,
+    pub(super) encodings: Vec<VncEncoding>,
+    pub(super) pseudo_encodings: Vec<VncEncoding>,
+    pub(super) jpeg_policy: JpegPolicy,
+    pub(super) palette_cache: usize,
+    pub(super) batch_copy_rect: bool,
+    pub(super) rect_encodings: bool,
+    pub(super) input_echoes: bool,
+    pub(super) name_policy: NamePolicy,
+    pub(super) max_image_bytes: Option<usize>,
+    pub(super) prefetch_rate: Option<usize>,
+    pub(super) max_update_rects: Option<u16>,
+    pub(super) update_timeout: Option<Duration>,
+    pub(super) read_timeout: Option<Duration>,
+    // the interval of the probes and the window for the server to answer
+    pub(super) keepalive: Option<(Duration, Duration)>,
+    pub(super) max_decode_failures: Option<u32>,
+    pub(super) outgoing_queue: usize,
+    pub(super) pointer_policy: PointerPolicy,
+    pub(super) content_mode: ContentMode,
+    pub(super) preferred_resolution: Option<(u16, u16)>,
+    // only a pixel is required as the first frame
+    pub(super) health_check: bool,
+    pub(super) unknown_message_policy: UnknownMessagePolicy,
+    pub(super) message_lengths: HashMap<u8, MessageLength>,
+    pub(super) byte_order: ByteOrder,
+    pub(super) strictness: DecodeStrictness,
+    pub(super) key_auto_release: Option<Duration>,
+    pub(super) transform: Transform,
+    pub(super) keyboard_layout: Option<KeyboardLayout>,
+    pub(super) framebuffer: Option<framebuffer::Memory>,
+    pub(super) frame_delta: Option<DeltaCompression>,
+    pub(super) cursor_layer: bool,
+    // neither encrypted nor upgraded to TLS
+    pub(super) detachable: bool,
+    // taken over instead of the initialization messages
+    pub(super) resume: Option<DetachedSession>,
+    #[cfg(feature = "bell")]
+    pub(super) bell: Option<BellNotifier>,
+    #[cfg(feature = "trace")]
+    pub(super) trace: Option<Tracer>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(super) decode_runtime: Option<tokio::runtime::Handle>,
+}
+
+impl ClientConfig {
+    /// The encodings informed to the server
+    ///
+    /// The real encodings in the order of priority, followed by the pseudo encodings
+    ///
+    /// which are supported in the current configuration
+    ///
+    pub(super) fn wire_encodings(&self) -> Vec<VncEncoding> {
+        let mut encodings = self.encodings.clone();
+        let mut pseudo_encodings = self.pseudo_encodings.clone();
+        match self.content_mode {
+            ContentMode::Mixed => (),
+            ContentMode::Text => {
+                pseudo_encodings
+                    .retain(|e| !e.is_jpeg_quality_level() && !e.is_compression_level());
+                pseudo_encodings.push(VncEncoding::CompressionLevel9Pseudo);
+            }
+            ContentMode::Video => {
+                // stable, so the order of the others is kept
+                encodings.sort_by_key(|e| !matches!(e, VncEncoding::Tight));
+                pseudo_encodings.retain(|e| !e.is_compression_level());
+                if !pseudo_encodings.iter().any(|e| e.is_jpeg_quality_level()) {
+                    pseudo_encodings.push(VncEncoding::JpegQualityLevel5Pseudo);
+                }
+                pseudo_encodings.push(VncEncoding::CompressionLevel1Pseudo);
+            }
+        }
+        // resizes are always handled by the engine
+        if !pseudo_encodings.contains(&VncEncoding::DesktopSizePseudo) {
+            pseudo_encodings.push(VncEncoding::DesktopSizePseudo);
+        }
+        // the cursor layer is fed by the cursor shapes & the pointer positions of the server
+        if self.cursor_layer {
+            if !pseudo_encodings.iter().any(|e| {
+                matches!(
+                    e,
+                    VncEncoding::CursorPseudo
+                        | VncEncoding::CursorWithAlphaPseudo
+                        | VncEncoding::XCursorPseudo
+                )
+            }) {
+                pseudo_encodings.push(VncEncoding::CursorWithAlphaPseudo);
+                pseudo_encodings.push(VncEncoding::CursorPseudo);
+            }
+            if !pseudo_encodings.contains(&VncEncoding::PointerPosPseudo) {
+                pseudo_encodings.push(VncEncoding::PointerPosPseudo);
+            }
+        }
+        // the preferred resolution is requested once the server proves the support
+        if self.preferred_resolution.is_some()
+            && !pseudo_encodings.contains(&VncEncoding::ExtendedDesktopSizePseudo)
+        {
+            pseudo_encodings.push(VncEncoding::ExtendedDesktopSizePseudo);
+        }
+        // the jpeg quality levels only take effect with tight
+        if matches!(self.jpeg_policy, JpegPolicy::Disable)
+            || !encodings
+                .iter()
+                .any(|e| matches!(e, VncEncoding::Tight | VncEncoding::TightPng))
+        {
+            pseudo_encodings.retain(|e| !e.is_jpeg_quality_level());
+        }
+        encodings.extend(pseudo_encodings);
+        encodings
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            shared: true,
+            pixel_format: None,
+            encodings: Vec::new(),
+            pseudo_encodings: Vec::new(),
+            jpeg_policy: JpegPolicy::default(),
+            palette_cache: 0,
+            batch_copy_rect: false,
+            rect_encodings: false,
+            input_echoes: false,
+            name_policy: NamePolicy::default(),
+            max_image_bytes: None,
+            prefetch_rate: None,
+            max_update_rects: None,
+            update_timeout: None,
+            read_timeout: None,
+            keepalive: None,
+            max_decode_failures: None,
+            outgoing_queue: 64,
+            pointer_policy: PointerPolicy::default(),
+            content_mode: ContentMode::default(),
+            preferred_resolution: None,
+            health_check: false,
+            unknown_message_policy: UnknownMessagePolicy::default(),
+            message_lengths: HashMap::new(),
+            byte_order: ByteOrder::default(),
+            strictness: DecodeStrictness::default(),
+            key_auto_release: None,
+            transform: Transform::default(),
+            keyboard_layout: None,
+            framebuffer: None,
+            frame_delta: None,
+            cursor_layer: false,
+            detachable: false,
+            resume: None,
+            #[cfg(feature = "bell")]
+            bell: None,
+            #[cfg(feature = "trace")]
+            trace: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            decode_runtime: None,
+        }
+    }
+}
+
+/// How often a band outside of the viewport is prefetched
+const PREFETCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Bytes of pixels decoded between two yield points during a framebuffer update
+const YIELD_BUDGET: usize = 64 * 1024;
+
+/// Bytes staged from the server by a single read
+const READ_BUFFER: usize = 256 * 1024;
+
+struct VncInner<S>
+where
+    S: AsyncBufRead + Unpin,
+{
+    reader: S,
+    // the head of a server message split across reads
+    pending: Vec<u8>,
+    outgoing: Outgoing,
+    config: ClientConfig,
+    pixel_format: Option<PixelFormat>,
+    name: String,
+    screen: (u16, u16),
+    // buttons pressed by the latest pointer event
+    buttons: u8,
+    viewport: Option<Rect>,
+    // where the next prefetch band starts
+    prefetch_row: u16,
+    decode_failures: HashMap<VncEncoding, u32>,
+    // keys pressed with the scancodes if any
+    pressed_keys: HashMap<u32, Option<u32>>,
+    // keys pressed and when they are released automatically
+    held_keys: HashMap<u32, tokio::time::Instant>,
+    // the region requested to be updated continuously
+    continuous_updates: Option<Rect>,
+    // the server has confirmed the support of the continuous updates
+    continuous_updates_supported: bool,
+    // the server has acknowledged the QEMU extended key events
+    qemu_keys_supported: bool,
+    // the latest lock keys informed by the server
+    led_state: Option<LockKeys>,
+    // the gii version has been agreed with the server
+    gii_supported: bool,
+    // the xvp version of the server
+    xvp_version: Option<u8>,
+    // the latest layout, only known if the server supports the ExtendedDesktopSize
+    screens: Option<Vec<ScreenLayout>>,
+    echoes: Option<EchoTracker>,
+    // the client is waiting for the session to be detached
+    detaching: Option<oneshot::Sender<Detached>>,
+    // the client is waiting for the session to be closed, after the update being decoded if set
+    closing: Option<(bool, oneshot::Sender<Flushed>)>,
+    lens: SharedLens,
+    watchers: Watchers,
+    report: Arc<std::sync::Mutex<DebugReport>>,
+    protocol_log: Arc<ProtocolLog>,
+    // the read timeout applies, off while waiting for the next message
+    read_armed: Arc<AtomicBool>,
+    // when the latest message from the server was handled, only kept for the keepalive
+    last_seen: Option<tokio::time::Instant>,
+    #[cfg(feature = "trace")]
+    tracer: Option<SharedTracer>,
+}
+
+impl<S> VncInner<S>
+where
+    S: AsyncBufRead + Unpin,
+{
+    fn new(
+        reader: S,
+        outgoing: Outgoing,
+        config: ClientConfig,
+        watchers: Watchers,
+        report: Arc<std::sync::Mutex<DebugReport>>,
+        protocol_log: Arc<ProtocolLog>,
+        read_armed: Arc<AtomicBool>,
+    ) -> Self {
+        // the clock is not available on every target, e.g. wasm32
+        let last_seen = config.keepalive.map(|_| tokio::time::Instant::now());
+        Self {
+            reader,
+            pending: Vec::new(),
+            outgoing,
+            pixel_format: config.pixel_format,
+            echoes: config.input_echoes.then(EchoTracker::default),
+            config,
+            name: String::new(),
+            screen: (0, 0),
+            buttons: 0,
+            viewport: None,
+            prefetch_row: 0,
+            decode_failures: HashMap::new(),
+            pressed_keys: HashMap::new(),
+            held_keys: HashMap::new(),
+            continuous_updates: None,
+            continuous_updates_supported: false,
+            qemu_keys_supported: false,
+            led_state: None,
+            gii_supported: false,
+            xvp_version: None,
+            screens: None,
+            detaching: None,
+            closing: None,
+            lens: SharedLens::default(),
+            watchers,
+            report,
+            protocol_log,
+            read_armed,
+            last_seen,
+            #[cfg(feature = "trace")]
+            tracer: None,
+        }
+    }
+
+    /// Exchange the initialization messages and require the first frame
+    ///
+    async fn init(&mut self, sender: &Sender<VncEvent>) -> Result<SessionInfo> {
+        let session = match self.config.resume.take() {
+            Some(detached) => {
+                trace!("resume the detached session");
+                self.resume(detached, sender).await?
+            }
+            None => {
+                trace!("client init msg");
+                self.send_client_init().await?;
+                trace!("server init msg");
+                let session = self.read_server_init(sender).await?;
+                #[cfg(feature = "trace")]
+                self.trace("ServerInit", None, None);
+                session
+            }
+        };
+        trace!("client encodings: {:?}", self.config.wire_encodings());
+        self.send_client_encoding().await?;
+        trace!("Require the first frame");
+        let (width, height) = match self.config.health_check {
+            true => (self.screen.0.min(1), self.screen.1.min(1)),
+            false => self.screen,
+        };
+        self.outgoing
+            .send(ClientMsg::FramebufferUpdateRequest(
+                Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                },
+                0,
+            ))
+            .await?;
+        Ok(session)
+    }
+
+    ///
+    /// Run the vnc engine
+    ///
+    /// Which will poll the data from the server and send output via `sender`
+    ///
+    /// Also poll the user input from the `recv`
+    ///
+    async fn run(mut self, sender: Sender<VncEvent>, mut recv: Receiver<Input>) -> Result<()> {
+        trace!("Start main loop");
+        let options = codec::ImageOptions {
+            max_bytes: self.config.max_image_bytes,
+            byte_order: self.config.byte_order,
+            strictness: self.config.strictness,
+        };
+        let mut raw_decoder = codec::RawDecoder::new(options);
+        let mut rre_decoder = codec::RreDecoder::new(options);
+        let mut zlib_decoder = codec::ZlibDecoder::new(options);
+        #[cfg(feature = "ultra")]
+        let mut ultra_decoder = codec::UltraDecoder::new(options);
+        let mut zrle_decoder = codec::ZrleDecoder::new(options);
+        let mut tight_decoder =
+            codec::TightDecoder::new(self.config.jpeg_policy, self.config.palette_cache, options);
+        let mut trle_decoder = codec::TrleDecoder::new(options);
+        let mut cursor = codec::CursorDecoder::new(options).keep_shapes(self.config.cursor_layer);
+        let pf = &self.pixel_format.unwrap();
+        warm_up(
+            &self.config.encodings,
+            pf,
+            self.screen,
+            &mut zlib_decoder,
+            &mut zrle_decoder,
+            &mut tight_decoder,
+        );
+        let mut prefetch = self
+            .config
+            .prefetch_rate
+            .map(|_| tokio::time::interval(PREFETCH_INTERVAL));
+        let mut keepalive = self
+            .config
+            .keepalive
+            .map(|(interval, _)| tokio::time::interval(interval));
+        loop {
+            if let Some(done) = self.detaching.take() {
+                // the rest of the stream is left to the next owner
+                let _ = done.send(self.detach().await);
+                return Ok(());
+            }
+            if let Some((_, done)) = self.closing.take() {
+                self.close(done);
+                return Ok(());
+            }
+            let release_at = self.held_keys.values().min().copied();
+            // an idle server is not a stalled one
+            self.read_armed.store(false, Ordering::Relaxed);
+            tokio::select! {
+                server_msg = ServerMsg::read_buffered(&mut self.reader, &mut self.pending) => {
+                    let server_msg = match server_msg {
+                        Err(e) if self.config.unknown_message_policy == UnknownMessagePolicy::Skip
+                            && matches!(e.downcast_ref(), Some(VncError::WrongServerMessage)) => {
+                            self.skip_message(&sender).await?;
+                            self.seen();
+                            continue;
+                        }
+                        server_msg => server_msg?,
+                    };
+                    self.read_armed.store(true, Ordering::Relaxed);
+                    trace!("Server message got: {:?}", server_msg);
+                    self.record_event(describe_server_msg(&server_msg));
+                    self.protocol_log.log(ProtocolLogging::Messages, || match server_msg {
+                        ServerMsg::FramebufferUpdate(rects) => format!("<- FramebufferUpdate ({} rects)", rects),
+                        ref server_msg => format!("<- {}", server_msg.name()),
+                    });
+                    #[cfg(feature = "trace")]
+                    let (message, rects, received) = (
+                        server_msg.name(),
+                        match server_msg {
+                            ServerMsg::FramebufferUpdate(rects) => Some(rects),
+                            _ => None,
+                        },
+                        report::now(),
+                    );
+                    match server_msg {
+                        ServerMsg::FramebufferUpdate(rect_num) => {
+                            if self.config.max_update_rects.is_some_and(|max| rect_num > max) {
+                                error!("Framebuffer update with {} rects, is the server sane?", rect_num);
+                                return Err(VncError::TooManyRects(rect_num).into());
+                            }
+                            let deadline = self.config.update_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+                            let mut copies = Vec::new();
+                            let received = report::now();
+                            let mut budget = YIELD_BUDGET;
+                            // copies cannot be magnified, so the region is required again
+                            let mut refresh_lens = None;
+                            for i in 0..rect_num {
+                                let rect = self.read_rect(deadline, i, rect_num).await?;
+                                trace!("Encoding: {:?}", rect.encoding);
+                                self.record_rect(&rect);
+                                self.protocol_log.log(ProtocolLogging::Rects, || {
+                                    format!("   rect {}/{}: {:?} {:?}", i + 1, rect_num, rect.encoding, rect.rect)
+                                });
+
+                                if rect.encoding != VncEncoding::CopyRect {
+                                    flush_copies(&mut copies, &sender).await?;
+                                }
+                                if self.config.rect_encodings {
+                                    sender.send(VncEvent::RectEncoding(rect.rect, rect.encoding)).await?;
+                                }
+                                match rect.encoding {
+                                    VncEncoding::Raw => {
+                                        raw_decoder.decode(pf, &rect.rect, &mut self.reader, &sender).await?;
+                                    }
+                                    VncEncoding::CopyRect => {
+                                        let source_x = self.reader.read_u16().await?;
+                                        let source_y = self.reader.read_u16().await?;
+                                        let mut src_rect = rect.rect;
+                                        src_rect.x = source_x;
+                                        src_rect.y = source_y;
+                                        if let Some(lens) = *self.lens.lock().unwrap() {
+                                            if lens.intersect(&rect.rect).is_some() {
+                                                refresh_lens = Some(lens.region);
+                                            }
+                                        }
+                                        if self.config.batch_copy_rect {
+                                            if let Some((dst, src)) = copies.last() {
+                                                if displacement(dst, src) != displacement(&rect.rect, &src_rect) {
+                                                    flush_copies(&mut copies, &sender).await?;
+                                                }
+                                            }
+                                            copies.push((rect.rect, src_rect));
+                                        } else {
+                                            sender.send(VncEvent::Copy(rect.rect, src_rect)).await?;
+                                        }
+                                    }
+                                    VncEncoding::Rre => {
+                                        rre_decoder.decode(pf, &rect.rect, &mut self.reader, &sender).await?;
+                                    }
+                                    VncEncoding::Zlib => {
+                                        if let Err(e) = zlib_decoder.decode(pf, &rect.rect, &mut self.reader, &sender).await {
+                                            self.decode_failed(&rect, e).await?;
+                                        }
+                                    }
+                                    #[cfg(feature = "ultra")]
+                                    VncEncoding::Ultra => {
+                                        if let Err(e) = ultra_decoder.decode(pf, &rect.rect, &mut self.reader, &sender).await {
+                                            self.decode_failed(&rect, e).await?;
+                                        }
+                                    }
+                                    VncEncoding::Tight => {
+                                        tight_decoder.decode(pf, &rect.rect, &mut self.reader, &sender).await?;
+                                    }
+                                    VncEncoding::TightPng => {
+                                        tight_decoder.decode_png(pf, &rect.rect, &mut self.reader, &sender).await?;
+                                    }
+                                    VncEncoding::Trle => {
+                                        trle_decoder.decode(pf, &rect.rect, &mut self.reader, &sender).await?;
+                                    }
+                                    VncEncoding::Zrle => {
+                                        if let Err(e) = zrle_decoder.decode(pf, &rect.rect, &mut self.reader, &sender).await {
+                                            self.decode_failed(&rect, e).await?;
+                                        }
+                                    }
+                                    VncEncoding::CursorPseudo => {
+                                        cursor.decode(pf, &rect.rect, &mut self.reader, &sender).await?;
+                                        self.set_cursor_shape(cursor.take_shape());
+                                    }
+                                    VncEncoding::CursorWithAlphaPseudo => {
+                                        cursor.decode_alpha(&rect.rect, &mut self.reader, &sender).await?;
+                                        self.set_cursor_shape(cursor.take_shape());
+                                    }
+                                    VncEncoding::QemuExtendedKeyEventPseudo => {
+                                        info!("The server supports the QEMU extended key events");
+                                        self.qemu_keys_supported = true;
+                                    }
+                                    VncEncoding::QemuLedStatePseudo => {
+                                        let state = self.reader.read_u8().await?;
+                                        let locks = LockKeys { caps: state & 4 > 0, num: state & 2 > 0, scroll: state & 1 > 0 };
+                                        self.led_state = Some(locks);
+                                        sender.send(VncEvent::LedState { caps: locks.caps, num: locks.num, scroll: locks.scroll }).await?;
+                                    }
+                                    VncEncoding::PointerPosPseudo => {
+                                        self.set_pointer(rect.rect.x, rect.rect.y);
+                                        sender.send(VncEvent::PointerPosition(rect.rect.x, rect.rect.y)).await?;
+                                    }
+                                    VncEncoding::XCursorPseudo => {
+                                        cursor.decode_x(&rect.rect, &mut self.reader, &sender).await?;
+                                        self.set_cursor_shape(cursor.take_shape());
+                                    }
+                                    VncEncoding::DesktopSizePseudo => {
+                                        self.set_screen(rect.rect.width, rect.rect.height);
+                                        warm_up(&self.config.encodings, pf, self.screen, &mut zlib_decoder, &mut zrle_decoder, &mut tight_decoder);
+                                        sender.send(VncEvent::SetResolution((rect.rect.width, rect.rect.height).into())).await?;
+                                    }
+                                    VncEncoding::ExtendedDesktopSizePseudo => {
+                                        let desktop = self.read_desktop_size(&rect.rect).await?;
+                                        if desktop.status == ResizeStatus::Ok {
+                                            self.set_screen(rect.rect.width, rect.rect.height);
+                                            warm_up(&self.config.encodings, pf, self.screen, &mut zlib_decoder, &mut zrle_decoder, &mut tight_decoder);
+                                            sender.send(VncEvent::SetResolution(desktop.screen.clone())).await?;
+                                        }
+                                        sender.send(VncEvent::ExtendedDesktopSize(desktop)).await?;
+                                        if let Some((width, height)) = self.config.preferred_resolution.take() {
+                                            if (width, height) != self.screen {
+                                                info!("Request the preferred resolution {}x{}", width, height);
+                                                self.handle_input(X11Event::SetDesktopSize(width, height, Vec::new()), &sender).await?;
+                                            }
+                                        }
+                                    }
+                                    _ => {
+                                        error!("Unexpected rect encoding {:?}", rect.encoding);
+                                        return Err(VncError::WrongServerMessage.into());
+                                    }
+                                }
+                                if !rect.encoding.is_pseudo() {
+                                    self.echo_damage(&rect.rect, &sender).await?;
+                                }
+                                self.yield_point(&mut budget, &rect, &mut recv, &sender).await?;
+                                if let Some((false, _)) = self.closing {
+                                    let (_, done) = self.closing.take().unwrap();
+                                    info!("Closed in the middle of an update");
+                                    self.close(done);
+                                    return Ok(());
+                                }
+                            }
+                            flush_copies(&mut copies, &sender).await?;
+                            if let (Some(received), Some(decoded)) = (received, report::now()) {
+                                self.report.lock().unwrap().record_frame(FrameTiming {
+                                    rects: rect_num,
+                                    received,
+                                    decoded,
+                                    delivered: None,
+                                });
+                            }
+                            sender.send(VncEvent::UpdateComplete).await?;
+                            if let Some(region) = refresh_lens {
+                                self.outgoing.send(ClientMsg::FramebufferUpdateRequest(region, 0)).await?;
+                            }
+                        }
+                        ServerMsg::SetColorMapEntries(first_color, colors) => {
+                            let last_color = first_color.saturating_add(colors.len() as u16);
+                            sender.send(VncEvent::PaletteUpdated(first_color..last_color, colors)).await?;
+                        }
+                        ServerMsg::Bell => {
+                            #[cfg(feature = "bell")]
+                            if let Some(ring) = self.config.bell.as_mut().and_then(|bell| bell.ring(report::now())) {
+                                spawn(ring);
+                            }
+                            sender.send(VncEvent::Bell).await?;
+                        }
+                        ServerMsg::ServerCutText(text) => {
+                            sender.send(VncEvent::Text(text)).await?;
+                        }
+                        ServerMsg::EndOfContinuousUpdates => {
+                            if !self.continuous_updates_supported {
+                                self.continuous_updates_supported = true;
+                                if let Some(rect) = self.continuous_updates {
+                                    self.outgoing.send(ClientMsg::EnableContinuousUpdates(true, rect)).await?;
+                                }
+                            }
+                            sender.send(VncEvent::EndOfContinuousUpdates).await?;
+                        }
+                        ServerMsg::XvpInit(version) => {
+                            info!("The server supports the xvp version {}", version);
+                            self.xvp_version = Some(version);
+                            sender.send(VncEvent::XvpInit(version)).await?;
+                        }
+                        ServerMsg::XvpFail(_) => {
+                            warn!("The xvp action failed");
+                            sender.send(VncEvent::XvpFail).await?;
+                        }
+                        ServerMsg::GiiVersion(max, min) => {
+                            if !(min..=max).contains(&GII_VERSION) {
+                                warn!("Unsupported gii versions {}..={}", min, max);
+                            } else if !self.gii_supported {
+                                self.gii_supported = true;
+                                self.outgoing.send(ClientMsg::GiiVersion(GII_VERSION)).await?;
+                                sender.send(VncEvent::GiiSupported).await?;
+                            }
+                        }
+                        ServerMsg::GiiDeviceCreated(origin) => {
+                            if origin == 0 {
+                                warn!("The server failed to create the gii device");
+                            }
+                            sender.send(VncEvent::GiiDeviceCreated(origin)).await?;
+                        }
+                    }
+                    #[cfg(feature = "trace")]
+                    self.trace(message, rects, received);
+                    self.seen();
+                }
+                input = recv.recv() => {
+                    let Some(input) = input else {
+                        // the client handle is dropped
+                        return Ok(());
+                    };
+                    self.handle(input, &sender).await?;
+                }
+                _ = tick(&mut prefetch) => {
+                    self.prefetch().await?;
+                }
+                _ = tick(&mut keepalive) => {
+                    self.keepalive(&sender).await?;
+                }
+                _ = sleep_until(release_at) => {
+                    self.release_keys().await?;
+                }
+            }
+        }
+    }
+
+    /// Probe the server if it has been quiet for an interval, or give up if for the whole window
+    ///
+    /// The probe is a FramebufferUpdateRequest of no area, which the servers answer with an empty update
+    async fn keepalive(&mut self, sender: &Sender<VncEvent>) -> Result<()> {
+        let (Some((interval, window)), Some(last_seen)) = (self.config.keepalive, self.last_seen)
+        else {
+            return Ok(());
+        };
+        let quiet = last_seen.elapsed();
+        if quiet >= window {
+            warn!("Nothing received from the server for {:?}", quiet);
+            sender.send(VncEvent::ConnectionStale).await?;
+            return Err(VncError::Timeout.into());
+        }
+        if quiet >= interval {
+            trace!("Probe the server quiet for {:?}", quiet);
+            let probe = Rect {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+            };
+            self.outgoing
+                .send(ClientMsg::FramebufferUpdateRequest(probe, 0))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Note that a message has been received from the server, if the keepalive is on
+    fn seen(&mut self) {
+        if let Some(last_seen) = self.last_seen.as_mut() {
+            *last_seen = tokio::time::Instant::now();
+        }
+    }
+
+    async fn handle(&mut self, input: Input, sender: &Sender<VncEvent>) -> Result<()> {
+        match input {
+            Input::Event(x11_event) => self.handle_input(x11_event, sender).await,
+            Input::Flush(done) => {
+                // the client may have given up waiting
+                let _ = done.send(self.outgoing.flushed());
+                Ok(())
+            }
+            Input::Close(wait_update, done) => {
+                // deferred to the main loop as well, unless the update is not waited
+                self.closing = Some((wait_update, done));
+                Ok(())
+            }
+            Input::Detach(done) => {
+                // deferred to the main loop, since a framebuffer update may be in the middle
+                match self.check_detachable() {
+                    std::result::Result::Ok(()) => self.detaching = Some(done),
+                    Err(e) => {
+                        let _ = done.send(Err(e));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn check_detachable(&self) -> Result<()> {
+        if !self.config.detachable {
+            return Err(VncError::Custom(
+                "Only the sessions over a plain transport can be detached".to_string(),
+            )
+            .into());
+        }
+        if let Some(encoding) = self
+            .config
+            .wire_encodings()
+            .into_iter()
+            .find(|e| STATEFUL_ENCODINGS.contains(e))
+        {
+            return Err(VncError::Custom(format!(
+                "The session can't be detached with the stateful encoding {:?}",
+                encoding
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Shut down the write half once the queued messages are written, which is waited by `done`
+    fn close(&mut self, done: oneshot::Sender<Flushed>) {
+        info!("Close the session");
+        let _ = done.send(self.outgoing.shutdown());
+    }
+
+    /// Wait for the queued messages to be written, and take the bytes buffered from the server
+    async fn detach(&mut self) -> Detached {
+        self.outgoing.flushed().wait().await?;
+        let reader = &mut self.reader;
+        let buffered = std::future::poll_fn(|cx| match Pin::new(&mut *reader).poll_fill_buf(cx) {
+            Poll::Ready(result) => Poll::Ready(result.map(|buf| buf.to_vec())),
+            // nothing is buffered, the rest is still in the socket
+            Poll::Pending => Poll::Ready(std::result::Result::Ok(Vec::new())),
+        })
+        .await?;
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend(buffered);
+        info!("Detached with {} bytes pending", pending.len());
+        Ok((self.pixel_format.unwrap(), self.screen, pending))
+    }
+
+    async fn handle_input(&mut self, x11_event: X11Event, sender: &Sender<VncEvent>) -> Result<()> {
+        self.record_event(describe_input(&x11_event));
+        match x11_event {
+            X11Event::Refresh => {
+                let rect = self.viewport.unwrap_or(Rect {
+                    x: 0,
+                    y: 0,
+                    width: self.screen.0,
+                    height: self.screen.1,
+                });
+                self.outgoing
+                    .send(ClientMsg::FramebufferUpdateRequest(rect, 1))
+                    .await?;
+            }
+            X11Event::SetViewport(viewport) => {
+                self.viewport = viewport;
+            }
+            X11Event::SetMagnifier(magnifier) => {
+                let bpp = self.pixel_format.unwrap().output().bits_per_pixel as usize / 8;
+                let lens = magnifier.map(|m| Lens::new(&m, self.screen, bpp));
+                *self.lens.lock().unwrap() = lens;
+                self.viewport = lens.map(|lens| lens.region);
+                if let Some(lens) = lens {
+                    // the whole region is required to fill the magnified output
+                    self.outgoing
+                        .send(ClientMsg::FramebufferUpdateRequest(lens.region, 0))
+                        .await?;
+                }
+            }
+            X11Event::KeyEvent(mut key) => {
+                if let Some(layout) = &self.config.keyboard_layout {
+                    key.keycode = layout.translate(key.keycode);
+                }
+                if let Some(timeout) = self.config.key_auto_release {
+                    if key.down {
+                        // repeated presses keep the key held
+                        self.held_keys
+                            .insert(key.keycode, tokio::time::Instant::now() + timeout);
+                    } else {
+                        self.held_keys.remove(&key.keycode);
+                    }
+                }
+                if key.down {
+                    self.pressed_keys.insert(key.keycode, None);
+                    self.echo_input(EchoedInput::Key(key.keycode));
+                } else {
+                    self.pressed_keys.remove(&key.keycode);
+                }
+                self.outgoing
+                    .send(ClientMsg::KeyEvent(key.keycode, key.down))
+                    .await?;
+            }
+            X11Event::ExtendedKeyEvent {
+                mut keysym,
+                keycode,
+                down,
+            } => {
+                // the scancode is layout independent, only the keysym is translated
+                if let Some(layout) = &self.config.keyboard_layout {
+                    keysym = layout.translate(keysym);
+                }
+                if down {
+                    self.pressed_keys.insert(keysym, Some(keycode));
+                    self.echo_input(EchoedInput::Key(keysym));
+                } else {
+                    self.pressed_keys.remove(&keysym);
+                }
+                self.send_key(keysym, Some(keycode), down).await?;
+            }
+            X11Event::FocusIn(locks) => {
+                if let Some(locks) = locks {
+                    self.sync_lock_keys(locks).await?;
+                }
+            }
+            X11Event::FocusOut => {
+                // the releases will go to another window
+                self.held_keys.clear();
+                for (keysym, keycode) in std::mem::take(&mut self.pressed_keys) {
+                    self.send_key(keysym, keycode, false).await?;
+                }
+                if self.buttons != 0 {
+                    self.buttons = 0;
+                    let (x, y) = *self.watchers.pointer.borrow();
+                    self.outgoing.send(ClientMsg::PointerEvent(x, y, 0)).await?;
+                }
+            }
+            X11Event::PointerEvent(mouse) => {
+                let (x, y) = self
+                    .config
+                    .transform
+                    .invert_point((mouse.position_x, mouse.position_y), self.screen);
+                self.outgoing
+                    .send(ClientMsg::PointerEvent(x, y, mouse.bottons))
+                    .await?;
+                self.set_pointer(x, y);
+                if mouse.bottons != self.buttons {
+                    self.echo_input(EchoedInput::Pointer(x, y));
+                }
+                self.buttons = mouse.bottons;
+            }
+            X11Event::WarpPointer { x, y, notify } => {
+                let (x, y) = self.config.transform.invert_point((x, y), self.screen);
+                self.outgoing
+                    .send(ClientMsg::PointerEvent(x, y, self.buttons))
+                    .await?;
+                self.set_pointer(x, y);
+                if notify {
+                    sender.send(VncEvent::CursorPosition(x, y)).await?;
+                }
+            }
+            X11Event::CopyText(text) => {
+                self.outgoing.send(ClientMsg::ClientCutText(text)).await?;
+            }
+            X11Event::SetContinuousUpdates(region) => {
+                let previous = std::mem::replace(&mut self.continuous_updates, region);
+                if !self.continuous_updates_supported {
+                    if region.is_some() {
+                        info!("Continuous updates are enabled once the server supports them");
+                    }
+                    return Ok(());
+                }
+                // the rect is ignored when disabling
+                let msg = match (region, previous) {
+                    (Some(rect), _) => ClientMsg::EnableContinuousUpdates(true, rect),
+                    (None, Some(rect)) => ClientMsg::EnableContinuousUpdates(false, rect),
+                    (None, None) => return Ok(()),
+                };
+                self.outgoing.send(msg).await?;
+            }
+            X11Event::SetDesktopSize(width, height, mut screens) => {
+                let Some(current) = &self.screens else {
+                    warn!("The server doesn't support ExtendedDesktopSize, resize ignored");
+                    return Ok(());
+                };
+                if screens.is_empty() {
+                    // keep the id of the primary screen
+                    screens.push(ScreenLayout {
+                        id: current.first().map_or(0, |screen| screen.id),
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                        flags: 0,
+                    });
+                }
+                self.outgoing
+                    .send(ClientMsg::SetDesktopSize(width, height, screens))
+                    .await?;
+            }
+            X11Event::GiiCreateDevice(_)
+            | X11Event::GiiDestroyDevice(_)
+            | X11Event::GiiEvents(_)
+                if !self.gii_supported =>
+            {
+                warn!("The server doesn't support gii, input ignored");
+            }
+            X11Event::GiiCreateDevice(device) => {
+                self.outgoing
+                    .send(ClientMsg::GiiCreateDevice(device))
+                    .await?;
+            }
+            X11Event::GiiDestroyDevice(origin) => {
+                self.outgoing
+                    .send(ClientMsg::GiiDestroyDevice(origin))
+                    .await?;
+            }
+            X11Event::GiiEvents(events) => {
+                self.outgoing.send(ClientMsg::GiiEvents(events)).await?;
+            }
+            X11Event::SetContentMode(mode) => {
+                if mode != self.config.content_mode {
+                    self.config.content_mode = mode;
+                    self.send_client_encoding().await?;
+                }
+            }
+            X11Event::Xvp(action) => {
+                let Some(version) = self.xvp_version else {
+                    warn!("The server doesn't support xvp, {:?} ignored", action);
+                    return Ok(());
+                };
+                self.outgoing.send(ClientMsg::Xvp(version, action)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the screens following an ExtendedDesktopSize rect
+    ///
+    /// Whose x & y carry the reason & the status
+    ///
+    async fn read_desktop_size(&mut self, rect: &Rect) -> Result<ExtendedDesktopSize> {
+        // +--------------+--------------+-------------------+
+        // | No. of bytes | Type [Value] | Description       |
+        // +--------------+--------------+-------------------+
+        // | 1            | U8           | number-of-screens |
+        // | 3            |              | padding           |
+        // +--------------+--------------+-------------------+
+        let num = self.reader.read_u8().await?;
+        let mut padding = [0; 3];
+        self.reader.read_exact(&mut padding).await?;
+        let mut screens = Vec::with_capacity(num as usize);
+        for _ in 0..num {
+            screens.push(read_screen(&mut self.reader).await?);
+        }
+        self.screens = Some(screens.clone());
+        Ok(ExtendedDesktopSize {
+            reason: rect.x.into(),
+            status: rect.y.into(),
+            screen: (rect.width, rect.height).into(),
+            screens,
+        })
+    }
+
+    /// Release the keys held longer than the auto release timeout
+    ///
+    /// In case their release events are lost
+    ///
+    async fn release_keys(&mut self) -> Result<()> {
+        let now = tokio::time::Instant::now();
+        let expired: Vec<u32> = self
+            .held_keys
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(keysym, _)| *keysym)
+            .collect();
+        for keysym in expired {
+            warn!("Key {:#x} is held too long, released", keysym);
+            self.held_keys.remove(&keysym);
+            self.pressed_keys.remove(&keysym);
+            self.record_event(format!("AutoRelease({:#x})", keysym));
+            self.outgoing
+                .send(ClientMsg::KeyEvent(keysym, false))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Send a key event, with the scancode if known and the server supports the QEMU extended key events
+    async fn send_key(&mut self, keysym: u32, keycode: Option<u32>, down: bool) -> Result<()> {
+        let msg = match keycode {
+            Some(keycode) if self.qemu_keys_supported => {
+                ClientMsg::QemuKeyEvent(keysym, keycode, down)
+            }
+            _ => ClientMsg::KeyEvent(keysym, down),
+        };
+        self.outgoing.send(msg).await
+    }
+
+    /// Toggle the lock keys of the guest which differ from the local `locks`
+    async fn sync_lock_keys(&mut self, locks: LockKeys) -> Result<()> {
+        let Some(led_state) = self.led_state else {
+            info!("The lock keys are not synced until the server informs the led state");
+            return Ok(());
+        };
+        // the keysyms & the XT scancodes of caps, num & scroll lock
+        let keys = [
+            (led_state.caps != locks.caps, 0xffe5, 0x3a),
+            (led_state.num != locks.num, 0xff7f, 0x45),
+            (led_state.scroll != locks.scroll, 0xff14, 0x46),
+        ];
+        for (_, keysym, keycode) in keys.into_iter().filter(|(differs, ..)| *differs) {
+            self.send_key(keysym, Some(keycode), true).await?;
+            self.send_key(keysym, Some(keycode), false).await?;
+        }
+        // not to toggle them again before the server informs the new state
+        self.led_state = Some(locks);
+        Ok(())
+    }
+
+    /// Skip a rect which cannot be decoded if the encoding fallback is enabled
+    ///
+    /// The rect is required again, until the encoding fails too many times
+    ///
+    /// then it is removed from the encodings and the whole screen is required
+    ///
+    async fn decode_failed(&mut self, rect: &ImageRect, e: anyhow::Error) -> Result<()> {
+        let Some(max_failures) = self.config.max_decode_failures else {
+            return Err(e);
+        };
+        if !matches!(e.downcast_ref(), Some(VncError::InvalidImageData)) {
+            return Err(e);
+        }
+        let failures = self.decode_failures.entry(rect.encoding).or_default();
+        *failures += 1;
+        warn!(
+            "Failed to decode {:?} rect {:?} ({}/{})",
+            rect.encoding, rect.rect, failures, max_failures
+        );
+        if *failures < max_failures {
+            return self
+                .outgoing
+                .send(ClientMsg::FramebufferUpdateRequest(rect.rect, 0))
+                .await;
+        }
+
+        warn!("Stop using encoding {:?}", rect.encoding);
+        self.record_event(format!("Demote({:?})", rect.encoding));
+        self.config.encodings.retain(|e| *e != rect.encoding);
+        self.send_client_encoding().await?;
+        self.outgoing
+            .send(ClientMsg::FramebufferUpdateRequest(
+                Rect {
+                    x: 0,
+                    y: 0,
+                    width: self.screen.0,
+                    height: self.screen.1,
+                },
+                0,
+            ))
+            .await
+    }
+
+    /// Require the next band outside of the viewport, if any
+    ///
+    async fn prefetch(&mut self) -> Result<()> {
+        let (Some(viewport), Some(rate)) = (self.viewport, self.config.prefetch_rate) else {
+            return Ok(());
+        };
+        let bpp = self.pixel_format.unwrap().bits_per_pixel as usize / 8;
+        let row_bytes = (self.screen.0 as usize * bpp).max(1);
+        let rows = (rate / row_bytes).clamp(1, u16::MAX as usize) as u16;
+        if let Some((band, next)) =
+            next_prefetch_band(self.screen, &viewport, self.prefetch_row, rows)
+        {
+            trace!("Prefetch {:?}", band);
+            self.prefetch_row = next;
+            self.outgoing
+                .send(ClientMsg::FramebufferUpdateRequest(band, 1))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Pass a message read from the server to the tracer, see [super::VncConnector::set_trace_sink]
+    #[cfg(feature = "trace")]
+    fn trace(
+        &self,
+        message: &'static str,
+        rects: Option<u16>,
+        received: Option<std::time::Instant>,
+    ) {
+        if let Some(tracer) = &self.tracer {
+            tracer.lock().unwrap().server(message, rects, received);
+        }
+    }
+
+    /// Skip a message of an unknown type, whose payload is delivered as [ExtensionEvent::ServerMessage]
+    async fn skip_message(&mut self, sender: &Sender<VncEvent>) -> Result<()> {
+        // the head of the message may have been moved aside by the read
+        let pending = std::mem::take(&mut self.pending);
+        let mut reader = pending.as_slice().chain(&mut self.reader);
+        let msg_type = reader.fill_buf().await?[0];
+        let Some(length) = self
+            .config
+            .message_lengths
+            .get(&msg_type)
+            .copied()
+            .or_else(|| MessageLength::well_known(msg_type))
+        else {
+            error!("Unknown server message {} of unknown length", msg_type);
+            return Err(VncError::WrongServerMessage.into());
+        };
+        reader.consume(1);
+        let mut payload = Vec::new();
+        let len = match length {
+            MessageLength::Fixed(len) => len as u64,
+            MessageLength::Prefixed { offset, size } => {
+                payload.resize(offset + size, 0);
+                reader.read_exact(&mut payload).await?;
+                payload[offset..]
+                    .iter()
+                    .fold(0, |len, byte| len << 8 | *byte as u64)
+            }
+        };
+        let skipped = (&mut reader).take(len).read_to_end(&mut payload).await?;
+        if (skipped as u64) < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        warn!("Skipped server message {} of {:?}", msg_type, length);
+        sender
+            .send(VncEvent::Extension(ExtensionEvent::ServerMessage {
+                msg_type,
+                payload,
+            }))
+            .await?;
+        self.report.lock().unwrap().skipped_messages += 1;
+        #[cfg(feature = "trace")]
+        self.trace("Unknown", None, None);
+        Ok(())
+    }
+
+    /// Read the header of the `index`th rect of an update, failing once the `deadline` has passed
+    ///
+    /// So that an update announcing more rects than sent doesn't hang the engine forever
+    async fn read_rect(
+        &mut self,
+        deadline: Option<tokio::time::Instant>,
+        index: u16,
+        rect_num: u16,
+    ) -> Result<ImageRect> {
+        let Some(deadline) = deadline else {
+            return ImageRect::read(&mut self.reader).await;
+        };
+        match tokio::time::timeout_at(deadline, ImageRect::read(&mut self.reader)).await {
+            std::result::Result::Ok(rect) => rect,
+            Err(_) => {
+                error!("Rect {} of {} not received in time", index, rect_num);
+                Err(VncError::UpdateTimeout(index, rect_num).into())
+            }
+        }
+    }
+
+    /// Called between the rects of an update
+    ///
+    /// The pending input is handled after every rect, e.g. a close dropping the rest of the update,
+    ///
+    /// and once `budget` bytes of pixels have been decoded, yield to the runtime to write it to the server
+    /// so that a huge update won't delay the input until it is fully decoded
+    ///
+    async fn yield_point(
+        &mut self,
+        budget: &mut usize,
+        rect: &ImageRect,
+        recv: &mut Receiver<Input>,
+        sender: &Sender<VncEvent>,
+    ) -> Result<()> {
+        // a closed channel will be handled by the main loop
+        while let std::result::Result::Ok(input) = recv.try_recv() {
+            self.handle(input, sender).await?;
+        }
+        let bpp = self.pixel_format.unwrap().bits_per_pixel as usize / 8;
+        let bytes = rect.rect.width as usize * rect.rect.height as usize * bpp;
+        *budget = budget.saturating_sub(bytes);
+        if *budget > 0 {
+            return Ok(());
+        }
+        *budget = YIELD_BUDGET;
+        tokio::task::yield_now().await;
+        Ok(())
+    }
+
+    async fn send_client_init(&mut self) -> Result<()> {
+        info!("Send shared flag: {}", self.config.shared);
+        self.outgoing
+            .send(ClientMsg::ClientInit(self.config.shared))
+            .await?;
+        Ok(())
+    }
+
+    async fn read_server_init(&mut self, sender: &Sender<VncEvent>) -> Result<SessionInfo> {
+        // +--------------+--------------+------------------------------+
+        // | No. of bytes | Type [Value] | Description                  |
+        // +--------------+--------------+------------------------------+
+        // | 2            | U16          | framebuffer-width in pixels  |
+        // | 2            | U16          | framebuffer-height in pixels |
+        // | 16           | PIXEL_FORMAT | server-pixel-format          |
+        // | 4            | U32          | name-length                  |
+        // | name-length  | U8 array     | name-string                  |
+        // +--------------+--------------+------------------------------+
+
+        let screen_width = self.reader.read_u16().await?;
+        let screen_height = self.reader.read_u16().await?;
+
+        sender
+            .send(VncEvent::SetResolution(
+                (screen_width, screen_height).into(),
+            ))
+            .await?;
+        self.set_screen(screen_width, screen_height);
+
+        let server_pf = PixelFormat::read(&mut self.reader).await?;
+        let requested_pf = self.pixel_format.unwrap_or(server_pf);
+        let encodings = self.config.wire_encodings();
+        let pixel_format = requested_pf.negotiate(&encodings);
+        if pixel_format != requested_pf {
+            warn!(
+                "Pixel format {:?} cannot be used with encodings {:?}, fall back to {:?}",
+                requested_pf, encodings, pixel_format
+            );
+        }
+        // the events carry the pixels in the output format, which differs from the wire for 8 bits
+        let output = pixel_format.output();
+        if self.pixel_format.is_none() || output != requested_pf {
+            sender.send(VncEvent::SetPixelFormat(output)).await?;
+        }
+        let send_our_pf = self.pixel_format.is_some() || pixel_format != server_pf;
+        self.pixel_format = Some(pixel_format);
+        self.watchers.pixel_format.send_replace(Some(output));
+
+        let name_len = self.reader.read_u32().await?;
+        let mut name_buf = vec![0_u8; name_len as usize];
+        self.reader.read_exact(&mut name_buf).await?;
+        self.name = self.config.name_policy.decode(&name_buf)?;
+
+        {
+            let mut report = self.report.lock().unwrap();
+            report.shared = self.config.shared;
+            report.name = self.name.clone();
+            report.pixel_format = self.pixel_format;
+        }
+
+        if send_our_pf {
+            info!(
+                "Send customized pixel format {:#?}",
+                self.pixel_format.as_ref().unwrap()
+            );
+            self.outgoing
+                .send(ClientMsg::SetPixelFormat(
+                    *self.pixel_format.as_ref().unwrap(),
+                ))
+                .await?;
+        }
+        Ok(SessionInfo {
+            name: self.name.clone(),
+            name_bytes: name_buf,
+            screen: (screen_width, screen_height).into(),
+            server_pixel_format: server_pf,
+            raw_server_version: String::new(),
+        })
+    }
+
+    /// Restore the state negotiated by the previous owner of the session
+    ///
+    /// The pixel format is kept, since the updates in flight are encoded with it
+    ///
+    async fn resume(
+        &mut self,
+        detached: DetachedSession,
+        sender: &Sender<VncEvent>,
+    ) -> Result<SessionInfo> {
+        let (screen_width, screen_height) = detached.screen;
+        sender
+            .send(VncEvent::SetResolution(
+                (screen_width, screen_height).into(),
+            ))
+            .await?;
+        self.set_screen(screen_width, screen_height);
+
+        let output = detached.pixel_format.output();
+        sender.send(VncEvent::SetPixelFormat(output)).await?;
+        self.pixel_format = Some(detached.pixel_format);
+        self.watchers.pixel_format.send_replace(Some(output));
+        self.name = self.config.name_policy.decode(&detached.name_bytes)?;
+
+        {
+            let mut report = self.report.lock().unwrap();
+            report.shared = self.config.shared;
+            report.name = self.name.clone();
+            report.pixel_format = self.pixel_format;
+        }
+        Ok(SessionInfo {
+            name: self.name.clone(),
+            name_bytes: detached.name_bytes,
+            screen: (screen_width, screen_height).into(),
+            server_pixel_format: detached.server_pixel_format,
+            raw_server_version: detached.raw_server_version,
+        })
+    }
+
+    fn set_screen(&mut self, width: u16, height: u16) {
+        self.screen = (width, height);
+        self.watchers.screen.send_replace((width, height).into());
+        self.report.lock().unwrap().screen = (width, height);
+    }
+
+    fn set_pointer(&self, x: u16, y: u16) {
+        self.watchers.pointer.send_replace((x, y));
+        if self.config.cursor_layer {
+            self.watchers
+                .cursor
+                .send_modify(|cursor| cursor.position = (x, y));
+        }
+    }
+
+    /// Publish the shape kept by the cursor decoder to the cursor layer
+    fn set_cursor_shape(&self, shape: Option<(Rect, Vec<u8>)>) {
+        if let Some((rect, rgba)) = shape {
+            self.watchers.cursor.send_modify(|cursor| {
+                cursor.width = rect.width;
+                cursor.height = rect.height;
+                cursor.hotspot = (rect.x, rect.y);
+                cursor.rgba = rgba;
+            });
+        }
+    }
+
+    fn record_event(&self, event: String) {
+        self.report.lock().unwrap().record_event(event);
+    }
+
+    fn echo_input(&mut self, input: EchoedInput) {
+        if let (Some(echoes), Some(now)) = (self.echoes.as_mut(), report::now()) {
+            echoes.sent(input, now);
+        }
+    }
+
+    /// Report the inputs echoed by the damage of a rect
+    async fn echo_damage(&mut self, damage: &Rect, sender: &Sender<VncEvent>) -> Result<()> {
+        let (Some(echoes), Some(now)) = (self.echoes.as_mut(), report::now()) else {
+            return Ok(());
+        };
+        for echo in echoes.damage(damage, now) {
+            sender.send(VncEvent::InputEcho(echo)).await?;
+        }
+        Ok(())
+    }
+
+    fn record_rect(&self, rect: &ImageRect) {
+        let mut report = self.report.lock().unwrap();
+        report.record_event(format!("Rect({:?}, {:?})", rect.encoding, rect.rect));
+        if !matches!(
+            rect.encoding,
+            VncEncoding::DesktopSizePseudo
+                | VncEncoding::ExtendedDesktopSizePseudo
+                | VncEncoding::PointerPosPseudo
+        ) {
+            report.record_rect(rect.encoding, rect.rect.width, rect.rect.height);
+        }
+    }
+
+    async fn send_client_encoding(&mut self) -> Result<()> {
+        let encodings = self.config.wire_encodings();
+        self.report.lock().unwrap().encodings = encodings.clone();
+        self.outgoing
+            .send(ClientMsg::SetEncodings(encodings))
+            .await?;
+        Ok(())
+    }
+}
+
+impl<S> Drop for VncInner<S>
+where
+    S: AsyncBufRead + Unpin,
+{
+    fn drop(&mut self) {
+        trace!("Client closed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{next_prefetch_band, ClientConfig, VncClient};
+    use crate::{
+        proto::messages::{ClientMsg, ServerMsg},
+        ContentMode, CursorState, DisconnectReason, ExtensionEvent, GiiDevice, GiiValuator,
+        JpegPolicy, LockKeys, MessageLength, PixelFormat, Rect, ResizeReason, ResizeStatus,
+        UnknownMessagePolicy, VncEncoding, VncError, VncEvent, X11Event, XvpAction,
+    };
+    use std::{
+        collections::HashMap,
+        io::{self, Write},
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+    };
+    use tokio::io::{
+        duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf,
+    };
+
+    /// Act as the server from the ServerInit message
+    ///
+    /// until the first FramebufferUpdateRequest of the client is received
+    async fn server_init(server: &mut DuplexStream) {
+        let _shared = server.read_u8().await.unwrap();
+        server.write_u16(800).await.unwrap();
+        server.write_u16(600).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_u32(4).await.unwrap();
+        server.write_all(b"test").await.unwrap();
+
+        // SetPixelFormat + SetEncodings + FramebufferUpdateRequest
+        let mut client_msgs = vec![0; 20 + 4];
+        server.read_exact(&mut client_msgs).await.unwrap();
+        let encodings = u16::from_be_bytes([client_msgs[22], client_msgs[23]]) as usize;
+        let mut client_msgs = vec![0; 4 * encodings + 10];
+        server.read_exact(&mut client_msgs).await.unwrap();
+    }
+
+    fn copy_rect(payload: &mut Vec<u8>, dst: (u16, u16), src: (u16, u16)) {
+        for v in [dst.0, dst.1, 10, 10] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::CopyRect as i32).to_be_bytes());
+        payload.extend_from_slice(&src.0.to_be_bytes());
+        payload.extend_from_slice(&src.1.to_be_bytes());
+    }
+
+    fn raw_rect(payload: &mut Vec<u8>, rect: (u16, u16, u16, u16)) {
+        for v in [rect.0, rect.1, rect.2, rect.3] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::Raw as i32).to_be_bytes());
+        payload.resize(payload.len() + rect.2 as usize * rect.3 as usize * 4, 0x55);
+    }
+
+    #[tokio::test]
+    async fn test_copy_rect_batching() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::CopyRect, VncEncoding::Raw],
+                    batch_copy_rect: true,
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        assert_eq!(vnc.session_info().name, "test");
+        let screen = vnc.next_resize().await.unwrap();
+        assert_eq!((screen.width, screen.height), (800, 600));
+        assert_eq!(*vnc.watch_screen().borrow(), screen);
+
+        let mut bells = vnc.subscribe_filtered(|e| matches!(e, VncEvent::Bell));
+
+        // FramebufferUpdate with 3 CopyRects followed by a Bell
+        let mut payload = vec![0, 0, 0, 3];
+        copy_rect(&mut payload, (10, 10), (0, 0));
+        copy_rect(&mut payload, (20, 10), (10, 0));
+        copy_rect(&mut payload, (0, 0), (10, 10));
+        payload.push(2);
+        server.write_all(&payload).await.unwrap();
+
+        match vnc.recv_event().await.unwrap() {
+            VncEvent::CopyBatch(copies) => {
+                assert_eq!(copies.len(), 2);
+                assert_eq!((copies[1].0.x, copies[1].1.x), (20, 10));
+            }
+            e => panic!("Unexpected event {:?}", e),
+        }
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::Copy(..)
+        ));
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::UpdateComplete
+        ));
+        assert!(matches!(vnc.recv_event().await.unwrap(), VncEvent::Bell));
+        assert!(matches!(bells.recv().await, Some(VncEvent::Bell)));
+        let report = vnc.debug_report();
+        assert_eq!(report.name, "test");
+        assert_eq!(report.rects[&VncEncoding::CopyRect].rects, 3);
+        assert_eq!(report.recent_frames.len(), 1);
+        assert_eq!(report.recent_frames[0].rects, 3);
+        assert!(report.recent_frames[0].delivery_time().is_some());
+
+        // the engine stops once the server is gone
+        drop(server);
+        assert!(vnc.recv_event().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_clipboard_redacted() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.next_resize().await.unwrap();
+
+        // ServerCutText
+        let mut payload = vec![3, 0, 0, 0, 0, 0, 0, 6];
+        payload.extend_from_slice(b"secret");
+        server.write_all(&payload).await.unwrap();
+        assert_eq!(vnc.next_clipboard().await.unwrap(), "secret");
+        let report = vnc.debug_report();
+        assert!(report
+            .recent_events
+            .contains(&"ServerCutText(6 bytes)".to_string()));
+        assert!(!report.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_watchers() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::rgba()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        let mut screen = vnc.watch_screen();
+        let mut pointer = vnc.watch_pointer();
+        // the format asked by the client rather than the one of the server
+        assert_eq!(
+            *vnc.watch_pixel_format().borrow(),
+            Some(PixelFormat::rgba())
+        );
+        screen.changed().await.unwrap();
+        assert_eq!(*screen.borrow_and_update(), (800, 600).into());
+
+        // resized by the server
+        let mut payload = vec![0, 0, 0, 1];
+        for v in [0_u16, 0, 1024, 768] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::DesktopSizePseudo as i32).to_be_bytes());
+        server.write_all(&payload).await.unwrap();
+        screen.changed().await.unwrap();
+        assert_eq!(*screen.borrow_and_update(), (1024, 768).into());
+
+        // moved by the client
+        assert_eq!(*pointer.borrow_and_update(), (0, 0));
+        vnc.input(X11Event::PointerEvent((30, 40, 0).into()))
+            .await
+            .unwrap();
+        pointer.changed().await.unwrap();
+        assert_eq!(*pointer.borrow_and_update(), (30, 40));
+    }
+
+    #[test]
+    fn test_wire_encodings() {
+        let config = ClientConfig {
+            encodings: vec![VncEncoding::Zrle, VncEncoding::Raw],
+            pseudo_encodings: vec![
+                VncEncoding::JpegQualityLevel5Pseudo,
+                VncEncoding::CursorPseudo,
+            ],
+            ..Default::default()
+        };
+        // the jpeg quality level is useless without tight
+        assert_eq!(
+            config.wire_encodings(),
+            vec![
+                VncEncoding::Zrle,
+                VncEncoding::Raw,
+                VncEncoding::CursorPseudo,
+                VncEncoding::DesktopSizePseudo,
+            ]
+        );
+
+        let config = ClientConfig {
+            encodings: vec![VncEncoding::Tight, VncEncoding::Raw],
+            ..config
+        };
+        assert_eq!(
+            config.wire_encodings(),
+            vec![
+                VncEncoding::Tight,
+                VncEncoding::Raw,
+                VncEncoding::JpegQualityLevel5Pseudo,
+                VncEncoding::CursorPseudo,
+                VncEncoding::DesktopSizePseudo,
+            ]
+        );
+
+        // the server is never asked for jpeg once disabled
+        let config = ClientConfig {
+            jpeg_policy: JpegPolicy::Disable,
+            ..config
+        };
+        assert_eq!(
+            config.wire_encodings(),
+            vec![
+                VncEncoding::Tight,
+                VncEncoding::Raw,
+                VncEncoding::CursorPseudo,
+                VncEncoding::DesktopSizePseudo,
+            ]
+        );
+
+        let config = ClientConfig {
+            encodings: vec![VncEncoding::Zrle, VncEncoding::Tight],
+            content_mode: ContentMode::Text,
+            jpeg_policy: JpegPolicy::Emit,
+            ..config
+        };
+        assert_eq!(
+            config.wire_encodings(),
+            vec![
+                VncEncoding::Zrle,
+                VncEncoding::Tight,
+                VncEncoding::CursorPseudo,
+                VncEncoding::CompressionLevel9Pseudo,
+                VncEncoding::DesktopSizePseudo,
+            ]
+        );
+
+        let config = ClientConfig {
+            content_mode: ContentMode::Video,
+            ..config
+        };
+        assert_eq!(
+            config.wire_encodings(),
+            vec![
+                VncEncoding::Tight,
+                VncEncoding::Zrle,
+                VncEncoding::JpegQualityLevel5Pseudo,
+                VncEncoding::CursorPseudo,
+                VncEncoding::CompressionLevel1Pseudo,
+                VncEncoding::DesktopSizePseudo,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_next_prefetch_band() {
+        let viewport = Rect {
+            x: 0,
+            y: 100,
+            width: 800,
+            height: 200,
+        };
+        // bands inside of the viewport are skipped
+        let (band, next) = next_prefetch_band((800, 600), &viewport, 0, 100).unwrap();
+        assert_eq!((band.y, band.height, next), (0, 100, 100));
+        let (band, next) = next_prefetch_band((800, 600), &viewport, 100, 100).unwrap();
+        assert_eq!((band.y, band.height, next), (300, 100, 400));
+        // the last band is clipped and wraps around
+        let (band, next) = next_prefetch_band((800, 600), &viewport, 400, 150).unwrap();
+        assert_eq!((band.y, band.height, next), (400, 150, 550));
+        let (band, next) = next_prefetch_band((800, 600), &viewport, 550, 150).unwrap();
+        assert_eq!((band.y, band.height, next), (550, 50, 0));
+        // nothing to prefetch if the viewport covers the screen
+        let viewport = Rect {
+            x: 0,
+            y: 0,
+            width: 800,
+            height: 600,
+        };
+        assert!(next_prefetch_band((800, 600), &viewport, 0, 100).is_none());
+    }
+
+    fn bad_zrle_rect(payload: &mut Vec<u8>) {
+        for v in [0_u16, 0, 8, 8] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::Zrle as i32).to_be_bytes());
+        // a rle tile with a single color palette, which is invalid
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&[0x81, 0, 0, 0]).unwrap();
+        let data = encoder.flush_finish().unwrap();
+        payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&data);
+    }
+
+    #[cfg(feature = "trace")]
+    #[tokio::test]
+    async fn test_trace() {
+        use crate::{client::trace::Tracer, TraceDirection, TraceRecord};
+        use std::sync::{Arc, Mutex};
+
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::CopyRect],
+                    trace: Some(Tracer::new(move |record: &TraceRecord| {
+                        sink.lock().unwrap().push(record.clone())
+                    })),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        // FramebufferUpdate with 2 CopyRects followed by a Bell
+        let mut payload = vec![0, 0, 0, 2];
+        copy_rect(&mut payload, (10, 10), (0, 0));
+        copy_rect(&mut payload, (20, 10), (10, 0));
+        payload.push(2);
+        server.write_all(&payload).await.unwrap();
+        // all the messages are traced once the engine stops
+        drop(server);
+        while vnc.recv_event().await.is_ok() {}
+
+        let records = records.lock().unwrap();
+        let traced = |direction| {
+            records
+                .iter()
+                .filter(|r| r.direction == direction)
+                .map(|r| (r.message, r.bytes, r.rects))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            traced(TraceDirection::Server),
+            [
+                ("ServerInit", 28, None),
+                ("FramebufferUpdate", 36, Some(2)),
+                ("Bell", 1, None),
+            ]
+        );
+        assert_eq!(
+            traced(TraceDirection::Client),
+            [
+                ("ClientInit", 1, None),
+                ("SetPixelFormat", 20, None),
+                ("SetEncodings", 12, None),
+                ("FramebufferUpdateRequest", 10, None),
+            ]
+        );
+        assert!(records.iter().enumerate().all(|(i, r)| r.seq == i as u64));
+        assert!(records
+            .iter()
+            .filter(|r| r.message == "FramebufferUpdate")
+            .all(|r| r.duration.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_input_between_rects() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        // the first rect uses up the whole budget
+        let mut payload = vec![0, 0, 0, 2];
+        raw_rect(&mut payload, (0, 0, 128, 128));
+        let (last, payload) = payload.split_last().unwrap();
+        // the engine is decoding the update once most of it has been taken from the duplex
+        server.write_all(payload).await.unwrap();
+        vnc.input(X11Event::PointerEvent((7, 8, 0).into()))
+            .await
+            .unwrap();
+        server.write_u8(*last).await.unwrap();
+
+        // written before the second rect is sent
+        let mut msg = [0; 6];
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            server.read_exact(&mut msg),
+        )
+        .await
+        .expect("The input waits for the whole update")
+        .unwrap();
+        assert_eq!(msg, [5, 0, 0, 7, 0, 8]);
+
+        let mut payload = Vec::new();
+        raw_rect(&mut payload, (0, 128, 1, 1));
+        server.write_all(&payload).await.unwrap();
+        vnc.next_event_matching(|e| matches!(e, VncEvent::UpdateComplete))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frame_timing() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        for _ in 0..2 {
+            let mut payload = vec![0, 0, 0, 2];
+            raw_rect(&mut payload, (0, 0, 16, 16));
+            raw_rect(&mut payload, (16, 0, 16, 16));
+            server.write_all(&payload).await.unwrap();
+        }
+        vnc.next_event_matching(|e| matches!(e, VncEvent::UpdateComplete))
+            .await
+            .unwrap();
+        vnc.next_event_matching(|e| matches!(e, VncEvent::UpdateComplete))
+            .await
+            .unwrap();
+        let frames = vnc.debug_report().recent_frames;
+        assert_eq!(frames.len(), 2);
+        for frame in frames.iter() {
+            assert_eq!(frame.rects, 2);
+            assert!(frame.received <= frame.decoded);
+            assert!(frame.decoded <= frame.delivered.unwrap());
+        }
+        assert!(frames[0].decoded <= frames[1].received);
+        assert!(frames[0].delivered <= frames[1].delivered);
+    }
+
+    #[tokio::test]
+    async fn test_rect_encodings() {
+        for emit in [false, true] {
+            let (client, mut server) = duplex(4096);
+            let (vnc, _) = tokio::join!(
+                VncClient::new(
+                    client,
+                    ClientConfig {
+                        pixel_format: Some(PixelFormat::bgra()),
+                        encodings: vec![VncEncoding::Raw],
+                        rect_encodings: emit,
+                        ..Default::default()
+                    },
+                ),
+                server_init(&mut server)
+            );
+            let vnc = vnc.unwrap();
+
+            let mut payload = vec![0, 0, 0, 1];
+            raw_rect(&mut payload, (3, 4, 2, 2));
+            server.write_all(&payload).await.unwrap();
+            let mut events = Vec::new();
+            loop {
+                match vnc.recv_event().await.unwrap() {
+                    VncEvent::SetResolution(_) => (),
+                    VncEvent::UpdateComplete => break,
+                    event => events.push(event),
+                }
+            }
+            let rect = Rect {
+                x: 3,
+                y: 4,
+                width: 2,
+                height: 2,
+            };
+            match (emit, events.as_slice()) {
+                (false, [VncEvent::RawImage(..)]) => (),
+                (
+                    true,
+                    [VncEvent::RectEncoding(encoded, VncEncoding::Raw), VncEvent::RawImage(..)],
+                ) if *encoded == rect => {}
+                _ => panic!("Unexpected events {:?}", events),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pointer_pos() {
+        let config = ClientConfig {
+            pixel_format: Some(PixelFormat::bgra()),
+            encodings: vec![VncEncoding::Raw],
+            pseudo_encodings: vec![VncEncoding::PointerPosPseudo],
+            transform: crate::Transform::FlipHorizontal,
+            ..Default::default()
+        };
+        assert!(config
+            .wire_encodings()
+            .contains(&VncEncoding::PointerPosPseudo));
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(VncClient::new(client, config), server_init(&mut server));
+        let vnc = vnc.unwrap();
+
+        // moved along with an image in the same update
+        let mut payload = vec![0, 0, 0, 2];
+        raw_rect(&mut payload, (0, 0, 1, 1));
+        for v in [10_u16, 20, 0, 0] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::PointerPosPseudo as i32).to_be_bytes());
+        server.write_all(&payload).await.unwrap();
+        let mut events = Vec::new();
+        loop {
+            match vnc.recv_event().await.unwrap() {
+                VncEvent::UpdateComplete => break,
+                VncEvent::RawImage(rect, _) => events.push((rect.x, rect.y)),
+                VncEvent::PointerPosition(x, y) => events.push((x, y)),
+                _ => (),
+            }
+        }
+        // mapped to the local display as the images
+        assert_eq!(events, [(799, 0), (789, 20)]);
+        assert_eq!(*vnc.watch_pointer().borrow(), (10, 20));
+    }
+
+    #[tokio::test]
+    async fn test_led_state() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![VncEncoding::QemuLedStatePseudo],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        // each bit of the state, then a rect right after the single byte payload
+        let mut payload = vec![0, 0, 0, 4];
+        for state in [1, 2, 4] {
+            payload.extend_from_slice(&[0; 8]);
+            payload.extend_from_slice(&(VncEncoding::QemuLedStatePseudo as i32).to_be_bytes());
+            payload.push(state);
+        }
+        raw_rect(&mut payload, (0, 0, 1, 1));
+        server.write_all(&payload).await.unwrap();
+        let mut events = Vec::new();
+        loop {
+            match vnc.recv_event().await.unwrap() {
+                VncEvent::UpdateComplete => break,
+                VncEvent::LedState { caps, num, scroll } => events.push(Some((caps, num, scroll))),
+                VncEvent::RawImage(..) => events.push(None),
+                _ => (),
+            }
+        }
+        assert_eq!(
+            events,
+            [
+                Some((false, false, true)),
+                Some((false, true, false)),
+                Some((true, false, false)),
+                None
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encoding_fallback() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Zrle, VncEncoding::Raw],
+                    max_decode_failures: Some(2),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        let mut payload = vec![0, 0, 0, 1];
+        bad_zrle_rect(&mut payload);
+        server.write_all(&payload).await.unwrap();
+        // the rect is required again
+        let mut msgs = [0; 10];
+        server.read_exact(&mut msgs).await.unwrap();
+        assert_eq!(msgs, [3, 0, 0, 0, 0, 0, 0, 8, 0, 8]);
+
+        server.write_all(&payload).await.unwrap();
+        // then zrle is removed and the whole screen is required
+        let mut msgs = [0; 12 + 10];
+        server.read_exact(&mut msgs).await.unwrap();
+        assert_eq!(msgs[..12], [2, 0, 0, 2, 0, 0, 0, 0, 255, 255, 255, 33]);
+        assert_eq!(msgs[12..], [3, 0, 0, 0, 0, 0, 3, 32, 2, 88]);
+        assert_eq!(
+            vnc.debug_report().encodings,
+            vec![VncEncoding::Raw, VncEncoding::DesktopSizePseudo]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drop_stale_pointer_events() {
+        // a small buffer to congest the connection
+        let (client, mut server) = duplex(64);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        for x in 0..100 {
+            vnc.input(X11Event::PointerEvent((x, 0, 0).into()))
+                .await
+                .unwrap();
+        }
+        let mut received = 0;
+        let mut msg = [0; 6];
+        while msg[2..4] != 99_u16.to_be_bytes() {
+            server.read_exact(&mut msg).await.unwrap();
+            received += 1;
+        }
+        assert!(received < 100);
+        let report = vnc.debug_report();
+        assert_eq!(report.dropped_pointer_events, 100 - received);
+        assert!(report.max_queued_messages > 0);
+    }
+
+    #[tokio::test]
+    async fn test_update_ordering() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw, VncEncoding::CopyRect],
+                    pseudo_encodings: vec![VncEncoding::CursorPseudo],
+                    rect_encodings: true,
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SetResolution(_)
+        ));
+
+        let rect = |payload: &mut Vec<u8>, x: u16, encoding: VncEncoding| {
+            for v in [x, 0, 1, 1] {
+                payload.extend_from_slice(&v.to_be_bytes());
+            }
+            payload.extend_from_slice(&(encoding as i32).to_be_bytes());
+        };
+        let mut payload = vec![0, 0, 0, 4];
+        rect(&mut payload, 0, VncEncoding::Raw);
+        payload.extend_from_slice(&[1, 1, 1, 0]);
+        copy_rect(&mut payload, (1, 0), (0, 0));
+        rect(&mut payload, 0, VncEncoding::CursorPseudo);
+        payload.extend_from_slice(&[2, 2, 2, 0, 0x80]);
+        rect(&mut payload, 2, VncEncoding::Raw);
+        payload.extend_from_slice(&[3, 3, 3, 0]);
+        server.write_all(&payload).await.unwrap();
+
+        let mut events = vec![];
+        loop {
+            match vnc.recv_event().await.unwrap() {
+                VncEvent::RawImage(rect, _) => events.push(format!("RawImage({})", rect.x)),
+                VncEvent::Copy(..) => events.push("Copy".to_string()),
+                VncEvent::SetCursor(..) => events.push("SetCursor".to_string()),
+                VncEvent::RectEncoding(_, encoding) => events.push(format!("{:?}", encoding)),
+                VncEvent::UpdateComplete => break,
+                e => panic!("Unexpected event {:?}", e),
+            }
+        }
+        assert_eq!(
+            events,
+            [
+                "Raw",
+                "RawImage(0)",
+                "CopyRect",
+                "Copy",
+                "CursorPseudo",
+                "SetCursor",
+                "Raw",
+                "RawImage(2)"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warp_pointer() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![VncEncoding::PointerPosPseudo],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        vnc.input(X11Event::PointerEvent((1, 2, 1).into()))
+            .await
+            .unwrap();
+        let mut msg = [0; 6];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [5, 1, 0, 1, 0, 2]);
+
+        // the pressed button is kept while warping
+        vnc.warp_pointer(300, 200, true).await.unwrap();
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [5, 1, 1, 44, 0, 200]);
+        assert!(matches!(
+            vnc.next_event_matching(|e| matches!(e, VncEvent::CursorPosition(..)))
+                .await
+                .unwrap(),
+            VncEvent::CursorPosition(300, 200)
+        ));
+        assert_eq!(*vnc.watch_pointer().borrow(), (300, 200));
+
+        // moved by the server
+        let mut payload = vec![0, 0, 0, 1];
+        for v in [10_u16, 20, 0, 0] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::PointerPosPseudo as i32).to_be_bytes());
+        server.write_all(&payload).await.unwrap();
+        assert!(matches!(
+            vnc.next_event_matching(|e| matches!(e, VncEvent::PointerPosition(..)))
+                .await
+                .unwrap(),
+            VncEvent::PointerPosition(10, 20)
+        ));
+        assert_eq!(*vnc.watch_pointer().borrow(), (10, 20));
+    }
+
+    #[tokio::test]
+    async fn test_cursor_layer() {
+        let config = ClientConfig {
+            encodings: vec![VncEncoding::Raw],
+            cursor_layer: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.wire_encodings(),
+            [
+                VncEncoding::Raw,
+                VncEncoding::DesktopSizePseudo,
+                VncEncoding::CursorWithAlphaPseudo,
+                VncEncoding::CursorPseudo,
+                VncEncoding::PointerPosPseudo,
+            ]
+        );
+
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    ..config
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        let mut cursor = vnc.watch_cursor();
+        assert_eq!(*cursor.borrow(), CursorState::default());
+
+        // a 1x1 half transparent white cursor with the hotspot at (1, 0), then moved by the server
+        let mut payload = vec![0, 0, 0, 2];
+        for v in [1_u16, 0, 1, 1] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::CursorWithAlphaPseudo as i32).to_be_bytes());
+        payload.extend_from_slice(&(VncEncoding::Raw as i32).to_be_bytes());
+        payload.extend_from_slice(&[128, 128, 128, 128]);
+        for v in [10_u16, 20, 0, 0] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::PointerPosPseudo as i32).to_be_bytes());
+        server.write_all(&payload).await.unwrap();
+        vnc.next_event_matching(|e| matches!(e, VncEvent::PointerPosition(..)))
+            .await
+            .unwrap();
+        assert_eq!(
+            *cursor.borrow_and_update(),
+            CursorState {
+                width: 1,
+                height: 1,
+                hotspot: (1, 0),
+                rgba: vec![255, 255, 255, 128],
+                position: (10, 20),
+            }
+        );
+
+        // moved by the client
+        vnc.input(X11Event::PointerEvent((3, 4, 0).into()))
+            .await
+            .unwrap();
+        cursor.changed().await.unwrap();
+        assert_eq!(cursor.borrow().position, (3, 4));
+    }
+
+    #[tokio::test]
+    async fn test_palette_updated() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        let colors = vec![[0, 0, 0], [65535, 0, 32768]];
+        server
+            .write_all(&ServerMsg::SetColorMapEntries(254, colors.clone()).to_bytes())
+            .await
+            .unwrap();
+        let event = vnc
+            .next_event_matching(|e| matches!(e, VncEvent::PaletteUpdated(..)))
+            .await
+            .unwrap();
+        assert!(
+            matches!(event, VncEvent::PaletteUpdated(range, c) if range == (254..256) && c == colors)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_qemu_extensions() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![
+                        VncEncoding::QemuExtendedKeyEventPseudo,
+                        VncEncoding::QemuLedStatePseudo,
+                    ],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        let key = X11Event::ExtendedKeyEvent {
+            keysym: 0x61,
+            keycode: 0x1e,
+            down: true,
+        };
+
+        // a plain key event until acknowledged
+        vnc.input(key.clone()).await.unwrap();
+        let mut msg = [0; 8];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [4, 1, 0, 0, 0, 0, 0, 0x61]);
+
+        let mut payload = vec![0, 0, 0, 2];
+        payload.extend_from_slice(&[0; 8]);
+        payload.extend_from_slice(&(VncEncoding::QemuExtendedKeyEventPseudo as i32).to_be_bytes());
+        // caps lock on
+        payload.extend_from_slice(&[0; 8]);
+        payload.extend_from_slice(&(VncEncoding::QemuLedStatePseudo as i32).to_be_bytes());
+        payload.push(4);
+        server.write_all(&payload).await.unwrap();
+        assert!(matches!(
+            vnc.next_event_matching(|e| matches!(e, VncEvent::LedState { .. }))
+                .await
+                .unwrap(),
+            VncEvent::LedState {
+                caps: true,
+                num: false,
+                scroll: false
+            }
+        ));
+        vnc.next_event_matching(|e| matches!(e, VncEvent::UpdateComplete))
+            .await
+            .unwrap();
+
+        vnc.input(key).await.unwrap();
+        let mut msg = [0; 12];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [255, 0, 0, 1, 0, 0, 0, 0x61, 0, 0, 0, 0x1e]);
+    }
+
+    #[tokio::test]
+    async fn test_focus() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![VncEncoding::QemuLedStatePseudo],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        vnc.input(X11Event::KeyEvent((0x61, true).into()))
+            .await
+            .unwrap();
+        vnc.input(X11Event::PointerEvent((5, 6, 1).into()))
+            .await
+            .unwrap();
+        let mut msgs = [0; 8 + 6];
+        server.read_exact(&mut msgs).await.unwrap();
+
+        // released on the loss
+        vnc.notify_focus(false).await.unwrap();
+        server.read_exact(&mut msgs).await.unwrap();
+        assert_eq!(msgs[..8], [4, 0, 0, 0, 0, 0, 0, 0x61]);
+        assert_eq!(msgs[8..], [5, 0, 0, 5, 0, 6]);
+
+        // nothing to sync before the led state is known
+        let locks = LockKeys {
+            caps: true,
+            ..Default::default()
+        };
+        vnc.notify_focus_with_locks(locks).await.unwrap();
+
+        // num lock on
+        let mut payload = vec![0, 0, 0, 1];
+        payload.extend_from_slice(&[0; 8]);
+        payload.extend_from_slice(&(VncEncoding::QemuLedStatePseudo as i32).to_be_bytes());
+        payload.push(2);
+        server.write_all(&payload).await.unwrap();
+        vnc.next_event_matching(|e| matches!(e, VncEvent::UpdateComplete))
+            .await
+            .unwrap();
+
+        // caps lock & num lock are tapped
+        vnc.notify_focus_with_locks(locks).await.unwrap();
+        vnc.notify_focus_with_locks(locks).await.unwrap();
+        vnc.tap_key(0x62).await.unwrap();
+        let mut msgs = [0; 8 * 6];
+        server.read_exact(&mut msgs).await.unwrap();
+        let keys: Vec<(u8, u8)> = msgs.chunks(8).map(|msg| (msg[1], msg[7])).collect();
+        assert_eq!(
+            keys,
+            [
+                (1, 0xe5),
+                (0, 0xe5),
+                (1, 0x7f),
+                (0, 0x7f),
+                (1, 0x62),
+                (0, 0x62)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_runtime() {
+        let decode = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let main = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        main.block_on(async {
+            let (client, mut server) = duplex(4096);
+            let (vnc, _) = tokio::join!(
+                VncClient::new(
+                    client,
+                    ClientConfig {
+                        pixel_format: Some(PixelFormat::bgra()),
+                        encodings: vec![VncEncoding::Raw],
+                        decode_runtime: Some(decode.handle().clone()),
+                        ..Default::default()
+                    },
+                ),
+                server_init(&mut server)
+            );
+            let vnc = vnc.unwrap();
+            assert_eq!(decode.metrics().num_alive_tasks(), 1);
+            vnc.recv_event().await.unwrap();
+            server.write_all(&[2]).await.unwrap();
+            assert!(matches!(vnc.recv_event().await.unwrap(), VncEvent::Bell));
+        });
+    }
+
+    #[tokio::test]
+    async fn test_gii() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![VncEncoding::GiiPseudo],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.recv_event().await.unwrap();
+
+        // version 1 only, in little endian
+        server.write_all(&[253, 1, 4, 0, 1, 0, 1, 0]).await.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::GiiSupported
+        ));
+        let mut msg = [0; 6];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [253, 129, 0, 2, 0, 1]);
+
+        let device = GiiDevice {
+            name: "pen".to_string(),
+            vendor_id: 0,
+            product_id: 0,
+            valuators: vec![GiiValuator::new("Pressure", "p", 0, 1023)],
+            buttons: 1,
+        };
+        vnc.input(X11Event::GiiCreateDevice(device.clone()))
+            .await
+            .unwrap();
+        let mut msg = vec![0; 4 + 56 + 116];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(
+            ClientMsg::from_bytes(&msg).unwrap().0,
+            ClientMsg::GiiCreateDevice(device)
+        );
+
+        server.write_all(&[253, 2, 4, 0, 7, 0, 0, 0]).await.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::GiiDeviceCreated(7)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_content_mode() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Tight, VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        vnc.prefer_text_mode().await.unwrap();
+        let mut msg = vec![0; 4];
+        server.read_exact(&mut msg).await.unwrap();
+        msg.resize(4 + 4 * msg[3] as usize, 0);
+        server.read_exact(&mut msg[4..]).await.unwrap();
+        let ClientMsg::SetEncodings(encodings) = ClientMsg::from_bytes(&msg).unwrap().0 else {
+            panic!("No encodings");
+        };
+        assert!(encodings.contains(&VncEncoding::CompressionLevel9Pseudo));
+        assert_eq!(vnc.debug_report().encodings, encodings);
+    }
+
+    #[tokio::test]
+    async fn test_xvp() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![VncEncoding::XvpPseudo],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.recv_event().await.unwrap();
+
+        server.write_all(&[250, 0, 1, 1]).await.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::XvpInit(1)
+        ));
+
+        vnc.input(X11Event::Xvp(XvpAction::Reboot)).await.unwrap();
+        let mut msg = [0; 4];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [250, 0, 1, 3]);
+        server.write_all(&[250, 0, 1, 0]).await.unwrap();
+        assert!(matches!(vnc.recv_event().await.unwrap(), VncEvent::XvpFail));
+    }
+
+    #[tokio::test]
+    async fn test_skip_unknown_messages() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    unknown_message_policy: UnknownMessagePolicy::Skip,
+                    message_lengths: HashMap::from([(
+                        200,
+                        MessageLength::Prefixed { offset: 1, size: 2 },
+                    )]),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.recv_event().await.unwrap();
+
+        // a fence message, a registered one, then a bell
+        let payload = [248, 0, 0, 0, 0, 0, 0, 0, 0, 200, 0, 0, 3, 1, 2, 3, 2];
+        server.write_all(&payload).await.unwrap();
+        for (msg_type, payload) in [(248, &[0; 8][..]), (200, &[0, 0, 3, 1, 2, 3])] {
+            assert!(matches!(
+                vnc.recv_event().await.unwrap(),
+                VncEvent::Extension(ExtensionEvent::ServerMessage { msg_type: t, payload: p })
+                    if t == msg_type && p == payload
+            ));
+        }
+        assert!(matches!(vnc.recv_event().await.unwrap(), VncEvent::Bell));
+        assert_eq!(vnc.debug_report().skipped_messages, 2);
+
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.recv_event().await.unwrap();
+        server
+            .write_all(&[248, 0, 0, 0, 0, 0, 0, 0, 0, 2])
+            .await
+            .unwrap();
+        let error = vnc.recv_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VncError>(),
+            Some(VncError::WrongServerMessage)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_extension_events() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    unknown_message_policy: UnknownMessagePolicy::Skip,
+                    message_lengths: HashMap::from([(201, MessageLength::Fixed(3))]),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.next_resize().await.unwrap();
+
+        // a bell, a message split across writes, then an update
+        server.write_all(&[2, 201, 7]).await.unwrap();
+        server.write_all(&[8, 9]).await.unwrap();
+        let mut update = ServerMsg::FramebufferUpdate(1).to_bytes();
+        raw_rect(&mut update, (0, 0, 1, 1));
+        server.write_all(&update).await.unwrap();
+
+        assert!(matches!(vnc.recv_event().await.unwrap(), VncEvent::Bell));
+        let event = vnc.recv_event().await.unwrap();
+        let VncEvent::Extension(extension) = event else {
+            panic!("Unexpected event {:?}", event);
+        };
+        assert_eq!(
+            extension,
+            ExtensionEvent::ServerMessage {
+                msg_type: 201,
+                payload: vec![7, 8, 9]
+            }
+        );
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::RawImage(..)
+        ));
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::UpdateComplete
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keepalive() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    keepalive: Some((
+                        std::time::Duration::from_millis(20),
+                        std::time::Duration::from_millis(100),
+                    )),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SetResolution(_)
+        ));
+
+        // alive as long as the probes are answered
+        let probe = Rect {
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+        };
+        let started = tokio::time::Instant::now();
+        while started.elapsed() < std::time::Duration::from_millis(300) {
+            let message = ClientMsg::read(&mut server).await.unwrap();
+            if message == ClientMsg::FramebufferUpdateRequest(probe, 0) {
+                ServerMsg::FramebufferUpdate(0)
+                    .write(&mut server)
+                    .await
+                    .unwrap();
+            }
+        }
+        // then stale once they are not
+        let event = loop {
+            match vnc.recv_event().await.unwrap() {
+                VncEvent::UpdateComplete => continue,
+                event => break event,
+            }
+        };
+        assert!(matches!(event, VncEvent::ConnectionStale));
+        let error = vnc.recv_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VncError>(),
+            Some(VncError::Timeout)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_read_timeout() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    read_timeout: Some(std::time::Duration::from_millis(50)),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SetResolution(_)
+        ));
+
+        // an idle server is fine
+        tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+        server.write_u8(2).await.unwrap();
+        assert!(matches!(vnc.recv_event().await.unwrap(), VncEvent::Bell));
+
+        // while a stalled rect is not
+        let mut payload = vec![0, 0, 0, 1];
+        for v in [0_u16, 0, 2, 2] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::Raw as i32).to_be_bytes());
+        payload.extend_from_slice(&[1, 1, 1, 0]);
+        server.write_all(&payload).await.unwrap();
+        let error = vnc.recv_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VncError>(),
+            Some(VncError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_sanity_checks() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    max_update_rects: Some(10),
+                    update_timeout: Some(std::time::Duration::from_millis(50)),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SetResolution(_)
+        ));
+
+        // 2 rects announced, only 1 sent
+        let mut payload = vec![0, 0, 0, 2];
+        for v in [0_u16, 0, 1, 1] {
+            payload.extend_from_slice(&v.to_be_bytes());
+        }
+        payload.extend_from_slice(&(VncEncoding::Raw as i32).to_be_bytes());
+        payload.extend_from_slice(&[1, 1, 1, 0]);
+        server.write_all(&payload).await.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::RawImage(..)
+        ));
+        let error = vnc.recv_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VncError>(),
+            Some(VncError::UpdateTimeout(1, 2))
+        ));
+
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    max_update_rects: Some(10),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.recv_event().await.unwrap();
+        server.write_all(&[0, 0, 0xff, 0xff]).await.unwrap();
+        let error = vnc.recv_event().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<VncError>(),
+            Some(VncError::TooManyRects(0xffff))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_key_auto_release() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    key_auto_release: Some(std::time::Duration::from_millis(20)),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        let mut msg = [0; 8];
+        vnc.tap_key(0x61).await.unwrap();
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [4, 1, 0, 0, 0, 0, 0, 0x61]);
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [4, 0, 0, 0, 0, 0, 0, 0x61]);
+
+        // the lost release is sent once the key is held too long
+        vnc.input(X11Event::KeyEvent((0x62, true).into()))
+            .await
+            .unwrap();
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [4, 1, 0, 0, 0, 0, 0, 0x62]);
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [4, 0, 0, 0, 0, 0, 0, 0x62]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_input() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        for keysym in 0x61..0x71 {
+            vnc.tap_key(keysym).await.unwrap();
+        }
+        vnc.flush_input().await.unwrap();
+        // all the key events are ready to read without waiting for the engine
+        let mut msgs = [0; 8 * 32];
+        tokio::time::timeout(std::time::Duration::ZERO, server.read_exact(&mut msgs))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msgs[8 * 31..], [4, 0, 0, 0, 0, 0, 0, 0x70]);
+
+        // the refresh can't be written anymore
+        drop(server);
+        vnc.input(X11Event::Refresh).await.ok();
+        assert!(vnc.flush_input().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        vnc.tap_key(0x61).await.unwrap();
+        // an update of two 1x1 raw rects, the second of which is not sent yet
+        let mut update = ServerMsg::FramebufferUpdate(2).to_bytes();
+        raw_rect(&mut update, (0, 0, 1, 1));
+        update.extend_from_slice(&[0, 1, 0, 0, 0, 1, 0, 1, 0, 0, 0, 0]);
+        server.write_all(&update).await.unwrap();
+        // being decoded once the first rect is delivered
+        vnc.next_event_matching(|e| matches!(e, VncEvent::RawImage(..)))
+            .await
+            .unwrap();
+        let (closed, _) = tokio::join!(vnc.close(true), server.write_all(&[1, 2, 3, 0]));
+        closed.unwrap();
+
+        // the key events are written before the write half is shut down
+        let mut msgs = Vec::new();
+        server.read_to_end(&mut msgs).await.unwrap();
+        assert_eq!(
+            msgs,
+            [[4, 1, 0, 0, 0, 0, 0, 0x61], [4, 0, 0, 0, 0, 0, 0, 0x61]].concat()
+        );
+        // and the update is delivered
+        assert!(matches!(
+            vnc.next_event_matching(|e| matches!(e, VncEvent::RawImage(..))).await.unwrap(),
+            VncEvent::RawImage(_, pixels) if pixels == [1, 2, 3, 0]
+        ));
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::UpdateComplete
+        ));
+        assert!(vnc.input(X11Event::Refresh).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_close_dropping_update() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        // an update of three small rects, far from the yield budget, the last of which is never sent
+        let mut update = ServerMsg::FramebufferUpdate(3).to_bytes();
+        raw_rect(&mut update, (0, 0, 1, 1));
+        server.write_all(&update).await.unwrap();
+        vnc.next_event_matching(|e| matches!(e, VncEvent::RawImage(..)))
+            .await
+            .unwrap();
+        let mut second = Vec::new();
+        raw_rect(&mut second, (1, 0, 1, 1));
+        let (closed, _) = tokio::join!(
+            tokio::time::timeout(std::time::Duration::from_secs(5), vnc.close(false)),
+            server.write_all(&second)
+        );
+        closed
+            .expect("The close waits for the whole update")
+            .unwrap();
+
+        // closed between the rects
+        while let std::result::Result::Ok(event) = vnc.recv_event().await {
+            assert!(!matches!(event, VncEvent::UpdateComplete));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_detach_refused() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Zrle, VncEncoding::Raw],
+                    detachable: true,
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        // the zlib stream of the server can't be taken over
+        let e = vnc.detach().await.unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(VncError::Custom(_))));
+        // while the engine keeps running
+        vnc.tap_key(0x61).await.unwrap();
+        vnc.flush_input().await.unwrap();
+        let mut msgs = [0; 16];
+        server.read_exact(&mut msgs).await.unwrap();
+        assert_eq!(msgs[7], 0x61);
+    }
+
+    #[tokio::test]
+    async fn test_drain_events() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+
+        let (wake, mut woken) = tokio::sync::mpsc::unbounded_channel();
+        vnc.register_waker(move || wake.send(()).unwrap());
+        // called right away
+        woken.recv().await.unwrap();
+        let mut events = Vec::new();
+        vnc.drain_events(&mut events).unwrap();
+
+        // two bells
+        server.write_all(&[2, 2]).await.unwrap();
+        events.clear();
+        while events.len() < 2 {
+            woken.recv().await.unwrap();
+            vnc.drain_events(&mut events).unwrap();
+        }
+        assert!(events.iter().all(|e| matches!(e, VncEvent::Bell)));
+
+        // the error follows the events once the engine stops
+        server.write_all(&[2]).await.unwrap();
+        drop(server);
+        events.clear();
+        loop {
+            woken.recv().await.unwrap();
+            if vnc.drain_events(&mut events).is_err() {
+                break;
+            }
+        }
+        assert!(matches!(events[..], [VncEvent::Bell]));
+    }
+
+    #[tokio::test]
+    async fn test_displaced_by_other_client() {
+        let (client, mut server) = duplex(4096);
+        let config = ClientConfig {
+            shared: false,
+            ..Default::default()
+        };
+        let (vnc, _) = tokio::join!(VncClient::new(client, config), async move {
+            assert_eq!(server.read_u8().await.unwrap(), 0);
+        });
+        let e = vnc.err().unwrap();
+        assert!(matches!(
+            e.downcast_ref::<VncError>(),
+            Some(VncError::Disconnected(
+                DisconnectReason::DisplacedByOtherClient
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_continuous_updates() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![VncEncoding::ContinuousUpdatesPseudo],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SetResolution(_)
+        ));
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 800,
+            height: 600,
+        };
+
+        // held until the server confirms the support
+        vnc.enable_continuous_updates(rect).await.unwrap();
+        server.write_u8(150).await.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::EndOfContinuousUpdates
+        ));
+        let mut msg = [0; 10];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [150, 1, 0, 0, 0, 0, 3, 0x20, 2, 0x58]);
+
+        vnc.disable_continuous_updates().await.unwrap();
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [150, 0, 0, 0, 0, 0, 3, 0x20, 2, 0x58]);
+    }
+
+    #[tokio::test]
+    async fn test_preferred_resolution() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    preferred_resolution: Some((1024, 768)),
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.recv_event().await.unwrap();
+
+        // the initial layout
+        let mut payload = vec![0, 0, 0, 1, 0, 0, 0, 0, 3, 0x20, 2, 0x58];
+        payload.extend_from_slice(&(VncEncoding::ExtendedDesktopSizePseudo as i32).to_be_bytes());
+        payload.extend_from_slice(&[
+            1, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 3, 0x20, 2, 0x58, 0, 0, 0, 0,
+        ]);
+        server.write_all(&payload).await.unwrap();
+        let mut msg = [0; 24];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(
+            msg,
+            [251, 0, 4, 0, 3, 0, 1, 0, 0, 0, 0, 7, 0, 0, 0, 0, 4, 0, 3, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_desktop_size() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    pseudo_encodings: vec![VncEncoding::ExtendedDesktopSizePseudo],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SetResolution(_)
+        ));
+
+        let desktop_size = |reason: u16, status: u16, width: u16, height: u16| {
+            let mut payload = vec![0, 0, 0, 1];
+            for v in [reason, status, width, height] {
+                payload.extend_from_slice(&v.to_be_bytes());
+            }
+            payload
+                .extend_from_slice(&(VncEncoding::ExtendedDesktopSizePseudo as i32).to_be_bytes());
+            payload.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0]);
+            payload.extend_from_slice(&width.to_be_bytes());
+            payload.extend_from_slice(&height.to_be_bytes());
+            payload.extend_from_slice(&[0; 4]);
+            payload
+        };
+        // the initial layout tells the support
+        server
+            .write_all(&desktop_size(0, 0, 800, 600))
+            .await
+            .unwrap();
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::SetResolution(_)
+        ));
+        let Ok(VncEvent::ExtendedDesktopSize(desktop)) = vnc.recv_event().await else {
+            panic!("No desktop size");
+        };
+        assert_eq!(desktop.screens[0].id, 7);
+        assert!(matches!(
+            vnc.recv_event().await.unwrap(),
+            VncEvent::UpdateComplete
+        ));
+
+        vnc.set_desktop_size(1024, 768, vec![]).await.unwrap();
+        let mut msg = [0; 24];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(
+            msg,
+            [251, 0, 4, 0, 3, 0, 1, 0, 0, 0, 0, 7, 0, 0, 0, 0, 4, 0, 3, 0, 0, 0, 0, 0]
+        );
+
+        // rejected, the size stays
+        server
+            .write_all(&desktop_size(1, 1, 800, 600))
+            .await
+            .unwrap();
+        let Ok(VncEvent::ExtendedDesktopSize(desktop)) = vnc.recv_event().await else {
+            panic!("No desktop size");
+        };
+        assert_eq!(desktop.reason, ResizeReason::ThisClient);
+        assert_eq!(desktop.status, ResizeStatus::Prohibited);
+    }
+
+    /// A stream which is not `Send`
+    struct LocalStream(DuplexStream, std::marker::PhantomData<std::rc::Rc<()>>);
+
+    impl tokio::io::AsyncRead for LocalStream {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl tokio::io::AsyncWrite for LocalStream {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::pin::Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::pin::Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    /// A stream which counts the reads reaching it
+    struct CountingStream(DuplexStream, Arc<AtomicUsize>);
+
+    impl AsyncRead for CountingStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let filled = buf.filled().len();
+            let poll = Pin::new(&mut self.0).poll_read(cx, buf);
+            if buf.filled().len() > filled {
+                self.1.fetch_add(1, Ordering::Relaxed);
+            }
+            poll
+        }
+    }
+
+    impl AsyncWrite for CountingStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_staged_reads() {
+        let (client, mut server) = duplex(64 * 1024);
+        let reads = Arc::new(AtomicUsize::new(0));
+        let client = CountingStream(client, reads.clone());
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.next_resize().await.unwrap();
+
+        // a single write of many small rects, each of several fields
+        let mut payload = vec![0, 0, 0, 200];
+        for x in 0..200 {
+            raw_rect(&mut payload, (x, 0, 1, 1));
+        }
+        let before = reads.load(Ordering::Relaxed);
+        server.write_all(&payload).await.unwrap();
+        vnc.next_event_matching(|e| matches!(e, VncEvent::UpdateComplete))
+            .await
+            .unwrap();
+        let staged = reads.load(Ordering::Relaxed) - before;
+        assert!(staged <= 2, "{} reads for an update", staged);
+    }
+
+    #[tokio::test]
+    async fn test_split_message() {
+        let (client, mut server) = duplex(4096);
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            server_init(&mut server)
+        );
+        let vnc = vnc.unwrap();
+        vnc.next_resize().await.unwrap();
+
+        let bytes = ServerMsg::ServerCutText("text".to_string()).to_bytes();
+        server.write_all(&bytes[..6]).await.unwrap();
+        // the read of the rest is interrupted by the input
+        vnc.input(X11Event::PointerEvent((7, 8, 0).into()))
+            .await
+            .unwrap();
+        let mut msg = [0; 6];
+        server.read_exact(&mut msg).await.unwrap();
+        assert_eq!(msg, [5, 0, 0, 7, 0, 8]);
+        server.write_all(&bytes[6..]).await.unwrap();
+        assert_eq!(vnc.next_clipboard().await.unwrap(), "text");
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_local_client() {
+        let (client, mut server) = duplex(4096);
+        let client = LocalStream(client, std::marker::PhantomData);
+        tokio::task::LocalSet::new()
+            .run_until(async move {
+                let (vnc, _) = tokio::join!(
+                    VncClient::new_local(
+                        client,
+                        ClientConfig {
+                            pixel_format: Some(PixelFormat::bgra()),
+                            encodings: vec![VncEncoding::Raw],
+                            ..Default::default()
+                        },
+                    ),
+                    server_init(&mut server)
+                );
+                let vnc = vnc.unwrap();
+                assert!(matches!(
+                    vnc.recv_event().await.unwrap(),
+                    VncEvent::SetResolution(_)
+                ));
+
+                server.write_all(&[2]).await.unwrap();
+                assert!(matches!(vnc.recv_event().await.unwrap(), VncEvent::Bell));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_tasks() {
+        let (client, mut server) = duplex(4096);
+        let mut set = tokio::task::JoinSet::new();
+        let (vnc, _) = tokio::join!(
+            VncClient::new_in(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+                &mut set,
+            ),
+            server_init(&mut server)
+        );
+        let (vnc, tasks) = vnc.unwrap();
+        assert_eq!(set.len(), 3);
+        vnc.next_resize().await.unwrap();
+
+        // the others stop once the reader is aborted
+        tasks.reader().abort();
+        let mut cancelled = vec![];
+        while let Some(result) = set.join_next_with_id().await {
+            if let Err(e) = result {
+                assert!(e.is_cancelled());
+                cancelled.push(e.id());
+            }
+        }
+        assert_eq!(cancelled, [tasks.reader().id()]);
+        assert!(tasks.is_finished());
+        let e = vnc.recv_event().await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(VncError::Custom(reason)) if reason == "The reader task of the engine is aborted"
+        ));
+        drop(server);
     }
 }
@@ -0,0 +1,112 @@
+use std::io::Write;
+
+use flate2::{write::ZlibEncoder, Compression};
+
+use crate::{DeltaCompression, DeltaRect, FrameDelta, VncEvent};
+
+/// Collect the images & copies of the updates into [FrameDelta]s
+pub(super) struct DeltaEncoder {
+    zlib: Option<ZlibEncoder<Vec<u8>>>,
+    delta: FrameDelta,
+}
+
+impl DeltaEncoder {
+    pub(super) fn new(compression: DeltaCompression) -> Self {
+        let zlib = match compression {
+            DeltaCompression::None => None,
+            DeltaCompression::Zlib(level) => {
+                Some(ZlibEncoder::new(Vec::new(), Compression::new(level.min(9))))
+            }
+        };
+        Self {
+            zlib,
+            delta: FrameDelta::default(),
+        }
+    }
+
+    /// Record the event into the current delta
+    ///
+    /// Returns the finished delta upon [VncEvent::UpdateComplete], and the event unless it is recorded
+    ///
+    pub(super) fn apply(&mut self, event: VncEvent) -> (Option<VncEvent>, Option<VncEvent>) {
+        let rect = match event {
+            VncEvent::RawImage(rect, pixels) => DeltaRect::Pixels(rect, self.compress(pixels)),
+            VncEvent::Copy(dst, src) => DeltaRect::Copy(dst, src),
+            VncEvent::CopyBatch(copies) => {
+                self.delta.rects.extend(
+                    copies
+                        .into_iter()
+                        .map(|(dst, src)| DeltaRect::Copy(dst, src)),
+                );
+                return (None, None);
+            }
+            VncEvent::JpegImage(rect, data) => DeltaRect::Jpeg(rect, data),
+            VncEvent::PngImage(rect, data) => DeltaRect::Png(rect, data),
+            VncEvent::Damage(rect) => DeltaRect::Damage(rect),
+            VncEvent::UpdateComplete => {
+                let delta = std::mem::take(&mut self.delta);
+                return (Some(VncEvent::FrameDelta(delta)), Some(event));
+            }
+            event => return (None, Some(event)),
+        };
+        self.delta.rects.push(rect);
+        (None, None)
+    }
+
+    fn compress(&mut self, pixels: Vec<u8>) -> Vec<u8> {
+        let Some(zlib) = self.zlib.as_mut() else {
+            return pixels;
+        };
+        // writing into a vec never fails
+        zlib.write_all(&pixels).unwrap();
+        zlib.flush().unwrap();
+        std::mem::take(zlib.get_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rect;
+    use flate2::Decompress;
+
+    #[test]
+    fn test_frame_delta() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 1,
+        };
+        let mut encoder = DeltaEncoder::new(DeltaCompression::Zlib(6));
+        let mut decompress = Decompress::new(true);
+        for pixels in [[1_u8; 16], [2; 16]] {
+            assert!(matches!(
+                encoder.apply(VncEvent::RawImage(rect, pixels.to_vec())),
+                (None, None)
+            ));
+            encoder.apply(VncEvent::Copy(rect, rect));
+            assert!(matches!(
+                encoder.apply(VncEvent::Bell),
+                (None, Some(VncEvent::Bell))
+            ));
+
+            let (Some(VncEvent::FrameDelta(delta)), Some(VncEvent::UpdateComplete)) =
+                encoder.apply(VncEvent::UpdateComplete)
+            else {
+                panic!("No delta");
+            };
+            assert_eq!(delta.damage().count(), 2);
+            let DeltaRect::Pixels(_, ref data) = delta.rects[0] else {
+                panic!("No pixels");
+            };
+            // the rects are inflated by the same stream
+            let mut inflated = Vec::with_capacity(16);
+            decompress
+                .decompress_vec(data, &mut inflated, flate2::FlushDecompress::Sync)
+                .unwrap();
+            assert_eq!(inflated, pixels);
+            assert_eq!(delta.rects[1], DeltaRect::Copy(rect, rect));
+        }
+    }
+}
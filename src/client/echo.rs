@@ -0,0 +1,97 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{EchoedInput, InputEcho, Rect};
+
+/// The inputs waiting for their echo at most
+const MAX_PENDING: usize = 64;
+/// The inputs without any echo within are forgotten
+const ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Correlate the inputs sent with the damages decoded afterwards
+///
+/// A key press is echoed by any damage,
+///
+/// a pointer button change only by a damage covering the position of the pointer
+#[derive(Default)]
+pub(super) struct EchoTracker {
+    pending: VecDeque<(EchoedInput, Instant)>,
+}
+
+impl EchoTracker {
+    pub(super) fn sent(&mut self, input: EchoedInput, sent: Instant) {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((input, sent));
+    }
+
+    /// Take the inputs echoed by the `damage`
+    pub(super) fn damage(&mut self, damage: &Rect, echoed: Instant) -> Vec<InputEcho> {
+        self.pending
+            .retain(|(_, sent)| echoed.duration_since(*sent) < ECHO_TIMEOUT);
+        let mut echoes = Vec::new();
+        self.pending.retain(|&(input, sent)| {
+            let echoed_by = match input {
+                EchoedInput::Key(_) => true,
+                EchoedInput::Pointer(x, y) => {
+                    x >= damage.x
+                        && y >= damage.y
+                        && (x as u32) < damage.x as u32 + damage.width as u32
+                        && (y as u32) < damage.y as u32 + damage.height as u32
+                }
+            };
+            if echoed_by {
+                echoes.push(InputEcho {
+                    input,
+                    sent,
+                    echoed,
+                    damage: *damage,
+                });
+            }
+            !echoed_by
+        });
+        echoes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo() {
+        let mut tracker = EchoTracker::default();
+        let start = Instant::now();
+        tracker.sent(EchoedInput::Pointer(50, 50), start);
+        tracker.sent(EchoedInput::Key(0x61), start);
+
+        let damage = Rect {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 10,
+        };
+        let later = start + Duration::from_millis(30);
+        let echoes = tracker.damage(&damage, later);
+        assert_eq!(echoes.len(), 1);
+        assert_eq!(echoes[0].input, EchoedInput::Key(0x61));
+        assert_eq!(echoes[0].latency(), Duration::from_millis(30));
+
+        // the pointer is not covered by the first damage
+        let damage = Rect {
+            x: 40,
+            y: 40,
+            width: 20,
+            height: 20,
+        };
+        let echoes = tracker.damage(&damage, later);
+        assert_eq!(echoes[0].input, EchoedInput::Pointer(50, 50));
+        assert!(tracker.damage(&damage, later).is_empty());
+
+        tracker.sent(EchoedInput::Key(0x62), start);
+        assert!(tracker.damage(&damage, start + ECHO_TIMEOUT).is_empty());
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_core::Stream;
+use tokio::sync::{mpsc::Receiver, MutexGuard};
+
+use super::VncClient;
+use crate::{VncError, VncEvent};
+
+/// The events of a [VncClient] as a [Stream], returned by [VncClient::events]
+///
+/// Ends once the engine stops and all the events are consumed,
+///
+/// after the error which stopped the engine if any
+///
+pub struct VncEvents<'a> {
+    client: &'a VncClient,
+    output: MutexGuard<'a, Receiver<VncEvent>>,
+}
+
+impl VncClient {
+    /// The events generated by the engine as a [Stream], e.g. to be merged with other async sources
+    ///
+    /// Holds the events while alive, so [VncClient::recv_event] & the like wait until it is dropped
+    ///
+    /// ```no_run
+    /// use vnc::{VncClient, VncEvent};
+    /// use futures_core::Stream;
+    ///
+    /// async fn next<S>(events: &mut S) -> Option<Result<VncEvent, vnc::VncError>>
+    /// where
+    ///     S: Stream<Item = Result<VncEvent, vnc::VncError>> + Unpin,
+    /// {
+    ///     std::future::poll_fn(|cx| std::pin::Pin::new(&mut *events).poll_next(cx)).await
+    /// }
+    ///
+    /// async fn render(vnc: &VncClient) {
+    ///     let mut events = vnc.events().await;
+    ///     while let Some(event) = next(&mut events).await {
+    ///         match event {
+    ///             Ok(VncEvent::RawImage(rect, pixels)) => println!("{:?}: {} bytes", rect, pixels.len()),
+    ///             Ok(_) => (),
+    ///             Err(e) => println!("Stopped: {}", e),
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub async fn events(&self) -> VncEvents<'_> {
+        VncEvents {
+            client: self,
+            output: self.output.lock().await,
+        }
+    }
+}
+
+impl Stream for VncEvents<'_> {
+    type Item = Result<VncEvent, VncError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.output.poll_recv(cx) {
+            Poll::Ready(Some(event)) => Poll::Ready(Some(Ok(self.client.delivered(event)))),
+            // the error is only taken once, then the stream ends
+            Poll::Ready(None) => Poll::Ready(self.client.error.lock().unwrap().take().map(|e| {
+                Err(e
+                    .downcast_ref::<VncError>()
+                    .cloned()
+                    .unwrap_or_else(|| VncError::Custom(format!("{:#}", e))))
+            })),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::connection::ClientConfig, PixelFormat, VncEncoding};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    async fn next(events: &mut VncEvents<'_>) -> Option<Result<VncEvent, VncError>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *events).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (client, mut server) = duplex(4096);
+        let serving = async {
+            // the handshake after the version & the security
+            let mut client_init = [0; 1];
+            server.read_exact(&mut client_init).await.unwrap();
+            let mut server_init = vec![0, 1, 0, 1];
+            server_init.extend_from_slice(&Vec::<u8>::from(PixelFormat::bgra()));
+            server_init.extend_from_slice(&[0, 0, 0, 0]);
+            server.write_all(&server_init).await.unwrap();
+        };
+        let (vnc, _) = tokio::join!(
+            VncClient::new(
+                client,
+                ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                },
+            ),
+            serving
+        );
+        let vnc = vnc.unwrap();
+        let mut events = vnc.events().await;
+        assert!(matches!(
+            next(&mut events).await,
+            Some(Ok(VncEvent::SetResolution(_)))
+        ));
+
+        // the error which stopped the engine ends the stream
+        drop(server);
+        let mut last = None;
+        while let Some(event) = next(&mut events).await {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(Err(VncError::Disconnected(_)))));
+        assert!(next(&mut events).await.is_none());
+    }
+}
@@ -0,0 +1,231 @@
+use std::ops::Range;
+
+use tracing::warn;
+
+use crate::{Rect, VncEvent};
+
+/// The memory given to [crate::VncConnector::set_framebuffer]
+pub(super) type Memory = Box<dyn AsMut<[u8]> + Send>;
+
+/// The framebuffer composed by the engine into the user supplied memory
+///
+/// The rows are packed without padding, in the pixel format of the session
+///
+pub(super) struct Framebuffer {
+    memory: Memory,
+    bpp: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Framebuffer {
+    pub(super) fn new(memory: Memory, bpp: usize) -> Self {
+        Self {
+            memory,
+            bpp,
+            width: 0,
+            height: 0,
+        }
+    }
+
+    /// Apply an event to the framebuffer
+    ///
+    /// The images & the copies are replaced by a [VncEvent::Damage], the other events are returned as is
+    ///
+    pub(super) fn apply(&mut self, event: VncEvent) -> VncEvent {
+        match event {
+            VncEvent::SetResolution(ref screen) => {
+                self.width = screen.width as usize;
+                self.height = screen.height as usize;
+                if self.pixels().len() < self.width * self.height * self.bpp {
+                    warn!(
+                        "The framebuffer memory is too small for {}x{}, the updates are dropped",
+                        screen.width, screen.height
+                    );
+                }
+                event
+            }
+            VncEvent::RawImage(rect, pixels) => {
+                self.blit(&rect, &pixels);
+                VncEvent::Damage(rect)
+            }
+            VncEvent::Copy(dst, src) => {
+                self.copy(&dst, &src);
+                VncEvent::Damage(dst)
+            }
+            VncEvent::CopyBatch(copies) => {
+                let mut damage = copies[0].0;
+                for (dst, src) in copies.iter() {
+                    self.copy(dst, src);
+                    damage = union(&damage, dst);
+                }
+                VncEvent::Damage(damage)
+            }
+            event => event,
+        }
+    }
+
+    /// The bands of rows showing the entries of the color map in `range`, which have to be redrawn
+    ///
+    /// Each band is as wide as the changed pixels in its rows, only the 8 bits indices are looked up
+    ///
+    pub(super) fn repaint(&mut self, range: &Range<u16>) -> Vec<Rect> {
+        let (width, height) = (self.width, self.height);
+        if self.bpp != 1 || self.pixels().len() < width * height || width == 0 {
+            return Vec::new();
+        }
+        let mut bands: Vec<Rect> = Vec::new();
+        for (y, row) in self.pixels()[..width * height]
+            .chunks_exact(width)
+            .enumerate()
+        {
+            let changed = |index: &u8| range.contains(&(*index as u16));
+            let Some(left) = row.iter().position(changed) else {
+                continue;
+            };
+            let right = row.iter().rposition(changed).unwrap() + 1;
+            let span = Rect {
+                x: left as u16,
+                y: y as u16,
+                width: (right - left) as u16,
+                height: 1,
+            };
+            match bands.last_mut() {
+                Some(band) if (band.y + band.height) as usize == y => *band = union(band, &span),
+                _ => bands.push(span),
+            }
+        }
+        bands
+    }
+
+    fn pixels(&mut self) -> &mut [u8] {
+        (*self.memory).as_mut()
+    }
+
+    /// Whether the rect is inside of the screen and the memory
+    fn fits(&mut self, rect: &Rect) -> bool {
+        let fits = rect.x as usize + rect.width as usize <= self.width
+            && rect.y as usize + rect.height as usize <= self.height
+            && self.pixels().len() >= self.width * self.height * self.bpp;
+        if !fits {
+            warn!("Rect {:?} is out of the framebuffer", rect);
+        }
+        fits
+    }
+
+    fn offset(&self, x: u16, y: usize) -> usize {
+        (y * self.width + x as usize) * self.bpp
+    }
+
+    fn blit(&mut self, rect: &Rect, pixels: &[u8]) {
+        let row_len = rect.width as usize * self.bpp;
+        if !self.fits(rect) || pixels.len() < row_len * rect.height as usize || row_len == 0 {
+            return;
+        }
+        for (i, row) in pixels
+            .chunks_exact(row_len)
+            .take(rect.height as usize)
+            .enumerate()
+        {
+            let start = self.offset(rect.x, rect.y as usize + i);
+            self.pixels()[start..start + row_len].copy_from_slice(row);
+        }
+    }
+
+    fn copy(&mut self, dst: &Rect, src: &Rect) {
+        if !self.fits(dst) || !self.fits(src) {
+            return;
+        }
+        let row_len = dst.width as usize * self.bpp;
+        let rows = dst.height as usize;
+        // copy from the bottom if moving down, so that the overlapped rows are read before written
+        let order: Box<dyn Iterator<Item = usize>> = if dst.y > src.y {
+            Box::new((0..rows).rev())
+        } else {
+            Box::new(0..rows)
+        };
+        for i in order {
+            let from = self.offset(src.x, src.y as usize + i);
+            let to = self.offset(dst.x, dst.y as usize + i);
+            self.pixels().copy_within(from..from + row_len, to);
+        }
+    }
+}
+
+/// The bounding box of two rects
+fn union(a: &Rect, b: &Rect) -> Rect {
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    let right = (a.x as u32 + a.width as u32).max(b.x as u32 + b.width as u32);
+    let bottom = (a.y as u32 + a.height as u32).max(b.y as u32 + b.height as u32);
+    Rect {
+        x,
+        y,
+        width: (right - x as u32) as u16,
+        height: (bottom - y as u32) as u16,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u16, y: u16, width: u16, height: u16) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_framebuffer() {
+        let mut framebuffer = Framebuffer::new(Box::new(vec![0_u8; 16]), 1);
+        framebuffer.apply(VncEvent::SetResolution((4, 4).into()));
+
+        let damage = framebuffer.apply(VncEvent::RawImage(rect(0, 0, 2, 2), vec![1, 2, 3, 4]));
+        assert!(matches!(damage, VncEvent::Damage(r) if r == rect(0, 0, 2, 2)));
+
+        // overlapping copy down & right
+        framebuffer.apply(VncEvent::Copy(rect(1, 1, 2, 2), rect(0, 0, 2, 2)));
+        let memory: &mut [u8] = framebuffer.pixels();
+        #[rustfmt::skip]
+        assert_eq!(memory, [
+            1, 2, 0, 0,
+            3, 1, 2, 0,
+            0, 3, 4, 0,
+            0, 0, 0, 0,
+        ]);
+
+        // out of the screen
+        let event = framebuffer.apply(VncEvent::RawImage(rect(3, 3, 2, 1), vec![9, 9]));
+        assert!(matches!(event, VncEvent::Damage(_)));
+        assert!(!framebuffer.pixels().contains(&9));
+    }
+
+    #[test]
+    fn test_repaint() {
+        let mut framebuffer = Framebuffer::new(Box::new(vec![0_u8; 16]), 1);
+        framebuffer.apply(VncEvent::SetResolution((4, 4).into()));
+        #[rustfmt::skip]
+        framebuffer.apply(VncEvent::RawImage(rect(0, 0, 4, 4), vec![
+            0, 1, 0, 0,
+            0, 0, 2, 0,
+            0, 0, 0, 0,
+            3, 0, 0, 1,
+        ]));
+
+        assert_eq!(
+            framebuffer.repaint(&(1..3)),
+            [rect(1, 0, 2, 2), rect(3, 3, 1, 1)]
+        );
+        assert_eq!(framebuffer.repaint(&(3..256)), [rect(0, 3, 1, 1)]);
+        assert!(framebuffer.repaint(&(4..256)).is_empty());
+
+        // the indices of 16 bits are not looked up
+        let mut framebuffer = Framebuffer::new(Box::new(vec![0_u8; 32]), 2);
+        framebuffer.apply(VncEvent::SetResolution((4, 4).into()));
+        assert!(framebuffer.repaint(&(0..256)).is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{PixelFormat, VncEncoding, VncError};
+
+const MAGIC: &[u8; 4] = b"VNCD";
+
+/// The version of the layout written by [DetachedSession::to_bytes]
+const VERSION: u8 = 1;
+
+/// The encodings whose zlib streams span the whole session,
+///
+/// the state of the server side can't be taken over by a new decoder
+///
+pub(super) const STATEFUL_ENCODINGS: [VncEncoding; 4] = [
+    VncEncoding::Zlib,
+    VncEncoding::Zrle,
+    VncEncoding::Tight,
+    VncEncoding::TightPng,
+];
+
+/// The negotiated state of a session detached by [super::VncClient::detach]
+///
+/// Which is resumed by [super::VncConnector::resume] over the same connection,
+///
+/// e.g. in another process of the same host which has been given the socket
+///
+/// The state is passed along as the bytes of [DetachedSession::to_bytes]
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetachedSession {
+    pub(super) raw_server_version: String,
+    pub(super) server_pixel_format: PixelFormat,
+    pub(super) pixel_format: PixelFormat,
+    pub(super) screen: (u16, u16),
+    pub(super) name_bytes: Vec<u8>,
+    // read from the server but not handled yet
+    pub(super) pending: Vec<u8>,
+}
+
+impl DetachedSession {
+    /// The pixel format in use on the wire
+    ///
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// The resolution when detached
+    ///
+    pub fn screen(&self) -> (u16, u16) {
+        self.screen
+    }
+
+    /// Serialize the state, in a versioned layout which is only meant to be read by [DetachedSession::from_bytes]
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(64 + self.name_bytes.len() + self.pending.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&Vec::<u8>::from(self.server_pixel_format));
+        bytes.extend_from_slice(&Vec::<u8>::from(self.pixel_format));
+        bytes.extend_from_slice(&self.screen.0.to_be_bytes());
+        bytes.extend_from_slice(&self.screen.1.to_be_bytes());
+        for field in [
+            self.raw_server_version.as_bytes(),
+            &self.name_bytes,
+            &self.pending,
+        ] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let malformed = || VncError::Custom("Malformed detached session".to_string());
+        let mut reader = bytes;
+        let mut take = |len: usize| -> Result<&[u8]> {
+            if reader.len() < len {
+                return Err(malformed().into());
+            }
+            let (field, rest) = reader.split_at(len);
+            reader = rest;
+            Ok(field)
+        };
+        if take(4)? != MAGIC {
+            return Err(malformed().into());
+        }
+        let version = take(1)?[0];
+        if version != VERSION {
+            return Err(VncError::Custom(format!(
+                "Version {} of the detached session is not supported",
+                version
+            ))
+            .into());
+        }
+        let server_pixel_format = PixelFormat::try_from(<[u8; 16]>::try_from(take(16)?)?)?;
+        let pixel_format = PixelFormat::try_from(<[u8; 16]>::try_from(take(16)?)?)?;
+        let width = u16::from_be_bytes(take(2)?.try_into()?);
+        let height = u16::from_be_bytes(take(2)?.try_into()?);
+        let mut field = || -> Result<Vec<u8>> {
+            let len = u32::from_be_bytes(take(4)?.try_into()?);
+            Ok(take(len as usize)?.to_vec())
+        };
+        let raw_server_version = String::from_utf8(field()?).map_err(|_| malformed())?;
+        let name_bytes = field()?;
+        let pending = field()?;
+        Ok(Self {
+            raw_server_version,
+            server_pixel_format,
+            pixel_format,
+            screen: (width, height),
+            name_bytes,
+            pending,
+        })
+    }
+}
+
+/// The stream of a resumed session, serving the bytes read by the previous owner first
+pub(super) struct Resumed<S> {
+    pending: Vec<u8>,
+    offset: usize,
+    stream: S,
+}
+
+impl<S> Resumed<S> {
+    pub(super) fn new(stream: S, pending: Vec<u8>) -> Self {
+        Self {
+            pending,
+            offset: 0,
+            stream,
+        }
+    }
+}
+
+impl<S> AsyncRead for Resumed<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.offset < self.pending.len() {
+            let len = buf.remaining().min(self.pending.len() - self.offset);
+            buf.put_slice(&self.pending[self.offset..self.offset + len]);
+            self.offset += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for Resumed<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_round_trip() {
+        let session = DetachedSession {
+            raw_server_version: "RFB 003.008".to_string(),
+            server_pixel_format: PixelFormat::rgba(),
+            pixel_format: PixelFormat::bgra(),
+            screen: (1920, 1080),
+            name_bytes: b"desktop".to_vec(),
+            pending: vec![2],
+        };
+        let bytes = session.to_bytes();
+        assert_eq!(DetachedSession::from_bytes(&bytes).unwrap(), session);
+        assert!(DetachedSession::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut newer = bytes.clone();
+        newer[4] = VERSION + 1;
+        assert!(DetachedSession::from_bytes(&newer).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resumed() {
+        let mut stream = Resumed::new(&[3_u8, 4][..], vec![1, 2]);
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [1, 2, 3, 4]);
+    }
+}
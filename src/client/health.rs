@@ -0,0 +1,132 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{VncClient, VncConnector, VncCredential};
+use crate::VncEvent;
+
+/// How long each phase of [VncClient::health_check] takes
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy)]
+pub struct HealthReport {
+    /// From the start to the security types received, i.e. the version exchange
+    ///
+    pub handshake: Duration,
+    /// From the security types received to the ServerInit message received, including the authentication
+    ///
+    pub initialization: Duration,
+    /// From the ServerInit message received to the first framebuffer update of a single pixel decoded
+    ///
+    pub first_update: Duration,
+}
+
+impl HealthReport {
+    /// The time of all the phases
+    ///
+    pub fn total(&self) -> Duration {
+        self.handshake + self.initialization + self.first_update
+    }
+}
+
+impl VncClient {
+    /// Connect with `connector`, wait for the first frame of a single pixel, then disconnect
+    ///
+    /// Intended for the load balancers and the monitoring probes, wrap it with [tokio::time::timeout] to bound it
+    ///
+    /// ```no_run
+    /// use vnc::{VncClient, VncConnector, VncEncoding};
+    /// use tokio::{self, net::TcpStream};
+    /// use anyhow::Result;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<()> {
+    ///     let tcp = TcpStream::connect("127.0.0.1:5900").await?;
+    ///     let connector = VncConnector::new(tcp)
+    ///         .set_auth_method(|_| async move { Ok("password".into()) })
+    ///         .add_encoding(VncEncoding::Raw);
+    ///     let report = VncClient::health_check(connector).await?;
+    ///     println!("Healthy in {:?}", report.total());
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    pub async fn health_check<S, F>(connector: VncConnector<S, F>) -> Result<HealthReport>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Future<Output = Result<VncCredential>> + Send + 'static,
+    {
+        let start = Instant::now();
+        let (_, state) = connector.minimal_first_update().build()?.inspect().await?;
+        let handshake = start.elapsed();
+
+        let start = Instant::now();
+        let client = state.try_start().await?.finish()?;
+        let initialization = start.elapsed();
+
+        let start = Instant::now();
+        client
+            .next_event_matching(|e| matches!(e, VncEvent::UpdateComplete))
+            .await?;
+        let first_update = start.elapsed();
+
+        // the engine stops and closes the connection once the client is dropped
+        drop(client);
+        Ok(HealthReport {
+            handshake,
+            initialization,
+            first_update,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PixelFormat, VncEncoding};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let (client, mut server) = duplex(1024);
+        let connector = VncConnector::new(client)
+            .set_auth_method(|_| async move { Ok(String::new().into()) })
+            .add_encoding(VncEncoding::Raw);
+        let (report, _) = tokio::join!(VncClient::health_check(connector), async move {
+            server.write_all(b"RFB 003.008\n").await.unwrap();
+            // None, then the SecurityResult
+            server.write_all(&[1, 1]).await.unwrap();
+            server.write_u32(0).await.unwrap();
+            let mut handshake = [0; 12 + 1 + 1];
+            server.read_exact(&mut handshake).await.unwrap();
+
+            server.write_all(&[0, 16, 0, 8]).await.unwrap();
+            server
+                .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+                .await
+                .unwrap();
+            server.write_u32(0).await.unwrap();
+            // SetEncodings
+            let mut header = [0; 4];
+            server.read_exact(&mut header).await.unwrap();
+            let encodings = u16::from_be_bytes([header[2], header[3]]) as usize;
+            let mut encodings = vec![0; encodings * 4];
+            server.read_exact(&mut encodings).await.unwrap();
+            // a single pixel is required
+            let mut request = [0; 10];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [3, 0, 0, 0, 0, 0, 0, 1, 0, 1]);
+            server.write_all(&[0, 0, 0, 0]).await.unwrap();
+
+            // disconnected
+            let mut rest = Vec::new();
+            server.read_to_end(&mut rest).await.unwrap();
+        });
+        let report = report.unwrap();
+        assert!(report.total() >= report.first_update);
+    }
+}
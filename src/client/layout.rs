@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+
+/// A table translating the keysyms sent by [crate::VncClient::input] before they reach the server
+///
+/// Local keyboards are often read by their physical keys and reported as US keysyms,
+///
+/// which types the wrong characters on a remote desktop expecting another layout
+///
+/// ```
+/// use vnc::KeyboardLayout;
+///
+/// // a custom table on top of a built-in one
+/// let layout = KeyboardLayout::azerty().map(0xa7, 0x3f);
+/// assert_eq!(layout.translate(0x71), 0x61);
+/// assert_eq!(layout.translate(0xa7), 0x3f);
+/// ```
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyboardLayout {
+    table: HashMap<u32, u32>,
+}
+
+impl KeyboardLayout {
+    /// An empty table which sends every keysym as is
+    ///
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `to` instead of `from`
+    ///
+    pub fn map(mut self, from: u32, to: u32) -> Self {
+        self.table.insert(from, to);
+        self
+    }
+
+    /// The keysym to be sent for `keysym`
+    ///
+    pub fn translate(&self, keysym: u32) -> u32 {
+        self.table.get(&keysym).copied().unwrap_or(keysym)
+    }
+
+    /// US keysyms to the French AZERTY layout, key by key
+    ///
+    pub fn azerty() -> Self {
+        Self::from_chars(&[
+            ('q', 'a'),
+            ('a', 'q'),
+            ('w', 'z'),
+            ('z', 'w'),
+            ('Q', 'A'),
+            ('A', 'Q'),
+            ('W', 'Z'),
+            ('Z', 'W'),
+            (';', 'm'),
+            (':', 'M'),
+            ('m', ','),
+            ('M', '?'),
+            (',', ';'),
+            ('<', '.'),
+            ('.', ':'),
+            ('>', '/'),
+            ('/', '!'),
+            ('?', '§'),
+            ('1', '&'),
+            ('2', 'é'),
+            ('3', '"'),
+            ('4', '\''),
+            ('5', '('),
+            ('6', '-'),
+            ('7', 'è'),
+            ('8', '_'),
+            ('9', 'ç'),
+            ('0', 'à'),
+            ('!', '1'),
+            ('@', '2'),
+            ('#', '3'),
+            ('$', '4'),
+            ('%', '5'),
+            ('^', '6'),
+            ('&', '7'),
+            ('*', '8'),
+            ('(', '9'),
+            (')', '0'),
+        ])
+    }
+
+    /// US keysyms to the German QWERTZ layout, key by key
+    ///
+    pub fn qwertz() -> Self {
+        Self::from_chars(&[
+            ('y', 'z'),
+            ('z', 'y'),
+            ('Y', 'Z'),
+            ('Z', 'Y'),
+            ('-', 'ß'),
+            ('[', 'ü'),
+            ('{', 'Ü'),
+            (';', 'ö'),
+            (':', 'Ö'),
+            ('\'', 'ä'),
+            ('"', 'Ä'),
+            ('/', '-'),
+            ('?', '_'),
+        ])
+    }
+
+    // the keysyms of the Latin-1 characters are their code points
+    fn from_chars(pairs: &[(char, char)]) -> Self {
+        Self {
+            table: pairs
+                .iter()
+                .map(|(from, to)| (*from as u32, *to as u32))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let azerty = KeyboardLayout::azerty();
+        assert_eq!(azerty.translate('q' as u32), 'a' as u32);
+        assert_eq!(azerty.translate('2' as u32), 0xe9);
+        // keysyms out of the table are kept
+        assert_eq!(azerty.translate(0xff0d), 0xff0d);
+        assert_eq!(KeyboardLayout::new().translate('q' as u32), 'q' as u32);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{io, net::SocketAddr};
+
+use anyhow::Result;
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tracing::info;
+
+use super::{VncConnector, VncCredential};
+
+/// The port that the viewers listen on for the reverse connections, e.g. of `vncserver -connect`
+pub const VNC_LISTEN_PORT: u16 = 5500;
+
+/// Listen for the servers connecting out, i.e. the reverse connections
+///
+/// The handshake goes as usual once connected, with the server still speaking first
+///
+/// ```no_run
+/// use vnc::{PixelFormat, VncListener, VNC_LISTEN_PORT};
+/// use anyhow::Result;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<()> {
+///     let listener = VncListener::bind(("0.0.0.0", VNC_LISTEN_PORT)).await?;
+///     let (connector, server) = listener.accept().await?;
+///     println!("Connected by {}", server);
+///     let vnc = connector
+///         .set_auth_method(|_| async move { Ok("password".into()) })
+///         .add_encoding(vnc::VncEncoding::Zrle)
+///         .add_encoding(vnc::VncEncoding::Raw)
+///         .set_pixel_format(PixelFormat::bgra())
+///         .build()?
+///         .try_start()
+///         .await?
+///         .finish()?;
+///     Ok(())
+/// }
+/// ```
+///
+pub struct VncListener {
+    listener: TcpListener,
+}
+
+impl VncListener {
+    /// Bind to `addr`, usually on [VNC_LISTEN_PORT]
+    ///
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listen for the servers on {:?}", listener.local_addr());
+        Ok(Self { listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Wait for a server to connect, returns a connector over its stream & the address of the server
+    ///
+    /// The listener can be kept to accept more servers
+    ///
+    pub async fn accept<F>(&self) -> Result<(VncConnector<TcpStream, F>, SocketAddr)>
+    where
+        F: std::future::Future<Output = Result<VncCredential>>,
+    {
+        let (stream, addr) = self.listener.accept().await?;
+        info!("Reverse connection from {}", addr);
+        stream.set_nodelay(true)?;
+        Ok((VncConnector::new(stream), addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SecurityType, VncEncoding, VncVersion};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_reverse_connection() {
+        let listener = VncListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut server = TcpStream::connect(addr).await.unwrap();
+            server.write_all(b"RFB 003.008\n").await.unwrap();
+            server.write_all(&[1, 2]).await.unwrap();
+            let mut version = [0; 12];
+            server.read_exact(&mut version).await.unwrap();
+            (server.local_addr().unwrap(), version)
+        });
+
+        let (connector, peer) = listener.accept().await.unwrap();
+        let (inspection, _) = connector
+            .set_auth_method(|_| async move { Ok("password".into()) })
+            .add_encoding(VncEncoding::Raw)
+            .build()
+            .unwrap()
+            .inspect()
+            .await
+            .unwrap();
+        assert_eq!(inspection.version, VncVersion::RFB38);
+        assert_eq!(inspection.security_types, [SecurityType::VncAuth]);
+        let (server_addr, version) = server.await.unwrap();
+        assert_eq!(peer, server_addr);
+        assert_eq!(&version, b"RFB 003.008\n");
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{Magnifier, Rect, VncEvent};
+
+/// The region of the remote desktop being magnified
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Lens {
+    pub(super) region: Rect,
+    zoom: usize,
+    bpp: usize,
+}
+
+impl Lens {
+    /// Center the region around the focus, keeping it inside of the `screen`
+    pub(super) fn new(magnifier: &Magnifier, screen: (u16, u16), bpp: usize) -> Self {
+        let zoom = magnifier.zoom.max(1) as u16;
+        let fit = |size: u16, focus: u16, screen: u16| {
+            let len = (size / zoom).clamp(1, screen.max(1));
+            let start = focus
+                .saturating_sub(len / 2)
+                .min(screen.saturating_sub(len));
+            (start, len)
+        };
+        let (x, width) = fit(magnifier.size.0, magnifier.focus.0, screen.0);
+        let (y, height) = fit(magnifier.size.1, magnifier.focus.1, screen.1);
+        Self {
+            region: Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            zoom: zoom as usize,
+            bpp,
+        }
+    }
+
+    /// The part of `rect` inside of the region
+    pub(super) fn intersect(&self, rect: &Rect) -> Option<Rect> {
+        let x = rect.x.max(self.region.x);
+        let y = rect.y.max(self.region.y);
+        let right = (rect.x as u32 + rect.width as u32)
+            .min(self.region.x as u32 + self.region.width as u32);
+        let bottom = (rect.y as u32 + rect.height as u32)
+            .min(self.region.y as u32 + self.region.height as u32);
+        if right <= x as u32 || bottom <= y as u32 {
+            return None;
+        }
+        Some(Rect {
+            x,
+            y,
+            width: (right - x as u32) as u16,
+            height: (bottom - y as u32) as u16,
+        })
+    }
+
+    /// Scale the part of the image inside of the region with the nearest neighbor
+    ///
+    /// Generates a [VncEvent::Magnified] positioned in the magnified output
+    pub(super) fn magnify(&self, rect: &Rect, pixels: &[u8]) -> Option<VncEvent> {
+        let part = self.intersect(rect)?;
+        let bpp = self.bpp;
+        if pixels.len() < rect.width as usize * rect.height as usize * bpp {
+            return None;
+        }
+        let row_len = part.width as usize * self.zoom * bpp;
+        let mut image = Vec::with_capacity(row_len * part.height as usize * self.zoom);
+        let mut row = Vec::with_capacity(row_len);
+        for y in (part.y - rect.y)..(part.y - rect.y + part.height) {
+            row.clear();
+            for x in (part.x - rect.x)..(part.x - rect.x + part.width) {
+                let start = (y as usize * rect.width as usize + x as usize) * bpp;
+                for _ in 0..self.zoom {
+                    row.extend_from_slice(&pixels[start..start + bpp]);
+                }
+            }
+            for _ in 0..self.zoom {
+                image.extend_from_slice(&row);
+            }
+        }
+        let zoom = self.zoom as u16;
+        Some(VncEvent::Magnified(
+            Rect {
+                x: (part.x - self.region.x) * zoom,
+                y: (part.y - self.region.y) * zoom,
+                width: part.width * zoom,
+                height: part.height * zoom,
+            },
+            image,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magnify() {
+        let magnifier = Magnifier {
+            focus: (0, 0),
+            zoom: 2,
+            size: (4, 4),
+        };
+        // the region is kept inside of the screen
+        let lens = Lens::new(&magnifier, (100, 100), 1);
+        assert_eq!(
+            lens.region,
+            Rect {
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2
+            }
+        );
+
+        // a 3x2 image at (1, 1), only its top-left pixel is inside of the region
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width: 3,
+            height: 2,
+        };
+        let Some(VncEvent::Magnified(rect, image)) = lens.magnify(&rect, &[1, 2, 3, 4, 5, 6])
+        else {
+            panic!("Nothing magnified");
+        };
+        assert_eq!((rect.x, rect.y, rect.width, rect.height), (2, 2, 2, 2));
+        assert_eq!(image, [1, 1, 1, 1]);
+
+        let outside = Rect {
+            x: 2,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        assert!(lens.magnify(&outside, &[0; 4]).is_none());
+    }
+}
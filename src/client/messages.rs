@@ -1,195 +0,0 @@
-use crate::{PixelFormat, Rect, VncEncoding, VncError};
-use anyhow::Result;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-
-pub(super) enum ClientMsg {
-    SetPixelFormat(PixelFormat),
-    SetEncodings(Vec<VncEncoding>),
-    FramebufferUpdateRequest(Rect, u8),
-    KeyEvent(u32, bool),
-    PointerEvent(u16, u16, u8),
-    ClientCutText(String),
-}
-
-impl ClientMsg {
-    pub(super) async fn write<S>(self, writer: &mut S) -> Result<()>
-    where
-        S: AsyncWrite + Unpin,
-    {
-        match self {
-            ClientMsg::SetPixelFormat(pf) => {
-                // +--------------+--------------+--------------+
-                // | No. of bytes | Type [Value] | Description  |
-                // +--------------+--------------+--------------+
-                // | 1            | U8 [0]       | message-type |
-                // | 3            |              | padding      |
-                // | 16           | PIXEL_FORMAT | pixel-format |
-                // +--------------+--------------+--------------+
-                let mut payload = vec![0_u8, 0, 0, 0];
-                payload.extend(<PixelFormat as Into<Vec<u8>>>::into(pf));
-                writer.write_all(&payload).await?;
-                Ok(())
-            }
-            ClientMsg::SetEncodings(encodings) => {
-                //  +--------------+--------------+---------------------+
-                // | No. of bytes | Type [Value] | Description         |
-                // +--------------+--------------+---------------------+
-                // | 1            | U8 [2]       | message-type        |
-                // | 1            |              | padding             |
-                // | 2            | U16          | number-of-encodings |
-                // +--------------+--------------+---------------------+
-
-                // This is followed by number-of-encodings repetitions of the following:
-                // +--------------+--------------+---------------+
-                // | No. of bytes | Type [Value] | Description   |
-                // +--------------+--------------+---------------+
-                // | 4            | S32          | encoding-type |
-                // +--------------+--------------+---------------+
-                let mut payload = vec![2, 0];
-                payload.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
-                for e in encodings {
-                    payload.write_u32(e.into()).await?;
-                }
-                writer.write_all(&payload).await?;
-                Ok(())
-            }
-            ClientMsg::FramebufferUpdateRequest(rect, incremental) => {
-                // +--------------+--------------+--------------+
-                // | No. of bytes | Type [Value] | Description  |
-                // +--------------+--------------+--------------+
-                // | 1            | U8 [3]       | message-type |
-                // | 1            | U8           | incremental  |
-                // | 2            | U16          | x-position   |
-                // | 2            | U16          | y-position   |
-                // | 2            | U16          | width        |
-                // | 2            | U16          | height       |
-                // +--------------+--------------+--------------+
-                let mut payload = vec![3, incremental];
-                payload.extend_from_slice(&rect.x.to_be_bytes());
-                payload.extend_from_slice(&rect.y.to_be_bytes());
-                payload.extend_from_slice(&rect.width.to_be_bytes());
-                payload.extend_from_slice(&rect.height.to_be_bytes());
-                writer.write_all(&payload).await?;
-                Ok(())
-            }
-            ClientMsg::KeyEvent(keycode, down) => {
-                // +--------------+--------------+--------------+
-                // | No. of bytes | Type [Value] | Description  |
-                // +--------------+--------------+--------------+
-                // | 1            | U8 [4]       | message-type |
-                // | 1            | U8           | down-flag    |
-                // | 2            |              | padding      |
-                // | 4            | U32          | key          |
-                // +--------------+--------------+--------------+
-                let mut payload = vec![4, down as u8, 0, 0];
-                payload.write_u32(keycode).await?;
-                writer.write_all(&payload).await?;
-                Ok(())
-            }
-            ClientMsg::PointerEvent(x, y, mask) => {
-                // +--------------+--------------+--------------+
-                // | No. of bytes | Type [Value] | Description  |
-                // +--------------+--------------+--------------+
-                // | 1            | U8 [5]       | message-type |
-                // | 1            | U8           | button-mask  |
-                // | 2            | U16          | x-position   |
-                // | 2            | U16          | y-position   |
-                // +--------------+--------------+--------------+
-                let mut payload = vec![5, mask];
-                payload.write_u16(x).await?;
-                payload.write_u16(y).await?;
-                writer.write_all(&payload).await?;
-                Ok(())
-            }
-            ClientMsg::ClientCutText(s) => {
-                //   +--------------+--------------+--------------+
-                //   | No. of bytes | Type [Value] | Description  |
-                //   +--------------+--------------+--------------+
-                //   | 1            | U8 [6]       | message-type |
-                //   | 3            |              | padding      |
-                //   | 4            | U32          | length       |
-                //   | length       | U8 array     | text         |
-                //   +--------------+--------------+--------------+
-                let mut payload = vec![6_u8, 0, 0, 0];
-                payload.write_u32(s.len() as u32).await?;
-                payload.write_all(s.as_bytes()).await?;
-                writer.write_all(&payload).await?;
-                Ok(())
-            }
-        }
-    }
-}
-
-#[derive(Debug)]
-pub(super) enum ServerMsg {
-    FramebufferUpdate(u16),
-    // SetColorMapEntries,
-    Bell,
-    ServerCutText(String),
-}
-
-impl ServerMsg {
-    pub(super) async fn read<S>(reader: &mut S) -> Result<Self>
-    where
-        S: AsyncRead + Unpin,
-    {
-        let server_msg = reader.read_u8().await?;
-
-        match server_msg {
-            0 => {
-                // FramebufferUpdate
-                //   +--------------+--------------+----------------------+
-                //   | No. of bytes | Type [Value] | Description          |
-                //   +--------------+--------------+----------------------+
-                //   | 1            | U8 [0]       | message-type         |
-                //   | 1            |              | padding              |
-                //   | 2            | U16          | number-of-rectangles |
-                //   +--------------+--------------+----------------------+
-                let _padding = reader.read_u8().await?;
-                let rects = reader.read_u16().await?;
-                Ok(ServerMsg::FramebufferUpdate(rects))
-            }
-            1 => {
-                // SetColorMapEntries
-                // +--------------+--------------+------------------+
-                // | No. of bytes | Type [Value] | Description      |
-                // +--------------+--------------+------------------+
-                // | 1            | U8 [1]       | message-type     |
-                // | 1            |              | padding          |
-                // | 2            | U16          | first-color      |
-                // | 2            | U16          | number-of-colors |
-                // +--------------+--------------+------------------+
-                unimplemented!()
-            }
-            2 => {
-                // Bell
-                //   +--------------+--------------+--------------+
-                //   | No. of bytes | Type [Value] | Description  |
-                //   +--------------+--------------+--------------+
-                //   | 1            | U8 [2]       | message-type |
-                //   +--------------+--------------+--------------+
-                Ok(ServerMsg::Bell)
-            }
-            3 => {
-                // ServerCutText
-                // +--------------+--------------+--------------+
-                // | No. of bytes | Type [Value] | Description  |
-                // +--------------+--------------+--------------+
-                // | 1            | U8 [3]       | message-type |
-                // | 3            |              | padding      |
-                // | 4            | U32          | length       |
-                // | length       | U8 array     | text         |
-                // +--------------+--------------+--------------+
-                let mut padding = [0; 3];
-                reader.read_exact(&mut padding).await?;
-                let len = reader.read_u32().await?;
-                let mut buffer_str = vec![0; len as usize];
-                reader.read_exact(&mut buffer_str).await?;
-                Ok(Self::ServerCutText(
-                    String::from_utf8_lossy(&buffer_str).to_string(),
-                ))
-            }
-            _ => Err(VncError::WrongServerMessage.into()),
-        }
-    }
-}
@@ -1,8 +1,55 @@
 mod auth;
+#[cfg(feature = "bell")]
+mod bell;
 pub mod connection;
 pub mod connector;
-mod messages;
-mod security;
+mod delta;
+mod echo;
+#[cfg(feature = "stream")]
+mod events;
+mod framebuffer;
+mod handoff;
+#[cfg(not(target_arch = "wasm32"))]
+mod health;
+mod layout;
+#[cfg(not(target_arch = "wasm32"))]
+mod listener;
+mod magnifier;
+mod probe;
+mod protocol_log;
+#[cfg(all(feature = "proxy", not(target_arch = "wasm32")))]
+mod proxy;
+#[cfg(not(target_arch = "wasm32"))]
+mod reconnect;
+mod report;
+pub(crate) mod security;
+mod session;
+mod stream;
+#[cfg(feature = "trace")]
+mod trace;
+mod transform;
+mod transport;
+mod writer;
 
+pub use auth::SecurityType;
 pub use connection::VncClient;
-pub use connector::VncConnector;
+#[cfg(not(target_arch = "wasm32"))]
+pub use connection::VncTasks;
+pub use connector::{AuthRequest, ServerInspection, VncConnector, VncCredential};
+#[cfg(feature = "stream")]
+pub use events::VncEvents;
+pub use handoff::DetachedSession;
+#[cfg(not(target_arch = "wasm32"))]
+pub use health::HealthReport;
+pub use layout::KeyboardLayout;
+#[cfg(not(target_arch = "wasm32"))]
+pub use listener::{VncListener, VNC_LISTEN_PORT};
+pub use probe::{probe, ServerProbeReport};
+#[cfg(all(feature = "proxy", not(target_arch = "wasm32")))]
+pub use proxy::{Proxy, ProxyKind};
+#[cfg(not(target_arch = "wasm32"))]
+pub use reconnect::{ReconnectPolicy, VncSession};
+pub use report::{DebugReport, EncodingStats, FrameTiming};
+pub use session::SessionInfo;
+#[cfg(feature = "trace")]
+pub use trace::{TraceDirection, TraceRecord, TRACE_SCHEMA_VERSION};
@@ -0,0 +1,268 @@
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, trace};
+
+use super::auth::SecurityType;
+use crate::{NamePolicy, PixelFormat, Screen, VncVersion};
+
+// https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#tight-security-type
+const TIGHT_NO_TUNNEL: u32 = 0;
+const TIGHT_NO_AUTH: u32 = 1;
+
+/// What a server tells without credentials, returned by [probe]
+///
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct ServerProbeReport {
+    /// The version informed by the server
+    ///
+    pub server_version: VncVersion,
+    /// The negotiated version
+    ///
+    pub version: VncVersion,
+    /// The version string sent by the server without the trailing newline, e.g. `RFB 003.008`
+    ///
+    pub raw_server_version: String,
+    /// The security types offered by the server
+    ///
+    /// With [VncVersion::RFB33] the server decides a single one
+    ///
+    pub security_types: Vec<SecurityType>,
+    /// The name of the desktop, decoded with [NamePolicy::Lossy]
+    ///
+    /// Only known if the server requires no authentication, so are the following ones
+    ///
+    pub name: Option<String>,
+    /// The resolution informed by the ServerInit message
+    ///
+    pub screen: Option<Screen>,
+    /// The pixel format informed by the ServerInit message
+    ///
+    pub pixel_format: Option<PixelFormat>,
+    /// The codes of the encodings that the server supports, which may be unknown to [crate::VncEncoding]
+    ///
+    /// Only known if the server offers [SecurityType::Tight], which lists them after the ServerInit message
+    ///
+    pub encodings: Option<Vec<i32>>,
+}
+
+/// Go through the handshake as far as possible without credentials, then disconnect
+///
+/// Useful for the inventory or scanning tools, the server is never authenticated against
+///
+/// ```no_run
+/// use vnc::probe;
+/// use tokio::{self, net::TcpStream};
+/// use anyhow::Result;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<()> {
+///     let tcp = TcpStream::connect("127.0.0.1:5900").await?;
+///     let report = probe(tcp).await?;
+///     println!("{} offers {:?}", report.raw_server_version, report.security_types);
+///     if let (Some(name), Some(screen)) = (report.name, report.screen) {
+///         println!("{} ({}x{}) is open", name, screen.width, screen.height);
+///     }
+///     Ok(())
+/// }
+/// ```
+///
+pub async fn probe<S>(mut stream: S) -> Result<ServerProbeReport>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let raw_version = VncVersion::read(&mut stream).await?;
+    let server_version = VncVersion::from(raw_version);
+    // the latest version is supported, so is whatever the server informs
+    let version = server_version;
+    version.write(&mut stream).await?;
+
+    let security_types = SecurityType::read(&mut stream, &version).await?;
+    let mut report = ServerProbeReport {
+        server_version,
+        version,
+        raw_server_version: String::from_utf8_lossy(&raw_version).trim_end().to_string(),
+        security_types,
+        name: None,
+        screen: None,
+        pixel_format: None,
+        encodings: None,
+    };
+
+    let tight = report.security_types.contains(&SecurityType::Tight);
+    let open = if tight {
+        open_tight(&mut stream, version).await?
+    } else if report.security_types.contains(&SecurityType::None) {
+        open_none(&mut stream, version).await?
+    } else {
+        false
+    };
+
+    if open {
+        // shared, not to disconnect the others
+        stream.write_u8(1).await?;
+        let width = stream.read_u16().await?;
+        let height = stream.read_u16().await?;
+        report.screen = Some((width, height).into());
+        report.pixel_format = Some(PixelFormat::read(&mut stream).await?);
+        let name_len = stream.read_u32().await?;
+        let mut name = vec![0; name_len as usize];
+        stream.read_exact(&mut name).await?;
+        report.name = Some(NamePolicy::Lossy.decode(&name)?);
+        if tight {
+            report.encodings = Some(read_tight_encodings(&mut stream).await?);
+        }
+    } else {
+        info!("Authentication required, stop probing");
+    }
+
+    // the failure to shut down doesn't spoil what has been collected
+    if let Err(e) = stream.shutdown().await {
+        trace!("Failed to shutdown the probed stream: {:?}", e);
+    }
+    Ok(report)
+}
+
+/// Pick [SecurityType::None], return whether the initialization follows
+async fn open_none<S>(stream: &mut S, version: VncVersion) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if version != VncVersion::RFB33 {
+        SecurityType::None.write(stream).await?;
+    }
+    if version == VncVersion::RFB38 {
+        return Ok(stream.read_u32().await? == 0);
+    }
+    Ok(true)
+}
+
+/// Pick [SecurityType::Tight] without the tunnels, return whether it requires no authentication
+async fn open_tight<S>(stream: &mut S, version: VncVersion) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if version != VncVersion::RFB33 {
+        SecurityType::Tight.write(stream).await?;
+    }
+    let num = stream.read_u32().await?;
+    let tunnels = read_capabilities(stream, num).await?;
+    if !tunnels.is_empty() {
+        stream.write_u32(TIGHT_NO_TUNNEL).await?;
+    }
+    let num = stream.read_u32().await?;
+    let auths = read_capabilities(stream, num).await?;
+    if !auths.is_empty() {
+        if !auths.contains(&(TIGHT_NO_AUTH as i32)) {
+            return Ok(false);
+        }
+        stream.write_u32(TIGHT_NO_AUTH).await?;
+    }
+    if version == VncVersion::RFB38 {
+        return Ok(stream.read_u32().await? == 0);
+    }
+    Ok(true)
+}
+
+/// Read the interaction capabilities following the ServerInit message, return the encodings
+async fn read_tight_encodings<S>(stream: &mut S) -> Result<Vec<i32>>
+where
+    S: AsyncRead + Unpin,
+{
+    let server_messages = stream.read_u16().await?;
+    let client_messages = stream.read_u16().await?;
+    let encodings = stream.read_u16().await?;
+    let _padding = stream.read_u16().await?;
+    read_capabilities(stream, server_messages as u32 + client_messages as u32).await?;
+    read_capabilities(stream, encodings as u32).await
+}
+
+/// Read `num` capabilities of the tight security type, return their codes
+async fn read_capabilities<S>(stream: &mut S, num: u32) -> Result<Vec<i32>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut codes = Vec::new();
+    for _ in 0..num {
+        // the code, followed by the vendor & the name signatures
+        codes.push(stream.read_i32().await?);
+        let mut signatures = [0; 12];
+        stream.read_exact(&mut signatures).await?;
+    }
+    Ok(codes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// The ServerInit message of a 16x8 desktop named `probe`
+    async fn write_server_init<S: AsyncWrite + Unpin>(server: &mut S) {
+        server.write_all(&[0, 16, 0, 8]).await.unwrap();
+        let pf: Vec<u8> = PixelFormat::bgra().into();
+        server.write_all(&pf).await.unwrap();
+        server.write_u32(5).await.unwrap();
+        server.write_all(b"probe").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        // VncAuth only, nothing beyond the security types
+        let (client, mut server) = duplex(1024);
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        server.write_all(&[1, 2]).await.unwrap();
+        let report = probe(client).await.unwrap();
+        assert_eq!(report.security_types, vec![SecurityType::VncAuth]);
+        assert!(report.name.is_none());
+
+        // None, the desktop is open
+        let (client, mut server) = duplex(1024);
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        server.write_all(&[2, 2, 1]).await.unwrap();
+        server.write_u32(0).await.unwrap();
+        write_server_init(&mut server).await;
+        let report = probe(client).await.unwrap();
+        assert_eq!(report.raw_server_version, "RFB 003.008");
+        assert_eq!(report.name.as_deref(), Some("probe"));
+        assert_eq!(report.screen, Some((16, 8).into()));
+        assert_eq!(report.pixel_format, Some(PixelFormat::bgra()));
+        assert!(report.encodings.is_none());
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        // version, security type, shared flag
+        assert_eq!(&sent[12..], [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_probe_tight() {
+        let capability = |code: i32| {
+            let mut capability = code.to_be_bytes().to_vec();
+            capability.extend_from_slice(b"TGHTSIGNATUR");
+            capability
+        };
+        let (client, mut server) = duplex(1024);
+        server.write_all(b"RFB 003.008\n").await.unwrap();
+        server.write_all(&[2, 2, 16]).await.unwrap();
+        // no tunnel, the none & vnc auths
+        server.write_u32(0).await.unwrap();
+        server.write_u32(2).await.unwrap();
+        server.write_all(&capability(1)).await.unwrap();
+        server.write_all(&capability(2)).await.unwrap();
+        server.write_u32(0).await.unwrap();
+        write_server_init(&mut server).await;
+        // a server & a client message, zrle & an unknown encoding
+        server.write_all(&[0, 1, 0, 1, 0, 2, 0, 0]).await.unwrap();
+        for code in [150, 150, 16, 0x574d5669] {
+            server.write_all(&capability(code)).await.unwrap();
+        }
+
+        let report = probe(client).await.unwrap();
+        assert_eq!(report.name.as_deref(), Some("probe"));
+        assert_eq!(report.encodings, Some(vec![16, 0x574d5669]));
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        // version, security type, the none auth, shared flag
+        assert_eq!(&sent[12..], [16, 0, 0, 0, 1, 1]);
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use super::report;
+use crate::ProtocolLogging;
+
+/// Lines logged within a window, the rest are counted and reported once the next window starts
+const MAX_LINES: u32 = 100;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Limiter {
+    start: Option<Instant>,
+    logged: u32,
+    suppressed: u64,
+}
+
+impl Limiter {
+    /// Whether a line can be logged at `now`, with the lines suppressed in the previous window
+    ///
+    /// Nothing is limited without a clock, i.e. on wasm
+    ///
+    fn admit(&mut self, now: Option<Instant>) -> (bool, u64) {
+        let Some(now) = now else {
+            return (true, 0);
+        };
+        let mut suppressed = 0;
+        if self.start.is_none_or(|start| now - start >= WINDOW) {
+            self.start = Some(now);
+            self.logged = 0;
+            suppressed = std::mem::take(&mut self.suppressed);
+        }
+        if self.logged < MAX_LINES {
+            self.logged += 1;
+            (true, suppressed)
+        } else {
+            self.suppressed += 1;
+            (false, suppressed)
+        }
+    }
+}
+
+/// The verbose logging of the protocol, which is switched by the client at any time
+///
+/// Shared by the reader & the writer of the engine
+///
+#[derive(Default)]
+pub(super) struct ProtocolLog {
+    level: AtomicU8,
+    limiter: Mutex<Limiter>,
+}
+
+impl ProtocolLog {
+    pub(super) fn set_level(&self, level: ProtocolLogging) {
+        self.level.store(level as u8, Ordering::Relaxed);
+    }
+
+    pub(super) fn enabled(&self, level: ProtocolLogging) -> bool {
+        level != ProtocolLogging::Off && self.level.load(Ordering::Relaxed) >= level as u8
+    }
+
+    /// Log the line if `level` is enabled, which is only formatted when admitted by the rate limit
+    pub(super) fn log(&self, level: ProtocolLogging, line: impl FnOnce() -> String) {
+        if !self.enabled(level) {
+            return;
+        }
+        let (admitted, suppressed) = self.limiter.lock().unwrap().admit(report::now());
+        if suppressed > 0 {
+            info!("{} lines of the protocol log suppressed", suppressed);
+        }
+        if admitted {
+            info!("{}", line());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let log = ProtocolLog::default();
+        assert!(!log.enabled(ProtocolLogging::Messages));
+        log.set_level(ProtocolLogging::Messages);
+        assert!(log.enabled(ProtocolLogging::Messages));
+        assert!(!log.enabled(ProtocolLogging::Rects));
+        log.set_level(ProtocolLogging::Rects);
+        assert!(log.enabled(ProtocolLogging::Messages));
+        assert!(!log.enabled(ProtocolLogging::Off));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limiter = Limiter::default();
+        let start = Instant::now();
+        for _ in 0..MAX_LINES {
+            assert_eq!(limiter.admit(Some(start)), (true, 0));
+        }
+        assert_eq!(limiter.admit(Some(start)), (false, 0));
+        assert_eq!(limiter.admit(Some(start + WINDOW / 2)), (false, 0));
+        // the next window reports the lines suppressed
+        assert_eq!(limiter.admit(Some(start + WINDOW)), (true, 2));
+        assert_eq!(limiter.admit(Some(start + WINDOW)), (true, 0));
+        assert_eq!(limiter.admit(None), (true, 0));
+    }
+}
@@ -0,0 +1,355 @@
+use std::net::IpAddr;
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tracing::{info, trace};
+
+use super::security::base64;
+use crate::VncError;
+
+/// Bound of the response header of an HTTP proxy
+const MAX_HTTP_HEADER: usize = 8192;
+
+/// The protocol spoken to the proxy
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    /// [RFC 1928](https://www.rfc-editor.org/rfc/rfc1928), with the username/password auth of [RFC 1929](https://www.rfc-editor.org/rfc/rfc1929)
+    ///
+    /// The host name is resolved by the proxy
+    ///
+    Socks5,
+    /// The `CONNECT` method of HTTP/1.1, with the basic auth
+    ///
+    HttpConnect,
+}
+
+/// A proxy to reach the vnc server through, before the handshake starts
+///
+/// ```no_run
+/// use vnc::{Proxy, VncConnector};
+/// use anyhow::Result;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<()> {
+///     let tcp = Proxy::socks5("proxy.example.com:1080")
+///         .with_credentials("user", "secret")
+///         .connect("vnc.internal", 5900)
+///         .await?;
+///     let vnc = VncConnector::new(tcp)
+///         .set_auth_method(|_| async move { Ok("password".into()) })
+///         .add_encoding(vnc::VncEncoding::Raw)
+///         .build()?
+///         .try_start()
+///         .await?
+///         .finish()?;
+///     Ok(())
+/// }
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    kind: ProxyKind,
+    addr: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// A SOCKS5 proxy listening on `addr`, e.g. `proxy.example.com:1080`
+    ///
+    pub fn socks5(addr: impl Into<String>) -> Self {
+        Self {
+            kind: ProxyKind::Socks5,
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+
+    /// An HTTP proxy listening on `addr`, which must allow `CONNECT` to the port of the vnc server
+    ///
+    pub fn http_connect(addr: impl Into<String>) -> Self {
+        Self {
+            kind: ProxyKind::HttpConnect,
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate to the proxy, which is only done if the proxy asks for it
+    ///
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Self {
+        self.credentials = Some((username.to_string(), password.to_string()));
+        self
+    }
+
+    pub fn kind(&self) -> ProxyKind {
+        self.kind
+    }
+
+    /// Connect to `host:port` through the proxy, returns the stream to be given to [super::VncConnector::new]
+    ///
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&self.addr).await?;
+        stream.set_nodelay(true)?;
+        self.tunnel(&mut stream, host, port).await?;
+        info!("Connected to {}:{} through {}", host, port, self.addr);
+        Ok(stream)
+    }
+
+    async fn tunnel<S>(&self, stream: &mut S, host: &str, port: u16) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.kind {
+            ProxyKind::Socks5 => self.socks5_connect(stream, host, port).await,
+            ProxyKind::HttpConnect => self.http_connect_to(stream, host, port).await,
+        }
+    }
+
+    async fn socks5_connect<S>(&self, stream: &mut S, host: &str, port: u16) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // no auth, and username/password if any
+        let methods: &[u8] = match self.credentials {
+            Some(_) => &[0, 2],
+            None => &[0],
+        };
+        let mut greeting = vec![5, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting).await?;
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice).await?;
+        trace!("SOCKS5 method {}", choice[1]);
+        match (choice, &self.credentials) {
+            ([5, 0], _) => (),
+            ([5, 2], Some((username, password))) => {
+                if username.len() > 255 || password.len() > 255 {
+                    return Err(VncError::Custom(
+                        "The SOCKS5 credentials are too long".to_string(),
+                    )
+                    .into());
+                }
+                let mut auth = vec![1, username.len() as u8];
+                auth.extend_from_slice(username.as_bytes());
+                auth.push(password.len() as u8);
+                auth.extend_from_slice(password.as_bytes());
+                stream.write_all(&auth).await?;
+                let mut status = [0; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(VncError::Custom(
+                        "The SOCKS5 proxy rejected the credentials".to_string(),
+                    )
+                    .into());
+                }
+            }
+            ([5, _], _) => {
+                return Err(VncError::Custom(
+                    "No acceptable auth method of the SOCKS5 proxy".to_string(),
+                )
+                .into())
+            }
+            _ => return Err(VncError::Custom("Not a SOCKS5 proxy".to_string()).into()),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) if host.len() <= 255 => {
+                request.extend_from_slice(&[3, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+            Err(_) => {
+                return Err(VncError::Custom(format!("Host name {} is too long", host)).into())
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request).await?;
+
+        // +-----+-----+-------+------+----------+----------+
+        // | VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+        // +-----+-----+-------+------+----------+----------+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            let reason = match reply[1] {
+                1 => "general failure",
+                2 => "not allowed by the ruleset",
+                3 => "network unreachable",
+                4 => "host unreachable",
+                5 => "connection refused",
+                6 => "TTL expired",
+                7 => "command not supported",
+                8 => "address type not supported",
+                _ => "unknown error",
+            };
+            return Err(VncError::Custom(format!(
+                "The SOCKS5 proxy failed to connect {}:{}: {}",
+                host, port, reason
+            ))
+            .into());
+        }
+        let bound = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            _ => {
+                return Err(
+                    VncError::Custom("Malformed reply of the SOCKS5 proxy".to_string()).into(),
+                )
+            }
+        };
+        let mut bound = vec![0; bound + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(())
+    }
+
+    async fn http_connect_to<S>(&self, stream: &mut S, host: &str, port: u16) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = &self.credentials {
+            request.push_str(&format!(
+                "Proxy-Authorization: Basic {}\r\n",
+                base64::encode(format!("{}:{}", username, password).as_bytes())
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // byte by byte, since the rfb follows the header immediately
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= MAX_HTTP_HEADER {
+                return Err(VncError::Custom(
+                    "The response of the HTTP proxy is too long".to_string(),
+                )
+                .into());
+            }
+            header.push(stream.read_u8().await?);
+        }
+        let header = String::from_utf8_lossy(&header);
+        let status_line = header.lines().next().unwrap_or_default();
+        trace!("HTTP proxy replied {}", status_line);
+        let status = match status_line.split_whitespace().collect::<Vec<_>>()[..] {
+            [version, status, ..] if version.starts_with("HTTP/1.") => status,
+            _ => {
+                return Err(VncError::Custom("Not an HTTP proxy".to_string()).into());
+            }
+        };
+        match status {
+            "200" => Ok(()),
+            "407" => Err(VncError::Custom(
+                "The HTTP proxy requires the authentication".to_string(),
+            )
+            .into()),
+            _ => Err(VncError::Custom(format!(
+                "The HTTP proxy failed to connect {}: {}",
+                authority, status_line
+            ))
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let (mut client, mut server) = duplex(1024);
+        let proxy = Proxy::socks5("proxy:1080").with_credentials("user", "pass");
+        let (result, _) = tokio::join!(proxy.tunnel(&mut client, "vnc.internal", 5900), async {
+            let mut greeting = [0; 4];
+            server.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 2, 0, 2]);
+            server.write_all(&[5, 2]).await.unwrap();
+            let mut auth = [0; 11];
+            server.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            server.write_all(&[1, 0]).await.unwrap();
+            let mut request = [0; 19];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x0cvnc.internal\x17\x0c");
+            // bound to a domain name, followed by the rfb
+            server
+                .write_all(b"\x05\x00\x00\x03\x05proxy\x04\x38RFB")
+                .await
+                .unwrap();
+        });
+        result.unwrap();
+        let mut rfb = [0; 3];
+        client.read_exact(&mut rfb).await.unwrap();
+        assert_eq!(&rfb, b"RFB");
+
+        let (mut client, mut server) = duplex(1024);
+        let proxy = Proxy::socks5("proxy:1080");
+        let (result, _) = tokio::join!(proxy.tunnel(&mut client, "10.0.0.1", 5900), async {
+            let mut greeting = [0; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            server.write_all(&[5, 0]).await.unwrap();
+            let mut request = [0; 10];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(request[3..8], [1, 10, 0, 0, 1]);
+            server
+                .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .ends_with("connection refused"));
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let (mut client, mut server) = duplex(1024);
+        let proxy = Proxy::http_connect("proxy:3128").with_credentials("user", "pass");
+        let (result, _) = tokio::join!(proxy.tunnel(&mut client, "::1", 5900), async {
+            let expected = "CONNECT [::1]:5900 HTTP/1.1\r\nHost: [::1]:5900\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+            let mut request = vec![0; expected.len()];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(String::from_utf8(request).unwrap(), expected);
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nRFB")
+                .await
+                .unwrap();
+        });
+        result.unwrap();
+        let mut rfb = [0; 3];
+        client.read_exact(&mut rfb).await.unwrap();
+        assert_eq!(&rfb, b"RFB");
+
+        let (mut client, mut server) = duplex(1024);
+        let proxy = Proxy::http_connect("proxy:3128");
+        let (result, _) = tokio::join!(proxy.tunnel(&mut client, "vnc.internal", 5900), async {
+            server
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        assert!(matches!(
+            result.unwrap_err().downcast_ref(),
+            Some(VncError::Custom(reason)) if reason.contains("authentication")
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
+use anyhow::Result;
+use tracing::{info, warn};
+
+use super::VncClient;
+use crate::{VncError, VncEvent};
+
+/// How a [VncSession] reconnects once the connection is lost
+///
+/// The delay is doubled after each failed attempt, up to `max_delay`
+///
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// Give up after this many failed attempts in a row, `None` to retry forever
+    ///
+    pub max_attempts: Option<u32>,
+    /// The delay before the first attempt
+    ///
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+// `connect` may go through [super::VncState::try_start_local], so the session is not `Send`
+type Connect = Box<dyn FnMut() -> Pin<Box<dyn Future<Output = Result<VncClient>>>>>;
+
+/// A [VncClient] which is connected again by `connect` once the connection is lost
+///
+/// `connect` goes through the whole connector, so the new client is authenticated again,
+///
+/// and informs the pixel format & the encodings to the server as the first one did
+///
+/// The settings changed on the running client (e.g. [VncClient::set_viewport] or the continuous updates)
+///
+/// are not carried over, they can be set again upon [VncEvent::Reconnected]
+///
+/// ```no_run
+/// use vnc::{PixelFormat, ReconnectPolicy, VncConnector, VncEncoding, VncEvent, VncSession};
+/// use tokio::{self, net::TcpStream};
+/// use anyhow::Result;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<()> {
+///     let mut session = VncSession::connect(ReconnectPolicy::default(), || async {
+///         let tcp = TcpStream::connect("127.0.0.1:5900").await?;
+///         VncConnector::new(tcp)
+///             .set_auth_method(|_| async move { Ok("password".into()) })
+///             .add_encoding(VncEncoding::Zrle)
+///             .add_encoding(VncEncoding::Raw)
+///             .set_pixel_format(PixelFormat::bgra())
+///             .build()?
+///             .try_start()
+///             .await?
+///             .finish()
+///     })
+///     .await?;
+///     loop {
+///         match session.recv_event().await? {
+///             VncEvent::Reconnected => println!("Reconnected"),
+///             event => println!("{:?}", event),
+///         }
+///     }
+/// }
+/// ```
+///
+pub struct VncSession {
+    connect: Connect,
+    policy: ReconnectPolicy,
+    client: VncClient,
+}
+
+impl VncSession {
+    /// Connect for the first time, which fails without retrying
+    ///
+    pub async fn connect<C, Fut>(policy: ReconnectPolicy, mut connect: C) -> Result<Self>
+    where
+        C: FnMut() -> Fut + 'static,
+        Fut: Future<Output = Result<VncClient>> + 'static,
+    {
+        let client = connect().await?;
+        Ok(Self {
+            connect: Box::new(move || Box::pin(connect())),
+            policy,
+            client,
+        })
+    }
+
+    /// The client currently connected, which is replaced after reconnecting
+    ///
+    pub fn client(&self) -> &VncClient {
+        &self.client
+    }
+
+    /// Wait for the next event of the client, see [VncClient::recv_event]
+    ///
+    /// Once the connection is lost, it reconnects according to the policy and returns [VncEvent::Reconnected],
+    ///
+    /// or the error of the last attempt if the attempts run out
+    ///
+    /// The other errors are returned as is
+    ///
+    pub async fn recv_event(&mut self) -> Result<VncEvent> {
+        let lost = match self.client.recv_event().await {
+            Ok(event) => return Ok(event),
+            Err(e) if is_connection_lost(&e) => e,
+            Err(e) => return Err(e),
+        };
+        warn!("Connection lost: {:#}, reconnecting", lost);
+        let mut delay = self.policy.initial_delay;
+        let mut attempts = 0;
+        loop {
+            tokio::time::sleep(delay).await;
+            attempts += 1;
+            match (self.connect)().await {
+                Ok(client) => {
+                    info!("Reconnected after {} attempts", attempts);
+                    self.client = client;
+                    return Ok(VncEvent::Reconnected);
+                }
+                Err(e) if self.policy.max_attempts.is_some_and(|max| attempts >= max) => {
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!("Reconnection attempt {} failed: {:#}", attempts, e);
+                    delay = (delay * 2).min(self.policy.max_delay);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the engine is stopped by the transport, rather than by the protocol
+fn is_connection_lost(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<VncError>(),
+            Some(VncError::Disconnected(_))
+        ) || cause.is::<std::io::Error>()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::connection::ClientConfig, PixelFormat, VncEncoding};
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    async fn server_init(server: &mut DuplexStream) {
+        let _shared = server.read_u8().await.unwrap();
+        server.write_u16(800).await.unwrap();
+        server.write_u16(600).await.unwrap();
+        server
+            .write_all(&Vec::<u8>::from(PixelFormat::bgra()))
+            .await
+            .unwrap();
+        server.write_u32(0).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let connections = Arc::new(AtomicU32::new(0));
+        let counter = connections.clone();
+        let policy = ReconnectPolicy {
+            max_attempts: Some(1),
+            initial_delay: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut session = VncSession::connect(policy, move || {
+            let connection = counter.fetch_add(1, Ordering::Relaxed);
+            async move {
+                let (client, mut server) = duplex(4096);
+                tokio::spawn(async move {
+                    server_init(&mut server).await;
+                    // a bell, then the first connection is closed while the second is kept
+                    server.write_u8(2).await.unwrap();
+                    if connection == 0 {
+                        return;
+                    }
+                    let mut sink = Vec::new();
+                    server.read_to_end(&mut sink).await.unwrap();
+                });
+                let config = ClientConfig {
+                    pixel_format: Some(PixelFormat::bgra()),
+                    encodings: vec![VncEncoding::Raw],
+                    ..Default::default()
+                };
+                VncClient::new(client, config).await
+            }
+        })
+        .await
+        .unwrap();
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            let event = session.recv_event().await.unwrap();
+            if matches!(event, VncEvent::Bell | VncEvent::Reconnected) {
+                events.push(event);
+            }
+        }
+        assert!(matches!(
+            events[..],
+            [VncEvent::Bell, VncEvent::Reconnected, VncEvent::Bell]
+        ));
+        assert_eq!(connections.load(Ordering::Relaxed), 2);
+    }
+}
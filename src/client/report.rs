@@ -0,0 +1,263 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use crate::{PixelFormat, VncEncoding};
+
+const RECENT_EVENTS: usize = 50;
+const RECENT_FRAMES: usize = 50;
+
+/// The current time, `None` on wasm where [Instant] is not available
+pub(super) fn now() -> Option<Instant> {
+    if cfg!(target_arch = "wasm32") {
+        None
+    } else {
+        Some(Instant::now())
+    }
+}
+
+/// Timestamps of a framebuffer update
+///
+/// The rects are decoded while they arrive, so `decode_time` includes the network transfer
+///
+/// A long `delivery_time` means the application is not keeping up with the updates
+///
+#[derive(Debug, Clone, Copy)]
+pub struct FrameTiming {
+    /// Number of rects in the update
+    ///
+    pub rects: u16,
+    /// When the update header is received
+    ///
+    pub received: Instant,
+    /// When all the rects are decoded
+    ///
+    pub decoded: Instant,
+    /// When the [crate::VncEvent::UpdateComplete] is taken by [crate::VncClient::recv_event] or [crate::VncClient::poll_event]
+    ///
+    pub delivered: Option<Instant>,
+}
+
+impl FrameTiming {
+    /// From the update received to all the rects decoded
+    ///
+    pub fn decode_time(&self) -> Duration {
+        self.decoded - self.received
+    }
+
+    /// From all the rects decoded to the update delivered to the application
+    ///
+    pub fn delivery_time(&self) -> Option<Duration> {
+        self.delivered.map(|delivered| delivered - self.decoded)
+    }
+}
+
+/// Counters of the rects received with an encoding
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EncodingStats {
+    /// Number of rects
+    ///
+    pub rects: u64,
+    /// Number of pixels covered by the rects
+    ///
+    pub pixels: u64,
+}
+
+/// A snapshot of the session generated by [crate::VncClient::debug_report]
+///
+/// Use its `Display` output when reporting issues
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, Default)]
+pub struct DebugReport {
+    /// The shared flag sent to the server
+    ///
+    pub shared: bool,
+    /// The name of the desktop
+    ///
+    pub name: String,
+    /// The current resolution
+    ///
+    pub screen: (u16, u16),
+    /// The negotiated pixel format
+    ///
+    pub pixel_format: Option<PixelFormat>,
+    /// The encodings informed to the server
+    ///
+    pub encodings: Vec<VncEncoding>,
+    /// Per-encoding counters of the received rects
+    ///
+    pub rects: HashMap<VncEncoding, EncodingStats>,
+    /// The latest protocol events, oldest first
+    ///
+    pub recent_events: VecDeque<String>,
+    /// Messages waiting to be written to the server
+    ///
+    pub queued_messages: usize,
+    /// The most messages ever waiting to be written
+    ///
+    pub max_queued_messages: usize,
+    /// Pointer events replaced by newer ones while the connection was congested
+    ///
+    pub dropped_pointer_events: u64,
+    /// Server messages of unknown types skipped by [crate::UnknownMessagePolicy::Skip]
+    ///
+    pub skipped_messages: u64,
+    /// Timestamps of the latest framebuffer updates, oldest first
+    ///
+    /// Always empty on wasm
+    ///
+    pub recent_frames: VecDeque<FrameTiming>,
+    /// The error which stopped the engine
+    ///
+    pub last_error: Option<String>,
+}
+
+impl DebugReport {
+    pub(super) fn record_event(&mut self, event: String) {
+        if self.recent_events.len() == RECENT_EVENTS {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back(event);
+    }
+
+    pub(super) fn record_frame(&mut self, frame: FrameTiming) {
+        if self.recent_frames.len() == RECENT_FRAMES {
+            self.recent_frames.pop_front();
+        }
+        self.recent_frames.push_back(frame);
+    }
+
+    /// The oldest frame not delivered yet has been taken by the application
+    pub(super) fn frame_delivered(&mut self, delivered: Instant) {
+        if let Some(frame) = self
+            .recent_frames
+            .iter_mut()
+            .find(|frame| frame.delivered.is_none())
+        {
+            frame.delivered = Some(delivered);
+        }
+    }
+
+    pub(super) fn record_rect(&mut self, encoding: VncEncoding, width: u16, height: u16) {
+        let stats = self.rects.entry(encoding).or_default();
+        stats.rects += 1;
+        stats.pixels += width as u64 * height as u64;
+    }
+}
+
+impl fmt::Display for DebugReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "vnc-rs {} debug report", env!("CARGO_PKG_VERSION"))?;
+        writeln!(f, "name: {:?}", self.name)?;
+        writeln!(f, "shared: {}", self.shared)?;
+        writeln!(f, "screen: {}x{}", self.screen.0, self.screen.1)?;
+        writeln!(f, "pixel format: {:?}", self.pixel_format)?;
+        writeln!(f, "encodings: {:?}", self.encodings)?;
+        writeln!(f, "rects:")?;
+        for (encoding, stats) in self.rects.iter() {
+            writeln!(
+                f,
+                "  {:?}: {} rects, {} pixels",
+                encoding, stats.rects, stats.pixels
+            )?;
+        }
+        writeln!(
+            f,
+            "outgoing queue: {} (max {}), {} pointer events dropped",
+            self.queued_messages, self.max_queued_messages, self.dropped_pointer_events
+        )?;
+        writeln!(f, "skipped server messages: {}", self.skipped_messages)?;
+        writeln!(f, "recent frames (rects, decode, delivery):")?;
+        for frame in self.recent_frames.iter() {
+            writeln!(
+                f,
+                "  {}, {:?}, {:?}",
+                frame.rects,
+                frame.decode_time(),
+                frame.delivery_time()
+            )?;
+        }
+        writeln!(f, "recent events:")?;
+        for event in self.recent_events.iter() {
+            writeln!(f, "  {}", event)?;
+        }
+        writeln!(
+            f,
+            "last error: {}",
+            self.last_error.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_report() {
+        let mut report = DebugReport {
+            name: "test".to_string(),
+            screen: (800, 600),
+            ..Default::default()
+        };
+        report.record_rect(VncEncoding::Raw, 10, 10);
+        report.record_rect(VncEncoding::Raw, 20, 5);
+        report.record_rect(VncEncoding::CopyRect, 800, 600);
+        assert_eq!(report.rects[&VncEncoding::Raw].rects, 2);
+        assert_eq!(report.rects[&VncEncoding::Raw].pixels, 200);
+        assert_eq!(report.rects[&VncEncoding::CopyRect].pixels, 480000);
+
+        // only the latest events are kept
+        for i in 0..RECENT_EVENTS + 10 {
+            report.record_event(i.to_string());
+        }
+        assert_eq!(report.recent_events.len(), RECENT_EVENTS);
+        assert_eq!(report.recent_events[0], "10");
+
+        report.last_error = Some("Disconnected".to_string());
+        let text = report.to_string();
+        assert!(text.contains("name: \"test\""));
+        assert!(text.contains("screen: 800x600"));
+        assert!(text.contains("Raw: 2 rects, 200 pixels"));
+        assert!(text.contains("last error: Disconnected"));
+    }
+
+    #[test]
+    fn test_frame_timing() {
+        let mut report = DebugReport::default();
+        let start = Instant::now();
+        for i in 0..RECENT_FRAMES as u16 + 2 {
+            let received = start + Duration::from_millis(i as u64 * 10);
+            report.record_frame(FrameTiming {
+                rects: i,
+                received,
+                decoded: received + Duration::from_millis(3),
+                delivered: None,
+            });
+        }
+        assert_eq!(report.recent_frames.len(), RECENT_FRAMES);
+        assert_eq!(report.recent_frames[0].rects, 2);
+        assert_eq!(
+            report.recent_frames[0].decode_time(),
+            Duration::from_millis(3)
+        );
+        assert_eq!(report.recent_frames[0].delivery_time(), None);
+
+        // the frames are delivered in order
+        let delivered = start + Duration::from_millis(25);
+        report.frame_delivered(delivered);
+        report.frame_delivered(delivered + Duration::from_millis(10));
+        assert_eq!(
+            report.recent_frames[0].delivery_time(),
+            Some(Duration::from_millis(2))
+        );
+        assert_eq!(
+            report.recent_frames[1].delivery_time(),
+            Some(Duration::from_millis(2))
+        );
+        assert_eq!(report.recent_frames[2].delivered, None);
+    }
+}
@@ -0,0 +1,125 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use anyhow::Result;
+use md5::{Digest, Md5};
+use num_bigint::BigUint;
+
+use crate::VncError;
+
+/// Big-endian bytes of `n`, left padded to `len`
+fn to_bytes(n: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+/// Compute the response of the Apple Remote Desktop security type
+///
+/// The Diffie-Hellman shared secret is hashed with MD5 into the AES-128 key,
+///
+/// which encrypts the NUL terminated username & password in two 64 bytes fields
+///
+/// Returns the encrypted credentials and the public key of the client
+///
+pub(crate) fn ard_response(
+    generator: u16,
+    prime: &[u8],
+    server_key: &[u8],
+    username: &str,
+    password: &[u8],
+) -> Result<([u8; 128], Vec<u8>)> {
+    let mut private_key = vec![0; prime.len()];
+    // the unused bytes of the fields are random as well
+    let mut credentials = [0; 128];
+    if getrandom::getrandom(&mut private_key)
+        .and_then(|_| getrandom::getrandom(&mut credentials))
+        .is_err()
+    {
+        return Err(VncError::Custom("No random source for the ARD auth".to_string()).into());
+    }
+    Ok(respond(
+        generator,
+        prime,
+        server_key,
+        &private_key,
+        credentials,
+        username,
+        password,
+    ))
+}
+
+fn respond(
+    generator: u16,
+    prime: &[u8],
+    server_key: &[u8],
+    private_key: &[u8],
+    mut credentials: [u8; 128],
+    username: &str,
+    password: &[u8],
+) -> ([u8; 128], Vec<u8>) {
+    let prime_n = BigUint::from_bytes_be(prime);
+    let private_key = BigUint::from_bytes_be(private_key);
+    let public_key = BigUint::from(generator).modpow(&private_key, &prime_n);
+    let shared = BigUint::from_bytes_be(server_key).modpow(&private_key, &prime_n);
+    let key = Md5::digest(to_bytes(&shared, prime.len()));
+
+    // at most 63 bytes each, leaving room for the NUL
+    for (field, value) in credentials
+        .chunks_exact_mut(64)
+        .zip([username.as_bytes(), password])
+    {
+        let len = value.len().min(63);
+        field[..len].copy_from_slice(&value[..len]);
+        field[len] = 0;
+    }
+    let cipher = Aes128::new(&key);
+    for block in credentials.chunks_exact_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    (credentials, to_bytes(&public_key, prime.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockDecrypt;
+
+    #[test]
+    fn test_ard_response() {
+        // the 768 bits MODP group, which is small enough for a test
+        let prime = BigUint::parse_bytes(
+            b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A3620FFFFFFFFFFFFFFFF",
+            16,
+        )
+        .unwrap();
+        let prime_bytes = prime.to_bytes_be();
+        let server_private = BigUint::from(0x1234_5678_u32);
+        let server_key = BigUint::from(2_u32).modpow(&server_private, &prime);
+
+        let (credentials, client_key) = respond(
+            2,
+            &prime_bytes,
+            &to_bytes(&server_key, prime_bytes.len()),
+            &[0x42; 32],
+            [0xaa; 128],
+            "user",
+            b"password",
+        );
+        assert_eq!(client_key.len(), prime_bytes.len());
+
+        // the server derives the same key from the public key of the client
+        let shared = BigUint::from_bytes_be(&client_key).modpow(&server_private, &prime);
+        let key = Md5::digest(to_bytes(&shared, prime_bytes.len()));
+        let cipher = Aes128::new(&key);
+        let mut plain = credentials;
+        for block in plain.chunks_exact_mut(16) {
+            cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        }
+        assert_eq!(&plain[..5], b"user\0");
+        assert_eq!(&plain[64..73], b"password\0");
+        assert_eq!(plain[73], 0xaa);
+    }
+}
@@ -0,0 +1,66 @@
+/// The alphabet of the standard base64, which is padded, used by the SASL messages & the proxy credentials
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub(crate) fn encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(feature = "sasl")]
+pub(crate) fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    let mut n = 0_u32;
+    for (i, c) in encoded.iter().enumerate() {
+        n = n << 6 | BASE64.iter().position(|b| b == c)? as u32;
+        if i % 4 == 3 {
+            decoded.extend_from_slice(&n.to_be_bytes()[1..]);
+            n = 0;
+        }
+    }
+    match encoded.len() % 4 {
+        0 => (),
+        2 => decoded.push((n >> 4) as u8),
+        3 => decoded.extend_from_slice(&((n >> 2) as u16).to_be_bytes()),
+        _ => return None,
+    }
+    Some(decoded)
+}
+
+#[cfg(all(test, feature = "sasl"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        for (data, encoded) in [
+            (&b""[..], ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (b"foob", "Zm9vYg=="),
+            (b"fooba", "Zm9vYmE="),
+            (b"foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(encode(data), encoded);
+            assert_eq!(decode(encoded).unwrap(), data);
+        }
+        assert!(decode("Z").is_none());
+        assert!(decode("Zm,v").is_none());
+    }
+}
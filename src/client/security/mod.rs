@@ -1 +1,121 @@
+#[cfg(feature = "apple")]
+mod ard;
+#[cfg(any(feature = "sasl", feature = "proxy"))]
+pub(crate) mod base64;
 pub(crate) mod des;
+#[cfg(feature = "ra2")]
+mod ra2;
+#[cfg(feature = "sasl")]
+mod scram;
+
+#[cfg(feature = "apple")]
+pub(crate) use ard::ard_response;
+#[cfg(feature = "ra2")]
+pub(crate) use ra2::{
+    ra2_ciphers, ra2_hash, random, MessageCipher, RsaPrivateKey, RsaPublicKey, CLIENT_KEY_BITS,
+    MAX_MESSAGE,
+};
+#[cfg(feature = "sasl")]
+pub(crate) use scram::{Scram, SCRAM_MECHANISMS};
+
+/// The VncAuth response to `challenge`, keyed by the first 8 bytes of `password`
+///
+pub(crate) fn vnc_auth_response(challenge: &[u8; 16], password: &[u8]) -> [u8; 16] {
+    let password_len = password.len();
+    let mut key = [0u8; 8];
+    for (i, key_i) in key.iter_mut().enumerate() {
+        let c = if i < password_len { password[i] } else { 0 };
+        let mut cs = 0u8;
+        for j in 0..8 {
+            cs |= ((c >> j) & 1) << (7 - j)
+        }
+        *key_i = cs;
+    }
+    encrypt_challenge(challenge, &key)
+}
+
+/// Encrypt the 16 bytes VncAuth challenge with the bit-reversed password `key`
+///
+/// Uses the RustCrypto `des` crate if the `rustcrypto-des` feature is enabled,
+///
+/// otherwise falls back to the vendored implementation in [des]
+///
+pub(crate) fn encrypt_challenge(challenge: &[u8; 16], key: &[u8; 8]) -> [u8; 16] {
+    #[cfg(feature = "rustcrypto-des")]
+    {
+        use ::des::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+
+        let cipher = ::des::Des::new(GenericArray::from_slice(key));
+        let mut response = *challenge;
+        for block in response.chunks_exact_mut(8) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        response
+    }
+    #[cfg(not(feature = "rustcrypto-des"))]
+    {
+        des::encrypt(challenge, key).try_into().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{des, encrypt_challenge};
+
+    // (password, challenge, response) triples
+    // cross-checked with `openssl enc -des-ecb -nopad`
+    const VECTORS: [(&str, [u8; 16], [u8; 16]); 3] = [
+        (
+            "password",
+            [
+                0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+                0x0e, 0x0f,
+            ],
+            [
+                0xb8, 0x66, 0x92, 0x41, 0x25, 0xc8, 0xee, 0xbb, 0x9d, 0xeb, 0xc1, 0xdb, 0x61, 0xc5,
+                0x38, 0xe2,
+            ],
+        ),
+        (
+            "123",
+            [
+                0x9f, 0x3a, 0x61, 0xc2, 0x0b, 0x77, 0xe4, 0xd8, 0x55, 0x12, 0xaa, 0x0c, 0x3e, 0x8f,
+                0x6b, 0x19,
+            ],
+            [
+                0xeb, 0x99, 0x74, 0x85, 0xae, 0x5f, 0x43, 0xf4, 0xd5, 0x44, 0x49, 0x78, 0xc9, 0xf2,
+                0xaf, 0x6a,
+            ],
+        ),
+        (
+            // only the first 8 characters take effect
+            "verylongpassword",
+            [
+                0xf0, 0xe1, 0xd2, 0xc3, 0xb4, 0xa5, 0x96, 0x87, 0x78, 0x69, 0x5a, 0x4b, 0x3c, 0x2d,
+                0x1e, 0x0f,
+            ],
+            [
+                0x07, 0x63, 0x9a, 0x6c, 0x6b, 0xa0, 0xb7, 0xfd, 0x14, 0xac, 0x4a, 0x76, 0x29, 0x65,
+                0xbe, 0xdb,
+            ],
+        ),
+    ];
+
+    fn password_to_key(password: &str) -> [u8; 8] {
+        let mut key = [0u8; 8];
+        for (k, c) in key.iter_mut().zip(password.bytes()) {
+            *k = c.reverse_bits();
+        }
+        key
+    }
+
+    #[test]
+    fn test_vnc_auth_vectors() {
+        for (password, challenge, response) in VECTORS {
+            let key = password_to_key(password);
+            assert_eq!(encrypt_challenge(&challenge, &key), response);
+            // the vendored implementation must always agree
+            assert_eq!(des::encrypt(&challenge, &key), response);
+        }
+    }
+}
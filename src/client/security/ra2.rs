@@ -0,0 +1,504 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128, Aes256,
+};
+use anyhow::Result;
+use num_bigint::BigUint;
+use ring::digest;
+
+use crate::VncError;
+
+/// The key lengths accepted from the server, in bits
+const MIN_KEY_BITS: u32 = 1024;
+const MAX_KEY_BITS: u32 = 8192;
+
+/// The largest message sealed at once
+pub(crate) const MAX_MESSAGE: usize = 8192;
+
+/// The length of the key generated by the client, in bits
+///
+/// while the smaller keys keep the tests quick
+///
+pub(crate) const CLIENT_KEY_BITS: u32 = if cfg!(test) { 1024 } else { 2048 };
+
+pub(crate) fn random(buf: &mut [u8]) -> Result<()> {
+    getrandom::getrandom(buf)
+        .map_err(|_| VncError::Custom("No random source for the RA2 auth".to_string()).into())
+}
+
+/// Big-endian bytes of `n`, left padded to `len`
+fn to_bytes(n: &BigUint, len: usize) -> Vec<u8> {
+    let bytes = n.to_bytes_be();
+    let mut padded = vec![0; len.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+/// A RSA public key as it is on the wire, the modulus & the exponent of the same length
+pub(crate) struct RsaPublicKey {
+    pub(crate) bits: u32,
+    pub(crate) n: Vec<u8>,
+    pub(crate) e: Vec<u8>,
+}
+
+impl RsaPublicKey {
+    /// Check the key informed by the peer, whose length is `bits`
+    pub(crate) fn new(bits: u32, n: Vec<u8>, e: Vec<u8>) -> Result<Self> {
+        if BigUint::from_bytes_be(&n).bits() != bits as u64 {
+            return Err(VncError::Custom("The RA2 key is malformed".to_string()).into());
+        }
+        Ok(Self { bits, n, e })
+    }
+
+    /// The bytes of the modulus & the exponent, once `bits` is acceptable
+    pub(crate) fn size(bits: u32) -> Result<usize> {
+        if !(MIN_KEY_BITS..=MAX_KEY_BITS).contains(&bits) {
+            return Err(
+                VncError::Custom(format!("The RA2 key of {} bits is refused", bits)).into(),
+            );
+        }
+        Ok(bits.div_ceil(8) as usize)
+    }
+
+    /// The length, the modulus & the exponent
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.bits.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.n);
+        bytes.extend_from_slice(&self.e);
+        bytes
+    }
+
+    /// The SHA-1 digest of the key, which identifies the server
+    pub(crate) fn fingerprint(&self) -> Vec<u8> {
+        digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &self.to_bytes())
+            .as_ref()
+            .to_vec()
+    }
+
+    /// Encrypt `msg` with the PKCS#1 v1.5 padding
+    pub(crate) fn encrypt(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        let k = self.n.len();
+        if msg.len() + 11 > k {
+            return Err(VncError::Custom("The RA2 key is too short".to_string()).into());
+        }
+        // 0x00 0x02, the non-zero random padding, 0x00, the message
+        let mut padded = vec![0; k];
+        padded[1] = 2;
+        let padding = &mut padded[2..k - msg.len() - 1];
+        random(padding)?;
+        for byte in padding.iter_mut() {
+            while *byte == 0 {
+                random(std::slice::from_mut(byte))?;
+            }
+        }
+        padded[k - msg.len()..].copy_from_slice(msg);
+        let m = BigUint::from_bytes_be(&padded);
+        let c = m.modpow(
+            &BigUint::from_bytes_be(&self.e),
+            &BigUint::from_bytes_be(&self.n),
+        );
+        Ok(to_bytes(&c, k))
+    }
+}
+
+/// The RSA key of the client, generated for each session
+pub(crate) struct RsaPrivateKey {
+    public: RsaPublicKey,
+    n: BigUint,
+    d: BigUint,
+}
+
+impl RsaPrivateKey {
+    pub(crate) fn generate(bits: u32) -> Result<Self> {
+        let e = BigUint::from(65537_u32);
+        let one = BigUint::from(1_u32);
+        loop {
+            let p = random_prime(bits / 2)?;
+            let q = random_prime(bits - bits / 2)?;
+            let n = &p * &q;
+            if p == q || n.bits() != bits as u64 {
+                continue;
+            }
+            let phi = (&p - &one) * (&q - &one);
+            // unless e divides phi
+            let Some(d) = e.modinv(&phi) else {
+                continue;
+            };
+            let size = RsaPublicKey::size(bits)?;
+            let public = RsaPublicKey {
+                bits,
+                n: to_bytes(&n, size),
+                e: to_bytes(&e, size),
+            };
+            return Ok(Self { public, n, d });
+        }
+    }
+
+    pub(crate) fn public(&self) -> &RsaPublicKey {
+        &self.public
+    }
+
+    /// Decrypt the PKCS#1 v1.5 padded `cipher`
+    pub(crate) fn decrypt(&self, cipher: &[u8]) -> Result<Vec<u8>> {
+        let malformed = || VncError::Custom("The RA2 random cannot be decrypted".to_string());
+        let c = BigUint::from_bytes_be(cipher);
+        if cipher.len() != self.public.n.len() || c >= self.n {
+            return Err(malformed().into());
+        }
+        let padded = to_bytes(&c.modpow(&self.d, &self.n), cipher.len());
+        // at least 8 bytes of padding
+        let separator = padded
+            .iter()
+            .skip(2)
+            .position(|byte| *byte == 0)
+            .filter(|len| padded[..2] == [0, 2] && *len >= 8)
+            .ok_or_else(malformed)?;
+        Ok(padded[separator + 3..].to_vec())
+    }
+}
+
+fn small_primes() -> Vec<u32> {
+    (3..2000_u32)
+        .step_by(2)
+        .filter(|n| {
+            (3..)
+                .step_by(2)
+                .take_while(|d| d * d <= *n)
+                .all(|d| n % d != 0)
+        })
+        .collect()
+}
+
+/// A random prime of exactly `bits` bits, whose top 2 bits are set
+fn random_prime(bits: u32) -> Result<BigUint> {
+    let small_primes = small_primes();
+    let zero = BigUint::from(0_u32);
+    let mut bytes = vec![0; bits.div_ceil(8) as usize];
+    loop {
+        random(&mut bytes)?;
+        let mut candidate = BigUint::from_bytes_be(&bytes) >> (bytes.len() * 8 - bits as usize);
+        candidate.set_bit(bits as u64 - 1, true);
+        candidate.set_bit(bits as u64 - 2, true);
+        candidate.set_bit(0, true);
+        if small_primes.iter().any(|p| &candidate % p == zero) {
+            continue;
+        }
+        if is_probable_prime(&candidate, 20)? {
+            return Ok(candidate);
+        }
+    }
+}
+
+/// The Miller-Rabin test with `rounds` random bases
+fn is_probable_prime(n: &BigUint, rounds: usize) -> Result<bool> {
+    let one = BigUint::from(1_u32);
+    let two = BigUint::from(2_u32);
+    let n_1 = n - &one;
+    let s = n_1.trailing_zeros().unwrap_or(0);
+    let d = &n_1 >> s;
+    let mut bytes = vec![0; n.to_bytes_be().len()];
+    'rounds: for _ in 0..rounds {
+        random(&mut bytes)?;
+        // in [2, n - 2]
+        let a = BigUint::from_bytes_be(&bytes) % (n - 3_u32) + &two;
+        let mut x = a.modpow(&d, n);
+        if x == one || x == n_1 {
+            continue;
+        }
+        for _ in 1..s {
+            x = x.modpow(&two, n);
+            if x == n_1 {
+                continue 'rounds;
+            }
+        }
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// SHA-1 truncated to 16 bytes for AES-128, SHA-256 for AES-256
+fn hash(key_size: usize, parts: &[&[u8]]) -> Vec<u8> {
+    let algorithm = match key_size {
+        16 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        _ => &digest::SHA256,
+    };
+    let mut context = digest::Context::new(algorithm);
+    for part in parts {
+        context.update(part);
+    }
+    context.finish().as_ref().to_vec()
+}
+
+/// The message ciphers of both directions, derived from the randoms exchanged
+///
+/// Returns the decryptor of the messages from the server and the encryptor of the ones to the server
+///
+pub(crate) fn ra2_ciphers(
+    client_random: &[u8],
+    server_random: &[u8],
+) -> (MessageCipher, MessageCipher) {
+    let key_size = client_random.len();
+    let key = hash(key_size, &[client_random, server_random]);
+    let decryptor = MessageCipher::new(&key[..key_size]);
+    let key = hash(key_size, &[server_random, client_random]);
+    let encryptor = MessageCipher::new(&key[..key_size]);
+    (decryptor, encryptor)
+}
+
+/// The hash of both keys proving the key exchange, the one of the sender first
+///
+/// SHA-1 with AES-128 & SHA-256 with AES-256
+///
+pub(crate) fn ra2_hash(key_size: usize, sender: &RsaPublicKey, receiver: &RsaPublicKey) -> Vec<u8> {
+    hash(key_size, &[&sender.to_bytes(), &receiver.to_bytes()])
+}
+
+enum BlockCipher {
+    Aes128(Box<Aes128>),
+    Aes256(Box<Aes256>),
+}
+
+impl BlockCipher {
+    fn encrypt(&self, block: &mut [u8; 16]) {
+        let block = GenericArray::from_mut_slice(block);
+        match self {
+            BlockCipher::Aes128(cipher) => cipher.encrypt_block(block),
+            BlockCipher::Aes256(cipher) => cipher.encrypt_block(block),
+        }
+    }
+}
+
+fn xor(block: &mut [u8; 16], other: &[u8]) {
+    for (a, b) in block.iter_mut().zip(other) {
+        *a ^= b;
+    }
+}
+
+/// Double in GF(2^128) for the CMAC subkeys
+fn double(block: &[u8; 16]) -> [u8; 16] {
+    let value = u128::from_be_bytes(*block);
+    let doubled = (value << 1) ^ if value >> 127 == 1 { 0x87 } else { 0 };
+    doubled.to_be_bytes()
+}
+
+/// The AES-EAX authenticated encryption
+pub(crate) struct Eax {
+    cipher: BlockCipher,
+    // the CMAC subkeys of the complete & the padded last blocks
+    k1: [u8; 16],
+    k2: [u8; 16],
+}
+
+impl Eax {
+    /// With a key of 16 or 32 bytes
+    pub(crate) fn new(key: &[u8]) -> Self {
+        let cipher = match key.len() {
+            16 => BlockCipher::Aes128(Box::new(Aes128::new(GenericArray::from_slice(key)))),
+            _ => BlockCipher::Aes256(Box::new(Aes256::new(GenericArray::from_slice(key)))),
+        };
+        let mut l = [0; 16];
+        cipher.encrypt(&mut l);
+        let k1 = double(&l);
+        let k2 = double(&k1);
+        Self { cipher, k1, k2 }
+    }
+
+    /// The CMAC of the block of `tweak` followed by `data`
+    fn omac(&self, tweak: u8, data: &[u8]) -> [u8; 16] {
+        let mut mac = [0; 16];
+        mac[15] = tweak;
+        if data.is_empty() {
+            xor(&mut mac, &self.k1);
+            self.cipher.encrypt(&mut mac);
+            return mac;
+        }
+        self.cipher.encrypt(&mut mac);
+        let last = (data.len() - 1) / 16;
+        for (i, block) in data.chunks(16).enumerate() {
+            xor(&mut mac, block);
+            if i == last {
+                if block.len() == 16 {
+                    xor(&mut mac, &self.k1);
+                } else {
+                    mac[block.len()] ^= 0x80;
+                    xor(&mut mac, &self.k2);
+                }
+            }
+            self.cipher.encrypt(&mut mac);
+        }
+        mac
+    }
+
+    fn ctr(&self, counter: [u8; 16], data: &mut [u8]) {
+        let mut counter = u128::from_be_bytes(counter);
+        for block in data.chunks_mut(16) {
+            let mut stream = counter.to_be_bytes();
+            self.cipher.encrypt(&mut stream);
+            for (a, b) in block.iter_mut().zip(stream) {
+                *a ^= b;
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+
+    /// Encrypt `data` in place, return the tag
+    pub(crate) fn seal(&self, nonce: &[u8], header: &[u8], data: &mut [u8]) -> [u8; 16] {
+        let nonce = self.omac(0, nonce);
+        self.ctr(nonce, data);
+        let mut tag = self.omac(2, data);
+        xor(&mut tag, &nonce);
+        xor(&mut tag, &self.omac(1, header));
+        tag
+    }
+
+    /// Decrypt `data` in place if the `tag` matches
+    pub(crate) fn open(&self, nonce: &[u8], header: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+        let nonce = self.omac(0, nonce);
+        let mut expected = self.omac(2, data);
+        xor(&mut expected, &nonce);
+        xor(&mut expected, &self.omac(1, header));
+        // in constant time
+        if expected
+            .iter()
+            .zip(tag)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            != 0
+            || tag.len() != 16
+        {
+            return false;
+        }
+        self.ctr(nonce, data);
+        true
+    }
+}
+
+/// The cipher of the messages in one direction
+///
+/// Each message is the 2 bytes length, the encrypted data, and the 16 bytes tag
+///
+/// sealed with the number of the message as the nonce
+///
+pub(crate) struct MessageCipher {
+    eax: Eax,
+    // the little-endian counter
+    nonce: [u8; 16],
+}
+
+impl MessageCipher {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            eax: Eax::new(key),
+            nonce: [0; 16],
+        }
+    }
+
+    fn next_nonce(&mut self) {
+        for byte in self.nonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+    }
+
+    /// Seal at most [MAX_MESSAGE] bytes of `data` into a message
+    pub(crate) fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let len = data.len().min(MAX_MESSAGE);
+        let mut message = (len as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&data[..len]);
+        let (header, body) = message.split_at_mut(2);
+        let tag = self.eax.seal(&self.nonce, header, body);
+        message.extend_from_slice(&tag);
+        self.next_nonce();
+        message
+    }
+
+    /// Open a whole `message` in place, return the data
+    pub(crate) fn open<'a>(&mut self, message: &'a mut [u8]) -> Option<&'a [u8]> {
+        let (header, rest) = message.split_at_mut(2);
+        let (body, tag) = rest.split_at_mut(rest.len().checked_sub(16)?);
+        if !self.eax.open(&self.nonce, header, body, tag) {
+            return None;
+        }
+        self.next_nonce();
+        Some(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_eax_vectors() {
+        // (key, nonce, header, message, cipher text with the tag), from the EAX paper
+        let vectors = [
+            (
+                "233952DEE4D5ED5F9B9C6D6FF80FF478",
+                "62EC67F9C3A4A407FCB2A8C49031A8B3",
+                "6BFB914FD07EAE6B",
+                "",
+                "E037830E8389F27B025A2D6527E79D01",
+            ),
+            (
+                "91945D3F4DCBEE0BF45EF52255F095A4",
+                "BECAF043B0A23D843194BA972C66DEBD",
+                "FA3BFD4806EB53FA",
+                "F7FB",
+                "19DD5C4C9331049D0BDAB0277408F67967E5",
+            ),
+            (
+                "01F74AD64077F2E704C0F60ADA3DD523",
+                "70C3DB4F0D26368400A10ED05D2BFF5E",
+                "234A3463C1264AC6",
+                "1A47CB4933",
+                "D851D5BAE03A59F238A23E39199DC9266626C40F80",
+            ),
+        ];
+        for (key, nonce, header, message, expected) in vectors {
+            let eax = Eax::new(&hex(key));
+            let mut data = hex(message);
+            let tag = eax.seal(&hex(nonce), &hex(header), &mut data);
+            data.extend_from_slice(&tag);
+            assert_eq!(data, hex(expected));
+
+            let (data, tag) = data.split_at_mut(hex(message).len());
+            assert!(eax.open(&hex(nonce), &hex(header), data, tag));
+            assert_eq!(data, hex(message));
+            tag[0] ^= 1;
+            assert!(!eax.open(&hex(nonce), &hex(header), data, tag));
+        }
+    }
+
+    #[test]
+    fn test_rsa() {
+        let key = RsaPrivateKey::generate(1024).unwrap();
+        assert_eq!(key.public().n.len(), 128);
+        let cipher = key.public().encrypt(&[0x42; 16]).unwrap();
+        assert_eq!(key.decrypt(&cipher).unwrap(), [0x42; 16]);
+        assert!(RsaPublicKey::size(512).is_err());
+        assert!(RsaPublicKey::new(1024, vec![0x7f; 128], vec![0; 128]).is_err());
+    }
+
+    #[test]
+    fn test_message_cipher() {
+        let (mut decryptor, _) = ra2_ciphers(&[1; 16], &[2; 16]);
+        let (_, mut encryptor) = ra2_ciphers(&[2; 16], &[1; 16]);
+        for data in [&b"hello"[..], b"", b"world"] {
+            let mut message = encryptor.seal(data);
+            assert_eq!(message.len(), 2 + data.len() + 16);
+            assert_eq!(decryptor.open(&mut message).unwrap(), data);
+        }
+        // out of order
+        let _ = encryptor.seal(b"lost");
+        let mut message = encryptor.seal(b"late");
+        assert!(decryptor.open(&mut message).is_none());
+    }
+}
@@ -0,0 +1,200 @@
+use std::num::NonZeroU32;
+
+use anyhow::Result;
+use ring::{digest, hmac, pbkdf2};
+
+use super::base64::{decode as base64_decode, encode as base64_encode};
+use crate::VncError;
+
+/// The SASL mechanisms implemented, the preferred first
+pub(crate) const SCRAM_MECHANISMS: [&str; 2] = ["SCRAM-SHA-256", "SCRAM-SHA-1"];
+
+/// A bound on the iterations asked by the server, the derivation is done on the current task
+const MAX_ITERATIONS: u32 = 1 << 20;
+
+/// The client side of the SCRAM mechanisms, [RFC 5802](https://www.rfc-editor.org/rfc/rfc5802)
+///
+/// No channel binding, and the credentials are used without SASLprep
+///
+pub(crate) struct Scram {
+    hmac: hmac::Algorithm,
+    pbkdf2: pbkdf2::Algorithm,
+    digest: &'static digest::Algorithm,
+    password: Vec<u8>,
+    client_first_bare: String,
+    nonce: String,
+    // known once the proof is sent
+    server_signature: Option<hmac::Tag>,
+}
+
+impl Scram {
+    /// `mechanism` is one of [SCRAM_MECHANISMS]
+    pub(crate) fn new(mechanism: &str, username: &str, password: &[u8]) -> Result<Self> {
+        let mut nonce = [0; 18];
+        if getrandom::getrandom(&mut nonce).is_err() {
+            return Err(VncError::Custom("No random source for the SCRAM auth".to_string()).into());
+        }
+        Self::with_nonce(mechanism, username, password, base64_encode(&nonce))
+    }
+
+    fn with_nonce(mechanism: &str, username: &str, password: &[u8], nonce: String) -> Result<Self> {
+        let (hmac, pbkdf2, digest) = match mechanism {
+            "SCRAM-SHA-256" => (
+                hmac::HMAC_SHA256,
+                pbkdf2::PBKDF2_HMAC_SHA256,
+                &digest::SHA256,
+            ),
+            "SCRAM-SHA-1" => (
+                hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+                pbkdf2::PBKDF2_HMAC_SHA1,
+                &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            ),
+            _ => {
+                return Err(VncError::Custom(format!(
+                    "SASL mechanism {} is not supported",
+                    mechanism
+                ))
+                .into())
+            }
+        };
+        let username = username.replace('=', "=3D").replace(',', "=2C");
+        Ok(Self {
+            hmac,
+            pbkdf2,
+            digest,
+            password: password.to_vec(),
+            client_first_bare: format!("n={},r={}", username, nonce),
+            nonce,
+            server_signature: None,
+        })
+    }
+
+    /// The first message, without channel binding
+    pub(crate) fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    /// The proof answering the salt & the iterations of `server_first`
+    pub(crate) fn client_final(&mut self, server_first: &[u8]) -> Result<String> {
+        let malformed = || VncError::Custom("Malformed SCRAM server message".to_string());
+        let server_first = std::str::from_utf8(server_first).map_err(|_| malformed())?;
+        let (mut nonce, mut salt, mut iterations) = (None, None, None);
+        for attribute in server_first.split(',') {
+            match attribute.split_once('=') {
+                Some(("r", value)) => nonce = Some(value),
+                Some(("s", value)) => salt = base64_decode(value),
+                Some(("i", value)) => iterations = value.parse::<u32>().ok(),
+                // the mandatory extensions are unknown
+                _ => return Err(malformed().into()),
+            }
+        }
+        let (Some(nonce), Some(salt), Some(iterations)) = (nonce, salt, iterations) else {
+            return Err(malformed().into());
+        };
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            return Err(VncError::Custom("The SCRAM nonce is tampered".to_string()).into());
+        }
+        let iterations = NonZeroU32::new(iterations)
+            .filter(|i| i.get() <= MAX_ITERATIONS)
+            .ok_or_else(malformed)?;
+
+        let mut salted_password = vec![0; self.digest.output_len()];
+        pbkdf2::derive(
+            self.pbkdf2,
+            iterations,
+            &salt,
+            &self.password,
+            &mut salted_password,
+        );
+        let salted_password = hmac::Key::new(self.hmac, &salted_password);
+        let client_key = hmac::sign(&salted_password, b"Client Key");
+        let stored_key = digest::digest(self.digest, client_key.as_ref());
+
+        // "biws" is the base64 of the gs2 header "n,,"
+        let client_final = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final
+        );
+        let client_signature = hmac::sign(
+            &hmac::Key::new(self.hmac, stored_key.as_ref()),
+            auth_message.as_bytes(),
+        );
+        let proof = client_key
+            .as_ref()
+            .iter()
+            .zip(client_signature.as_ref())
+            .map(|(a, b)| a ^ b)
+            .collect::<Vec<_>>();
+
+        let server_key = hmac::sign(&salted_password, b"Server Key");
+        self.server_signature = Some(hmac::sign(
+            &hmac::Key::new(self.hmac, server_key.as_ref()),
+            auth_message.as_bytes(),
+        ));
+        Ok(format!("{},p={}", client_final, base64_encode(&proof)))
+    }
+
+    /// Check that the server knows the password as well
+    pub(crate) fn verify(&self, server_final: &[u8]) -> Result<()> {
+        let server_final = std::str::from_utf8(server_final).unwrap_or_default();
+        if let Some(error) = server_final.strip_prefix("e=") {
+            return Err(VncError::Custom(format!("SCRAM auth failed: {}", error)).into());
+        }
+        let verified = match (
+            server_final.strip_prefix("v=").and_then(base64_decode),
+            self.server_signature.as_ref(),
+        ) {
+            (Some(signature), Some(expected)) => signature == expected.as_ref(),
+            _ => false,
+        };
+        if !verified {
+            return Err(
+                VncError::Custom("The SCRAM signature of the server is wrong".to_string()).into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scram_vectors() {
+        // the examples of RFC 5802 & RFC 7677
+        let vectors = [
+            (
+                "SCRAM-SHA-1",
+                "fyko+d2lbbFgONRv9qkxdawL",
+                "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+                "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=",
+                "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=",
+            ),
+            (
+                "SCRAM-SHA-256",
+                "rOprNGfwEbeRWgbNEkqO",
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+                "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=",
+            ),
+        ];
+        for (mechanism, nonce, server_first, client_final, server_final) in vectors {
+            let mut scram =
+                Scram::with_nonce(mechanism, "user", b"pencil", nonce.to_string()).unwrap();
+            assert_eq!(scram.client_first(), format!("n,,n=user,r={}", nonce));
+            assert_eq!(
+                scram.client_final(server_first.as_bytes()).unwrap(),
+                client_final
+            );
+            scram.verify(server_final.as_bytes()).unwrap();
+            assert!(scram.verify(b"v=AAAA").is_err());
+        }
+
+        // the server nonce must extend the one of the client
+        let mut scram = Scram::with_nonce("SCRAM-SHA-1", "u,=", b"p", "abc".to_string()).unwrap();
+        assert_eq!(scram.client_first(), "n,,n=u=2C=3D,r=abc");
+        assert!(scram.client_final(b"r=xyz,s=QSXC,i=1").is_err());
+    }
+}
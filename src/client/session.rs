@@ -0,0 +1,25 @@
+use crate::{PixelFormat, Screen};
+
+/// Information about the session, collected while connecting
+///
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    /// The name of the desktop, decoded with the [crate::NamePolicy] of the connector
+    ///
+    pub name: String,
+    /// The raw bytes of the desktop name sent by the server
+    ///
+    pub name_bytes: Vec<u8>,
+    /// The resolution informed by the ServerInit message
+    ///
+    pub screen: Screen,
+    /// The pixel format informed by the ServerInit message
+    ///
+    pub server_pixel_format: PixelFormat,
+    /// The version string sent by the server without the trailing newline, e.g. `RFB 003.008`
+    ///
+    /// Which tells the actual version if the server reports a nonstandard one
+    ///
+    pub raw_server_version: String,
+}
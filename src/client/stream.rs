@@ -0,0 +1,311 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "ra2")]
+use super::security::{MessageCipher, MAX_MESSAGE};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "tls")]
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
+
+/// The stream given to [super::VncConnector], which may be upgraded to TLS during the security handshake
+///
+/// or encrypted by the RA2 security types
+///
+pub(super) enum UpgradableStream<S> {
+    Plain(S),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<S>>),
+    #[cfg(feature = "ra2")]
+    Aes(Box<AesStream<S>>),
+}
+
+#[cfg(feature = "tls")]
+impl<S> UpgradableStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Run the TLS handshake over the plain stream
+    pub(super) async fn upgrade(
+        self,
+        config: std::sync::Arc<rustls::ClientConfig>,
+        server_name: rustls::pki_types::ServerName<'static>,
+    ) -> io::Result<Self> {
+        match self {
+            UpgradableStream::Plain(stream) => {
+                let tls = TlsConnector::from(config)
+                    .connect(server_name, stream)
+                    .await?;
+                Ok(UpgradableStream::Tls(Box::new(tls)))
+            }
+            _ => Err(io::Error::other("The stream is already upgraded")),
+        }
+    }
+}
+
+#[cfg(feature = "ra2")]
+impl<S> UpgradableStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Encrypt the messages over the plain stream from now on
+    pub(super) fn encrypt(
+        self,
+        decryptor: MessageCipher,
+        encryptor: MessageCipher,
+    ) -> io::Result<Self> {
+        match self {
+            UpgradableStream::Plain(stream) => Ok(UpgradableStream::Aes(Box::new(AesStream::new(
+                stream, decryptor, encryptor,
+            )))),
+            _ => Err(io::Error::other("The stream is already upgraded")),
+        }
+    }
+
+    /// Go back to the plain stream, which must have been flushed
+    pub(super) fn into_plain(self) -> io::Result<Self> {
+        match self {
+            UpgradableStream::Aes(stream) if stream.is_drained() => {
+                Ok(UpgradableStream::Plain(stream.inner))
+            }
+            _ => Err(io::Error::other("The stream is not encrypted")),
+        }
+    }
+}
+
+impl<S> UpgradableStream<S> {
+    /// Whether the messages go over the stream as is
+    pub(super) fn is_plain(&self) -> bool {
+        matches!(self, UpgradableStream::Plain(_))
+    }
+}
+
+impl<S> AsyncRead for UpgradableStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpgradableStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            UpgradableStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "ra2")]
+            UpgradableStream::Aes(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for UpgradableStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UpgradableStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            UpgradableStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "ra2")]
+            UpgradableStream::Aes(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpgradableStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            UpgradableStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "ra2")]
+            UpgradableStream::Aes(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UpgradableStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            UpgradableStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "ra2")]
+            UpgradableStream::Aes(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// The stream sealing the data into the messages of [MessageCipher]
+///
+#[cfg(feature = "ra2")]
+pub(super) struct AesStream<S> {
+    inner: S,
+    decryptor: MessageCipher,
+    encryptor: MessageCipher,
+    // the message being read, `received` bytes of which are filled
+    incoming: Vec<u8>,
+    received: usize,
+    // the data opened but not yet read
+    plain: Vec<u8>,
+    consumed: usize,
+    // the message sealed but not yet written
+    outgoing: Vec<u8>,
+    written: usize,
+}
+
+#[cfg(feature = "ra2")]
+impl<S> AesStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn new(inner: S, decryptor: MessageCipher, encryptor: MessageCipher) -> Self {
+        Self {
+            inner,
+            decryptor,
+            encryptor,
+            incoming: Vec::new(),
+            received: 0,
+            plain: Vec::new(),
+            consumed: 0,
+            outgoing: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Nothing is buffered in either direction
+    fn is_drained(&self) -> bool {
+        self.received == 0
+            && self.consumed == self.plain.len()
+            && self.written == self.outgoing.len()
+    }
+
+    /// Write the sealed message out
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.outgoing.len() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.outgoing[self.written..]) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => self.written += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Read a whole message, `Ok(false)` if the stream ends between the messages
+    fn poll_message(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        loop {
+            // the length first, then the data & the tag
+            let expected = match self.received {
+                0 | 1 => 2,
+                _ => 2 + u16::from_be_bytes([self.incoming[0], self.incoming[1]]) as usize + 16,
+            };
+            if self.received == expected {
+                break;
+            }
+            self.incoming.resize(expected, 0);
+            let mut buf = ReadBuf::new(&mut self.incoming[self.received..]);
+            match Pin::new(&mut self.inner).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => {
+                    if self.received == 0 {
+                        return Poll::Ready(Ok(false));
+                    }
+                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                }
+                Poll::Ready(Ok(())) => self.received += buf.filled().len(),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.received = 0;
+        let data = self.decryptor.open(&mut self.incoming).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The message cannot be authenticated",
+            )
+        })?;
+        self.plain.clear();
+        self.plain.extend_from_slice(data);
+        self.consumed = 0;
+        Poll::Ready(Ok(true))
+    }
+}
+
+#[cfg(feature = "ra2")]
+impl<S> AsyncRead for AesStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // the messages may be empty
+        while this.consumed == this.plain.len() {
+            match this.poll_message(cx) {
+                Poll::Ready(Ok(true)) => (),
+                Poll::Ready(Ok(false)) => return Poll::Ready(Ok(())),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.remaining().min(this.plain.len() - this.consumed);
+        buf.put_slice(&this.plain[this.consumed..this.consumed + len]);
+        this.consumed += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "ra2")]
+impl<S> AsyncWrite for AesStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // one message at a time
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let len = buf.len().min(MAX_MESSAGE);
+        this.outgoing = this.encryptor.seal(&buf[..len]);
+        this.written = 0;
+        // the data is taken once sealed, the rest is written by the next write or flush
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_drain(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    fmt::Write as _,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+use tracing::warn;
+
+use super::report;
+
+/// The version of the schema written by [TraceRecord::to_json]
+///
+/// Bumped only if a key is removed or its meaning is changed, new keys may be added within a version
+///
+pub const TRACE_SCHEMA_VERSION: u32 = 1;
+
+/// The sender of a traced message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceDirection {
+    /// From the server to the client
+    ///
+    Server,
+    /// From the client to the server
+    ///
+    Client,
+}
+
+impl TraceDirection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TraceDirection::Server => "server",
+            TraceDirection::Client => "client",
+        }
+    }
+}
+
+/// A protocol message of the session, passed to the sink given to [crate::VncConnector::set_trace_sink]
+///
+/// The messages are traced from the `ClientInit` on, the handshake & the security types are not traced
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Starting from 0, the messages of both the directions share the sequence
+    ///
+    pub seq: u64,
+    /// Since the session is initialized, `None` on wasm where [Instant] is not available
+    ///
+    pub elapsed: Option<Duration>,
+    pub direction: TraceDirection,
+    /// The name of the message type, e.g. `FramebufferUpdate` or `PointerEvent`
+    ///
+    /// See [crate::proto::messages::ServerMsg::name] & [crate::proto::messages::ClientMsg::name]
+    ///
+    pub message: &'static str,
+    /// Bytes of the message on the wire, including the rects of a framebuffer update
+    ///
+    /// The encryption overhead of the secure transports is not counted
+    ///
+    pub bytes: u64,
+    /// Number of rects of a framebuffer update
+    ///
+    pub rects: Option<u16>,
+    /// From the header read to the message handled, only of the server messages
+    ///
+    /// For a framebuffer update it includes the decoding & the network transfer of the rects
+    ///
+    pub duration: Option<Duration>,
+}
+
+impl TraceRecord {
+    /// The record as a line of JSON, without the line break
+    ///
+    /// ```json
+    /// {"v":1,"seq":3,"t_us":1520,"dir":"server","msg":"FramebufferUpdate","bytes":4120,"rects":2,"dur_us":310}
+    /// ```
+    ///
+    /// All the keys are always present, `null` for the values which don't apply
+    ///
+    pub fn to_json(&self) -> String {
+        let micros = |d: Option<Duration>| match d {
+            Some(d) => d.as_micros().to_string(),
+            None => "null".to_string(),
+        };
+        let mut json = String::with_capacity(128);
+        // the names are identifiers, so nothing needs to be escaped
+        let _ = write!(
+            json,
+            r#"{{"v":{},"seq":{},"t_us":{},"dir":"{}","msg":"{}","bytes":{},"rects":{},"dur_us":{}}}"#,
+            TRACE_SCHEMA_VERSION,
+            self.seq,
+            micros(self.elapsed),
+            self.direction.as_str(),
+            self.message,
+            self.bytes,
+            self.rects
+                .map_or_else(|| "null".to_string(), |r| r.to_string()),
+            micros(self.duration),
+        );
+        json
+    }
+}
+
+type TraceSink = Box<dyn FnMut(&TraceRecord) + Send>;
+
+/// Number the messages and pass them to the sink
+///
+/// Shared by the reader & the writer of the engine
+///
+pub(super) struct Tracer {
+    sink: TraceSink,
+    seq: u64,
+    start: Option<Instant>,
+    // bytes read from the server so far, counted by [Counted]
+    read: Arc<AtomicU64>,
+    traced: u64,
+}
+
+pub(super) type SharedTracer = Arc<Mutex<Tracer>>;
+
+impl Tracer {
+    pub(super) fn new<F>(sink: F) -> Self
+    where
+        F: FnMut(&TraceRecord) + Send + 'static,
+    {
+        Self {
+            sink: Box::new(sink),
+            seq: 0,
+            start: None,
+            read: Arc::default(),
+            traced: 0,
+        }
+    }
+
+    /// A sink writing JSON lines to `writer`, which is given up upon the first io error
+    pub(super) fn json_lines<W>(mut writer: W) -> Self
+    where
+        W: io::Write + Send + 'static,
+    {
+        let mut failed = false;
+        Self::new(move |record| {
+            if failed {
+                return;
+            }
+            if let Err(e) = writeln!(writer, "{}", record.to_json()).and_then(|_| writer.flush()) {
+                warn!("The trace is stopped: {}", e);
+                failed = true;
+            }
+        })
+    }
+
+    /// Trace a message from the server, of all the bytes read since the previous one
+    pub(super) fn server(
+        &mut self,
+        message: &'static str,
+        rects: Option<u16>,
+        received: Option<Instant>,
+    ) {
+        let read = self.read.load(Ordering::Relaxed);
+        let bytes = read - self.traced;
+        self.traced = read;
+        let duration = received.zip(report::now()).map(|(r, now)| now - r);
+        self.record(TraceDirection::Server, message, bytes, rects, duration);
+    }
+
+    /// Trace a message written to the server
+    pub(super) fn client(&mut self, message: &'static str, bytes: usize) {
+        self.record(TraceDirection::Client, message, bytes as u64, None, None);
+    }
+
+    fn record(
+        &mut self,
+        direction: TraceDirection,
+        message: &'static str,
+        bytes: u64,
+        rects: Option<u16>,
+        duration: Option<Duration>,
+    ) {
+        let record = TraceRecord {
+            seq: self.seq,
+            elapsed: self.start.zip(report::now()).map(|(s, now)| now - s),
+            direction,
+            message,
+            bytes,
+            rects,
+            duration,
+        };
+        self.seq += 1;
+        (self.sink)(&record);
+    }
+}
+
+/// The reader of the session, counting the bytes taken out of it
+pub(super) struct Counted<R> {
+    reader: R,
+    read: Arc<AtomicU64>,
+}
+
+impl<R> Counted<R> {
+    /// Count for the `tracer` if any, whose clock is started as well
+    pub(super) fn new(reader: R, tracer: Option<&SharedTracer>) -> Self {
+        let read = match tracer {
+            Some(tracer) => {
+                let mut tracer = tracer.lock().unwrap();
+                tracer.start = report::now();
+                tracer.read.clone()
+            }
+            None => Arc::default(),
+        };
+        Self { reader, read }
+    }
+}
+
+impl<R> AsyncRead for Counted<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.reader).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        self.read.fetch_add(read as u64, Ordering::Relaxed);
+        poll
+    }
+}
+
+impl<R> AsyncBufRead for Counted<R>
+where
+    R: AsyncBufRead + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().reader).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.read.fetch_add(amt as u64, Ordering::Relaxed);
+        Pin::new(&mut self.reader).consume(amt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+    #[tokio::test]
+    async fn test_tracer() {
+        let records = Arc::new(Mutex::new(Vec::new()));
+        let sink = records.clone();
+        let shared = Arc::new(Mutex::new(Tracer::new(move |record: &TraceRecord| {
+            sink.lock().unwrap().push(record.clone())
+        })));
+        let mut reader = Counted::new(
+            BufReader::new(&[2_u8, 3, 0, 0, 0, 0, 0, 1, 0xff][..]),
+            Some(&shared),
+        );
+
+        reader.read_u8().await.unwrap();
+        shared.lock().unwrap().server("Bell", None, None);
+        reader.fill_buf().await.unwrap();
+        reader.consume(1);
+        let mut text = [0; 7];
+        reader.read_exact(&mut text).await.unwrap();
+        let mut tracer = shared.lock().unwrap();
+        tracer.client("PointerEvent", 6);
+        tracer.server("ServerCutText", None, report::now());
+
+        let records = records.lock().unwrap();
+        assert_eq!(
+            records
+                .iter()
+                .map(|r| (r.seq, r.direction, r.message, r.bytes))
+                .collect::<Vec<_>>(),
+            [
+                (0, TraceDirection::Server, "Bell", 1),
+                (1, TraceDirection::Client, "PointerEvent", 6),
+                (2, TraceDirection::Server, "ServerCutText", 8),
+            ]
+        );
+        assert!(records[2].duration.is_some());
+        assert!(records[2].elapsed >= records[0].elapsed);
+    }
+
+    #[test]
+    fn test_json() {
+        let record = TraceRecord {
+            seq: 3,
+            elapsed: Some(Duration::from_micros(1520)),
+            direction: TraceDirection::Server,
+            message: "FramebufferUpdate",
+            bytes: 4120,
+            rects: Some(2),
+            duration: Some(Duration::from_micros(310)),
+        };
+        assert_eq!(
+            record.to_json(),
+            r#"{"v":1,"seq":3,"t_us":1520,"dir":"server","msg":"FramebufferUpdate","bytes":4120,"rects":2,"dur_us":310}"#
+        );
+        let record = TraceRecord {
+            elapsed: None,
+            direction: TraceDirection::Client,
+            message: "KeyEvent",
+            bytes: 8,
+            rects: None,
+            duration: None,
+            ..record
+        };
+        assert_eq!(
+            record.to_json(),
+            r#"{"v":1,"seq":3,"t_us":null,"dir":"client","msg":"KeyEvent","bytes":8,"rects":null,"dur_us":null}"#
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{Rect, Transform, VncEvent};
+
+impl Transform {
+    /// Whether the width and the height are swapped
+    fn swaps_axes(&self) -> bool {
+        matches!(self, Transform::Rotate90 | Transform::Rotate270)
+    }
+
+    /// Map the point `(x, y)` of an area sized `(width, height)` to the local display
+    pub(super) fn point(&self, (x, y): (u16, u16), (width, height): (u16, u16)) -> (u16, u16) {
+        let right = |x: u16| width.saturating_sub(1).saturating_sub(x);
+        let bottom = |y: u16| height.saturating_sub(1).saturating_sub(y);
+        match self {
+            Transform::Identity => (x, y),
+            Transform::Rotate90 => (bottom(y), x),
+            Transform::Rotate180 => (right(x), bottom(y)),
+            Transform::Rotate270 => (y, right(x)),
+            Transform::FlipHorizontal => (right(x), y),
+            Transform::FlipVertical => (x, bottom(y)),
+        }
+    }
+
+    /// Map the local point `(x, y)` back to an area sized `(width, height)`
+    pub(super) fn invert_point(
+        &self,
+        (x, y): (u16, u16),
+        (width, height): (u16, u16),
+    ) -> (u16, u16) {
+        let right = |x: u16| width.saturating_sub(1).saturating_sub(x);
+        let bottom = |y: u16| height.saturating_sub(1).saturating_sub(y);
+        match self {
+            Transform::Rotate90 => (y, bottom(x)),
+            Transform::Rotate270 => (right(y), x),
+            // the others are their own inverse
+            _ => self.point((x, y), (width, height)),
+        }
+    }
+
+    /// The size of an area sized `(width, height)` on the local display
+    pub(super) fn size(&self, (width, height): (u16, u16)) -> (u16, u16) {
+        if self.swaps_axes() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// Map a rect of the remote `screen` to the local display
+    pub(super) fn rect(&self, rect: &Rect, screen: (u16, u16)) -> Rect {
+        if rect.width == 0 || rect.height == 0 {
+            return *rect;
+        }
+        // the image of the opposite corners
+        let (x0, y0) = self.point((rect.x, rect.y), screen);
+        let (x1, y1) = self.point((rect.x + rect.width - 1, rect.y + rect.height - 1), screen);
+        let (width, height) = self.size((rect.width, rect.height));
+        Rect {
+            x: x0.min(x1),
+            y: y0.min(y1),
+            width,
+            height,
+        }
+    }
+
+    /// Rearrange the pixels of an image sized `(width, height)`
+    pub(super) fn image(&self, pixels: &[u8], (width, height): (u16, u16)) -> Vec<u8> {
+        let pixel_count = width as usize * height as usize;
+        if *self == Transform::Identity
+            || pixel_count == 0
+            || pixels.is_empty()
+            || !pixels.len().is_multiple_of(pixel_count)
+        {
+            return pixels.to_vec();
+        }
+        let bpp = pixels.len() / pixel_count;
+        let (local_width, _) = self.size((width, height));
+        let mut image = vec![0; pixels.len()];
+        for (i, pixel) in pixels.chunks_exact(bpp).enumerate() {
+            let x = (i % width as usize) as u16;
+            let y = (i / width as usize) as u16;
+            let (x, y) = self.point((x, y), (width, height));
+            let start = (y as usize * local_width as usize + x as usize) * bpp;
+            image[start..start + bpp].copy_from_slice(pixel);
+        }
+        image
+    }
+
+    /// Rearrange a cursor image, the position of the rect is the hotspot inside of it
+    fn cursor(&self, rect: &Rect, pixels: &[u8]) -> (Rect, Vec<u8>) {
+        let size = (rect.width, rect.height);
+        let (x, y) = self.point((rect.x, rect.y), size);
+        let (width, height) = self.size(size);
+        (
+            Rect {
+                x,
+                y,
+                width,
+                height,
+            },
+            self.image(pixels, size),
+        )
+    }
+
+    /// Map an event generated for the remote `screen` to the local display
+    ///
+    /// `screen` follows the resolution changes
+    ///
+    /// The jpeg & png images cannot be transformed without decoding, they are left as is
+    pub(super) fn event(&self, event: VncEvent, screen: &mut (u16, u16)) -> VncEvent {
+        if *self == Transform::Identity {
+            if let VncEvent::SetResolution(resolution) = &event {
+                *screen = (resolution.width, resolution.height);
+            }
+            return event;
+        }
+        match event {
+            VncEvent::SetResolution(resolution) => {
+                *screen = (resolution.width, resolution.height);
+                VncEvent::SetResolution(self.size(*screen).into())
+            }
+            VncEvent::RawImage(rect, pixels) => VncEvent::RawImage(
+                self.rect(&rect, *screen),
+                self.image(&pixels, (rect.width, rect.height)),
+            ),
+            VncEvent::Copy(dst, src) => {
+                VncEvent::Copy(self.rect(&dst, *screen), self.rect(&src, *screen))
+            }
+            VncEvent::CopyBatch(copies) => VncEvent::CopyBatch(
+                copies
+                    .iter()
+                    .map(|(dst, src)| (self.rect(dst, *screen), self.rect(src, *screen)))
+                    .collect(),
+            ),
+            VncEvent::SetCursor(rect, pixels) => {
+                let (rect, pixels) = self.cursor(&rect, &pixels);
+                VncEvent::SetCursor(rect, pixels)
+            }
+            VncEvent::SetAlphaCursor(rect, pixels) => {
+                let (rect, pixels) = self.cursor(&rect, &pixels);
+                VncEvent::SetAlphaCursor(rect, pixels)
+            }
+            VncEvent::PointerPosition(x, y) => {
+                let (x, y) = self.point((x, y), *screen);
+                VncEvent::PointerPosition(x, y)
+            }
+            VncEvent::CursorPosition(x, y) => {
+                let (x, y) = self.point((x, y), *screen);
+                VncEvent::CursorPosition(x, y)
+            }
+            event => event,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let screen = (4, 3);
+        let rect = Rect {
+            x: 1,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let transform = Transform::Rotate90;
+        assert_eq!(
+            transform.rect(&rect, screen),
+            Rect {
+                x: 2,
+                y: 1,
+                width: 1,
+                height: 2,
+            }
+        );
+        // [1, 2] becomes a column
+        assert_eq!(transform.image(&[1, 2], (2, 1)), [1, 2]);
+        // [1, 2]    [3, 1]
+        // [3, 4] => [4, 2]
+        assert_eq!(transform.image(&[1, 2, 3, 4], (2, 2)), [3, 1, 4, 2]);
+
+        for transform in [
+            Transform::Rotate90,
+            Transform::Rotate180,
+            Transform::Rotate270,
+            Transform::FlipHorizontal,
+            Transform::FlipVertical,
+        ] {
+            let local = transform.point((3, 1), screen);
+            assert_eq!(transform.invert_point(local, screen), (3, 1));
+        }
+        assert_eq!(Transform::Rotate270.point((3, 1), screen), (1, 0));
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::Sleep,
+};
+
+use crate::{DisconnectReason, VncError};
+
+pub(super) type SharedReason = Arc<Mutex<Option<DisconnectReason>>>;
+
+/// The stream of the session, which records why it is closed
+///
+/// The first end of file or io error is kept, the results are passed through as is
+///
+pub(super) struct Transport<S> {
+    stream: S,
+    reason: SharedReason,
+}
+
+impl<S> Transport<S> {
+    pub(super) fn new(stream: S) -> (Self, SharedReason) {
+        let reason = SharedReason::default();
+        (
+            Self {
+                stream,
+                reason: reason.clone(),
+            },
+            reason,
+        )
+    }
+
+    fn record<T>(&self, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if let Poll::Ready(Err(ref e)) = poll {
+            self.reason.lock().unwrap().get_or_insert_with(|| e.into());
+        }
+        poll
+    }
+}
+
+impl<S> AsyncRead for Transport<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(())))
+            && buf.filled().len() == filled
+            && buf.remaining() > 0
+        {
+            self.reason
+                .lock()
+                .unwrap()
+                .get_or_insert(DisconnectReason::Closed);
+        }
+        self.record(poll)
+    }
+}
+
+impl<S> AsyncWrite for Transport<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.stream).poll_write(cx, buf);
+        self.record(poll)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_flush(cx);
+        self.record(poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut self.stream).poll_shutdown(cx);
+        self.record(poll)
+    }
+}
+
+/// The reader of the session, failing with [VncError::Timeout] if the server stalls while armed
+///
+/// The timer restarts whenever some bytes arrive, which also arm it again,
+///
+/// so that a message is bounded from its first byte even if the engine disarmed it while idle
+///
+pub(super) struct Timed<R> {
+    reader: R,
+    timeout: Option<Duration>,
+    armed: Arc<AtomicBool>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> Timed<R> {
+    /// Armed until told otherwise through [Timed::armed]
+    pub(super) fn new(reader: R, timeout: Option<Duration>) -> Self {
+        Self {
+            reader,
+            timeout,
+            armed: Arc::new(AtomicBool::new(true)),
+            sleep: None,
+        }
+    }
+
+    /// The switch of the timeout, e.g. off while waiting for the next message from the server
+    pub(super) fn armed(&self) -> Arc<AtomicBool> {
+        self.armed.clone()
+    }
+}
+
+impl<R> AsyncRead for Timed<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.reader).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.armed.store(true, Ordering::Relaxed);
+        }
+        match (&poll, self.timeout) {
+            (Poll::Pending, Some(timeout)) if self.armed.load(Ordering::Relaxed) => {
+                let sleep = self
+                    .sleep
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                if sleep.as_mut().poll(cx).is_ready() {
+                    self.sleep = None;
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        VncError::Timeout,
+                    )));
+                }
+            }
+            _ => self.sleep = None,
+        }
+        poll
+    }
+}
+
+/// Attach the reason to the error with [VncError::Disconnected], if the transport is closed
+///
+/// or [VncError::Timeout] if the server stalled
+///
+pub(super) fn with_reason(e: anyhow::Error, reason: &SharedReason) -> anyhow::Error {
+    let timed_out = e
+        .downcast_ref::<io::Error>()
+        .and_then(|e| e.get_ref())
+        .and_then(|e| e.downcast_ref::<VncError>())
+        .is_some_and(|e| matches!(e, VncError::Timeout));
+    if timed_out {
+        return e.context(VncError::Timeout);
+    }
+    match reason.lock().unwrap().take() {
+        Some(reason) => e.context(VncError::Disconnected(reason)),
+        None => e,
+    }
+}
+
+/// Blame the other clients for the stream closed at the initialization
+pub(super) fn displaced(reason: &SharedReason) {
+    let mut reason = reason.lock().unwrap();
+    if matches!(
+        *reason,
+        Some(
+            DisconnectReason::Closed
+                | DisconnectReason::Io(io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe)
+        )
+    ) {
+        *reason = Some(DisconnectReason::DisplacedByOtherClient);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    /// A transport failing with a close frame
+    struct Aborted;
+
+    impl AsyncRead for Aborted {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "close 4001: token expired",
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_reason() {
+        let (mut transport, reason) = Transport::new(Aborted);
+        let e: anyhow::Error = transport.read_u8().await.unwrap_err().into();
+        let e = with_reason(e, &reason);
+        assert!(matches!(
+            e.downcast_ref::<VncError>(),
+            Some(VncError::Disconnected(DisconnectReason::Transport(detail)))
+                if detail == "close 4001: token expired"
+        ));
+        assert!(e.downcast_ref::<io::Error>().is_some());
+
+        let (client, server) = duplex(16);
+        let (mut transport, reason) = Transport::new(client);
+        drop(server);
+        assert!(transport.read_u8().await.is_err());
+        assert_eq!(*reason.lock().unwrap(), Some(DisconnectReason::Closed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_timed() {
+        let (client, mut server) = duplex(16);
+        let mut reader = Timed::new(client, Some(Duration::from_millis(50)));
+        let armed = reader.armed();
+
+        // the stall is fine while disarmed, until some bytes arrive
+        armed.store(false, Ordering::Relaxed);
+        let read = tokio::spawn(async move {
+            let byte = reader.read_u8().await.unwrap();
+            assert!(armed.load(Ordering::Relaxed));
+            (byte, reader.read_u8().await)
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        server.write_all(&[1]).await.unwrap();
+        let (byte, result) = read.await.unwrap();
+        assert_eq!(byte, 1);
+        let e = with_reason(result.unwrap_err().into(), &SharedReason::default());
+        assert!(matches!(e.downcast_ref(), Some(VncError::Timeout)));
+    }
+}
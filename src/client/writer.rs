@@ -0,0 +1,239 @@
+use anyhow::Result;
+use std::{
+    collections::VecDeque,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::Notify,
+};
+use tracing::trace;
+
+#[cfg(feature = "trace")]
+use super::trace::SharedTracer;
+use super::{protocol_log::ProtocolLog, report::DebugReport};
+use crate::{proto::messages::ClientMsg, PointerPolicy, ProtocolLogging, VncError};
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<ClientMsg>,
+    // no more messages will be queued
+    closed: bool,
+    // the write half is to be shut down once the messages are written
+    shutdown: bool,
+    // the write half has been shut down
+    shut_down: bool,
+    // the writer stopped with an error
+    failed: bool,
+    // how many messages have been queued & written so far
+    pushed: u64,
+    written: u64,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    // a message is queued or the queue is closed
+    queued: Notify,
+    // a message is written or the writer failed
+    written: Notify,
+}
+
+/// The sending side of the outgoing queue
+///
+/// The messages are written by [writer] in order
+///
+pub(super) struct Outgoing {
+    shared: Arc<Shared>,
+    capacity: usize,
+    pointer_policy: PointerPolicy,
+    report: Arc<Mutex<DebugReport>>,
+}
+
+impl Outgoing {
+    /// Queue a message, waiting for room if the queue is full
+    ///
+    /// A pointer event may replace the latest queued one according to the [PointerPolicy]
+    ///
+    pub(super) async fn send(&self, msg: ClientMsg) -> Result<()> {
+        let mut msg = Some(msg);
+        loop {
+            let written = self.shared.written.notified();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if queue.failed {
+                    return Err(VncError::ClientNotRunning.into());
+                }
+                if let (
+                    PointerPolicy::DropStale,
+                    Some(ClientMsg::PointerEvent(_, _, mask)),
+                    Some(ClientMsg::PointerEvent(_, _, queued_mask)),
+                ) = (self.pointer_policy, &msg, queue.messages.back())
+                {
+                    // the socket is congested since a message is still pending
+                    // so the pending motion is replaced by the newer one
+                    if mask == queued_mask {
+                        *queue.messages.back_mut().unwrap() = msg.take().unwrap();
+                        self.report.lock().unwrap().dropped_pointer_events += 1;
+                        return Ok(());
+                    }
+                }
+                if queue.messages.len() < self.capacity {
+                    queue.messages.push_back(msg.take().unwrap());
+                    queue.pushed += 1;
+                    let mut report = self.report.lock().unwrap();
+                    report.queued_messages = queue.messages.len();
+                    report.max_queued_messages =
+                        report.max_queued_messages.max(queue.messages.len());
+                    drop(report);
+                    drop(queue);
+                    self.shared.queued.notify_one();
+                    return Ok(());
+                }
+            }
+            trace!("Outgoing queue is full");
+            written.await;
+        }
+    }
+}
+
+impl Outgoing {
+    /// The waiter of all the messages queued so far
+    pub(super) fn flushed(&self) -> Flushed {
+        Flushed {
+            shared: self.shared.clone(),
+            target: self.shared.queue.lock().unwrap().pushed,
+            shutdown: false,
+        }
+    }
+
+    /// Shut down the write half once the messages queued so far are written and the [Outgoing] is dropped
+    ///
+    /// Returns the waiter of the shutdown
+    pub(super) fn shutdown(&self) -> Flushed {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        Flushed {
+            shutdown: true,
+            ..self.flushed()
+        }
+    }
+}
+
+/// Returned by [Outgoing::flushed], which can be waited without the [Outgoing]
+pub(super) struct Flushed {
+    shared: Arc<Shared>,
+    target: u64,
+    // the write half is shut down as well
+    shutdown: bool,
+}
+
+impl Flushed {
+    /// Wait until the messages are written and flushed to the stream
+    pub(super) async fn wait(self) -> Result<()> {
+        loop {
+            let written = self.shared.written.notified();
+            {
+                let queue = self.shared.queue.lock().unwrap();
+                if queue.written >= self.target && (!self.shutdown || queue.shut_down) {
+                    return Ok(());
+                }
+                if queue.failed {
+                    return Err(VncError::ClientNotRunning.into());
+                }
+            }
+            written.await;
+        }
+    }
+}
+
+/// Fails the waiters of [Flushed] once the writer is gone, e.g. stopped since the reader has failed
+struct WriterGuard(Arc<Shared>);
+
+impl Drop for WriterGuard {
+    fn drop(&mut self) {
+        if let Ok(mut queue) = self.0.queue.lock() {
+            queue.failed = true;
+        }
+        self.0.written.notify_waiters();
+    }
+}
+
+impl Drop for Outgoing {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.queued.notify_one();
+    }
+}
+
+/// Create the outgoing queue of at most `capacity` messages and the future which writes them to `writer`
+///
+/// The future completes once the [Outgoing] is dropped and all the queued messages are written
+///
+pub(super) fn writer<W>(
+    mut writer: W,
+    capacity: usize,
+    pointer_policy: PointerPolicy,
+    report: Arc<Mutex<DebugReport>>,
+    protocol_log: Arc<ProtocolLog>,
+    #[cfg(feature = "trace")] tracer: Option<SharedTracer>,
+) -> (Outgoing, impl Future<Output = Result<()>>)
+where
+    W: AsyncWrite + Unpin,
+{
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue::default()),
+        queued: Notify::new(),
+        written: Notify::new(),
+    });
+    let outgoing = Outgoing {
+        shared: shared.clone(),
+        capacity: capacity.max(1),
+        pointer_policy,
+        report: report.clone(),
+    };
+    let write = async move {
+        let _guard = WriterGuard(shared.clone());
+        loop {
+            let queued = shared.queued.notified();
+            let msg = {
+                let mut queue = shared.queue.lock().unwrap();
+                match queue.messages.pop_front() {
+                    Some(msg) => {
+                        report.lock().unwrap().queued_messages = queue.messages.len();
+                        Some(msg)
+                    }
+                    None if queue.closed && queue.shutdown => break,
+                    None if queue.closed => return Ok(()),
+                    None => None,
+                }
+            };
+            let Some(msg) = msg else {
+                queued.await;
+                continue;
+            };
+            let bytes = msg.to_bytes();
+            let mut result = writer.write_all(&bytes).await;
+            // for the buffered streams, e.g. TLS
+            if result.is_ok() && shared.queue.lock().unwrap().messages.is_empty() {
+                result = writer.flush().await;
+            }
+            // the waiters are failed by the guard
+            result?;
+            protocol_log.log(ProtocolLogging::Messages, || {
+                format!("-> {} ({} bytes)", msg.name(), bytes.len())
+            });
+            #[cfg(feature = "trace")]
+            if let Some(tracer) = &tracer {
+                tracer.lock().unwrap().client(msg.name(), bytes.len());
+            }
+            shared.queue.lock().unwrap().written += 1;
+            shared.written.notify_waiters();
+        }
+        writer.shutdown().await?;
+        trace!("Write half shut down");
+        shared.queue.lock().unwrap().shut_down = true;
+        shared.written.notify_waiters();
+        Ok(())
+    };
+    (outgoing, write)
+}
@@ -1,17 +1,46 @@
-use crate::{PixelFormat, Rect, VncEvent};
+use crate::{PixelFormat, Rect, VncEncoding, VncError, VncEvent};
 use anyhow::Result;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt},
     sync::mpsc::Sender,
 };
+use tracing::error;
 
-use super::uninit_vec;
+use super::{convert_byte_order, expand, uninit_vec, ImageOptions};
 
-pub struct Decoder {}
+/// Scale the channel at `shift` of a pixel to 8 bits
+fn channel(value: u32, max: u16, shift: u8) -> u8 {
+    if max == 0 {
+        return 0;
+    }
+    ((value >> shift & max as u32) * 255 / max as u32) as u8
+}
+
+pub struct Decoder {
+    options: ImageOptions,
+    // the RGBA shapes are kept for the cursor layer
+    keep_shapes: bool,
+    shape: Option<(Rect, Vec<u8>)>,
+}
 
 impl Decoder {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(options: ImageOptions) -> Self {
+        Self {
+            options,
+            keep_shapes: false,
+            shape: None,
+        }
+    }
+
+    /// Keep the latest cursor as straight RGBA, see [Decoder::take_shape]
+    pub fn keep_shapes(mut self, keep: bool) -> Self {
+        self.keep_shapes = keep;
+        self
+    }
+
+    /// The hotspot & the RGBA pixels of the cursor decoded last, if kept
+    pub fn take_shape(&mut self) -> Option<(Rect, Vec<u8>)> {
+        self.shape.take()
     }
 
     pub async fn decode<S>(
@@ -22,7 +51,7 @@ impl Decoder {
         output: &Sender<VncEvent>,
     ) -> Result<()>
     where
-        S: AsyncRead + AsyncWrite + Unpin,
+        S: AsyncRead + Unpin,
     {
         let _hotx = rect.x;
         let _hoty = rect.y;
@@ -30,7 +59,7 @@ impl Decoder {
         let h = rect.height;
 
         let pixels_length = w as usize * h as usize * format.bits_per_pixel as usize / 8;
-        let mask_length = (w as usize + 7) / 8 * h as usize;
+        let mask_length = (w as usize).div_ceil(8) * h as usize;
 
         let _bytes = pixels_length + mask_length;
 
@@ -38,44 +67,240 @@ impl Decoder {
         input.read_exact(&mut pixels).await?;
         let mut mask = uninit_vec(mask_length);
         input.read_exact(&mut mask).await?;
-        let mut image = uninit_vec(pixels_length);
-        let mut pix_idx = 0;
-
-        let pixel_mask = (format.red_max as u32) << format.red_shift
-            | (format.green_max as u32) << format.green_shift
-            | (format.blue_max as u32) << format.blue_shift;
-
-        let mut alpha_idx = match pixel_mask {
-            0xff_ff_ff_00 => 3,
-            0xff_ff_00_ff => 2,
-            0xff_00_ff_ff => 1,
-            0x00_ff_ff_ff => 0,
-            _ => unreachable!(),
-        };
-        if format.big_endian_flag == 0 {
-            alpha_idx = 3 - alpha_idx;
+        let mut image = expand(pixels, format);
+        let format = &format.output();
+        if format.bits_per_pixel != 32 {
+            error!(
+                "Cursor received with {} bits per pixel",
+                format.bits_per_pixel
+            );
+            return Err(VncError::InvalidImageData.into());
         }
+
+        // the bits uncovered by the channels carry the alpha
+        let pixel_mask = format.pixel_mask();
+        let alpha_mask = !pixel_mask;
+        let mut rgba = Vec::with_capacity(if self.keep_shapes { image.len() } else { 0 });
+        let mut pixels = image.chunks_exact_mut(4);
         for y in 0..h as usize {
             for x in 0..w as usize {
-                let mask_idx = y * ((w as usize + 7) / 8) + (x / 8);
+                let mask_idx = y * (w as usize).div_ceil(8) + (x / 8);
                 let alpha = if (mask[mask_idx] << (x % 8)) & 0x80 > 0 {
-                    255
+                    alpha_mask
                 } else {
                     0
                 };
-                image[pix_idx] = pixels[pix_idx];
-                image[pix_idx + 1] = pixels[pix_idx + 1];
-                image[pix_idx + 2] = pixels[pix_idx + 2];
-                image[pix_idx + 3] = pixels[pix_idx + 3];
+                let pixel = pixels.next().unwrap();
+                let bytes = [pixel[0], pixel[1], pixel[2], pixel[3]];
 
                 // use alpha from the bitmask to cover it.
-                image[pix_idx + alpha_idx] = alpha;
-                pix_idx += 4;
+                let value = if format.big_endian_flag > 0 {
+                    let value = u32::from_be_bytes(bytes) & pixel_mask;
+                    pixel.copy_from_slice(&(value | alpha).to_be_bytes());
+                    value
+                } else {
+                    let value = u32::from_le_bytes(bytes) & pixel_mask;
+                    pixel.copy_from_slice(&(value | alpha).to_le_bytes());
+                    value
+                };
+                if self.keep_shapes {
+                    rgba.extend([
+                        channel(value, format.red_max, format.red_shift),
+                        channel(value, format.green_max, format.green_shift),
+                        channel(value, format.blue_max, format.blue_shift),
+                        if alpha > 0 { 255 } else { 0 },
+                    ]);
+                }
             }
         }
+        if self.keep_shapes {
+            self.shape = Some((*rect, rgba));
+        }
 
+        convert_byte_order(&mut image, format, self.options.byte_order);
         output.send(VncEvent::SetCursor(*rect, image)).await?;
 
         Ok(())
     }
+
+    /// Decode a CursorWithAlpha rect into straight RGBA pixels
+    ///
+    /// The pixels are sent as pre-multiplied RGBA, with a nested encoding of which only Raw is supported
+    ///
+    pub async fn decode_alpha<S>(
+        &mut self,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let encoding = input.read_i32().await?;
+        if encoding != VncEncoding::Raw as i32 {
+            error!("Unsupported encoding {} of the alpha cursor", encoding);
+            return Err(VncError::InvalidImageData.into());
+        }
+        let mut image = uninit_vec(rect.width as usize * rect.height as usize * 4);
+        input.read_exact(&mut image).await?;
+        for pixel in image.chunks_exact_mut(4) {
+            let alpha = pixel[3] as u16;
+            if alpha > 0 && alpha < 255 {
+                for channel in &mut pixel[..3] {
+                    *channel = (*channel as u16 * 255 / alpha).min(255) as u8;
+                }
+            }
+        }
+        if self.keep_shapes {
+            self.shape = Some((*rect, image.clone()));
+        }
+        output.send(VncEvent::SetAlphaCursor(*rect, image)).await?;
+        Ok(())
+    }
+
+    /// Decode a XCursor rect into straight RGBA pixels
+    ///
+    /// The foreground & background colors are followed by the bitmap choosing between them and the mask
+    ///
+    pub async fn decode_x<S>(
+        &mut self,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let (w, h) = (rect.width as usize, rect.height as usize);
+        // an empty cursor carries no data
+        if w == 0 || h == 0 {
+            if self.keep_shapes {
+                self.shape = Some((*rect, Vec::new()));
+            }
+            output
+                .send(VncEvent::SetAlphaCursor(*rect, Vec::new()))
+                .await?;
+            return Ok(());
+        }
+        let mut colors = [0; 6];
+        input.read_exact(&mut colors).await?;
+        let row_len = w.div_ceil(8);
+        let mut bitmap = uninit_vec(row_len * h);
+        input.read_exact(&mut bitmap).await?;
+        let mut mask = uninit_vec(row_len * h);
+        input.read_exact(&mut mask).await?;
+
+        let mut image = Vec::with_capacity(w * h * 4);
+        for y in 0..h {
+            for x in 0..w {
+                let idx = y * row_len + x / 8;
+                let bit = 0x80 >> (x % 8);
+                let color = if bitmap[idx] & bit > 0 {
+                    &colors[..3]
+                } else {
+                    &colors[3..]
+                };
+                image.extend_from_slice(color);
+                image.push(if mask[idx] & bit > 0 { 255 } else { 0 });
+            }
+        }
+        if self.keep_shapes {
+            self.shape = Some((*rect, image.clone()));
+        }
+        output.send(VncEvent::SetAlphaCursor(*rect, image)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_decode_big_endian() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let mut format = PixelFormat::rgba();
+        format.big_endian_flag = 1;
+        // an opaque red, a masked out blue with garbage in the unused byte
+        let data = [0, 0, 0, 255, 0x12, 255, 0, 0, 0b1000_0000];
+        let mut decoder = Decoder::new(ImageOptions::default());
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::SetCursor(_, pixels)) = rx.recv().await else {
+            panic!("No cursor");
+        };
+        assert_eq!(pixels, [255, 0, 0, 255, 0, 255, 0, 0]);
+        assert!(decoder.take_shape().is_none());
+
+        // straight RGBA regardless of the pixel format
+        let mut decoder = Decoder::new(ImageOptions::default()).keep_shapes(true);
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let (hotspot, rgba) = decoder.take_shape().unwrap();
+        assert_eq!(hotspot, rect);
+        assert_eq!(rgba, [255, 0, 0, 255, 0, 0, 255, 0]);
+        assert!(decoder.take_shape().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_decode_alpha() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let rect = Rect {
+            x: 1,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let mut data = (VncEncoding::Raw as i32).to_be_bytes().to_vec();
+        // an opaque red and a half transparent white
+        data.extend_from_slice(&[255, 0, 0, 255, 128, 128, 128, 128]);
+        let mut decoder = Decoder::new(ImageOptions::default());
+        decoder
+            .decode_alpha(&rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::SetAlphaCursor(hotspot, pixels)) = rx.recv().await else {
+            panic!("No cursor");
+        };
+        assert_eq!((hotspot.x, hotspot.y), (1, 0));
+        assert_eq!(pixels, [255, 0, 0, 255, 255, 255, 255, 128]);
+
+        let data = (VncEncoding::Zrle as i32).to_be_bytes();
+        assert!(decoder
+            .decode_alpha(&rect, &mut data.as_slice(), &tx)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decode_x() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 3,
+            height: 1,
+        };
+        // white foreground, black background, the last pixel is masked out
+        let data = [255, 255, 255, 0, 0, 0, 0b1000_0000, 0b1100_0000];
+        let mut decoder = Decoder::new(ImageOptions::default());
+        decoder
+            .decode_x(&rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::SetAlphaCursor(_, pixels)) = rx.recv().await else {
+            panic!("No cursor");
+        };
+        assert_eq!(pixels, [255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 0]);
+    }
 }
@@ -0,0 +1,137 @@
+//! LZO1X decompression as used by the UltraVNC Ultra encoding
+//!
+//! A bounds checked port of the reference `lzo1x_decompress_safe`
+
+/// The distance limit of the matches following a long literal run
+const M2_MAX_OFFSET: usize = 0x0800;
+
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Input<'_> {
+    fn byte(&mut self) -> Option<usize> {
+        let byte = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(byte as usize)
+    }
+
+    fn le16(&mut self) -> Option<usize> {
+        Some(self.byte()? | self.byte()? << 8)
+    }
+
+    /// A length encoded as `base` plus a run of zeros each adding 255
+    fn length(&mut self, base: usize) -> Option<usize> {
+        let mut length = 0;
+        loop {
+            match self.byte()? {
+                0 => length += 255,
+                byte => return Some(length + base + byte),
+            }
+        }
+    }
+}
+
+fn copy_literals(input: &mut Input, output: &mut Vec<u8>, count: usize, max: usize) -> Option<()> {
+    let literals = input.data.get(input.pos..input.pos + count)?;
+    if output.len() + count > max {
+        return None;
+    }
+    output.extend_from_slice(literals);
+    input.pos += count;
+    Some(())
+}
+
+fn copy_match(output: &mut Vec<u8>, distance: usize, count: usize, max: usize) -> Option<()> {
+    if distance == 0 || distance > output.len() || output.len() + count > max {
+        return None;
+    }
+    // the source may overlap with the bytes being written
+    let start = output.len() - distance;
+    for i in 0..count {
+        output.push(output[start + i]);
+    }
+    Some(())
+}
+
+/// Decompress `data` into at most `max` bytes
+///
+/// Returns `None` if the data is malformed or the output exceeds `max`
+///
+pub(super) fn decompress(data: &[u8], max: usize) -> Option<Vec<u8>> {
+    let mut input = Input { data, pos: 0 };
+    let mut output = Vec::with_capacity(max);
+    // 0..=3 for the literals copied after the last match, 4 after a literal run
+    let mut state;
+
+    if *data.first()? > 17 {
+        let count = input.byte()? - 17;
+        copy_literals(&mut input, &mut output, count, max)?;
+        state = if count < 4 { count } else { 4 };
+    } else {
+        state = 0;
+    }
+
+    loop {
+        let t = input.byte()?;
+        let (distance, count, next) = if t < 16 {
+            if state == 0 {
+                // a literal run
+                let count = if t == 0 { input.length(15)? } else { t } + 3;
+                copy_literals(&mut input, &mut output, count, max)?;
+                state = 4;
+                continue;
+            }
+            let distance = 1 + (t >> 2) + (input.byte()? << 2);
+            if state == 4 {
+                (distance + M2_MAX_OFFSET, 3, t & 3)
+            } else {
+                (distance, 2, t & 3)
+            }
+        } else if t >= 64 {
+            let distance = 1 + ((t >> 2) & 7) + (input.byte()? << 3);
+            (distance, (t >> 5) + 1, t & 3)
+        } else if t >= 32 {
+            let count = match t & 31 {
+                0 => input.length(31)?,
+                count => count,
+            } + 2;
+            let next = input.le16()?;
+            (1 + (next >> 2), count, next & 3)
+        } else {
+            let count = match t & 7 {
+                0 => input.length(7)?,
+                count => count,
+            } + 2;
+            let next = input.le16()?;
+            let distance = ((t & 8) << 11) + (next >> 2);
+            if distance == 0 {
+                // the end of stream marker
+                return (count == 3 && input.pos == data.len()).then_some(output);
+            }
+            (distance + 0x4000, count, next & 3)
+        };
+        copy_match(&mut output, distance, count, max)?;
+        copy_literals(&mut input, &mut output, next, max)?;
+        state = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        // 4 literals, a match of 4 bytes at distance 4, then the end marker
+        let data = [21, b'a', b'b', b'c', b'd', 0x6c, 0, 17, 0, 0];
+        assert_eq!(decompress(&data, 8).unwrap(), b"abcdabcd");
+        // the output is limited
+        assert!(decompress(&data, 7).is_none());
+        // truncated
+        assert!(decompress(&data[..7], 8).is_none());
+        // a match before the start of the output
+        assert!(decompress(&[21, b'a', b'b', b'c', b'd', 0x6c, 1, 17, 0, 0], 16).is_none());
+    }
+}
@@ -1,16 +1,31 @@
+use crate::{ByteOrder, DecodeStrictness, PixelFormat, Rect, VncError, VncEvent};
+use anyhow::Result;
+use tokio::sync::mpsc::Sender;
+use tracing::{error, trace};
+
 mod cursor;
+#[cfg(feature = "ultra")]
+mod lzo;
 mod raw;
+mod rre;
 mod tight;
 mod trle;
+#[cfg(feature = "ultra")]
+mod ultra;
 mod zlib;
+mod zlib_raw;
 mod zrle;
 pub(crate) use cursor::Decoder as CursorDecoder;
 pub(crate) use raw::Decoder as RawDecoder;
+pub(crate) use rre::Decoder as RreDecoder;
 pub(crate) use tight::Decoder as TightDecoder;
 pub(crate) use trle::Decoder as TrleDecoder;
+#[cfg(feature = "ultra")]
+pub(crate) use ultra::Decoder as UltraDecoder;
+pub(crate) use zlib_raw::Decoder as ZlibDecoder;
 pub(crate) use zrle::Decoder as ZrleDecoder;
 
-pub(self) fn uninit_vec(len: usize) -> Vec<u8> {
+fn uninit_vec(len: usize) -> Vec<u8> {
     let mut v = Vec::with_capacity(len);
     #[allow(clippy::uninit_vec)]
     unsafe {
@@ -18,3 +33,199 @@ pub(self) fn uninit_vec(len: usize) -> Vec<u8> {
     };
     v
 }
+
+/// Resize a scratch buffer to `len` uninitialized bytes, reusing its allocation
+fn reuse_vec(buffer: &mut Vec<u8>, len: usize) {
+    buffer.clear();
+    buffer.reserve(len);
+    #[allow(clippy::uninit_vec)]
+    unsafe {
+        buffer.set_len(len)
+    };
+}
+
+/// Allocate and touch `len` bytes of a scratch buffer before they are needed
+fn preallocate(buffer: &mut Vec<u8>, len: usize) {
+    if buffer.len() < len {
+        buffer.resize(len, 0);
+    }
+}
+
+/// How the decoded images are delivered
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ImageOptions {
+    /// Split the images into bands of at most `max_bytes`
+    pub(crate) max_bytes: Option<usize>,
+    pub(crate) byte_order: ByteOrder,
+    pub(crate) strictness: DecodeStrictness,
+}
+
+/// The color at `index` of a palette of `bpp` bytes colors
+///
+/// An index out of range fails unless the strictness is lenient, which takes the last color instead
+fn palette_color(
+    palette: &[u8],
+    bpp: usize,
+    index: u8,
+    strictness: DecodeStrictness,
+) -> Result<&[u8]> {
+    let start = index as usize * bpp;
+    if let Some(color) = palette.get(start..start + bpp) {
+        return Ok(color);
+    }
+    let last = (palette.len() / bpp).checked_sub(1);
+    match (strictness, last) {
+        (DecodeStrictness::Lenient, Some(last)) => {
+            trace!("Palette index {} clamped to {}", index, last);
+            Ok(&palette[last * bpp..(last + 1) * bpp])
+        }
+        _ => {
+            error!("Palette index {} out of range", index);
+            Err(VncError::InvalidImageData.into())
+        }
+    }
+}
+
+/// Reverse the bytes of every pixel if the byte order differs from the wire
+fn convert_byte_order(pixels: &mut [u8], format: &PixelFormat, byte_order: ByteOrder) {
+    let bpp = format.bits_per_pixel as usize / 8;
+    if bpp > 1 && byte_order.swaps(format) {
+        pixels
+            .chunks_exact_mut(bpp)
+            .for_each(|pixel| pixel.reverse());
+    }
+}
+
+/// Convert the pixels decoded in the wire `format` into its [PixelFormat::output]
+///
+/// The 8 bits true color pixels are expanded to 32 bits, then the byte order is converted
+fn to_output(pixels: Vec<u8>, format: &PixelFormat, byte_order: ByteOrder) -> Vec<u8> {
+    let mut pixels = expand(pixels, format);
+    convert_byte_order(&mut pixels, &format.output(), byte_order);
+    pixels
+}
+
+/// Expand the 8 bits true color pixels of `format` to 32 bits, the others are kept as is
+fn expand(pixels: Vec<u8>, format: &PixelFormat) -> Vec<u8> {
+    match format.expansion() {
+        Some(table) => pixels
+            .iter()
+            .flat_map(|pixel| table[*pixel as usize])
+            .collect(),
+        None => pixels,
+    }
+}
+
+/// How many rows of `row_bytes` fit in a band of `max_bytes`, at least one row
+fn rows_per_band(row_bytes: usize, max_bytes: Option<usize>) -> usize {
+    match max_bytes {
+        Some(max_bytes) if row_bytes > 0 => (max_bytes / row_bytes).max(1),
+        _ => usize::MAX,
+    }
+}
+
+/// Emit a decoded image as [VncEvent::RawImage]
+///
+/// which is converted to the output format and split into horizontal bands according to the `options`
+async fn send_image(
+    output: &Sender<VncEvent>,
+    rect: &Rect,
+    image: Vec<u8>,
+    format: &PixelFormat,
+    options: &ImageOptions,
+) -> Result<()> {
+    let image = to_output(image, format, options.byte_order);
+    let height = rect.height as usize;
+    let row_bytes = image.len().checked_div(height).unwrap_or(0);
+    let rows = rows_per_band(row_bytes, options.max_bytes);
+    if rows >= height {
+        output.send(VncEvent::RawImage(*rect, image)).await?;
+        return Ok(());
+    }
+    for (i, band) in image.chunks(rows * row_bytes).enumerate() {
+        let band_rect = Rect {
+            x: rect.x,
+            y: rect.y + (i * rows) as u16,
+            width: rect.width,
+            height: (band.len() / row_bytes) as u16,
+        };
+        output
+            .send(VncEvent::RawImage(band_rect, band.to_vec()))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_send_image_bands() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+        let rect = Rect {
+            x: 10,
+            y: 20,
+            width: 4,
+            height: 5,
+        };
+        let options = ImageOptions {
+            max_bytes: Some(40),
+            ..Default::default()
+        };
+        // 16 bytes per row, 2 rows per band
+        send_image(&tx, &rect, vec![0; 80], &PixelFormat::bgra(), &options)
+            .await
+            .unwrap();
+        drop(tx);
+        let mut bands = vec![];
+        while let Some(VncEvent::RawImage(rect, image)) = rx.recv().await {
+            bands.push((rect.y, rect.height, image.len()));
+        }
+        assert_eq!(bands, vec![(20, 2, 32), (22, 2, 32), (24, 1, 16)]);
+    }
+
+    #[test]
+    fn test_preallocate() {
+        let mut buffer = Vec::new();
+        preallocate(&mut buffer, 1024);
+        assert_eq!(buffer.len(), 1024);
+        let allocation = buffer.as_ptr();
+
+        // the smaller requests reuse the allocation
+        preallocate(&mut buffer, 16);
+        assert_eq!(buffer.len(), 1024);
+        reuse_vec(&mut buffer, 512);
+        assert_eq!(buffer.len(), 512);
+        reuse_vec(&mut buffer, 1024);
+        assert_eq!(buffer.len(), 1024);
+        assert_eq!(buffer.as_ptr(), allocation);
+    }
+
+    #[test]
+    fn test_convert_byte_order() {
+        let le = PixelFormat::bgra();
+        let mut pixels = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        convert_byte_order(&mut pixels, &le, ByteOrder::LittleEndian);
+        assert_eq!(pixels, [1, 2, 3, 4, 5, 6, 7, 8]);
+        convert_byte_order(&mut pixels, &le, ByteOrder::BigEndian);
+        assert_eq!(pixels, [4, 3, 2, 1, 8, 7, 6, 5]);
+
+        let mut be = le;
+        be.big_endian_flag = 1;
+        convert_byte_order(&mut pixels, &be, ByteOrder::LittleEndian);
+        assert_eq!(pixels, [1, 2, 3, 4, 5, 6, 7, 8]);
+        convert_byte_order(&mut pixels, &be, ByteOrder::Wire);
+        assert_eq!(pixels, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_palette_color() {
+        let palette = [1, 1, 2, 2];
+        let color = |index, strictness| palette_color(&palette, 2, index, strictness);
+        assert_eq!(color(1, DecodeStrictness::Strict).unwrap(), [2, 2]);
+        assert!(color(2, DecodeStrictness::Strict).is_err());
+        assert_eq!(color(127, DecodeStrictness::Lenient).unwrap(), [2, 2]);
+        assert!(palette_color(&[], 2, 0, DecodeStrictness::Lenient).is_err());
+    }
+}
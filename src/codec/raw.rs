@@ -1,17 +1,19 @@
 use crate::{PixelFormat, Rect, VncEvent};
 use anyhow::Result;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt},
     sync::mpsc::Sender,
 };
 
-use super::uninit_vec;
+use super::{rows_per_band, to_output, uninit_vec, ImageOptions};
 
-pub struct Decoder {}
+pub struct Decoder {
+    options: ImageOptions,
+}
 
 impl Decoder {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(options: ImageOptions) -> Self {
+        Self { options }
     }
 
     pub async fn decode<S>(
@@ -22,7 +24,7 @@ impl Decoder {
         output: &Sender<VncEvent>,
     ) -> Result<()>
     where
-        S: AsyncRead + AsyncWrite + Unpin,
+        S: AsyncRead + Unpin,
     {
         // +----------------------------+--------------+-------------+
         // | No. of bytes               | Type [Value] | Description |
@@ -30,10 +32,25 @@ impl Decoder {
         // | width*height*bytesPerPixel | PIXEL array  | pixels      |
         // +----------------------------+--------------+-------------+
         let bpp = format.bits_per_pixel / 8;
-        let buffer_size = bpp as usize * rect.height as usize * rect.width as usize;
-        let mut pixels = uninit_vec(buffer_size);
-        input.read_exact(&mut pixels).await?;
-        output.send(VncEvent::RawImage(*rect, pixels)).await?;
+        let row_bytes = bpp as usize * rect.width as usize;
+        let rows = rows_per_band(row_bytes, self.options.max_bytes).min(rect.height as usize);
+
+        // read band by band so that only one band is buffered at a time
+        let mut y = 0;
+        while y < rect.height {
+            let height = (rows as u16).min(rect.height - y);
+            let mut pixels = uninit_vec(row_bytes * height as usize);
+            input.read_exact(&mut pixels).await?;
+            let pixels = to_output(pixels, format, self.options.byte_order);
+            let band = Rect {
+                x: rect.x,
+                y: rect.y + y,
+                width: rect.width,
+                height,
+            };
+            output.send(VncEvent::RawImage(band, pixels)).await?;
+            y += height;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,114 @@
+use crate::{PixelFormat, Rect, VncError, VncEvent};
+use anyhow::Result;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc::Sender,
+};
+use tracing::error;
+
+use super::{send_image, ImageOptions};
+
+pub struct Decoder {
+    options: ImageOptions,
+}
+
+impl Decoder {
+    pub fn new(options: ImageOptions) -> Self {
+        Self { options }
+    }
+
+    pub async fn decode<S>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        // +---------------+--------------+-------------------------+
+        // | No. of bytes  | Type [Value] | Description             |
+        // +---------------+--------------+-------------------------+
+        // | 4             | U32          | number-of-subrectangles |
+        // | bytesPerPixel | PIXEL        | background-pixel-value  |
+        // +---------------+--------------+-------------------------+
+        //
+        // followed by number-of-subrectangles of
+        //
+        // +---------------+--------------+---------------------+
+        // | No. of bytes  | Type [Value] | Description         |
+        // +---------------+--------------+---------------------+
+        // | bytesPerPixel | PIXEL        | subrect-pixel-value |
+        // | 2             | U16          | x-position          |
+        // | 2             | U16          | y-position          |
+        // | 2             | U16          | width               |
+        // | 2             | U16          | height              |
+        // +---------------+--------------+---------------------+
+        let bpp = format.bits_per_pixel as usize / 8;
+        let subrects = input.read_u32().await?;
+        let mut pixel = [0; 4];
+        input.read_exact(&mut pixel[..bpp]).await?;
+        let row_bytes = rect.width as usize * bpp;
+        let mut image = pixel[..bpp].repeat(rect.width as usize * rect.height as usize);
+
+        for _ in 0..subrects {
+            input.read_exact(&mut pixel[..bpp]).await?;
+            let x = input.read_u16().await? as usize;
+            let y = input.read_u16().await? as usize;
+            let width = input.read_u16().await? as usize;
+            let height = input.read_u16().await? as usize;
+            if x + width > rect.width as usize || y + height > rect.height as usize {
+                error!(
+                    "Subrect {}x{} at ({}, {}) is out of the rect",
+                    width, height, x, y
+                );
+                return Err(VncError::InvalidImageData.into());
+            }
+            let color = pixel[..bpp].repeat(width);
+            for row in image.chunks_exact_mut(row_bytes).skip(y).take(height) {
+                row[x * bpp..(x + width) * bpp].copy_from_slice(&color);
+            }
+        }
+        send_image(output, rect, image, format, &self.options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_subrects() {
+        let format = PixelFormat::bgra();
+        let rect = Rect {
+            x: 5,
+            y: 5,
+            width: 3,
+            height: 2,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(ImageOptions::default());
+
+        let mut data = vec![0, 0, 0, 1, 1, 1, 1, 1];
+        data.extend_from_slice(&[2, 2, 2, 2, 0, 1, 0, 1, 0, 2, 0, 1]);
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::RawImage(image_rect, image)) = rx.recv().await else {
+            panic!("No image decoded");
+        };
+        assert_eq!(image_rect, rect);
+        let pixels: Vec<u8> = image.chunks(4).map(|pixel| pixel[0]).collect();
+        assert_eq!(pixels, [1, 1, 1, 1, 2, 2]);
+
+        // a subrect outside of the rect is rejected
+        let mut data = vec![0, 0, 0, 1, 1, 1, 1, 1];
+        data.extend_from_slice(&[2, 2, 2, 2, 0, 2, 0, 0, 0, 2, 0, 1]);
+        assert!(decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .is_err());
+    }
+}
@@ -1,13 +1,20 @@
-use crate::{PixelFormat, Rect, VncError, VncEvent};
+use crate::{JpegPolicy, PixelFormat, Rect, VncError, VncEvent};
 use anyhow::{Ok, Result};
-use std::io::Read;
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    hash::{Hash, Hasher},
+    io::Read,
+    sync::Arc,
+};
 use tokio::{
     io::{AsyncRead, AsyncReadExt},
     sync::mpsc::Sender,
 };
-use tracing::error;
+use tracing::{error, warn};
 
-use super::{uninit_vec, zlib::ZlibReader};
+use super::{
+    palette_color, preallocate, reuse_vec, send_image, uninit_vec, zlib::ZlibReader, ImageOptions,
+};
 
 const MAX_PALETTE: usize = 256;
 
@@ -17,13 +24,30 @@ pub struct Decoder {
     ctrl: u8,
     filter: u8,
     palette: Vec<u8>,
-    alpha_shift: u32,
+    // the compressed & the inflated data of the latest basic rect
+    compressed: Vec<u8>,
+    inflated: Vec<u8>,
+    // the bits of a 32 bits pixel uncovered by the channels, which are made opaque
+    alpha_mask: u32,
+    // the bytes per pixel of the pixels & of the TPIXELs on the wire
+    bpp: usize,
+    tpixel: usize,
+    jpeg_policy: JpegPolicy,
+    palettes: PaletteCache,
+    options: ImageOptions,
 }
 
 impl Decoder {
-    pub fn new() -> Self {
+    /// Keep the conversion tables of the `palette_cache` most recent palettes, none if 0
+    pub fn new(jpeg_policy: JpegPolicy, palette_cache: usize, options: ImageOptions) -> Self {
         let mut new = Self {
             palette: Vec::with_capacity(MAX_PALETTE * 4),
+            jpeg_policy,
+            palettes: PaletteCache {
+                capacity: palette_cache,
+                ..Default::default()
+            },
+            options,
             ..Default::default()
         };
         for i in 0..4 {
@@ -33,6 +57,14 @@ impl Decoder {
         new
     }
 
+    /// Preallocate the buffers for a full screen rect
+    pub fn reserve(&mut self, width: u16, height: u16) {
+        // the basic compression carries at most 3 bytes per pixel
+        let len = width as usize * height as usize * 3;
+        preallocate(&mut self.compressed, len);
+        preallocate(&mut self.inflated, len);
+    }
+
     pub async fn decode<S>(
         &mut self,
         format: &PixelFormat,
@@ -43,16 +75,44 @@ impl Decoder {
     where
         S: AsyncRead + Unpin,
     {
-        let pixel_mask = (format.red_max as u32) << format.red_shift
-            | (format.green_max as u32) << format.green_shift
-            | (format.blue_max as u32) << format.blue_shift;
-
-        self.alpha_shift = match pixel_mask {
-            0xff_ff_ff_00 => 0,
-            0xff_ff_00_ff => 8,
-            0xff_00_ff_ff => 16,
-            0x00_ff_ff_ff => 24,
-            _ => unreachable!(),
+        self.decode_rect(format, rect, input, output, false).await
+    }
+
+    /// Decode a TightPng rect, which uses png instead of the basic compression
+    pub async fn decode_png<S>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        self.decode_rect(format, rect, input, output, true).await
+    }
+
+    async fn decode_rect<S>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+        png: bool,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        self.bpp = format.bits_per_pixel as usize / 8;
+        // the 32 bits pixels with 8 bits channels are sent as 3 bytes, the others as they are
+        let packed = self.bpp == 4
+            && format.depth == 24
+            && [format.red_max, format.green_max, format.blue_max] == [255; 3];
+        self.tpixel = if packed { 3 } else { self.bpp };
+        self.alpha_mask = if self.bpp == 4 {
+            !format.pixel_mask()
+        } else {
+            0
         };
 
         let ctrl = input.read_u8().await?;
@@ -74,11 +134,20 @@ impl Decoder {
                 // jpeg Rect
                 self.jpeg_rect(format, rect, input, output).await
             }
-            10 => {
+            10 if png => {
                 // png Rect
+                let data = self.read_data(input).await?;
+                output.send(VncEvent::PngImage(*rect, data)).await?;
+                Ok(())
+            }
+            10 => {
                 error!("PNG received in standard Tight rect");
                 Err(VncError::InvalidImageData.into())
             }
+            x if x & 0x8 == 0 && png => {
+                error!("Basic compression received in TightPng rect");
+                Err(VncError::InvalidImageData.into())
+            }
             x if x & 0x8 == 0 => {
                 // basic Rect
                 self.basic_rect(format, rect, input, output).await
@@ -91,6 +160,17 @@ impl Decoder {
     }
 
     async fn read_data<S>(&mut self, input: &mut S) -> Result<Vec<u8>>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let len = Self::read_len(input).await?;
+        let mut data = uninit_vec(len);
+        input.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    /// Read the compact representation of a data length
+    async fn read_len<S>(input: &mut S) -> Result<usize>
     where
         S: AsyncRead + Unpin,
     {
@@ -109,9 +189,7 @@ impl Decoder {
             }
             len
         };
-        let mut data = uninit_vec(len);
-        input.read_exact(&mut data).await?;
-        Ok(data)
+        Ok(len)
     }
 
     async fn fill_rect<S>(
@@ -124,25 +202,25 @@ impl Decoder {
     where
         S: AsyncRead + Unpin,
     {
-        let mut color = [0; 3];
-        input.read_exact(&mut color).await?;
-        let bpp = format.bits_per_pixel as usize / 8;
+        let mut color = [0; 4];
+        input.read_exact(&mut color[..self.tpixel]).await?;
+        let bpp = self.bpp;
         let mut image = Vec::with_capacity(rect.width as usize * rect.height as usize * bpp);
 
         let true_color = self.to_true_color(format, &color);
 
         for _ in 0..rect.width {
             for _ in 0..rect.height {
-                image.extend_from_slice(&true_color);
+                image.extend_from_slice(&true_color[..bpp]);
             }
         }
-        output.send(VncEvent::RawImage(*rect, image)).await?;
+        send_image(output, rect, image, format, &self.options).await?;
         Ok(())
     }
 
     async fn jpeg_rect<S>(
         &mut self,
-        _format: &PixelFormat,
+        #[allow(unused_variables)] format: &PixelFormat,
         rect: &Rect,
         input: &mut S,
         output: &Sender<VncEvent>,
@@ -151,10 +229,60 @@ impl Decoder {
         S: AsyncRead + Unpin,
     {
         let data = self.read_data(input).await?;
-        output.send(VncEvent::JpegImage(*rect, data)).await?;
+        match self.jpeg_policy {
+            JpegPolicy::Emit => output.send(VncEvent::JpegImage(*rect, data)).await?,
+            JpegPolicy::Drop => warn!("Drop jpeg rect {:?}", rect),
+            #[cfg(feature = "jpeg")]
+            JpegPolicy::Decode => self.decode_jpeg(format, rect, &data, output).await?,
+            JpegPolicy::Disable => {
+                error!("Jpeg rect received while jpeg is disabled");
+                return Err(VncError::InvalidImageData.into());
+            }
+        }
         Ok(())
     }
 
+    #[cfg(feature = "jpeg")]
+    async fn decode_jpeg(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        data: &[u8],
+        output: &Sender<VncEvent>,
+    ) -> Result<()> {
+        use zune_jpeg::zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+
+        let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGB);
+        let mut decoder = zune_jpeg::JpegDecoder::new_with_options(data, options);
+        let rgb = match decoder.decode() {
+            std::result::Result::Ok(rgb) => rgb,
+            Err(e) => {
+                error!("Failed to decode the jpeg rect: {:?}", e);
+                return Err(VncError::InvalidImageData.into());
+            }
+        };
+        if rgb.len() != rect.width as usize * rect.height as usize * 3 {
+            error!("Jpeg image size mismatches the rect {:?}", rect);
+            return Err(VncError::InvalidImageData.into());
+        }
+        // converted just like the TPIXELs, the 8 bits channels are scaled to the nearest unless packed
+        let max = [format.red_max, format.green_max, format.blue_max];
+        let shift = [format.red_shift, format.green_shift, format.blue_shift];
+        let mut image = Vec::with_capacity(rgb.len() / 3 * self.bpp);
+        for color in rgb.chunks_exact(3) {
+            let pixel = if self.tpixel == 3 {
+                self.to_true_color(format, color)
+            } else {
+                let value = (0..3).fold(self.alpha_mask, |value, i| {
+                    value | ((color[i] as u32 * max[i] as u32 + 127) / 255) << shift[i]
+                });
+                self.to_pixel(format, value)
+            };
+            image.extend_from_slice(&pixel[..self.bpp]);
+        }
+        send_image(output, rect, image, format, &self.options).await
+    }
+
     async fn basic_rect<S>(
         &mut self,
         format: &PixelFormat,
@@ -208,7 +336,7 @@ impl Decoder {
     where
         S: AsyncRead + Unpin,
     {
-        let uncompressed_size = rect.width as usize * rect.height as usize * 3;
+        let uncompressed_size = rect.width as usize * rect.height as usize * self.tpixel;
         if uncompressed_size == 0 {
             return Ok(());
         };
@@ -216,14 +344,13 @@ impl Decoder {
         let data = self
             .read_tight_data(stream, input, uncompressed_size)
             .await?;
-        let mut image = Vec::with_capacity(uncompressed_size / 3 * 4);
-        let mut j = 0;
-        while j < uncompressed_size {
-            image.extend_from_slice(&self.to_true_color(format, &data[j..j + 3]));
-            j += 3;
+        let mut image = Vec::with_capacity(uncompressed_size / self.tpixel * self.bpp);
+        for color in data.chunks_exact(self.tpixel) {
+            image.extend_from_slice(&self.to_true_color(format, color)[..self.bpp]);
         }
+        self.inflated = data;
 
-        output.send(VncEvent::RawImage(*rect, image)).await?;
+        send_image(output, rect, image, format, &self.options).await?;
 
         Ok(())
     }
@@ -240,13 +367,13 @@ impl Decoder {
         S: AsyncRead + Unpin,
     {
         let num_colors = input.read_u8().await? as usize + 1;
-        let palette_size = num_colors * 3;
+        let palette_size = num_colors * self.tpixel;
 
-        self.palette = uninit_vec(palette_size);
+        reuse_vec(&mut self.palette, palette_size);
         input.read_exact(&mut self.palette).await?;
 
         let bpp = if num_colors <= 2 { 1 } else { 8 };
-        let row_size = (rect.width as usize * bpp + 7) / 8;
+        let row_size = (rect.width as usize * bpp).div_ceil(8);
         let uncompressed_size = rect.height as usize * row_size;
 
         if uncompressed_size == 0 {
@@ -257,67 +384,90 @@ impl Decoder {
             .read_tight_data(stream, input, uncompressed_size)
             .await?;
 
-        if num_colors == 2 {
-            self.mono_rect(data, rect, format, output).await?
-        } else {
-            self.palette_rect(data, rect, format, output).await?
+        match &*self.palette_table(format, num_colors == 2) {
+            PaletteTable::Mono(table) => self.mono_rect(&data, rect, table, format, output).await?,
+            PaletteTable::Colors(colors) => {
+                self.palette_rect(&data, rect, colors, format, output)
+                    .await?
+            }
         }
+        self.inflated = data;
 
         Ok(())
     }
 
+    /// The pixels of the latest palette, converted unless found in the cache
+    fn palette_table(&mut self, format: &PixelFormat, mono: bool) -> Arc<PaletteTable> {
+        if let Some(table) = self.palettes.get(format, &self.palette) {
+            return table;
+        }
+        let (tpixel, bpp) = (self.tpixel, self.bpp);
+        let table = if mono {
+            let colors = [
+                self.to_true_color(format, &self.palette[..tpixel]),
+                self.to_true_color(format, &self.palette[tpixel..tpixel * 2]),
+            ];
+            PaletteTable::Mono(mono_table(&colors, bpp))
+        } else {
+            let mut colors = Vec::with_capacity(self.palette.len() / tpixel * bpp);
+            for color in self.palette.chunks_exact(tpixel) {
+                colors.extend_from_slice(&self.to_true_color(format, color)[..bpp]);
+            }
+            PaletteTable::Colors(colors)
+        };
+        let table = Arc::new(table);
+        self.palettes.insert(format, &self.palette, table.clone());
+        table
+    }
+
     async fn mono_rect(
         &mut self,
-        data: Vec<u8>,
+        data: &[u8],
         rect: &Rect,
+        table: &[[u8; 32]],
         format: &PixelFormat,
         output: &Sender<VncEvent>,
     ) -> Result<()> {
-        // Convert indexed (palette based) image data to RGB
-        let total = rect.width as usize * rect.height as usize;
-        let mut image = uninit_vec(total * 4);
-        let mut offset = 8_usize;
-        let mut index = -1_isize;
-        let mut dp = 0;
-        for i in 0..total {
-            if offset == 0 || i % rect.width as usize == 0 {
-                offset = 8;
-                index += 1;
-            }
-            offset -= 1;
-            let sp = ((data[index as usize] >> offset) & 0x01) as usize * 3;
-            let true_color = self.to_true_color(format, &self.palette[sp..sp + 3]);
-            unsafe {
-                std::ptr::copy_nonoverlapping(true_color.as_ptr(), image.as_mut_ptr().add(dp), 4)
+        // Convert the 1 bit per pixel bitmap to RGB, a byte of the bitmap at a time
+        let bpp = self.bpp;
+        let width = rect.width as usize;
+        let mut image = Vec::with_capacity(width * rect.height as usize * bpp);
+        // each row is padded to whole bytes
+        for row in data.chunks_exact(width.div_ceil(8)) {
+            let mut remaining = width;
+            for byte in row {
+                let pixels = remaining.min(8);
+                image.extend_from_slice(&table[*byte as usize][..pixels * bpp]);
+                remaining -= pixels;
             }
-            dp += 4;
         }
-        output.send(VncEvent::RawImage(*rect, image)).await?;
+        send_image(output, rect, image, format, &self.options).await?;
         Ok(())
     }
 
     async fn palette_rect(
         &mut self,
-        data: Vec<u8>,
+        data: &[u8],
         rect: &Rect,
+        colors: &[u8],
         format: &PixelFormat,
         output: &Sender<VncEvent>,
     ) -> Result<()> {
         // Convert indexed (palette based) image data to RGB
+        let bpp = self.bpp;
         let total = rect.width as usize * rect.height as usize;
-        let mut image = uninit_vec(total * 4);
+        let mut image = uninit_vec(total * bpp);
         let mut i = 0;
         let mut dp = 0;
         while i < total {
-            let sp = data[i] as usize * 3;
-            let true_color = self.to_true_color(format, &self.palette[sp..sp + 3]);
+            let color = palette_color(colors, bpp, data[i], self.options.strictness)?;
             unsafe {
-                std::ptr::copy_nonoverlapping(true_color.as_ptr(), image.as_mut_ptr().add(dp), 4)
+                std::ptr::copy_nonoverlapping(color.as_ptr(), image.as_mut_ptr().add(dp), bpp)
             }
-            dp += 4;
+            dp += bpp;
             i += 1;
         }
-        output.send(VncEvent::RawImage(*rect, image)).await?;
+        send_image(output, rect, image, format, &self.options).await?;
         Ok(())
     }
 
@@ -332,14 +482,15 @@ impl Decoder {
     where
         S: AsyncRead + Unpin,
     {
-        let uncompressed_size = rect.width as usize * rect.height as usize * 3;
+        let (tpixel, bpp) = (self.tpixel, self.bpp);
+        let uncompressed_size = rect.width as usize * rect.height as usize * tpixel;
         if uncompressed_size == 0 {
             return Ok(());
         };
         let data = self
             .read_tight_data(stream, input, uncompressed_size)
             .await?;
-        let mut image = uninit_vec(rect.width as usize * rect.height as usize * 4);
+        let mut image = uninit_vec(rect.width as usize * rect.height as usize * bpp);
 
         let row_len = rect.width as usize * 3 + 3;
         let mut row_0 = vec![0_u16; row_len];
@@ -357,7 +508,23 @@ impl Decoder {
             };
             let mut x = 3;
             while x < row_len {
-                let rgb = &data[sp..sp + 3];
+                let rgb = if tpixel == 3 {
+                    [data[sp], data[sp + 1], data[sp + 2]].map(u16::from)
+                } else {
+                    // the channels are packed into the pixel on the wire
+                    let pixel = &data[sp..sp + tpixel];
+                    let value = if format.big_endian_flag > 0 {
+                        pixel
+                            .iter()
+                            .fold(0, |value, byte| value << 8 | *byte as u32)
+                    } else {
+                        pixel
+                            .iter()
+                            .rev()
+                            .fold(0, |value, byte| value << 8 | *byte as u32)
+                    };
+                    [0, 1, 2].map(|i| (value >> shift[i]) as u16 & max[i])
+                };
                 let mut color = 0;
                 for index in 0..3 {
                     let d = prev_row[index + x] as i32 + this_row[index + x - 3] as i32
@@ -369,23 +536,24 @@ impl Decoder {
                     } else {
                         d as u16
                     };
-                    this_row[index + x] = (converted + rgb[index] as u16) & max[index];
+                    this_row[index + x] = (converted + rgb[index]) & max[index];
                     color |= (this_row[x + index] as u32 & max[index] as u32) << shift[index];
                 }
                 unsafe {
                     std::ptr::copy_nonoverlapping(
-                        color.to_le_bytes().as_ptr(),
+                        self.to_pixel(format, color).as_ptr(),
                         image.as_mut_ptr().add(dp),
-                        4,
+                        bpp,
                     )
                 }
-                dp += 4;
-                sp += 3;
+                dp += bpp;
+                sp += tpixel;
                 x += 3;
             }
         }
+        self.inflated = data;
 
-        output.send(VncEvent::RawImage(*rect, image)).await?;
+        send_image(output, rect, image, format, &self.options).await?;
         Ok(())
     }
 
@@ -398,27 +566,451 @@ impl Decoder {
     where
         S: AsyncRead + Unpin,
     {
-        let mut data;
+        // the buffer is given back by the filter once the image is converted
+        let mut data = std::mem::take(&mut self.inflated);
+        reuse_vec(&mut data, uncompressed_size);
         if uncompressed_size < 12 {
-            data = uninit_vec(uncompressed_size);
             input.read_exact(&mut data).await?;
         } else {
-            let d = self.read_data(input).await?;
-            let mut reader = ZlibReader::new(self.zlibs[stream as usize].take().unwrap(), &d);
-            data = uninit_vec(uncompressed_size);
+            let len = Self::read_len(input).await?;
+            reuse_vec(&mut self.compressed, len);
+            input.read_exact(&mut self.compressed).await?;
+            let mut reader = ZlibReader::new(
+                self.zlibs[stream as usize].take().unwrap(),
+                &self.compressed,
+            );
             reader.read_exact(&mut data)?;
             self.zlibs[stream as usize] = Some(reader.into_inner()?);
         };
         Ok(data)
     }
 
+    /// The pixel of a TPIXEL, of which only the first `bpp` bytes are meaningful
     fn to_true_color(&self, format: &PixelFormat, color: &[u8]) -> [u8; 4] {
-        let alpha = 255;
+        if self.tpixel != 3 {
+            let mut pixel = [0; 4];
+            pixel[..self.tpixel].copy_from_slice(&color[..self.tpixel]);
+            return pixel;
+        }
         // always rgb
-        (((color[0] as u32 & format.red_max as u32) << format.red_shift)
-            | ((color[1] as u32 & format.green_max as u32) << format.green_shift)
-            | ((color[2] as u32 & format.blue_max as u32) << format.blue_shift)
-            | ((alpha as u32) << self.alpha_shift))
-            .to_le_bytes()
+        let value = ((color[0] as u32) << format.red_shift)
+            | ((color[1] as u32) << format.green_shift)
+            | ((color[2] as u32) << format.blue_shift)
+            | self.alpha_mask;
+        self.to_pixel(format, value)
+    }
+
+    /// The bytes of a pixel `value` in the byte order of `format`,
+    /// of which only the first `bpp` bytes are meaningful
+    fn to_pixel(&self, format: &PixelFormat, value: u32) -> [u8; 4] {
+        if format.big_endian_flag > 0 {
+            let mut pixel = [0; 4];
+            pixel[..self.bpp].copy_from_slice(&value.to_be_bytes()[4 - self.bpp..]);
+            pixel
+        } else {
+            value.to_le_bytes()
+        }
+    }
+}
+
+/// The converted pixels of a palette
+enum PaletteTable {
+    /// The 8 pixels of every byte of the bitmap, see [mono_table]
+    Mono(Vec<[u8; 32]>),
+    /// The `bpp` bytes of every color
+    Colors(Vec<u8>),
+}
+
+struct CachedPalette {
+    hash: u64,
+    format: PixelFormat,
+    palette: Vec<u8>,
+    table: Arc<PaletteTable>,
+}
+
+/// The recently converted palettes, the most recently used first
+///
+/// The servers tend to send the same few palettes over and over, e.g. when a text is scrolled
+#[derive(Default)]
+struct PaletteCache {
+    capacity: usize,
+    entries: VecDeque<CachedPalette>,
+}
+
+impl PaletteCache {
+    fn hash(palette: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        palette.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn get(&mut self, format: &PixelFormat, palette: &[u8]) -> Option<Arc<PaletteTable>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let hash = Self::hash(palette);
+        let i = self.entries.iter().position(|entry| {
+            entry.hash == hash && entry.format == *format && entry.palette == palette
+        })?;
+        let entry = self.entries.remove(i)?;
+        let table = entry.table.clone();
+        self.entries.push_front(entry);
+        Some(table)
+    }
+
+    fn insert(&mut self, format: &PixelFormat, palette: &[u8], table: Arc<PaletteTable>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.truncate(self.capacity - 1);
+        self.entries.push_front(CachedPalette {
+            hash: Self::hash(palette),
+            format: *format,
+            palette: palette.to_vec(),
+            table,
+        });
+    }
+}
+
+/// The 8 pixels of every byte of a mono bitmap, the most significant bit first
+fn mono_table(colors: &[[u8; 4]; 2], bpp: usize) -> Vec<[u8; 32]> {
+    (0..=255_u8)
+        .map(|byte| {
+            let mut pixels = [0; 32];
+            for (i, pixel) in pixels.chunks_exact_mut(bpp).take(8).enumerate() {
+                pixel.copy_from_slice(&colors[(byte >> (7 - i)) as usize & 1][..bpp]);
+            }
+            pixels
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_png_rect() {
+        let format = PixelFormat::bgra();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(JpegPolicy::Emit, 0, ImageOptions::default());
+        let data = [0xa0, 4, 0x89, b'P', b'N', b'G'];
+
+        decoder
+            .decode_png(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::PngImage(_, png)) = rx.recv().await else {
+            panic!("No png emitted");
+        };
+        assert_eq!(png, [0x89, b'P', b'N', b'G']);
+
+        // png is not allowed in standard tight rects
+        assert!(decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_jpeg_policy() {
+        let format = PixelFormat::bgra();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let data = [0x90, 4, 0xff, 0xd8, 0xff, 0xd9];
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+
+        let mut decoder = Decoder::new(JpegPolicy::Emit, 0, ImageOptions::default());
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::JpegImage(_, jpeg)) = rx.recv().await else {
+            panic!("No jpeg emitted");
+        };
+        assert_eq!(jpeg, data[2..]);
+
+        // the whole rect is consumed without any event
+        let mut decoder = Decoder::new(JpegPolicy::Drop, 0, ImageOptions::default());
+        let mut input = data.as_slice();
+        decoder
+            .decode(&format, &rect, &mut input, &tx)
+            .await
+            .unwrap();
+        assert!(input.is_empty());
+        assert!(rx.try_recv().is_err());
+
+        let mut decoder = Decoder::new(JpegPolicy::Disable, 0, ImageOptions::default());
+        assert!(decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .is_err());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mono_rect() {
+        let format = PixelFormat::bgra();
+        // two rows of 10 pixels, padded to 2 bytes each
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 10,
+            height: 2,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(JpegPolicy::Emit, 0, ImageOptions::default());
+        // the preallocated buffers are larger than the rect
+        decoder.reserve(64, 64);
+        // palette filter with black & white
+        let mut data = vec![0x40, 1, 1, 0, 0, 0, 255, 255, 255];
+        data.extend_from_slice(&[0b1010_0000, 0b0100_0000, 0b0000_0001, 0b1100_0000]);
+
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+            panic!("No image decoded");
+        };
+        let bits: Vec<u8> = image.chunks(4).map(|pixel| pixel[0] & 1).collect();
+        assert_eq!(
+            bits,
+            [1, 0, 1, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_palette_cache() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(JpegPolicy::Emit, 1, ImageOptions::default());
+        // palette filter with red, green & blue
+        let palette_rect = |indices: [u8; 2]| {
+            let mut data = vec![0x40, 1, 2, 255, 0, 0, 0, 255, 0, 0, 0, 255];
+            data.extend_from_slice(&indices);
+            data
+        };
+        let bgra = PixelFormat::bgra();
+        let rgba = PixelFormat::rgba();
+        let cases = [
+            (bgra, [2, 0], [255, 0, 0, 255, 0, 0, 255, 255]),
+            // found in the cache
+            (bgra, [1, 1], [0, 255, 0, 255, 0, 255, 0, 255]),
+            // the same palette in another format is converted again
+            (rgba, [2, 0], [0, 0, 255, 255, 255, 0, 0, 255]),
+        ];
+        for (format, indices, expected) in cases {
+            decoder
+                .decode(&format, &rect, &mut palette_rect(indices).as_slice(), &tx)
+                .await
+                .unwrap();
+            let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+                panic!("No image decoded");
+            };
+            assert_eq!(image, expected);
+        }
+        assert_eq!(decoder.palettes.entries.len(), 1);
+        assert_eq!(decoder.palettes.entries[0].format, rgba);
+    }
+
+    #[tokio::test]
+    async fn test_bgr233_rect() {
+        let format = PixelFormat::bgr233();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let mut decoder = Decoder::new(JpegPolicy::Emit, 0, ImageOptions::default());
+        // copy filter with the 1 byte TPIXELs, then a fill
+        let data = [0x00, 0b00_000_111, 0b11_000_000, 0x80, 0b00_111_000];
+        let mut input = data.as_slice();
+        decoder
+            .decode(&format, &rect, &mut input, &tx)
+            .await
+            .unwrap();
+        decoder
+            .decode(&format, &rect, &mut input, &tx)
+            .await
+            .unwrap();
+
+        let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+            panic!("No image decoded");
+        };
+        assert_eq!(image, [255, 0, 0, 255, 0, 0, 255, 255]);
+        let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+            panic!("No image filled");
+        };
+        assert_eq!(image, [0, 255, 0, 255, 0, 255, 0, 255]);
+    }
+
+    #[tokio::test]
+    async fn test_big_endian_rect() {
+        let mut format = PixelFormat::bgra();
+        format.big_endian_flag = 1;
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let mut decoder = Decoder::new(JpegPolicy::Emit, 0, ImageOptions::default());
+        // a fill, then the copy filter with the 3 bytes TPIXELs
+        let data = [0x80, 1, 2, 3, 0x00, 4, 5, 6];
+        let mut input = data.as_slice();
+        decoder
+            .decode(&format, &rect, &mut input, &tx)
+            .await
+            .unwrap();
+        decoder
+            .decode(&format, &rect, &mut input, &tx)
+            .await
+            .unwrap();
+        for expected in [[255, 1, 2, 3], [255, 4, 5, 6]] {
+            let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+                panic!("No image decoded");
+            };
+            assert_eq!(image, expected);
+        }
+
+        // the channels of 10 bits are sent as whole pixels
+        format.depth = 30;
+        (format.red_max, format.green_max, format.blue_max) = (1023, 1023, 1023);
+        (format.red_shift, format.green_shift, format.blue_shift) = (20, 10, 0);
+        let data = [0x80, 0x3f, 0xf0, 0x00, 0x00];
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+            panic!("No image filled");
+        };
+        assert_eq!(image, [0x3f, 0xf0, 0x00, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_rgb565_gradient() {
+        let format = PixelFormat::rgb565();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(JpegPolicy::Emit, 0, ImageOptions::default());
+        // gradient filter with the 2 bytes TPIXELs, red then the difference of blue
+        let data = [0x40, 2, 0x00, 0xf8, 0x1f, 0x00];
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+            panic!("No image decoded");
+        };
+        assert_eq!(image, [0x00, 0xf8, 0x1f, 0xf8]);
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[tokio::test]
+    async fn test_decode_malformed_jpeg() {
+        let format = PixelFormat::bgra();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(JpegPolicy::Decode, 0, ImageOptions::default());
+        let data = [0x90, 4, 0xff, 0xd8, 0xff, 0xd9];
+        let e = decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(VncError::InvalidImageData)));
+    }
+
+    /// A baseline jpeg of 8x8 red pixels, made of the DC coefficients only
+    #[cfg(feature = "jpeg")]
+    fn red_jpeg() -> Vec<u8> {
+        // start of image, the quantization table of ones
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xdb, 0x00, 0x43, 0x00];
+        jpeg.extend_from_slice(&[1; 64]);
+        // 8x8, 3 components without subsampling
+        jpeg.extend_from_slice(&[0xff, 0xc0, 0x00, 0x11, 0x08, 0x00, 0x08, 0x00, 0x08, 0x03]);
+        jpeg.extend_from_slice(&[0x01, 0x11, 0x00, 0x02, 0x11, 0x00, 0x03, 0x11, 0x00]);
+        // the DC categories 0 to 11 in 4 bits
+        jpeg.extend_from_slice(&[0xff, 0xc4, 0x00, 0x1f, 0x00, 0, 0, 0, 12]);
+        jpeg.extend_from_slice(&[0; 12]);
+        jpeg.extend(0..12);
+        // the end of block in 1 bit
+        jpeg.extend_from_slice(&[0xff, 0xc4, 0x00, 0x14, 0x10, 1]);
+        jpeg.extend_from_slice(&[0; 16]);
+        // start of scan, then the coefficients & the end of image
+        jpeg.extend_from_slice(&[0xff, 0xda, 0x00, 0x0c, 0x03, 0x01, 0x00, 0x02, 0x00, 0x03]);
+        jpeg.extend_from_slice(&[0x00, 0x00, 0x3f, 0x00]);
+        jpeg.extend_from_slice(&[0x93, 0x0a, 0x54, 0xea, 0xff, 0x00, 0x1f, 0xff, 0xd9]);
+        jpeg
+    }
+
+    #[cfg(feature = "jpeg")]
+    #[tokio::test]
+    async fn test_decode_jpeg() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 8,
+            height: 8,
+        };
+        let jpeg = red_jpeg();
+        let mut data = vec![0x90, 0x80 | jpeg.len() as u8, (jpeg.len() >> 7) as u8];
+        data.extend_from_slice(&jpeg);
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(JpegPolicy::Decode, 0, ImageOptions::default());
+        // the same opaque red as the other encodings, whichever the format is
+        let mut big_endian = PixelFormat::bgra();
+        big_endian.big_endian_flag = 1;
+        let cases = [
+            (PixelFormat::bgra(), [0, 0, 255, 255]),
+            (PixelFormat::rgba(), [255, 0, 0, 255]),
+            (big_endian, [255, 255, 0, 0]),
+            (PixelFormat::bgr233(), [255, 0, 0, 255]),
+        ];
+        for (format, red) in cases {
+            decoder
+                .decode(&format, &rect, &mut data.as_slice(), &tx)
+                .await
+                .unwrap();
+            let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+                panic!("No image decoded");
+            };
+            assert_eq!(image.len(), 8 * 8 * 4);
+            for pixel in image.chunks_exact(4) {
+                // the channels are close enough after the lossy compression
+                let close = pixel.iter().zip(red).all(|(a, b)| a.abs_diff(b) < 4);
+                assert!(close, "{:?} is not {:?}", pixel, red);
+            }
+        }
     }
 }
@@ -6,7 +6,7 @@ use tokio::{
 };
 use tracing::error;
 
-use super::uninit_vec;
+use super::{to_output, ImageOptions};
 
 async fn read_run_length<S>(reader: &mut S) -> Result<usize>
 where
@@ -42,16 +42,31 @@ where
     Ok(())
 }
 
-fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) {
+fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) -> Result<()> {
     let start = index as usize * bpp;
-    pixels.extend_from_slice(&palette[start..start + bpp])
+    let Some(color) = palette.get(start..start + bpp) else {
+        error!("Palette index {} out of range", index);
+        return Err(VncError::InvalidImageData.into());
+    };
+    pixels.extend_from_slice(color);
+    Ok(())
 }
 
-pub struct Decoder {}
+fn check_run_length(count: usize, run_length: usize, pixel_count: usize) -> Result<()> {
+    if count + run_length > pixel_count {
+        error!("Run length {} overflows the tile", run_length);
+        return Err(VncError::InvalidImageData.into());
+    }
+    Ok(())
+}
+
+pub struct Decoder {
+    options: ImageOptions,
+}
 
 impl Decoder {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(options: ImageOptions) -> Self {
+        Self { options }
     }
 
     pub async fn decode<S>(
@@ -64,11 +79,11 @@ impl Decoder {
     where
         S: AsyncRead + Unpin,
     {
-        let data_len = input.read_u32().await? as usize;
-        let mut zlib_data = uninit_vec(data_len);
-        input.read_exact(&mut zlib_data).await?;
-
         let bpp = format.bits_per_pixel as usize / 8;
+        if !matches!(bpp, 1..=4) {
+            error!("Unsupported bits per pixel {}", format.bits_per_pixel);
+            return Err(VncError::InvalidImageData.into());
+        }
         let pixel_mask = (format.red_max as u32) << format.red_shift
             | (format.green_max as u32) << format.green_shift
             | (format.blue_max as u32) << format.blue_shift;
@@ -133,7 +148,7 @@ impl Decoder {
                     (false, 1) => {
                         // Color fill
                         for _ in 0..pixel_count {
-                            copy_indexed(&palette, &mut pixels, bpp, 0)
+                            copy_indexed(&palette, &mut pixels, bpp, 0)?
                         }
                     }
                     (false, 2..=16) => {
@@ -144,11 +159,12 @@ impl Decoder {
                             5..=16 => 4,
                             _ => unreachable!(),
                         };
-                        let mut encoded = input.read_u8().await?;
                         let mask = (1 << bits_per_index) - 1;
 
-                        for y in 0..height {
-                            let mut shift = 8 - bits_per_index;
+                        for _ in 0..height {
+                            // every row starts at a byte boundary
+                            let mut encoded = 0;
+                            let mut shift = -1;
                             for _ in 0..width {
                                 if shift < 0 {
                                     shift = 8 - bits_per_index;
@@ -156,12 +172,9 @@ impl Decoder {
                                 }
                                 let idx = (encoded >> shift) & mask;
 
-                                copy_indexed(&palette, &mut pixels, bpp, idx);
+                                copy_indexed(&palette, &mut pixels, bpp, idx)?;
                                 shift -= bits_per_index;
                             }
-                            if shift < 8 - bits_per_index && y < height - 1 {
-                                encoded = input.read_u8().await?;
-                            }
                         }
                     }
                     (true, 0) => {
@@ -173,6 +186,7 @@ impl Decoder {
                             copy_true_color(input, &mut pixel, alpha_at_first, compressed_bpp, bpp)
                                 .await?;
                             let run_length = read_run_length(input).await?;
+                            check_run_length(count, run_length, pixel_count)?;
                             for _ in 0..run_length {
                                 pixels.extend(&pixel)
                             }
@@ -191,8 +205,9 @@ impl Decoder {
                             } else {
                                 1
                             };
+                            check_run_length(count, run_length, pixel_count)?;
                             for _ in 0..run_length {
-                                copy_indexed(&palette, &mut pixels, bpp, index);
+                                copy_indexed(&palette, &mut pixels, bpp, index)?;
                             }
                             count += run_length;
                         }
@@ -202,6 +217,7 @@ impl Decoder {
                         return Err(VncError::InvalidImageData.into());
                     }
                 }
+                let pixels = to_output(pixels, format, self.options.byte_order);
                 output
                     .send(VncEvent::RawImage(
                         Rect {
@@ -221,3 +237,48 @@ impl Decoder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn decode(tile: &[u8], width: u16, height: u16) -> Result<Vec<u8>> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let mut input = tile;
+        Decoder::new(ImageOptions::default())
+            .decode(&PixelFormat::rgba(), &rect, &mut input, &tx)
+            .await?;
+        match rx.recv().await {
+            Some(VncEvent::RawImage(_, pixels)) => Ok(pixels),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_packed_palette() {
+        // 2 colors, 1 bit per index, every row is padded to a byte
+        let tile = [2, 1, 1, 1, 2, 2, 2, 0b1010_0000, 0b0100_0000];
+        let pixels = decode(&tile, 3, 2).await.unwrap();
+        let (c0, c1) = ([1, 1, 1, 255], [2, 2, 2, 255]);
+        assert_eq!(pixels, [c1, c0, c1, c0, c1, c0].concat());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_tiles() {
+        // index 3 of a palette with 3 colors
+        let tile = [3, 1, 1, 1, 2, 2, 2, 3, 3, 3, 0b1100_0000];
+        assert!(decode(&tile, 1, 1).await.is_err());
+        // indexed rle with a run longer than the tile
+        let tile = [0x82, 1, 1, 1, 2, 2, 2, 0x81, 4];
+        assert!(decode(&tile, 2, 2).await.is_err());
+        // true color rle with a run longer than the tile
+        let tile = [0x80, 1, 1, 1, 1];
+        assert!(decode(&tile, 1, 1).await.is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use crate::{PixelFormat, Rect, VncError, VncEvent};
+use anyhow::Result;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc::Sender,
+};
+use tracing::error;
+
+use super::{lzo, send_image, uninit_vec, ImageOptions};
+
+pub struct Decoder {
+    options: ImageOptions,
+}
+
+impl Decoder {
+    pub fn new(options: ImageOptions) -> Self {
+        Self { options }
+    }
+
+    pub async fn decode<S>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        // +--------------+--------------+-------------+
+        // | No. of bytes | Type [Value] | Description |
+        // +--------------+--------------+-------------+
+        // | 4            | U32          | length      |
+        // | length       | U8 array     | lzoData     |
+        // +--------------+--------------+-------------+
+        //
+        // the lzoData is the raw pixels of the rect compressed independently
+        let data_len = input.read_u32().await? as usize;
+        let mut lzo_data = uninit_vec(data_len);
+        input.read_exact(&mut lzo_data).await?;
+
+        let bpp = format.bits_per_pixel as usize / 8;
+        let image_len = rect.width as usize * rect.height as usize * bpp;
+        let image = match lzo::decompress(&lzo_data, image_len) {
+            Some(image) if image.len() == image_len => image,
+            _ => {
+                error!("Failed to decompress the ultra rect {:?}", rect);
+                return Err(VncError::InvalidImageData.into());
+            }
+        };
+        send_image(output, rect, image, format, &self.options).await
+    }
+}
@@ -23,7 +23,9 @@ IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 DEALINGS IN THE SOFTWARE.
 */
 
-use std::io::{Read, Result};
+use std::io::{Error, ErrorKind, Read, Result};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 pub struct ZlibReader<'a> {
     decompressor: flate2::Decompress,
@@ -48,12 +50,6 @@ impl<'a> ZlibReader<'a> {
             ))
         }
     }
-
-    pub fn read_u8(&mut self) -> std::io::Result<u8> {
-        let mut buf = [0; 1];
-        self.read_exact(&mut buf)?;
-        Ok(buf[0])
-    }
 }
 
 impl<'a> Read for ZlibReader<'a> {
@@ -78,3 +74,182 @@ impl<'a> Read for ZlibReader<'a> {
         }
     }
 }
+
+/// Compressed bytes read from the input at once by [ZlibWindow]
+const COMPRESSED_WINDOW: usize = 16 * 1024;
+
+/// Inflate a zlib stream of a known length from an async input, a bounded window at a time
+///
+/// So that neither the whole compressed data nor the whole inflated data is held in memory
+///
+#[derive(Default)]
+pub struct ZlibWindow {
+    compressed: Vec<u8>,
+    // the compressed bytes of the window fed to the decompressor
+    fed: usize,
+    // the compressed bytes not read from the input yet
+    unread: usize,
+    inflated: Vec<u8>,
+    // the inflated bytes taken by the decoder
+    taken: usize,
+}
+
+impl ZlibWindow {
+    /// Preallocate the windows, `inflated` bytes are at most required at once
+    pub fn reserve(&mut self, inflated: usize) {
+        self.compressed.reserve(COMPRESSED_WINDOW);
+        self.inflated.reserve(inflated);
+    }
+
+    /// Begin a stream of `len` compressed bytes
+    pub fn start(&mut self, len: usize) {
+        self.compressed.clear();
+        self.fed = 0;
+        self.unread = len;
+        self.inflated.clear();
+        self.taken = 0;
+    }
+
+    /// Inflate until `want` bytes are available or the stream is exhausted
+    pub async fn fill<S>(
+        &mut self,
+        decompressor: &mut flate2::Decompress,
+        input: &mut S,
+        want: usize,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        self.inflated.drain(..self.taken);
+        self.taken = 0;
+        while self.inflated.len() < want {
+            if self.fed == self.compressed.len() && self.unread > 0 {
+                let len = self.unread.min(COMPRESSED_WINDOW);
+                self.compressed.resize(len, 0);
+                input.read_exact(&mut self.compressed).await?;
+                self.unread -= len;
+                self.fed = 0;
+            }
+            self.inflated.reserve(want - self.inflated.len());
+            let in_before = decompressor.total_in();
+            let out_before = decompressor.total_out();
+            let status = decompressor
+                .decompress_vec(
+                    &self.compressed[self.fed..],
+                    &mut self.inflated,
+                    flate2::FlushDecompress::None,
+                )
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let consumed = (decompressor.total_in() - in_before) as usize;
+            let produced = decompressor.total_out() - out_before;
+            self.fed += consumed;
+            match status {
+                flate2::Status::StreamEnd => {
+                    return Err(Error::new(ErrorKind::InvalidData, "zlib stream end"))
+                }
+                // the input is exhausted, with the pending output flushed
+                _ if consumed == 0 && produced == 0 && self.fed == self.compressed.len() => break,
+                // neither the input nor the output is the bottleneck
+                _ if consumed == 0 && produced == 0 => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "leftover zlib byte data",
+                    ))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// The inflated bytes not taken yet
+    pub fn inflated(&self) -> &[u8] {
+        &self.inflated[self.taken..]
+    }
+
+    pub fn consume(&mut self, len: usize) {
+        self.taken += len;
+    }
+
+    /// Check that the stream is exhausted by the decoder
+    pub async fn finish<S>(
+        &mut self,
+        decompressor: &mut flate2::Decompress,
+        input: &mut S,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        self.fill(decompressor, input, 1).await?;
+        if !self.inflated().is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "leftover zlib byte data",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read out the rest of the stream after a failure, so that the input is still in sync
+    pub async fn skip<S>(&mut self, input: &mut S) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let unread = std::mem::take(&mut self.unread) as u64;
+        let skipped = tokio::io::copy(&mut input.take(unread), &mut tokio::io::sink()).await?;
+        if skipped < unread {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_window() {
+        // larger than the compressed window even if compressed
+        let data = (0..100_000_u32)
+            .flat_map(|i| i.wrapping_mul(2654435761).to_be_bytes())
+            .collect::<Vec<_>>();
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&data).unwrap();
+        encoder.flush().unwrap();
+        let compressed = encoder.get_ref().clone();
+        assert!(compressed.len() > COMPRESSED_WINDOW);
+
+        let mut decompressor = flate2::Decompress::new(true);
+        let mut window = ZlibWindow::default();
+        let mut input = compressed.as_slice();
+        window.start(compressed.len());
+        let mut inflated = Vec::new();
+        while inflated.len() < data.len() {
+            window
+                .fill(&mut decompressor, &mut input, 1000)
+                .await
+                .unwrap();
+            let len = window.inflated().len().min(777);
+            inflated.extend_from_slice(&window.inflated()[..len]);
+            window.consume(len);
+            // bounded by the largest fill
+            assert!(window.inflated.capacity() < 4096);
+        }
+        assert_eq!(inflated, data);
+        window.finish(&mut decompressor, &mut input).await.unwrap();
+        assert!(input.is_empty());
+
+        // the rest is read out after a failure
+        let mut input = compressed.as_slice();
+        window.start(compressed.len());
+        window
+            .fill(&mut flate2::Decompress::new(true), &mut input, 10)
+            .await
+            .unwrap();
+        window.skip(&mut input).await.unwrap();
+        assert!(input.is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::{PixelFormat, Rect, VncError, VncEvent};
+use anyhow::Result;
+use std::io::Read;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::mpsc::Sender,
+};
+use tracing::error;
+
+use super::{preallocate, reuse_vec, send_image, uninit_vec, zlib::ZlibReader, ImageOptions};
+
+pub struct Decoder {
+    decompressor: Option<flate2::Decompress>,
+    // the zlibData of the latest rect
+    compressed: Vec<u8>,
+    options: ImageOptions,
+}
+
+impl Decoder {
+    pub fn new(options: ImageOptions) -> Self {
+        Self {
+            decompressor: Some(flate2::Decompress::new(true)),
+            compressed: Vec::new(),
+            options,
+        }
+    }
+
+    /// Preallocate the buffers for a full screen rect
+    pub fn reserve(&mut self, format: &PixelFormat, width: u16, height: u16) {
+        let bpp = format.bits_per_pixel as usize / 8;
+        preallocate(&mut self.compressed, width as usize * height as usize * bpp);
+    }
+
+    pub async fn decode<S>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        // +--------------+--------------+-------------+
+        // | No. of bytes | Type [Value] | Description |
+        // +--------------+--------------+-------------+
+        // | 4            | U32          | length      |
+        // | length       | U8 array     | zlibData    |
+        // +--------------+--------------+-------------+
+        //
+        // the zlibData is the raw pixels compressed in a single stream for the whole session
+        let data_len = input.read_u32().await? as usize;
+        reuse_vec(&mut self.compressed, data_len);
+        input.read_exact(&mut self.compressed).await?;
+        // the decompressor is lost if the previous rect failed to decode
+        let decompressor = self
+            .decompressor
+            .take()
+            .unwrap_or_else(|| flate2::Decompress::new(true));
+        let mut reader = ZlibReader::new(decompressor, &self.compressed);
+
+        let bpp = format.bits_per_pixel as usize / 8;
+        let mut image = uninit_vec(rect.width as usize * rect.height as usize * bpp);
+        let inflated = reader
+            .read_exact(&mut image)
+            .and_then(|_| reader.into_inner());
+        match inflated {
+            std::result::Result::Ok(decompressor) => self.decompressor = Some(decompressor),
+            Err(e) => {
+                error!("Failed to inflate the zlib rect: {}", e);
+                return Err(VncError::InvalidImageData.into());
+            }
+        }
+        send_image(output, rect, image, format, &self.options).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[tokio::test]
+    async fn test_persistent_stream() {
+        let format = PixelFormat::bgra();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let mut decoder = Decoder::new(ImageOptions::default());
+
+        // two rects compressed in the same stream
+        let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::fast());
+        let mut data = vec![];
+        for pixels in [[1; 8], [2; 8]] {
+            encoder.write_all(&pixels).unwrap();
+            encoder.flush().unwrap();
+            let compressed = std::mem::take(encoder.get_mut());
+            data.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+            data.extend_from_slice(&compressed);
+        }
+
+        let mut input = data.as_slice();
+        for pixel in [1, 2] {
+            decoder
+                .decode(&format, &rect, &mut input, &tx)
+                .await
+                .unwrap();
+            let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+                panic!("No image decoded");
+            };
+            assert_eq!(image, [pixel; 8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reserve() {
+        let format = PixelFormat::bgra();
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 16,
+            height: 16,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let mut decoder = Decoder::new(ImageOptions::default());
+        decoder.reserve(&format, 64, 64);
+        assert!(decoder.compressed.capacity() >= 64 * 64 * 4);
+        let allocation = decoder.compressed.as_ptr();
+
+        let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::none());
+        encoder.write_all(&[3; 16 * 16 * 4]).unwrap();
+        encoder.flush().unwrap();
+        let compressed = encoder.get_ref();
+        let mut data = (compressed.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(compressed);
+        decoder
+            .decode(&format, &rect, &mut data.as_slice(), &tx)
+            .await
+            .unwrap();
+        let Some(VncEvent::RawImage(_, image)) = rx.recv().await else {
+            panic!("No image decoded");
+        };
+        assert_eq!(image, [3; 16 * 16 * 4]);
+        // the rect is read into the preallocated buffer
+        assert_eq!(decoder.compressed.as_ptr(), allocation);
+    }
+}
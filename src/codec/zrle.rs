@@ -1,18 +1,29 @@
-use crate::{PixelFormat, Rect, VncError, VncEvent};
+use crate::{DecodeStrictness, PixelFormat, Rect, VncError, VncEvent};
 use anyhow::Result;
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    io::{AsyncRead, AsyncReadExt},
     sync::mpsc::Sender,
 };
 use tracing::error;
 
-use super::{uninit_vec, zlib::ZlibReader};
+use super::{palette_color, to_output, zlib::ZlibWindow, ImageOptions};
 
-fn read_run_length(reader: &mut ZlibReader) -> Result<usize> {
+/// The most bytes a tile can be inflated to, i.e. the subencoding, a full palette
+///
+/// and 4 bytes pixels each with a run length, along with the extra 255s of the longer runs
+const MAX_TILE: usize = 1 + 127 * 4 + 64 * 64 * 5 + 64 * 64 / 255 + 1;
+
+fn read_u8(reader: &mut &[u8]) -> std::io::Result<u8> {
+    let mut buf = [0; 1];
+    std::io::Read::read_exact(reader, &mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_run_length(reader: &mut &[u8]) -> Result<usize> {
     let mut run_length_part;
     let mut run_length = 1;
     loop {
-        run_length_part = reader.read_u8()?;
+        run_length_part = read_u8(reader)?;
         run_length += run_length_part as usize;
         if 255 != run_length_part {
             break;
@@ -22,7 +33,7 @@ fn read_run_length(reader: &mut ZlibReader) -> Result<usize> {
 }
 
 fn copy_true_color(
-    reader: &mut ZlibReader,
+    reader: &mut &[u8],
     pixels: &mut Vec<u8>,
     pad: bool,
     compressed_bpp: usize,
@@ -37,39 +48,42 @@ fn copy_true_color(
     Ok(())
 }
 
-fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) {
-    let start = index as usize * bpp;
-    pixels.extend_from_slice(&palette[start..start + bpp])
-}
-
-pub struct Decoder {
-    decompressor: Option<flate2::Decompress>,
+fn copy_indexed(
+    palette: &[u8],
+    pixels: &mut Vec<u8>,
+    bpp: usize,
+    index: u8,
+    strictness: DecodeStrictness,
+) -> Result<()> {
+    pixels.extend_from_slice(palette_color(palette, bpp, index, strictness)?);
+    Ok(())
 }
 
-impl Decoder {
-    pub fn new() -> Self {
-        Self {
-            decompressor: Some(flate2::Decompress::new(true)),
-        }
+fn check_run_length(count: usize, run_length: usize, pixel_count: usize) -> Result<()> {
+    if count + run_length > pixel_count {
+        error!("Run length {} overflows the tile", run_length);
+        return Err(VncError::InvalidImageData.into());
     }
+    Ok(())
+}
 
-    pub async fn decode<S>(
-        &mut self,
-        format: &PixelFormat,
-        rect: &Rect,
-        input: &mut S,
-        output: &Sender<VncEvent>,
-    ) -> Result<()>
-    where
-        S: AsyncRead + AsyncWrite + Unpin,
-    {
-        let data_len = input.read_u32().await? as usize;
-        let mut zlib_data = uninit_vec(data_len);
-        input.read_exact(&mut zlib_data).await?;
-        let decompressor = self.decompressor.take().unwrap();
-        let mut reader = ZlibReader::new(decompressor, &zlib_data);
+/// How the pixels of a format are carried in the tiles
+#[derive(Clone, Copy)]
+struct TileFormat {
+    bpp: usize,
+    // the CPIXEL size
+    compressed_bpp: usize,
+    alpha_at_first: bool,
+    strictness: DecodeStrictness,
+}
 
+impl TileFormat {
+    fn new(format: &PixelFormat, strictness: DecodeStrictness) -> Result<Self> {
         let bpp = format.bits_per_pixel as usize / 8;
+        if !matches!(bpp, 1..=4) {
+            error!("Unsupported bits per pixel {}", format.bits_per_pixel);
+            return Err(VncError::InvalidImageData.into());
+        }
         let pixel_mask = (format.red_max as u32) << format.red_shift
             | (format.green_max as u32) << format.green_shift
             | (format.blue_max as u32) << format.blue_shift;
@@ -94,7 +108,189 @@ impl Decoder {
             } else {
                 (bpp, false)
             };
-        let mut palette = Vec::with_capacity(128 * bpp);
+        Ok(Self {
+            bpp,
+            compressed_bpp,
+            alpha_at_first,
+            strictness,
+        })
+    }
+}
+
+/// Decode the pixels of a `width` x `height` tile from the inflated `reader`
+fn decode_tile(
+    reader: &mut &[u8],
+    tile: TileFormat,
+    palette: &mut Vec<u8>,
+    width: u16,
+    height: u16,
+) -> Result<Vec<u8>> {
+    let TileFormat {
+        bpp,
+        compressed_bpp,
+        alpha_at_first,
+        strictness,
+    } = tile;
+    let pixel_count = height as usize * width as usize;
+
+    let control = read_u8(reader)?;
+    let is_rle = control & 0x80 > 0;
+    let palette_size = control & 0x7f;
+    palette.truncate(0);
+
+    for _ in 0..palette_size {
+        copy_true_color(reader, palette, alpha_at_first, compressed_bpp, bpp)?
+    }
+
+    let mut pixels = Vec::with_capacity(pixel_count * bpp);
+    match (is_rle, palette_size) {
+        (false, 0) => {
+            // True Color pixels
+            for _ in 0..pixel_count {
+                copy_true_color(reader, &mut pixels, alpha_at_first, compressed_bpp, bpp)?
+            }
+        }
+        (false, 1) => {
+            // Color fill
+            for _ in 0..pixel_count {
+                copy_indexed(palette, &mut pixels, bpp, 0, strictness)?
+            }
+        }
+        (false, 2..=16) => {
+            // Indexed pixels
+            let bits_per_index = match palette_size {
+                2 => 1,
+                3..=4 => 2,
+                5..=16 => 4,
+                _ => unreachable!(),
+            };
+            let mask = (1 << bits_per_index) - 1;
+
+            for _ in 0..height {
+                // every row starts at a byte boundary
+                let mut encoded = 0;
+                let mut shift = -1;
+                for _ in 0..width {
+                    if shift < 0 {
+                        shift = 8 - bits_per_index;
+                        encoded = read_u8(reader)?;
+                    }
+                    let idx = (encoded >> shift) & mask;
+
+                    copy_indexed(palette, &mut pixels, bpp, idx, strictness)?;
+                    shift -= bits_per_index;
+                }
+            }
+        }
+        (true, 0) => {
+            // True Color RLE
+            let mut count = 0;
+            let mut pixel = Vec::new();
+            while count < pixel_count {
+                pixel.truncate(0);
+                copy_true_color(reader, &mut pixel, alpha_at_first, compressed_bpp, bpp)?;
+                let run_length = read_run_length(reader)?;
+                check_run_length(count, run_length, pixel_count)?;
+                for _ in 0..run_length {
+                    pixels.extend(&pixel)
+                }
+                count += run_length;
+            }
+        }
+        (true, 2..=127) => {
+            // Indexed RLE
+            let mut count = 0;
+            while count < pixel_count {
+                let control = read_u8(reader)?;
+                let longer_than_one = control & 0x80 > 0;
+                let index = control & 0x7f;
+                let run_length = if longer_than_one {
+                    read_run_length(reader)?
+                } else {
+                    1
+                };
+                check_run_length(count, run_length, pixel_count)?;
+                for _ in 0..run_length {
+                    copy_indexed(palette, &mut pixels, bpp, index, strictness)?;
+                }
+                count += run_length;
+            }
+        }
+        (x, y) => {
+            error!("ZRLE subencoding error {:?}", (x, y));
+            return Err(VncError::InvalidImageData.into());
+        }
+    }
+    Ok(pixels)
+}
+
+pub struct Decoder {
+    decompressor: Option<flate2::Decompress>,
+    // the zlibData of the latest rect is inflated a tile at a time
+    window: ZlibWindow,
+    options: ImageOptions,
+}
+
+impl Decoder {
+    pub fn new(options: ImageOptions) -> Self {
+        Self {
+            decompressor: Some(flate2::Decompress::new(true)),
+            window: ZlibWindow::default(),
+            options,
+        }
+    }
+
+    /// Preallocate the buffers, which are bounded regardless of the size of the rects
+    pub fn reserve(&mut self) {
+        self.window.reserve(MAX_TILE);
+    }
+
+    pub async fn decode<S>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let data_len = input.read_u32().await? as usize;
+        self.window.start(data_len);
+        // the decompressor is lost if the previous rect failed to decode
+        let mut decompressor = self
+            .decompressor
+            .take()
+            .unwrap_or_else(|| flate2::Decompress::new(true));
+        match self
+            .decode_tiles(format, rect, &mut decompressor, input, output)
+            .await
+        {
+            Ok(()) => {
+                self.decompressor = Some(decompressor);
+                Ok(())
+            }
+            Err(e) => {
+                // the rest of the rect is skipped, so that the next one can still be read
+                let _ = self.window.skip(input).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn decode_tiles<S>(
+        &mut self,
+        format: &PixelFormat,
+        rect: &Rect,
+        decompressor: &mut flate2::Decompress,
+        input: &mut S,
+        output: &Sender<VncEvent>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let tile = TileFormat::new(format, self.options.strictness)?;
+        let mut palette = Vec::with_capacity(128 * tile.bpp);
 
         let mut y = 0;
         while y < rect.height {
@@ -110,114 +306,25 @@ impl Decoder {
                 } else {
                     64
                 };
-                let pixel_count = height as usize * width as usize;
-
-                let control = reader.read_u8()?;
-                let is_rle = control & 0x80 > 0;
-                let palette_size = control & 0x7f;
-                palette.truncate(0);
-
-                for _ in 0..palette_size {
-                    copy_true_color(
-                        &mut reader,
-                        &mut palette,
-                        alpha_at_first,
-                        compressed_bpp,
-                        bpp,
-                    )?
-                }
 
-                let mut pixels = Vec::with_capacity(pixel_count * bpp);
-                match (is_rle, palette_size) {
-                    (false, 0) => {
-                        // True Color pixels
-                        for _ in 0..pixel_count {
-                            copy_true_color(
-                                &mut reader,
-                                &mut pixels,
-                                alpha_at_first,
-                                compressed_bpp,
-                                bpp,
-                            )?
-                        }
-                    }
-                    (false, 1) => {
-                        // Color fill
-                        for _ in 0..pixel_count {
-                            copy_indexed(&palette, &mut pixels, bpp, 0)
-                        }
-                    }
-                    (false, 2..=16) => {
-                        // Indexed pixels
-                        let bits_per_index = match palette_size {
-                            2 => 1,
-                            3..=4 => 2,
-                            5..=16 => 4,
-                            _ => unreachable!(),
-                        };
-                        let mut encoded = reader.read_u8()?;
-                        let mask = (1 << bits_per_index) - 1;
-
-                        for y in 0..height {
-                            let mut shift = 8 - bits_per_index;
-                            for _ in 0..width {
-                                if shift < 0 {
-                                    shift = 8 - bits_per_index;
-                                    encoded = reader.read_u8()?;
-                                }
-                                let idx = (encoded >> shift) & mask;
-
-                                copy_indexed(&palette, &mut pixels, bpp, idx);
-                                shift -= bits_per_index;
-                            }
-                            if shift < 8 - bits_per_index && y < height - 1 {
-                                encoded = reader.read_u8()?;
-                            }
-                        }
-                    }
-                    (true, 0) => {
-                        // True Color RLE
-                        let mut count = 0;
-                        let mut pixel = Vec::new();
-                        while count < pixel_count {
-                            pixel.truncate(0);
-                            copy_true_color(
-                                &mut reader,
-                                &mut pixel,
-                                alpha_at_first,
-                                compressed_bpp,
-                                bpp,
-                            )?;
-                            let run_length = read_run_length(&mut reader)?;
-                            for _ in 0..run_length {
-                                pixels.extend(&pixel)
-                            }
-                            count += run_length;
-                        }
-                    }
-                    (true, 2..=127) => {
-                        // Indexed RLE
-                        let mut count = 0;
-                        while count < pixel_count {
-                            let control = reader.read_u8()?;
-                            let longer_than_one = control & 0x80 > 0;
-                            let index = control & 0x7f;
-                            let run_length = if longer_than_one {
-                                read_run_length(&mut reader)?
-                            } else {
-                                1
-                            };
-                            for _ in 0..run_length {
-                                copy_indexed(&palette, &mut pixels, bpp, index);
+                self.window.fill(decompressor, input, MAX_TILE).await?;
+                let mut reader = self.window.inflated();
+                let available = reader.len();
+                let pixels =
+                    decode_tile(&mut reader, tile, &mut palette, width, height).map_err(|e| {
+                        match e.downcast_ref::<std::io::Error>() {
+                            // a tile never exceeds the window
+                            Some(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                                error!("ZRLE tile overruns the data");
+                                VncError::InvalidImageData.into()
                             }
-                            count += run_length;
+                            _ => e,
                         }
-                    }
-                    (x, y) => {
-                        error!("ZRLE subencoding error {:?}", (x, y));
-                        return Err(VncError::InvalidImageData.into());
-                    }
-                }
+                    })?;
+                let used = available - reader.len();
+                self.window.consume(used);
+
+                let pixels = to_output(pixels, format, self.options.byte_order);
                 output
                     .send(VncEvent::RawImage(
                         Rect {
@@ -234,8 +341,119 @@ impl Decoder {
             y += height;
         }
 
-        self.decompressor = Some(reader.into_inner()?);
+        self.window.finish(decompressor, input).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::io::Write;
+
+    /// A 2x1 rect with a 3 colors palette, whose first index overflows
+    fn overflowing_rect() -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&[3, 1, 1, 1, 2, 2, 2, 3, 3, 3, 0b1101_0000])
+            .unwrap();
+        encoder.flush().unwrap();
+        let compressed = encoder.get_ref();
+        let mut data = (compressed.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(compressed);
+        data
+    }
+
+    async fn decode(strictness: DecodeStrictness) -> Result<Vec<u8>> {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let mut decoder = Decoder::new(ImageOptions {
+            strictness,
+            ..Default::default()
+        });
+        let data = overflowing_rect();
+        decoder
+            .decode(&PixelFormat::bgra(), &rect, &mut data.as_slice(), &tx)
+            .await?;
+        let Some(VncEvent::RawImage(_, pixels)) = rx.recv().await else {
+            panic!("No image");
+        };
+        Ok(pixels)
+    }
+
+    #[tokio::test]
+    async fn test_stream_tiles() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 130,
+            height: 70,
+        };
+        let pixel = |x: usize, y: usize| [x as u8, y as u8, (x ^ y) as u8];
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        for (ty, height) in [(0, 64), (64, 6)] {
+            for (tx, width) in [(0, 64), (64, 64), (128, 2)] {
+                // raw CPIXELs
+                encoder.write_all(&[0]).unwrap();
+                for y in ty..ty + height {
+                    for x in tx..tx + width {
+                        encoder.write_all(&pixel(x, y)).unwrap();
+                    }
+                }
+            }
+        }
+        encoder.flush().unwrap();
+        let mut data = (encoder.get_ref().len() as u32).to_be_bytes().to_vec();
+        data.append(encoder.get_mut());
+        // followed by a rect of an unknown subencoding, and the next message
+        encoder.write_all(&[17, 0, 0, 0]).unwrap();
+        encoder.flush().unwrap();
+        data.extend_from_slice(&(encoder.get_ref().len() as u32).to_be_bytes());
+        data.append(encoder.get_mut());
+        data.push(0xab);
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+        let mut decoder = Decoder::new(ImageOptions::default());
+        let mut input = data.as_slice();
+        decoder
+            .decode(&PixelFormat::bgra(), &rect, &mut input, &tx)
+            .await
+            .unwrap();
+        let mut tiles = 0;
+        while let Ok(VncEvent::RawImage(tile, pixels)) = rx.try_recv() {
+            for (i, color) in pixels.chunks(4).enumerate() {
+                let (x, y) = (
+                    tile.x as usize + i % tile.width as usize,
+                    tile.y as usize + i / tile.width as usize,
+                );
+                assert_eq!(color[..3], pixel(x, y));
+            }
+            tiles += 1;
+        }
+        assert_eq!(tiles, 6);
+
+        let e = decoder
+            .decode(&PixelFormat::bgra(), &rect, &mut input, &tx)
+            .await
+            .unwrap_err();
+        assert!(matches!(e.downcast_ref(), Some(VncError::InvalidImageData)));
+        assert_eq!(input, [0xab]);
+    }
+
+    #[tokio::test]
+    async fn test_palette_index_overflow() {
+        assert!(decode(DecodeStrictness::Strict).await.is_err());
+        // clamped to the last color
+        assert_eq!(
+            decode(DecodeStrictness::Lenient).await.unwrap(),
+            [3, 3, 3, 255, 2, 2, 2, 255]
+        );
+    }
+}
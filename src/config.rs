@@ -4,23 +4,398 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// All supported vnc encodings
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum VncEncoding {
     Raw = 0,
     CopyRect = 1,
-    // Rre = 2,
-    // Hextile = 5,
+    Rre = 2,
+    /// Only encoded by [crate::server::VncServer], the client can't decode it
+    ///
+    #[cfg(feature = "server")]
+    Hextile = 5,
+    Zlib = 6,
+    /// The UltraVNC Ultra encoding, Ultra2 is not supported
+    ///
+    #[cfg(feature = "ultra")]
+    Ultra = 9,
     Tight = 7,
     Trle = 15,
     Zrle = 16,
+    TightPng = -260,
     CursorPseudo = -239,
+    /// Generates [crate::VncEvent::SetAlphaCursor] with the full alpha channel
+    ///
+    CursorWithAlphaPseudo = -314,
+    /// The two colors cursor of X servers, generates [crate::VncEvent::SetAlphaCursor] as well
+    ///
+    XCursorPseudo = -240,
+    /// Generates [crate::VncEvent::PointerPosition] when the server moves the pointer
+    ///
+    PointerPosPseudo = -232,
+    /// Required to send the scancodes of [crate::X11Event::ExtendedKeyEvent] to QEMU/KVM
+    ///
+    QemuExtendedKeyEventPseudo = -258,
+    /// Generates [crate::VncEvent::LedState] when the lock keys of the guest change
+    ///
+    QemuLedStatePseudo = -261,
+    /// The General Input Interface, required by the [crate::X11Event::GiiCreateDevice] of tablets and pens
+    ///
+    GiiPseudo = -305,
+    /// The power control of the virtual machines, required by [crate::X11Event::Xvp]
+    ///
+    XvpPseudo = -309,
     DesktopSizePseudo = -223,
+    /// Required by [crate::VncClient::enable_continuous_updates]
+    ///
+    ContinuousUpdatesPseudo = -313,
+    /// Generates [crate::VncEvent::ExtendedDesktopSize], required by [crate::VncClient::set_desktop_size]
+    ///
+    ExtendedDesktopSizePseudo = -308,
+    JpegQualityLevel0Pseudo = -32,
+    JpegQualityLevel1Pseudo = -31,
+    JpegQualityLevel2Pseudo = -30,
+    JpegQualityLevel3Pseudo = -29,
+    JpegQualityLevel4Pseudo = -28,
+    JpegQualityLevel5Pseudo = -27,
+    JpegQualityLevel6Pseudo = -26,
+    JpegQualityLevel7Pseudo = -25,
+    JpegQualityLevel8Pseudo = -24,
+    JpegQualityLevel9Pseudo = -23,
+    CompressionLevel0Pseudo = -256,
+    CompressionLevel1Pseudo = -255,
+    CompressionLevel2Pseudo = -254,
+    CompressionLevel3Pseudo = -253,
+    CompressionLevel4Pseudo = -252,
+    CompressionLevel5Pseudo = -251,
+    CompressionLevel6Pseudo = -250,
+    CompressionLevel7Pseudo = -249,
+    CompressionLevel8Pseudo = -248,
+    CompressionLevel9Pseudo = -247,
+}
+
+impl VncEncoding {
+    /// Whether the encoding is a pseudo encoding, which declares a capability of the client
+    ///
+    /// rather than a way to encode the pixels
+    ///
+    pub fn is_pseudo(&self) -> bool {
+        // TightPng is a real encoding despite its negative number
+        (*self as i32) < 0 && *self != VncEncoding::TightPng
+    }
+
+    /// Whether the encoding is one of the jpeg quality level pseudo encodings
+    ///
+    /// Tight & TightPng servers only send jpeg rects if one of them is informed
+    ///
+    pub fn is_jpeg_quality_level(&self) -> bool {
+        (VncEncoding::JpegQualityLevel0Pseudo as i32..=VncEncoding::JpegQualityLevel9Pseudo as i32)
+            .contains(&(*self as i32))
+    }
+
+    /// Whether the encoding is one of the compression level pseudo encodings
+    ///
+    pub fn is_compression_level(&self) -> bool {
+        (VncEncoding::CompressionLevel0Pseudo as i32..=VncEncoding::CompressionLevel9Pseudo as i32)
+            .contains(&(*self as i32))
+    }
+}
+
+/// How the desktop name sent by the server is decoded
+///
+/// The RFC doesn't specify its encoding, most servers send UTF-8
+///
+/// while some send locale-encoded bytes
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamePolicy {
+    /// Decode as UTF-8, replacing invalid sequences with `U+FFFD`
+    ///
+    #[default]
+    Lossy,
+    /// Decode as Latin-1
+    ///
+    Latin1,
+    /// Decode as UTF-8, fail the connection if it is not valid
+    ///
+    Strict,
+}
+
+impl NamePolicy {
+    pub(crate) fn decode(&self, bytes: &[u8]) -> Result<String> {
+        match self {
+            NamePolicy::Lossy => Ok(String::from_utf8_lossy(bytes).into_owned()),
+            NamePolicy::Latin1 => Ok(bytes.iter().map(|b| *b as char).collect()),
+            NamePolicy::Strict => Ok(String::from_utf8(bytes.to_vec())?),
+        }
+    }
+}
+
+/// Byte order of the pixels delivered by [crate::VncEvent::RawImage] and [crate::VncEvent::SetCursor]
+///
+/// Independent of the byte order on the wire, which is described by the negotiated [PixelFormat]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+    /// Keep the byte order of the wire
+    ///
+    #[default]
+    Wire,
+    /// Least significant byte first
+    ///
+    LittleEndian,
+    /// Most significant byte first
+    ///
+    BigEndian,
+}
+
+impl ByteOrder {
+    /// Whether the bytes of the pixels in `format` have to be reversed
+    ///
+    pub(crate) fn swaps(&self, format: &PixelFormat) -> bool {
+        match self {
+            ByteOrder::Wire => false,
+            ByteOrder::LittleEndian => format.big_endian_flag != 0,
+            ByteOrder::BigEndian => format.big_endian_flag == 0,
+        }
+    }
+}
+
+/// How the decoders handle the malformed rects they can recover from
+///
+/// e.g. a palette index exceeding the palette size sent by a buggy server
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodeStrictness {
+    /// Fail the rect with [crate::VncError::InvalidImageData]
+    ///
+    #[default]
+    Strict,
+    /// Clamp the palette index to the last palette entry
+    ///
+    Lenient,
+}
+
+/// How the remote desktop is oriented on the local display, e.g. a rotated kiosk panel
+///
+/// The rotations are clockwise
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transform {
+    #[default]
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    /// Mirror left and right
+    ///
+    FlipHorizontal,
+    /// Mirror top and bottom
+    ///
+    FlipVertical,
 }
 
-impl From<u32> for VncEncoding {
-    fn from(num: u32) -> Self {
-        unsafe { std::mem::transmute(num) }
+/// How the pointer events are queued when the connection is congested
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PointerPolicy {
+    /// A motion replaces the pending one if no button is pressed or released in between
+    ///
+    /// So that the server won't replay a stale trail of the pointer on slow links
+    ///
+    #[default]
+    DropStale,
+    /// Send every pointer event
+    ///
+    KeepAll,
+}
+
+/// How much of the protocol is logged at the info level, see [crate::VncClient::set_protocol_logging]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum ProtocolLogging {
+    #[default]
+    Off,
+    /// A line per message of both the directions
+    ///
+    Messages,
+    /// Besides the messages, a line per rect of the framebuffer updates
+    ///
+    Rects,
+}
+
+/// How the server messages of unknown types are handled
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownMessagePolicy {
+    /// Stop the engine with [crate::VncError::WrongServerMessage]
+    ///
+    #[default]
+    Fail,
+    /// Skip the messages whose [MessageLength] is known, fail on the others
+    ///
+    /// The skipped messages are delivered as [crate::ExtensionEvent::ServerMessage]
+    ///
+    /// The lengths of the xvp, fence & gii extensions are built in,
+    ///
+    /// more can be registered by `set_message_length` on the connector
+    ///
+    Skip,
+}
+
+/// How to find the length of a server message, following its message type
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageLength {
+    /// A fixed number of bytes
+    ///
+    Fixed(usize),
+    /// A big endian length field of `size` (1, 2 or 4) bytes at `offset`,
+    ///
+    /// which is followed by that many bytes
+    ///
+    Prefixed { offset: usize, size: usize },
+}
+
+impl MessageLength {
+    /// The lengths of the extensions which have a message type assigned
+    ///
+    pub(crate) fn well_known(msg_type: u8) -> Option<Self> {
+        match msg_type {
+            // ServerFence: padding, flags, length & payload
+            248 => Some(MessageLength::Prefixed { offset: 7, size: 1 }),
+            // xvp: padding, version & code
+            250 => Some(MessageLength::Fixed(3)),
+            // gii: endian & sub type, length & payload
+            253 => Some(MessageLength::Prefixed { offset: 1, size: 2 }),
+            _ => None,
+        }
+    }
+}
+
+/// How the Tight jpeg rects are handled
+///
+/// By default, the jpeg data is delivered as [crate::VncEvent::JpegImage] without being decoded
+///
+/// Consumers which cannot render jpeg (e.g. headless ones) may choose another policy
+///
+/// so that they won't silently miss screen regions
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JpegPolicy {
+    /// Emit [crate::VncEvent::JpegImage] as is
+    ///
+    #[default]
+    Emit,
+    /// Drop the jpeg rects with a warning
+    ///
+    Drop,
+    /// Decode the jpeg rects inside of the crate
+    ///
+    /// and deliver them as [crate::VncEvent::RawImage] in the negotiated pixel format
+    ///
+    /// with the byte order & the opaque alpha of the other encodings
+    ///
+    #[cfg(feature = "jpeg")]
+    Decode,
+    /// Never inform the jpeg quality level pseudo encodings to the server
+    ///
+    /// So that a well-behaved server won't send any jpeg rects,
+    ///
+    /// the session fails if the server sends a jpeg rect anyway
+    ///
+    Disable,
+}
+
+/// What the encodings informed to the server are tuned for
+///
+/// Switched at runtime by [crate::VncClient::prefer_text_mode] & [crate::VncClient::prefer_video_mode]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentMode {
+    /// The encodings as configured
+    ///
+    #[default]
+    Mixed,
+    /// Crisp text, lossless with the highest compression
+    ///
+    /// The jpeg quality levels are withdrawn
+    ///
+    Text,
+    /// Motion, Tight first with jpeg and the lowest compression
+    ///
+    /// The configured jpeg quality level is kept, level 5 is used if none
+    ///
+    Video,
+}
+
+/// How the pixels of a [crate::FrameDelta] are delivered
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeltaCompression {
+    /// The pixels as decoded
+    ///
+    #[default]
+    None,
+    /// Recompress the pixels with zlib at the level (0-9)
+    ///
+    /// All the rects of the session share one zlib stream which is flushed after each rect,
+    ///
+    /// so they have to be inflated in order by the same decompressor, like the Zlib encoding of VNC
+    ///
+    Zlib(u32),
+}
+
+impl TryFrom<u32> for VncEncoding {
+    type Error = VncError;
+    fn try_from(num: u32) -> Result<Self, Self::Error> {
+        let encoding = match num as i32 {
+            0 => VncEncoding::Raw,
+            1 => VncEncoding::CopyRect,
+            2 => VncEncoding::Rre,
+            #[cfg(feature = "server")]
+            5 => VncEncoding::Hextile,
+            6 => VncEncoding::Zlib,
+            #[cfg(feature = "ultra")]
+            9 => VncEncoding::Ultra,
+            7 => VncEncoding::Tight,
+            15 => VncEncoding::Trle,
+            16 => VncEncoding::Zrle,
+            -260 => VncEncoding::TightPng,
+            -239 => VncEncoding::CursorPseudo,
+            -314 => VncEncoding::CursorWithAlphaPseudo,
+            -240 => VncEncoding::XCursorPseudo,
+            -232 => VncEncoding::PointerPosPseudo,
+            -258 => VncEncoding::QemuExtendedKeyEventPseudo,
+            -261 => VncEncoding::QemuLedStatePseudo,
+            -305 => VncEncoding::GiiPseudo,
+            -309 => VncEncoding::XvpPseudo,
+            -223 => VncEncoding::DesktopSizePseudo,
+            -313 => VncEncoding::ContinuousUpdatesPseudo,
+            -308 => VncEncoding::ExtendedDesktopSizePseudo,
+            -32 => VncEncoding::JpegQualityLevel0Pseudo,
+            -31 => VncEncoding::JpegQualityLevel1Pseudo,
+            -30 => VncEncoding::JpegQualityLevel2Pseudo,
+            -29 => VncEncoding::JpegQualityLevel3Pseudo,
+            -28 => VncEncoding::JpegQualityLevel4Pseudo,
+            -27 => VncEncoding::JpegQualityLevel5Pseudo,
+            -26 => VncEncoding::JpegQualityLevel6Pseudo,
+            -25 => VncEncoding::JpegQualityLevel7Pseudo,
+            -24 => VncEncoding::JpegQualityLevel8Pseudo,
+            -23 => VncEncoding::JpegQualityLevel9Pseudo,
+            -256 => VncEncoding::CompressionLevel0Pseudo,
+            -255 => VncEncoding::CompressionLevel1Pseudo,
+            -254 => VncEncoding::CompressionLevel2Pseudo,
+            -253 => VncEncoding::CompressionLevel3Pseudo,
+            -252 => VncEncoding::CompressionLevel4Pseudo,
+            -251 => VncEncoding::CompressionLevel5Pseudo,
+            -250 => VncEncoding::CompressionLevel6Pseudo,
+            -249 => VncEncoding::CompressionLevel7Pseudo,
+            -248 => VncEncoding::CompressionLevel8Pseudo,
+            -247 => VncEncoding::CompressionLevel9Pseudo,
+            unknown => return Err(VncError::UnknownEncoding(unknown)),
+        };
+        std::result::Result::Ok(encoding)
     }
 }
 
@@ -46,6 +421,8 @@ impl From<[u8; 12]> for VncVersion {
             b"RFB 003.003\n" => VncVersion::RFB33,
             b"RFB 003.007\n" => VncVersion::RFB37,
             b"RFB 003.008\n" => VncVersion::RFB38,
+            // the macOS Screen Sharing, which speaks 3.8 with its own security types
+            b"RFB 003.889\n" => VncVersion::RFB38,
             // https://www.rfc-editor.org/rfc/rfc6143#section-7.1.1
             //  Other version numbers are reported by some servers and clients,
             //  but should be interpreted as 3.3 since they do not implement the
@@ -65,14 +442,90 @@ impl From<VncVersion> for &[u8; 12] {
     }
 }
 
+/// How the version string sent by the server is interpreted, see [VersionPolicy::resolve]
+///
+/// The RFC maps the unknown versions to 3.3, which breaks the handshake with the servers
+///
+/// speaking 3.8 under a nonstandard version, e.g. `RFB 004.001` of RealVNC
+///
+#[derive(Debug, Clone, Copy, Default)]
+pub enum VersionPolicy {
+    /// As the RFC, the unknown versions are interpreted as [VncVersion::RFB33]
+    ///
+    /// Except `RFB 003.889` of macOS, which is known to speak 3.8
+    ///
+    #[default]
+    Rfc,
+    /// The versions newer than 3.8 are interpreted as [VncVersion::RFB38],
+    ///
+    /// the older unknown ones as [VncVersion::RFB33]
+    ///
+    /// A version string which is not of the `RFB xxx.yyy` form fails the handshake
+    ///
+    MapTo38,
+    /// Only `RFB 003.003`, `RFB 003.007` & `RFB 003.008` are accepted, the others fail the handshake
+    ///
+    Strict,
+    /// Decided by the callback, with the version string without the line break
+    ///
+    /// `None` fails the handshake
+    ///
+    Custom(fn(&str) -> Option<VncVersion>),
+}
+
+impl VersionPolicy {
+    /// The version spoken by the server of the version string `raw`, `None` if it is rejected
+    ///
+    pub fn resolve(&self, raw: &[u8; 12]) -> Option<VncVersion> {
+        let standard = match raw {
+            b"RFB 003.003\n" => Some(VncVersion::RFB33),
+            b"RFB 003.007\n" => Some(VncVersion::RFB37),
+            b"RFB 003.008\n" => Some(VncVersion::RFB38),
+            _ => None,
+        };
+        match self {
+            VersionPolicy::Rfc => Some(VncVersion::from(*raw)),
+            VersionPolicy::MapTo38 => {
+                let (major, minor) = VncVersion::parse(raw)?;
+                Some(match standard {
+                    Some(version) => version,
+                    None if (major, minor) > (3, 8) => VncVersion::RFB38,
+                    None => VncVersion::RFB33,
+                })
+            }
+            VersionPolicy::Strict => standard,
+            VersionPolicy::Custom(callback) => {
+                callback(String::from_utf8_lossy(raw).trim_end_matches('\n'))
+            }
+        }
+    }
+}
+
 impl VncVersion {
-    pub(crate) async fn read<S>(reader: &mut S) -> Result<Self>
+    /// The numbers of a `RFB xxx.yyy\n` version string
+    ///
+    fn parse(raw: &[u8; 12]) -> Option<(u16, u16)> {
+        let number = |digits: &[u8]| {
+            digits
+                .iter()
+                .all(u8::is_ascii_digit)
+                .then(|| std::str::from_utf8(digits).ok()?.parse().ok())
+                .flatten()
+        };
+        if &raw[..4] != b"RFB " || raw[7] != b'.' || raw[11] != b'\n' {
+            return None;
+        }
+        Some((number(&raw[4..7])?, number(&raw[8..11])?))
+    }
+
+    /// Read the raw version string, see [VncVersion::from] for how it is interpreted
+    pub(crate) async fn read<S>(reader: &mut S) -> Result<[u8; 12]>
     where
         S: AsyncRead + Unpin,
     {
         let mut buffer = [0_u8; 12];
         reader.read_exact(&mut buffer).await?;
-        Ok(buffer.into())
+        Ok(buffer)
     }
 
     pub(crate) async fn write<S>(self, writer: &mut S) -> Result<()>
@@ -105,7 +558,7 @@ impl VncVersion {
 /// | 3            |              | padding         |
 /// +--------------+--------------+-----------------+
 /// ```
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct PixelFormat {
     /// the number of bits used for each pixel value on the wire
     ///
@@ -240,6 +693,152 @@ impl PixelFormat {
         }
     }
 
+    /// The 16 bits true color format with 5 bits of red & blue and 6 bits of green
+    ///
+    /// Which halves the bandwidth of the 32 bits formats
+    ///
+    pub fn rgb565() -> PixelFormat {
+        Self {
+            bits_per_pixel: 16,
+            depth: 16,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            ..Default::default()
+        }
+    }
+
+    /// The 8 bits true color format with 3 bits of red & green and 2 bits of blue
+    ///
+    /// For the low bandwidth connections, the pixels are expanded to 32 bits when delivered
+    ///
+    /// by [PixelFormat::output]
+    ///
+    pub fn bgr233() -> PixelFormat {
+        Self {
+            bits_per_pixel: 8,
+            depth: 8,
+            red_max: 7,
+            green_max: 7,
+            blue_max: 3,
+            red_shift: 0,
+            green_shift: 3,
+            blue_shift: 6,
+            ..Default::default()
+        }
+    }
+
+    /// The format of the pixels delivered by the events, when `self` is used on the wire
+    ///
+    /// The 8 bits true color formats are expanded to [PixelFormat::bgra] or [PixelFormat::rgba]
+    ///
+    /// (whichever keeps the channel order), the others are delivered as is
+    ///
+    pub fn output(&self) -> PixelFormat {
+        if !self.is_8bit_true_color() {
+            *self
+        } else if self.red_shift < self.blue_shift {
+            PixelFormat::rgba()
+        } else {
+            PixelFormat::bgra()
+        }
+    }
+
+    /// The [PixelFormat::output] pixels of all the 256 pixels of an 8 bits true color format
+    ///
+    pub(crate) fn expansion(&self) -> Option<[[u8; 4]; 256]> {
+        if !self.is_8bit_true_color() {
+            return None;
+        }
+        let output = self.output();
+        let scale = |pixel: u8, max: u16, shift: u8| {
+            let value = (pixel as u32 >> shift) & max as u32;
+            value * 255 / (max as u32).max(1)
+        };
+        let mut table = [[0; 4]; 256];
+        for (pixel, expanded) in table.iter_mut().enumerate() {
+            let pixel = pixel as u8;
+            let rgb = scale(pixel, self.red_max, self.red_shift) << output.red_shift
+                | scale(pixel, self.green_max, self.green_shift) << output.green_shift
+                | scale(pixel, self.blue_max, self.blue_shift) << output.blue_shift;
+            // the unused byte of both outputs is opaque, like the pixels decoded by tight
+            *expanded = (rgb | 0xff << 24).to_le_bytes();
+        }
+        Some(table)
+    }
+
+    /// Pick the closest format that all of the `encodings` can be decoded into
+    ///
+    /// Tight can only produce true color pixels, in any byte order & channel layout,
+    ///
+    /// the cursor pseudo encoding cannot produce the 16 bits ones either,
+    ///
+    /// so the format falls back to [PixelFormat::bgra] or [PixelFormat::rgba]
+    /// (whichever keeps the channel order) if they are selected
+    ///
+    pub(crate) fn negotiate(&self, encodings: &[VncEncoding]) -> PixelFormat {
+        let tight = encodings
+            .iter()
+            .any(|e| matches!(e, VncEncoding::Tight | VncEncoding::TightPng));
+        let cursor = encodings.contains(&VncEncoding::CursorPseudo);
+        let workable = self.is_32bit_true_color()
+            || self.is_8bit_true_color()
+            || (!cursor && self.is_16bit_true_color());
+        if !(tight || cursor) || workable {
+            *self
+        } else if self.red_shift < self.blue_shift {
+            PixelFormat::rgba()
+        } else {
+            PixelFormat::bgra()
+        }
+    }
+
+    /// The bits covered by the channels
+    pub(crate) fn pixel_mask(&self) -> u32 {
+        (self.red_max as u32) << self.red_shift
+            | (self.green_max as u32) << self.green_shift
+            | (self.blue_max as u32) << self.blue_shift
+    }
+
+    /// Pack the RGBA pixels, 4 bytes each, into the pixels of `self` on the wire
+    ///
+    /// The channels are scaled to the maximums of the format, which must be a true color one
+    ///
+    #[cfg(feature = "server")]
+    pub(crate) fn pack(&self, rgba: &[u8]) -> Vec<u8> {
+        let bpp = self.bits_per_pixel as usize / 8;
+        let scale = |value: u8, max: u16| (value as u32 * max as u32 + 127) / 255;
+        let mut pixels = Vec::with_capacity(rgba.len() / 4 * bpp);
+        for pixel in rgba.chunks_exact(4) {
+            let value = scale(pixel[0], self.red_max) << self.red_shift
+                | scale(pixel[1], self.green_max) << self.green_shift
+                | scale(pixel[2], self.blue_max) << self.blue_shift;
+            match (bpp, self.big_endian_flag > 0) {
+                (1, _) => pixels.push(value as u8),
+                (2, true) => pixels.extend_from_slice(&(value as u16).to_be_bytes()),
+                (2, false) => pixels.extend_from_slice(&(value as u16).to_le_bytes()),
+                (_, true) => pixels.extend_from_slice(&value.to_be_bytes()),
+                (_, false) => pixels.extend_from_slice(&value.to_le_bytes()),
+            }
+        }
+        pixels
+    }
+
+    fn is_8bit_true_color(&self) -> bool {
+        self.bits_per_pixel == 8 && self.true_color_flag > 0
+    }
+
+    fn is_16bit_true_color(&self) -> bool {
+        self.bits_per_pixel == 16 && self.true_color_flag > 0
+    }
+
+    fn is_32bit_true_color(&self) -> bool {
+        self.bits_per_pixel == 32 && self.true_color_flag > 0
+    }
+
     pub(crate) async fn read<S>(reader: &mut S) -> Result<Self>
     where
         S: AsyncRead + Unpin,
@@ -251,3 +850,129 @@ impl PixelFormat {
             .with_context(|| "Invalid pixel format from the reader")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{NamePolicy, PixelFormat, VersionPolicy, VncEncoding, VncVersion};
+
+    #[test]
+    fn test_name_policy() {
+        let name = b"caf\xe9";
+        assert_eq!(NamePolicy::Lossy.decode(name).unwrap(), "caf\u{fffd}");
+        assert_eq!(NamePolicy::Latin1.decode(name).unwrap(), "caf\u{e9}");
+        assert!(NamePolicy::Strict.decode(name).is_err());
+        assert_eq!(NamePolicy::Strict.decode(b"cafe").unwrap(), "cafe");
+    }
+
+    #[test]
+    fn test_version_policy() {
+        let cases: [(&[u8; 12], [Option<VncVersion>; 3]); 6] = [
+            (b"RFB 003.008\n", [Some(VncVersion::RFB38); 3]),
+            (b"RFB 003.007\n", [Some(VncVersion::RFB37); 3]),
+            (
+                b"RFB 003.889\n",
+                [Some(VncVersion::RFB38), Some(VncVersion::RFB38), None],
+            ),
+            (
+                b"RFB 004.001\n",
+                [Some(VncVersion::RFB33), Some(VncVersion::RFB38), None],
+            ),
+            (
+                b"RFB 003.006\n",
+                [Some(VncVersion::RFB33), Some(VncVersion::RFB33), None],
+            ),
+            (b"HTTP/1.1 400", [Some(VncVersion::RFB33), None, None]),
+        ];
+        for (raw, [rfc, map_to_38, strict]) in cases {
+            assert_eq!(VersionPolicy::Rfc.resolve(raw), rfc);
+            assert_eq!(VersionPolicy::MapTo38.resolve(raw), map_to_38);
+            assert_eq!(VersionPolicy::Strict.resolve(raw), strict);
+        }
+
+        let custom =
+            VersionPolicy::Custom(|raw| (raw == "RFB 003.006").then_some(VncVersion::RFB38));
+        assert_eq!(custom.resolve(b"RFB 003.006\n"), Some(VncVersion::RFB38));
+        assert_eq!(custom.resolve(b"RFB 003.008\n"), None);
+    }
+
+    #[test]
+    fn test_negotiate_pixel_format() {
+        let rgb565 = PixelFormat::rgb565();
+
+        // zrle & raw are fine with any format
+        let encodings = [VncEncoding::Zrle, VncEncoding::Raw];
+        assert_eq!(rgb565.negotiate(&encodings), rgb565);
+        assert_eq!(
+            PixelFormat::rgba().negotiate(&encodings),
+            PixelFormat::rgba()
+        );
+
+        // tight takes 16 bits true color
+        let encodings = [VncEncoding::Tight, VncEncoding::Raw];
+        assert_eq!(rgb565.negotiate(&encodings), rgb565);
+        let indexed = PixelFormat {
+            true_color_flag: 0,
+            ..rgb565
+        };
+        assert_eq!(indexed.negotiate(&encodings), PixelFormat::bgra());
+
+        // the cursor needs 32 bits true color and keeps the channel order
+        let encodings = [VncEncoding::Tight, VncEncoding::CursorPseudo];
+        assert_eq!(rgb565.negotiate(&encodings), PixelFormat::bgra());
+        assert_eq!(
+            PixelFormat::rgba().negotiate(&encodings),
+            PixelFormat::rgba()
+        );
+        let big_endian_rgba = PixelFormat {
+            big_endian_flag: 1,
+            ..PixelFormat::rgba()
+        };
+        assert_eq!(big_endian_rgba.negotiate(&encodings), big_endian_rgba);
+        let indexed = PixelFormat {
+            true_color_flag: 0,
+            ..PixelFormat::rgba()
+        };
+        assert_eq!(indexed.negotiate(&encodings), PixelFormat::rgba());
+
+        // bgr233 is expanded by all of the decoders
+        let bgr233 = PixelFormat::bgr233();
+        assert_eq!(bgr233.negotiate(&encodings), bgr233);
+        assert_eq!(bgr233.output(), PixelFormat::rgba());
+        assert_eq!(rgb565.output(), rgb565);
+    }
+
+    #[test]
+    fn test_bgr233_expansion() {
+        let table = PixelFormat::bgr233().expansion().unwrap();
+        assert_eq!(table[0], [0, 0, 0, 255]);
+        assert_eq!(table[0b00_000_111], [255, 0, 0, 255]);
+        assert_eq!(table[0b00_111_000], [0, 255, 0, 255]);
+        assert_eq!(table[0b11_000_000], [0, 0, 255, 255]);
+        assert_eq!(table[0b01_100_010], [72, 145, 85, 255]);
+        assert!(PixelFormat::bgra().expansion().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn test_pack() {
+        let rgba = [1, 2, 3, 255, 255, 255, 255, 0];
+        assert_eq!(
+            PixelFormat::bgra().pack(&rgba),
+            [3, 2, 1, 0, 255, 255, 255, 0]
+        );
+        assert_eq!(
+            PixelFormat::rgba().pack(&rgba),
+            [1, 2, 3, 0, 255, 255, 255, 0]
+        );
+        assert_eq!(PixelFormat::rgb565().pack(&rgba[4..]), [0xff, 0xff]);
+        let big_endian = PixelFormat {
+            big_endian_flag: 1,
+            ..PixelFormat::rgb565()
+        };
+        assert_eq!(big_endian.pack(&[255, 0, 0, 0]), [0xf8, 0]);
+        assert_eq!(
+            PixelFormat::bgr233().pack(&[255, 0, 255, 0]),
+            [0b11_000_111]
+        );
+    }
+}
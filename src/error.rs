@@ -17,8 +17,75 @@ pub enum VncError {
     WrongPixelFormat,
     #[error("Unkonw server message")]
     WrongServerMessage,
+    #[error("Unknown client message")]
+    WrongClientMessage,
+    #[error("Unknown vnc encoding: {0}")]
+    UnknownEncoding(i32),
     #[error("Image data cannot be decoded correctly")]
     InvalidImageData,
+    #[error("Framebuffer update with {0} rects exceeds the limit")]
+    TooManyRects(u16),
+    #[error("Framebuffer update timed out after {0} of {1} rects")]
+    UpdateTimeout(u16, u16),
+    #[error("The server is not responding in time")]
+    Timeout,
+    #[error("Disconnected: {0}")]
+    Disconnected(DisconnectReason),
+    #[error("Client is not running")]
+    ClientNotRunning,
     #[error("Vnc Error with message: {0}")]
     Custom(String),
 }
+
+/// Why the transport under the session is closed
+///
+/// Attached as the context of the engine error, i.e. `error.downcast_ref::<VncError>()`
+///
+/// returns [VncError::Disconnected] while the underlying io error is still reachable
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The server closed the stream
+    ///
+    Closed,
+    /// An io error with a detail from the transport,
+    ///
+    /// e.g. the alert of a TLS wrapper or the close frame of a WebSocket wrapper
+    ///
+    Transport(String),
+    /// A bare io error
+    ///
+    Io(std::io::ErrorKind),
+    /// The server closed the stream right after the ClientInit message,
+    ///
+    /// which is how the servers keep another client's exclusive access, or refuse
+    ///
+    /// the exclusive access required by [crate::VncConnector::allow_shared] while others are connected
+    ///
+    /// Reconnecting with the shared access may succeed, yet the displacement during the session
+    ///
+    /// can't be told from the other closes, see [DisconnectReason::Closed]
+    ///
+    DisplacedByOtherClient,
+}
+
+impl From<&std::io::Error> for DisconnectReason {
+    fn from(e: &std::io::Error) -> Self {
+        match e.get_ref() {
+            Some(detail) => DisconnectReason::Transport(detail.to_string()),
+            None => DisconnectReason::Io(e.kind()),
+        }
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Closed => write!(f, "closed by the server"),
+            DisconnectReason::Transport(detail) => write!(f, "{}", detail),
+            DisconnectReason::Io(kind) => write!(f, "{}", kind),
+            DisconnectReason::DisplacedByOtherClient => write!(f, "displaced by another client"),
+        }
+    }
+}
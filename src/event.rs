@@ -1,9 +1,14 @@
-use crate::PixelFormat;
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+use crate::{ContentMode, PixelFormat, VncEncoding};
 
 type ImageData = Vec<u8>;
 
 /// A rect where the image should be updated
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rect {
     pub x: u16,
     pub y: u16,
@@ -12,7 +17,7 @@ pub struct Rect {
 }
 
 /// Resolution format to resize window
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct Screen {
     pub width: u16,
     pub height: u16,
@@ -27,11 +32,355 @@ impl From<(u16, u16)> for Screen {
     }
 }
 
+/// The remote cursor as a layer of its own, watched with [crate::VncClient::watch_cursor]
+///
+/// So that the cursor can be composited above the framebuffer as soon as it moves,
+///
+/// in the coordinates of the remote screen, i.e. regardless of the [crate::Transform]
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CursorState {
+    pub width: u16,
+    pub height: u16,
+    /// The point of the image at the pointer position
+    ///
+    pub hotspot: (u16, u16),
+    /// Straight (not pre-multiplied) RGBA pixels, empty if the cursor is hidden or not known yet
+    ///
+    pub rgba: Vec<u8>,
+    /// The position of the remote pointer, as with [crate::VncClient::watch_pointer]
+    ///
+    pub position: (u16, u16),
+}
+
+/// A screen of the remote desktop, which may consist of several monitors
+///
+/// Referring to the [ExtendedDesktopSize](https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#extendeddesktopsize-pseudo-encoding) extension
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScreenLayout {
+    pub id: u32,
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    pub flags: u32,
+}
+
+/// A valuator of a [GiiDevice], e.g. the pressure or the tilt of a pen
+///
+/// Referring to the [gii](https://github.com/rfbproto/rfbproto/blob/master/rfbproto.rst#gii-client-message) extension
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GiiValuator {
+    /// Up to 74 bytes
+    ///
+    pub long_name: String,
+    /// Up to 4 bytes
+    ///
+    pub short_name: String,
+    pub range_min: i32,
+    pub range_center: i32,
+    pub range_max: i32,
+    pub si_unit: u32,
+    pub si_add: i32,
+    pub si_mul: i32,
+    pub si_div: i32,
+    pub si_shift: i32,
+}
+
+impl GiiValuator {
+    /// A valuator ranging from `min` to `max` without any SI unit
+    ///
+    pub fn new(long_name: &str, short_name: &str, min: i32, max: i32) -> Self {
+        Self {
+            long_name: long_name.to_string(),
+            short_name: short_name.to_string(),
+            range_min: min,
+            range_center: min,
+            range_max: max,
+            si_unit: 0,
+            si_add: 0,
+            si_mul: 1,
+            si_div: 1,
+            si_shift: 0,
+        }
+    }
+}
+
+/// An input device created by [X11Event::GiiCreateDevice]
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GiiDevice {
+    /// Up to 31 bytes
+    ///
+    pub name: String,
+    pub vendor_id: u32,
+    pub product_id: u32,
+    pub valuators: Vec<GiiValuator>,
+    pub buttons: u32,
+}
+
+/// An event of a [GiiDevice], identified by the origin from [VncEvent::GiiDeviceCreated]
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GiiEvent {
+    /// The absolute values of the valuators from the index `first`
+    ///
+    Valuators {
+        origin: u32,
+        first: u32,
+        values: Vec<i32>,
+    },
+    Button {
+        origin: u32,
+        button: u32,
+        down: bool,
+    },
+}
+
+/// The power control actions of the xvp extension
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum XvpAction {
+    Shutdown = 2,
+    Reboot = 3,
+    Reset = 4,
+}
+
+impl TryFrom<u8> for XvpAction {
+    type Error = u8;
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        match code {
+            2 => Ok(XvpAction::Shutdown),
+            3 => Ok(XvpAction::Reboot),
+            4 => Ok(XvpAction::Reset),
+            code => Err(code),
+        }
+    }
+}
+
+/// Why the desktop is resized, carried by [VncEvent::ExtendedDesktopSize]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeReason {
+    /// Changed by the server itself, or the initial layout
+    ///
+    Server,
+    /// Requested by this client with [crate::VncClient::set_desktop_size]
+    ///
+    ThisClient,
+    /// Requested by another client
+    ///
+    OtherClient,
+    Unknown(u16),
+}
+
+impl From<u16> for ResizeReason {
+    fn from(reason: u16) -> Self {
+        match reason {
+            0 => ResizeReason::Server,
+            1 => ResizeReason::ThisClient,
+            2 => ResizeReason::OtherClient,
+            unknown => ResizeReason::Unknown(unknown),
+        }
+    }
+}
+
+/// The result of a resize request, carried by [VncEvent::ExtendedDesktopSize]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeStatus {
+    Ok,
+    Prohibited,
+    OutOfResources,
+    InvalidLayout,
+    Unknown(u16),
+}
+
+impl From<u16> for ResizeStatus {
+    fn from(status: u16) -> Self {
+        match status {
+            0 => ResizeStatus::Ok,
+            1 => ResizeStatus::Prohibited,
+            2 => ResizeStatus::OutOfResources,
+            3 => ResizeStatus::InvalidLayout,
+            unknown => ResizeStatus::Unknown(unknown),
+        }
+    }
+}
+
+/// The desktop size with its screens, see [VncEvent::ExtendedDesktopSize]
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedDesktopSize {
+    pub reason: ResizeReason,
+    /// Always [ResizeStatus::Ok] unless the reason is [ResizeReason::ThisClient]
+    ///
+    pub status: ResizeStatus,
+    /// The size of the whole framebuffer
+    ///
+    pub screen: Screen,
+    pub screens: Vec<ScreenLayout>,
+}
+
 type SrcRect = Rect;
 type DstRect = Rect;
 
+/// A rect of a [FrameDelta]
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaRect {
+    /// Pixels in the negotiated pixel format, compressed according to [crate::DeltaCompression]
+    ///
+    Pixels(Rect, ImageData),
+    /// Copy the pixels from the second rect to the first
+    ///
+    Copy(DstRect, SrcRect),
+    /// A jpeg image, see [VncEvent::JpegImage]
+    ///
+    Jpeg(Rect, ImageData),
+    /// A png image, see [VncEvent::PngImage]
+    ///
+    Png(Rect, ImageData),
+    /// The pixels have been written to the shared framebuffer, see [VncEvent::Damage]
+    ///
+    Damage(Rect),
+}
+
+impl DeltaRect {
+    /// The region of the framebuffer which is updated
+    ///
+    pub fn rect(&self) -> &Rect {
+        match self {
+            DeltaRect::Pixels(rect, _)
+            | DeltaRect::Copy(rect, _)
+            | DeltaRect::Jpeg(rect, _)
+            | DeltaRect::Png(rect, _)
+            | DeltaRect::Damage(rect) => rect,
+        }
+    }
+}
+
+/// The changes of a framebuffer update, see [VncEvent::FrameDelta]
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameDelta {
+    /// The rects to be applied in order
+    ///
+    pub rects: Vec<DeltaRect>,
+}
+
+impl FrameDelta {
+    /// The damaged regions of the update
+    ///
+    pub fn damage(&self) -> impl Iterator<Item = &Rect> {
+        self.rects.iter().map(DeltaRect::rect)
+    }
+}
+
+/// Settings of [crate::VncClient::set_magnifier]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Magnifier {
+    /// The point of the remote desktop to magnify around
+    ///
+    pub focus: (u16, u16),
+    /// How many times the region is enlarged, at least 1
+    ///
+    pub zoom: u8,
+    /// The size of the magnified output, e.g. the magnifier window
+    ///
+    pub size: (u16, u16),
+}
+
+/// The input of an [InputEcho]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoedInput {
+    /// A key pressed, with its keysym
+    ///
+    Key(u32),
+    /// A pointer button pressed or released at the position, in remote coordinates
+    ///
+    Pointer(u16, u16),
+}
+
+/// The first screen change following an input, see [crate::VncEvent::InputEcho]
+///
+#[derive(Debug, Clone, Copy)]
+pub struct InputEcho {
+    pub input: EchoedInput,
+    /// When the engine sent the input
+    ///
+    pub sent: Instant,
+    /// When the damage was decoded
+    ///
+    pub echoed: Instant,
+    /// The rect of the damage, in remote coordinates
+    ///
+    pub damage: Rect,
+}
+
+impl InputEcho {
+    /// The end-to-end latency of the input
+    ///
+    pub fn latency(&self) -> Duration {
+        self.echoed - self.sent
+    }
+}
+
+/// The events of the protocol extensions, delivered as [VncEvent::Extension]
+///
+/// New extensions add their events here rather than to [VncEvent],
+///
+/// so that the matches on [VncEvent] with a `VncEvent::Extension(_)` arm keep compiling
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtensionEvent {
+    /// A server message of a type that the engine doesn't handle, skipped by [crate::UnknownMessagePolicy::Skip]
+    ///
+    /// The payload is all the bytes following the message type, as described by its [crate::MessageLength]
+    ///
+    ServerMessage { msg_type: u8, payload: Vec<u8> },
+}
+
 /// Events generated by the [crate::VncClient]
 ///
+/// ## Ordering
+///
+/// The events of a framebuffer update are delivered in the order of its rects,
+///
+/// all the events of a rect come before the ones of the next rect, e.g.
+///
+/// ```no_compile
+/// RawImage(a), RawImage(a), Copy(b), SetCursor(c), RawImage(d), UpdateComplete
+/// ```
+///
+/// So applying them in order gives the same result as the server framebuffer, in particular:
+///
+/// * A rect split into several [VncEvent::RawImage] (tiles or bands) is delivered contiguously
+///
+/// * A [VncEvent::Copy] is delivered after the images of the previous rects,
+///   so it copies the updated pixels, and a [VncEvent::CopyBatch] only contains consecutive CopyRects
+///
+/// * A [VncEvent::Magnified] immediately follows the [VncEvent::RawImage] it is scaled from
+///
+/// * A [VncEvent::RectEncoding] precedes the events of its rect, except that the ones of the
+///   CopyRects in a [VncEvent::CopyBatch] precede the whole batch
+///
+/// * [VncEvent::UpdateComplete] is delivered after all the events of the update
+///
+/// The events unrelated to the framebuffer (e.g. [VncEvent::CursorPosition]) may interleave with an update
+///
+/// ## Stability
+///
+/// The events of the protocol extensions are added to [ExtensionEvent] under [VncEvent::Extension],
+///
+/// the variants of [VncEvent] itself are only added along with the core protocol & the engine
+///
 #[non_exhaustive]
 #[derive(Debug, Clone)]
 pub enum VncEvent {
@@ -48,29 +397,81 @@ pub enum VncEvent {
     /// If the [crate::VncEncoding::DesktopSizePseudo] is set
     ///
     SetResolution(Screen),
+    /// The layout of the remote desktop
+    ///
+    /// Will be generated if [crate::VncEncoding::ExtendedDesktopSizePseudo] is set,
+    ///
+    /// when connected, whenever the desktop is resized and in response to [crate::VncClient::set_desktop_size]
+    ///
+    /// A successful resize is preceded by a [VncEvent::SetResolution]
+    ///
+    ExtendedDesktopSize(ExtendedDesktopSize),
     /// If the connector doesn't call `set_pixel_format` method
     ///
     /// The engine will generate a [VncEvent::SetPixelFormat] to let the window know how to render image
     ///
+    /// It is also generated if the requested format cannot be produced by the selected encodings
+    ///
+    /// (e.g. 16 bits pixels with Tight), in which case the closest workable format is used instead
+    ///
+    /// or if the 8 bits pixels are expanded, see [crate::PixelFormat::output]
+    ///
     SetPixelFormat(PixelFormat),
     /// Raw image data in the order followed by informed PixelFormat
     ///
+    /// The byte order of the pixels can be overridden by [crate::ByteOrder]
+    ///
     RawImage(Rect, ImageData),
     /// Copy image data from the second rect to the first
     ///
     Copy(DstRect, SrcRect),
+    /// Consecutive copies sharing the same displacement within one update
+    ///
+    /// Will be generated instead of [VncEvent::Copy] if `batch_copy_rect` is set on the connector
+    ///
+    /// The copies shall be applied in order
+    ///
+    CopyBatch(Vec<(DstRect, SrcRect)>),
     /// A jpeg image if using Tight encoding,
     ///
     /// Encoding the bytes with base64 and render it with "<img src=data:image/jpeg;base64,.../>",
     ///
+    /// See [crate::JpegPolicy] if the jpeg rects cannot be rendered
+    ///
     JpegImage(Rect, ImageData),
-
-    // PngImage(Rect, ImageData),
+    /// The encoding of the next rect sent by the server
+    ///
+    /// Will be generated if `emit_rect_encodings` is set on the connector
+    ///
+    /// Which tells the encodings that the server actually uses, e.g. for diagnostics or adaptive UIs
+    ///
+    RectEncoding(Rect, VncEncoding),
+    /// A png image if using TightPng encoding
+    ///
+    /// Which can be rendered with "<img src=data:image/png;base64,.../>" as well
+    ///
+    PngImage(Rect, ImageData),
     /// Will be generated if [crate::VncEncoding::CursorPseudo] is set
     ///
     /// According to [RFC6143, section-7.8.1](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.8.1)
     ///
     SetCursor(Rect, ImageData),
+    /// Will be generated if [crate::VncEncoding::CursorWithAlphaPseudo] or [crate::VncEncoding::XCursorPseudo] is set
+    ///
+    /// The pixels are straight (not pre-multiplied) RGBA, whatever the pixel format and [crate::ByteOrder] are
+    ///
+    /// The position of the rect is the hotspot, as with [VncEvent::SetCursor]
+    ///
+    SetAlphaCursor(Rect, ImageData),
+    /// The entries in the range of the color map are set to the 16 bits RGB colors
+    ///
+    /// Only sent with an indexed pixel format (the `true_color_flag` is 0), whose pixels are delivered as the indices
+    ///
+    /// With `set_framebuffer` on the connector, it is followed by a [VncEvent::Damage] for each band of rows
+    ///
+    /// showing the changed entries, if the pixels are of 8 bits. e.g. to animate by cycling the colors
+    ///
+    PaletteUpdated(Range<u16>, Vec<[u16; 3]>),
     /// Just ring a bell
     ///
     Bell,
@@ -81,6 +482,98 @@ pub enum VncEvent {
     /// According to [RFC6143](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.6.4)
     ///
     Text(String),
+    /// The remote pointer has been moved to `(x, y)` by the client
+    ///
+    /// Only generated by [X11Event::WarpPointer] with `notify` set,
+    ///
+    /// so that the window can move the local cursor accordingly
+    ///
+    CursorPosition(u16, u16),
+    /// The remote pointer has been moved to `(x, y)` by the server,
+    ///
+    /// e.g. warped by the desktop or moved by another client of a shared session
+    ///
+    /// Will be generated if [crate::VncEncoding::PointerPosPseudo] is set
+    ///
+    PointerPosition(u16, u16),
+    /// The keyboard indicators of the guest, so that the local ones can be kept consistent
+    ///
+    /// Will be generated if [crate::VncEncoding::QemuLedStatePseudo] is set
+    ///
+    LedState { caps: bool, num: bool, scroll: bool },
+    /// The server supports the General Input Interface, the devices can be created from now on
+    ///
+    /// Will be generated once if [crate::VncEncoding::GiiPseudo] is set
+    ///
+    GiiSupported,
+    /// The origin of a device created by [X11Event::GiiCreateDevice], in the order of creation
+    ///
+    /// `0` if the server failed to create the device
+    ///
+    GiiDeviceCreated(u32),
+    /// The server supports the power control of the xvp `version`
+    ///
+    /// Will be generated once if [crate::VncEncoding::XvpPseudo] is set
+    ///
+    XvpInit(u8),
+    /// The server failed to perform an [X11Event::Xvp], or rejected it
+    ///
+    XvpFail,
+    /// The part of a [VncEvent::RawImage] inside of the magnified region, scaled with the nearest neighbor
+    ///
+    /// The rect is the position in the magnified output
+    ///
+    /// Will be generated after the [VncEvent::RawImage] if [crate::VncClient::set_magnifier] is called
+    ///
+    Magnified(Rect, ImageData),
+    /// The rect of the shared framebuffer which has been updated
+    ///
+    /// Will be generated instead of the [VncEvent::RawImage], [VncEvent::Copy] and [VncEvent::CopyBatch]
+    ///
+    /// if `set_framebuffer` is set on the connector, a batch is reported as its bounding box
+    ///
+    Damage(Rect),
+    /// The images & copies of a framebuffer update, delivered right before its [VncEvent::UpdateComplete]
+    ///
+    /// Will be generated instead of them if `set_frame_delta` is set on the connector,
+    ///
+    /// which suits the gateways forwarding the updates over another protocol
+    ///
+    FrameDelta(FrameDelta),
+    /// An input has been echoed on the screen, which measures the end-to-end latency
+    ///
+    /// Will be generated if `emit_input_echoes` is set on the connector, never on wasm
+    ///
+    InputEcho(InputEcho),
+    /// The server supports the continuous updates, or has stopped them
+    ///
+    /// Will be generated if [crate::VncEncoding::ContinuousUpdatesPseudo] is set,
+    ///
+    /// once after connecting to a server with the support and after each [crate::VncClient::disable_continuous_updates]
+    ///
+    EndOfContinuousUpdates,
+    /// All the rects of a framebuffer update have been delivered
+    ///
+    /// A good time to present the frame
+    ///
+    UpdateComplete,
+    /// The connection of a [crate::VncSession] was lost and has been established again
+    ///
+    /// The events of the new connection follow, starting with its [VncEvent::SetResolution]
+    ///
+    Reconnected,
+    /// Nothing has been received from the server within the window of the keepalive,
+    ///
+    /// the engine stops with [crate::VncError::Timeout] right after it
+    ///
+    /// Will be generated if [crate::VncConnector::set_keepalive] is called
+    ///
+    ConnectionStale,
+    /// An event of a protocol extension
+    ///
+    /// The stable namespace of the events added with the extensions from now on, see [ExtensionEvent]
+    ///
+    Extension(ExtensionEvent),
 }
 
 /// X11 keyboard event to notify the server
@@ -102,6 +595,15 @@ impl From<(u32, bool)> for ClientKeyEvent {
     }
 }
 
+/// The lock keys of the local keyboard, see [crate::VncClient::notify_focus_with_locks]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LockKeys {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
 /// X11 mouse event to notify the server
 ///
 /// Referring to [RFC6143, seciont-7.5.5](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.5.5)
@@ -134,6 +636,17 @@ pub enum X11Event {
     /// Key down/up
     ///
     KeyEvent(ClientKeyEvent),
+    /// Key down/up with the raw XT scancode besides the keysym
+    ///
+    /// Sent as the QEMU extended key event once the server acknowledges
+    ///
+    /// the [crate::VncEncoding::QemuExtendedKeyEventPseudo], as a plain key event otherwise
+    ///
+    ExtendedKeyEvent {
+        keysym: u32,
+        keycode: u32,
+        down: bool,
+    },
     /// Mouse move/up/down/scroll
     ///
     PointerEvent(ClientMouseEvent),
@@ -142,4 +655,60 @@ pub enum X11Event {
     /// Only Latin-1 character set is allowed
     ///
     CopyText(String),
+    /// Move the remote pointer to `(x, y)` keeping the buttons pressed as they are
+    ///
+    /// A [VncEvent::CursorPosition] is generated as well if `notify` is set
+    ///
+    WarpPointer { x: u16, y: u16, notify: bool },
+    /// Only require updates of the given area on [X11Event::Refresh]
+    ///
+    /// `None` to require the whole screen again
+    ///
+    SetViewport(Option<Rect>),
+    /// Magnify a region of the remote desktop, `None` to stop
+    ///
+    /// See [crate::VncClient::set_magnifier]
+    ///
+    SetMagnifier(Option<Magnifier>),
+    /// Let the server send the updates of the rect without waiting for [X11Event::Refresh], `None` to stop
+    ///
+    /// See [crate::VncClient::enable_continuous_updates]
+    ///
+    SetContinuousUpdates(Option<Rect>),
+    /// Request the server to resize the desktop to `width` x `height` with the `screens`
+    ///
+    /// See [crate::VncClient::set_desktop_size]
+    ///
+    SetDesktopSize(u16, u16, Vec<ScreenLayout>),
+    /// Inform the encodings tuned for the content to the server
+    ///
+    /// See [crate::VncClient::prefer_text_mode] & [crate::VncClient::prefer_video_mode]
+    ///
+    SetContentMode(ContentMode),
+    /// Create an input device, whose origin is returned by [VncEvent::GiiDeviceCreated]
+    ///
+    /// Dropped unless [VncEvent::GiiSupported] has been generated
+    ///
+    GiiCreateDevice(GiiDevice),
+    /// Destroy the device of the origin
+    ///
+    GiiDestroyDevice(u32),
+    /// Inject the events of the devices
+    ///
+    GiiEvents(Vec<GiiEvent>),
+    /// Shutdown, reboot or reset the virtual machine, [VncEvent::XvpFail] is generated on failure
+    ///
+    /// Dropped unless [VncEvent::XvpInit] has been generated
+    ///
+    Xvp(XvpAction),
+    /// The local window gains the focus, the lock keys of the guest are synced to the given ones
+    ///
+    /// See [crate::VncClient::notify_focus] & [crate::VncClient::notify_focus_with_locks]
+    ///
+    FocusIn(Option<LockKeys>),
+    /// The local window loses the focus, the keys & the buttons pressed are released
+    ///
+    /// See [crate::VncClient::notify_focus]
+    ///
+    FocusOut,
 }
@@ -2,6 +2,7 @@
 //!
 //! ## Description
 //! + An async implementation of VNC client side protocol
+//! + The server side behind the `server` feature, see [server]
 //!
 //! ## Simple example
 //!
@@ -28,7 +29,7 @@
 //!
 //!     let tcp = TcpStream::connect("127.0.0.1:5900").await?;
 //!     let vnc = VncConnector::new(tcp)
-//!         .set_auth_method(async move { Ok("123".to_string()) })
+//!         .set_auth_method(|_| async move { Ok("123".into()) })
 //!         .add_encoding(vnc::VncEncoding::Tight)
 //!         .add_encoding(vnc::VncEncoding::Zrle)
 //!         .add_encoding(vnc::VncEncoding::CopyRect)
@@ -39,19 +40,16 @@
 //!         .try_start()
 //!         .await?
 //!         .finish()?;
-//!     let (vnc_event_sender, mut vnc_event_receiver) = tokio::sync::mpsc::channel(100);
-//!     let (x11_event_sender, x11_event_receiver) = tokio::sync::mpsc::channel(100);
-//!     tokio::spawn(async move { vnc.run(vnc_event_sender, x11_event_receiver).await.unwrap() });
 //!
 //!     let mut canvas = CanvasUtils::new()?;
 //!
-//!     while let Some(event) = vnc_event_receiver.recv().await {
+//!     while let Ok(event) = vnc.recv_event().await {
 //!         canvas.hande_vnc_event(event)?;
-//!         while let Ok(e) = vnc_event_receiver.try_recv() {
+//!         while let Some(e) = vnc.poll_event().await? {
 //!             canvas.hande_vnc_event(e)?;
 //!         }
 //!         canvas.flush()?;
-//!         let _ = x11_event_sender.send(X11Event::Refresh).await;
+//!         let _ = vnc.input(X11Event::Refresh).await;
 //!     }
 //!     canvas.close();
 //!     Ok(())
@@ -160,13 +158,18 @@
 //!             VncEvent::Bell => {
 //!                 tracing::warn!("Bell event got, but ignore it");
 //!             }
-//!             VncEvent::SetPixelFormat(_) => unreachable!(),
+//!             VncEvent::SetPixelFormat(pf) => {
+//!                 tracing::warn!("Pixel format {:?} got, but ignore it", pf);
+//!             }
 //!             VncEvent::Copy(dst, src) => {
 //!                 self.copy(dst, src)?;
 //!             }
 //!             VncEvent::JpegImage(_rect, _data) => {
 //!                 tracing::warn!("Jpeg event got, but ignore it");
 //!             }
+//!             VncEvent::PngImage(_rect, _data) => {
+//!                 tracing::warn!("Png event got, but ignore it");
+//!             }
 //!             VncEvent::SetCursor(rect, data) => {
 //!                 if rect.width != 0 {
 //!                     self.draw(rect, data)?;
@@ -175,13 +178,26 @@
 //!             VncEvent::Text(string) => {
 //!                 tracing::info!("Got clipboard message {}", string);
 //!             }
-//!             _ => unreachable!(),
+//!             VncEvent::UpdateComplete => {}
+//!             // more events are added over time, skip the ones not rendered here
+//!             _ => {}
 //!         }
 //!         Ok(())
 //!     }
 //! }
 //! ```
 //!
+//! ## Layering
+//!
+//! The crate is split into a protocol core and a tokio driver on top of it
+//!
+//! * The core: [config], [error], [event] and [proto], i.e. the messages, the pixel formats
+//!   and the events, which can be serialized with `to_bytes` / `from_bytes` without any runtime
+//!
+//! * The driver: [client] and the decoders, enabled by the default `client` feature
+//!
+//! Alternative drivers (e.g. sync or io_uring based) can depend on the crate with `default-features = false`
+//!
 //! ## License
 //!
 //! Licensed under either of
@@ -199,14 +215,43 @@
 //! for inclusion in the work by you, as defined in the Apache-2.0 license, shall be
 //! dual licensed as above, without any additional terms or conditions.
 
+// the core (config, error, event & proto) is shared by the drivers,
+// the items only used by the tokio driver are dead without it
+#![cfg_attr(not(feature = "client"), allow(dead_code))]
+
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "client")]
 mod codec;
 pub mod config;
 pub mod error;
 pub mod event;
+#[cfg(all(feature = "mdns", not(target_arch = "wasm32")))]
+pub mod mdns;
+pub mod proto;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "ws")]
+pub mod transport;
 
-pub use client::VncClient;
-pub use client::VncConnector;
+#[cfg(feature = "stream")]
+pub use client::VncEvents;
+#[cfg(feature = "client")]
+pub use client::{
+    probe, AuthRequest, DebugReport, DetachedSession, EncodingStats, FrameTiming, KeyboardLayout,
+    SecurityType, ServerInspection, ServerProbeReport, SessionInfo, VncClient, VncConnector,
+    VncCredential,
+};
+#[cfg(all(feature = "client", not(target_arch = "wasm32")))]
+pub use client::{
+    HealthReport, ReconnectPolicy, VncListener, VncSession, VncTasks, VNC_LISTEN_PORT,
+};
+#[cfg(all(feature = "proxy", not(target_arch = "wasm32")))]
+pub use client::{Proxy, ProxyKind};
+#[cfg(feature = "trace")]
+pub use client::{TraceDirection, TraceRecord, TRACE_SCHEMA_VERSION};
 pub use config::*;
 pub use error::*;
 pub use event::*;
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
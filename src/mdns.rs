@@ -0,0 +1,117 @@
+//! Advertise a vnc server on the LAN by mDNS/DNS-SD
+//!
+//! The session is registered as a `_rfb._tcp` service with its name and port,
+//! so that the clients browsing the LAN, e.g. the macOS Finder or Remmina, discover it
+//!
+//! ```no_run
+//! use vnc::mdns::Advertisement;
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! // withdrawn once dropped
+//! let _advertisement = Advertisement::new("My Desktop", "0.0.0.0:5900".parse()?)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+use std::{collections::HashMap, net::SocketAddr};
+
+use anyhow::Result;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::{info, warn};
+
+/// The DNS-SD service type of the vnc servers
+const SERVICE_TYPE: &str = "_rfb._tcp.local.";
+
+/// The advertisement of a served session on the LAN, withdrawn once dropped
+///
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Advertise the session of `name` listening on `addr` by mDNS/DNS-SD
+    ///
+    /// The addresses of all the interfaces are advertised if `addr` is unspecified, e.g. `0.0.0.0`
+    ///
+    pub fn new(name: &str, addr: SocketAddr) -> Result<Self> {
+        let service = service_info(name, addr)?;
+        let fullname = service.get_fullname().to_string();
+        let daemon = ServiceDaemon::new()?;
+        daemon.register(service)?;
+        info!("Advertise {} on port {}", fullname, addr.port());
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            warn!("Failed to withdraw {}: {}", self.fullname, e);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+/// The `_rfb._tcp` service of the session `name` on `addr`,
+///
+/// whose addresses follow the interfaces of the host if `addr` is unspecified
+///
+fn service_info(name: &str, addr: SocketAddr) -> Result<ServiceInfo> {
+    // the name of the address records, e.g. `my-desktop.local.`
+    let host = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_lowercase(),
+            _ => '-',
+        })
+        .collect::<String>();
+    let host = match host.trim_matches('-') {
+        "" => "vnc-rs".to_string(),
+        host => host.to_string(),
+    };
+    let ip = addr.ip();
+    if ip.is_unspecified() {
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            name,
+            &format!("{}.local.", host),
+            (),
+            addr.port(),
+            None::<HashMap<String, String>>,
+        )?;
+        Ok(service.enable_addr_auto())
+    } else {
+        Ok(ServiceInfo::new(
+            SERVICE_TYPE,
+            name,
+            &format!("{}.local.", host),
+            ip,
+            addr.port(),
+            None::<HashMap<String, String>>,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_info() {
+        let service = service_info("My Desktop", "0.0.0.0:5900".parse().unwrap()).unwrap();
+        assert_eq!(service.get_type(), SERVICE_TYPE);
+        assert_eq!(service.get_fullname(), "My Desktop._rfb._tcp.local.");
+        assert_eq!(service.get_hostname(), "my-desktop.local.");
+        assert_eq!(service.get_port(), 5900);
+        assert!(service.get_addresses().is_empty());
+        assert!(service.is_addr_auto());
+
+        let service = service_info("**", "192.168.1.2:5901".parse().unwrap()).unwrap();
+        assert_eq!(service.get_hostname(), "vnc-rs.local.");
+        assert_eq!(service.get_port(), 5901);
+        assert!(service
+            .get_addresses()
+            .contains(&"192.168.1.2".parse().unwrap()));
+        assert!(!service.is_addr_auto());
+    }
+}
@@ -0,0 +1,1253 @@
+use crate::{
+    GiiDevice, GiiEvent, GiiValuator, PixelFormat, Rect, ScreenLayout, VncEncoding, VncError,
+    XvpAction,
+};
+use anyhow::Result;
+use std::{
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+
+/// Messages sent from the client to the server
+///
+/// Referring to [RFC6143, section-7.5](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.5)
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMsg {
+    /// The shared flag sent during the initialization
+    ///
+    /// Which has no message type, so it is never returned by [ClientMsg::read]
+    ///
+    ClientInit(bool),
+    SetPixelFormat(PixelFormat),
+    /// The unknown encodings are skipped when parsed
+    ///
+    SetEncodings(Vec<VncEncoding>),
+    /// The rect to update and the incremental flag
+    ///
+    FramebufferUpdateRequest(Rect, u8),
+    /// The keysym and the down flag
+    ///
+    KeyEvent(u32, bool),
+    /// The position and the button mask
+    ///
+    PointerEvent(u16, u16, u8),
+    ClientCutText(String),
+    /// The enable flag and the rect to update continuously
+    ///
+    /// Only sent if the server supports the [VncEncoding::ContinuousUpdatesPseudo]
+    ///
+    EnableContinuousUpdates(bool, Rect),
+    /// The requested width, height and screens
+    ///
+    /// Only sent if the server supports the [VncEncoding::ExtendedDesktopSizePseudo]
+    ///
+    SetDesktopSize(u16, u16, Vec<ScreenLayout>),
+    /// The keysym, the XT scancode and the down flag
+    ///
+    /// Only sent if the server supports the [VncEncoding::QemuExtendedKeyEventPseudo]
+    ///
+    QemuKeyEvent(u32, u32, bool),
+    /// The gii version chosen by the client
+    ///
+    /// Only sent if the server supports the [VncEncoding::GiiPseudo], as are the other gii messages
+    ///
+    GiiVersion(u16),
+    GiiCreateDevice(GiiDevice),
+    /// The origin of the device
+    ///
+    GiiDestroyDevice(u32),
+    GiiEvents(Vec<GiiEvent>),
+    /// The xvp version and the action
+    ///
+    /// Only sent if the server supports the [VncEncoding::XvpPseudo]
+    ///
+    Xvp(u8, XvpAction),
+}
+
+impl ClientMsg {
+    /// The name of the message type, which is the name of the variant
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientMsg::ClientInit(..) => "ClientInit",
+            ClientMsg::SetPixelFormat(..) => "SetPixelFormat",
+            ClientMsg::SetEncodings(..) => "SetEncodings",
+            ClientMsg::FramebufferUpdateRequest(..) => "FramebufferUpdateRequest",
+            ClientMsg::KeyEvent(..) => "KeyEvent",
+            ClientMsg::PointerEvent(..) => "PointerEvent",
+            ClientMsg::ClientCutText(..) => "ClientCutText",
+            ClientMsg::EnableContinuousUpdates(..) => "EnableContinuousUpdates",
+            ClientMsg::SetDesktopSize(..) => "SetDesktopSize",
+            ClientMsg::QemuKeyEvent(..) => "QemuKeyEvent",
+            ClientMsg::GiiVersion(..) => "GiiVersion",
+            ClientMsg::GiiCreateDevice(..) => "GiiCreateDevice",
+            ClientMsg::GiiDestroyDevice(..) => "GiiDestroyDevice",
+            ClientMsg::GiiEvents(..) => "GiiEvents",
+            ClientMsg::Xvp(..) => "Xvp",
+        }
+    }
+
+    /// Serialize the message into its wire format
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ClientMsg::ClientInit(shared) => {
+                // +--------------+--------------+-------------+
+                // | No. of bytes | Type [Value] | Description |
+                // +--------------+--------------+-------------+
+                // | 1            | U8           | shared-flag |
+                // +--------------+--------------+-------------+
+                vec![*shared as u8]
+            }
+            ClientMsg::SetPixelFormat(pf) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [0]       | message-type |
+                // | 3            |              | padding      |
+                // | 16           | PIXEL_FORMAT | pixel-format |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![0_u8, 0, 0, 0];
+                payload.extend(<PixelFormat as Into<Vec<u8>>>::into(*pf));
+                payload
+            }
+            ClientMsg::SetEncodings(encodings) => {
+                //  +--------------+--------------+---------------------+
+                // | No. of bytes | Type [Value] | Description         |
+                // +--------------+--------------+---------------------+
+                // | 1            | U8 [2]       | message-type        |
+                // | 1            |              | padding             |
+                // | 2            | U16          | number-of-encodings |
+                // +--------------+--------------+---------------------+
+
+                // This is followed by number-of-encodings repetitions of the following:
+                // +--------------+--------------+---------------+
+                // | No. of bytes | Type [Value] | Description   |
+                // +--------------+--------------+---------------+
+                // | 4            | S32          | encoding-type |
+                // +--------------+--------------+---------------+
+                let mut payload = vec![2, 0];
+                payload.extend_from_slice(&(encodings.len() as u16).to_be_bytes());
+                for e in encodings {
+                    payload.extend_from_slice(&u32::from(*e).to_be_bytes());
+                }
+                payload
+            }
+            ClientMsg::FramebufferUpdateRequest(rect, incremental) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [3]       | message-type |
+                // | 1            | U8           | incremental  |
+                // | 2            | U16          | x-position   |
+                // | 2            | U16          | y-position   |
+                // | 2            | U16          | width        |
+                // | 2            | U16          | height       |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![3, *incremental];
+                payload.extend_from_slice(&rect.x.to_be_bytes());
+                payload.extend_from_slice(&rect.y.to_be_bytes());
+                payload.extend_from_slice(&rect.width.to_be_bytes());
+                payload.extend_from_slice(&rect.height.to_be_bytes());
+                payload
+            }
+            ClientMsg::KeyEvent(keycode, down) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [4]       | message-type |
+                // | 1            | U8           | down-flag    |
+                // | 2            |              | padding      |
+                // | 4            | U32          | key          |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![4, *down as u8, 0, 0];
+                payload.extend_from_slice(&keycode.to_be_bytes());
+                payload
+            }
+            ClientMsg::PointerEvent(x, y, mask) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [5]       | message-type |
+                // | 1            | U8           | button-mask  |
+                // | 2            | U16          | x-position   |
+                // | 2            | U16          | y-position   |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![5, *mask];
+                payload.extend_from_slice(&x.to_be_bytes());
+                payload.extend_from_slice(&y.to_be_bytes());
+                payload
+            }
+            ClientMsg::ClientCutText(s) => {
+                //   +--------------+--------------+--------------+
+                //   | No. of bytes | Type [Value] | Description  |
+                //   +--------------+--------------+--------------+
+                //   | 1            | U8 [6]       | message-type |
+                //   | 3            |              | padding      |
+                //   | 4            | U32          | length       |
+                //   | length       | U8 array     | text         |
+                //   +--------------+--------------+--------------+
+                let mut payload = vec![6_u8, 0, 0, 0];
+                payload.extend_from_slice(&(s.len() as u32).to_be_bytes());
+                payload.extend_from_slice(s.as_bytes());
+                payload
+            }
+            ClientMsg::EnableContinuousUpdates(enable, rect) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [150]     | message-type |
+                // | 1            | U8           | enable-flag  |
+                // | 2            | U16          | x-position   |
+                // | 2            | U16          | y-position   |
+                // | 2            | U16          | width        |
+                // | 2            | U16          | height       |
+                // +--------------+--------------+--------------+
+                let mut payload = vec![150, *enable as u8];
+                payload.extend_from_slice(&rect.x.to_be_bytes());
+                payload.extend_from_slice(&rect.y.to_be_bytes());
+                payload.extend_from_slice(&rect.width.to_be_bytes());
+                payload.extend_from_slice(&rect.height.to_be_bytes());
+                payload
+            }
+            ClientMsg::SetDesktopSize(width, height, screens) => {
+                // +--------------+--------------+-------------------+
+                // | No. of bytes | Type [Value] | Description       |
+                // +--------------+--------------+-------------------+
+                // | 1            | U8 [251]     | message-type      |
+                // | 1            |              | padding           |
+                // | 2            | U16          | width             |
+                // | 2            | U16          | height            |
+                // | 1            | U8           | number-of-screens |
+                // | 1            |              | padding           |
+                // +--------------+--------------+-------------------+
+                //
+                // This is followed by number-of-screens SCREEN structures
+                let mut payload = vec![251, 0];
+                payload.extend_from_slice(&width.to_be_bytes());
+                payload.extend_from_slice(&height.to_be_bytes());
+                payload.extend_from_slice(&[screens.len() as u8, 0]);
+                for screen in screens {
+                    write_screen(&mut payload, screen);
+                }
+                payload
+            }
+            ClientMsg::QemuKeyEvent(keysym, keycode, down) => {
+                // +--------------+--------------+-----------------+
+                // | No. of bytes | Type [Value] | Description     |
+                // +--------------+--------------+-----------------+
+                // | 1            | U8 [255]     | message-type    |
+                // | 1            | U8 [0]       | submessage-type |
+                // | 2            | U16          | down-flag       |
+                // | 4            | U32          | keysym          |
+                // | 4            | U32          | keycode         |
+                // +--------------+--------------+-----------------+
+                let mut payload = vec![255, 0];
+                payload.extend_from_slice(&(*down as u16).to_be_bytes());
+                payload.extend_from_slice(&keysym.to_be_bytes());
+                payload.extend_from_slice(&keycode.to_be_bytes());
+                payload
+            }
+            ClientMsg::GiiVersion(version) => {
+                // +--------------+--------------+---------------------+
+                // | No. of bytes | Type [Value] | Description         |
+                // +--------------+--------------+---------------------+
+                // | 1            | U8 [253]     | message-type        |
+                // | 1            | U8 [129]     | endian-and-sub-type |
+                // | 2            | EU16 [2]     | length              |
+                // | 2            | EU16         | version             |
+                // +--------------+--------------+---------------------+
+                //
+                // The messages of the client are always big endian
+                let mut payload = vec![253, GII_BIG_ENDIAN | 1, 0, 2];
+                payload.extend_from_slice(&version.to_be_bytes());
+                payload
+            }
+            ClientMsg::GiiCreateDevice(device) => {
+                // +--------------+--------------+---------------------+
+                // | No. of bytes | Type [Value] | Description         |
+                // +--------------+--------------+---------------------+
+                // | 1            | U8 [253]     | message-type        |
+                // | 1            | U8 [130]     | endian-and-sub-type |
+                // | 2            | EU16         | length              |
+                // | 31           | U8 array     | device-name         |
+                // | 1            | U8 [0]       | nul-terminator      |
+                // | 4            | EU32         | vendor-id           |
+                // | 4            | EU32         | product-id          |
+                // | 4            | EU32         | event-mask          |
+                // | 4            | EU32         | num-registers       |
+                // | 4            | EU32         | num-valuators       |
+                // | 4            | EU32         | num-buttons         |
+                // +--------------+--------------+---------------------+
+                //
+                // This is followed by num-valuators VALUATOR structures
+                let mut payload = vec![253, GII_BIG_ENDIAN | 2];
+                let len = 56 + device.valuators.len() * GII_VALUATOR_LEN;
+                payload.extend_from_slice(&(len as u16).to_be_bytes());
+                write_name(&mut payload, &device.name, 32);
+                for field in [
+                    device.vendor_id,
+                    device.product_id,
+                    GII_EVENT_MASK,
+                    0,
+                    device.valuators.len() as u32,
+                    device.buttons,
+                ] {
+                    payload.extend_from_slice(&field.to_be_bytes());
+                }
+                for (index, valuator) in device.valuators.iter().enumerate() {
+                    write_valuator(&mut payload, index as u32, valuator);
+                }
+                payload
+            }
+            ClientMsg::GiiDestroyDevice(origin) => {
+                // +--------------+--------------+---------------------+
+                // | No. of bytes | Type [Value] | Description         |
+                // +--------------+--------------+---------------------+
+                // | 1            | U8 [253]     | message-type        |
+                // | 1            | U8 [131]     | endian-and-sub-type |
+                // | 2            | EU16 [4]     | length              |
+                // | 4            | EU32         | device-origin       |
+                // +--------------+--------------+---------------------+
+                let mut payload = vec![253, GII_BIG_ENDIAN | 3, 0, 4];
+                payload.extend_from_slice(&origin.to_be_bytes());
+                payload
+            }
+            ClientMsg::GiiEvents(events) => {
+                // +--------------+--------------+---------------------+
+                // | No. of bytes | Type [Value] | Description         |
+                // +--------------+--------------+---------------------+
+                // | 1            | U8 [253]     | message-type        |
+                // | 1            | U8 [128]     | endian-and-sub-type |
+                // | 2            | EU16         | length              |
+                // +--------------+--------------+---------------------+
+                //
+                // This is followed by the events of `length` bytes
+                let mut payload = vec![253, GII_BIG_ENDIAN, 0, 0];
+                for event in events {
+                    write_gii_event(&mut payload, event);
+                }
+                let len = (payload.len() - 4) as u16;
+                payload[2..4].copy_from_slice(&len.to_be_bytes());
+                payload
+            }
+            ClientMsg::Xvp(version, action) => {
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [250]     | message-type |
+                // | 1            |              | padding      |
+                // | 1            | U8           | version      |
+                // | 1            | U8           | message-code |
+                // +--------------+--------------+--------------+
+                vec![250, 0, *version, *action as u8]
+            }
+        }
+    }
+
+    /// Write the message to `writer`
+    ///
+    pub async fn write<S>(&self, writer: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
+
+    /// Read a message sent after the initialization from `reader`
+    ///
+    pub async fn read<S>(reader: &mut S) -> Result<Self>
+    where
+        S: AsyncRead + Unpin,
+    {
+        match reader.read_u8().await? {
+            0 => {
+                let mut padding = [0; 3];
+                reader.read_exact(&mut padding).await?;
+                Ok(ClientMsg::SetPixelFormat(PixelFormat::read(reader).await?))
+            }
+            2 => {
+                let _padding = reader.read_u8().await?;
+                let num = reader.read_u16().await?;
+                let mut encodings = Vec::with_capacity(num as usize);
+                for _ in 0..num {
+                    if let Ok(encoding) = reader.read_u32().await?.try_into() {
+                        encodings.push(encoding);
+                    }
+                }
+                Ok(ClientMsg::SetEncodings(encodings))
+            }
+            3 => {
+                let incremental = reader.read_u8().await?;
+                let rect = Rect {
+                    x: reader.read_u16().await?,
+                    y: reader.read_u16().await?,
+                    width: reader.read_u16().await?,
+                    height: reader.read_u16().await?,
+                };
+                Ok(ClientMsg::FramebufferUpdateRequest(rect, incremental))
+            }
+            4 => {
+                let down = reader.read_u8().await? > 0;
+                let _padding = reader.read_u16().await?;
+                let keycode = reader.read_u32().await?;
+                Ok(ClientMsg::KeyEvent(keycode, down))
+            }
+            5 => {
+                let mask = reader.read_u8().await?;
+                let x = reader.read_u16().await?;
+                let y = reader.read_u16().await?;
+                Ok(ClientMsg::PointerEvent(x, y, mask))
+            }
+            6 => {
+                let mut padding = [0; 3];
+                reader.read_exact(&mut padding).await?;
+                Ok(ClientMsg::ClientCutText(read_text(reader).await?))
+            }
+            150 => {
+                let enable = reader.read_u8().await? > 0;
+                let rect = Rect {
+                    x: reader.read_u16().await?,
+                    y: reader.read_u16().await?,
+                    width: reader.read_u16().await?,
+                    height: reader.read_u16().await?,
+                };
+                Ok(ClientMsg::EnableContinuousUpdates(enable, rect))
+            }
+            250 => {
+                let _padding = reader.read_u8().await?;
+                let version = reader.read_u8().await?;
+                let action = reader
+                    .read_u8()
+                    .await?
+                    .try_into()
+                    .map_err(|_| VncError::WrongClientMessage)?;
+                Ok(ClientMsg::Xvp(version, action))
+            }
+            251 => {
+                let _padding = reader.read_u8().await?;
+                let width = reader.read_u16().await?;
+                let height = reader.read_u16().await?;
+                let num = reader.read_u8().await?;
+                let _padding = reader.read_u8().await?;
+                let mut screens = Vec::with_capacity(num as usize);
+                for _ in 0..num {
+                    screens.push(read_screen(reader).await?);
+                }
+                Ok(ClientMsg::SetDesktopSize(width, height, screens))
+            }
+            // the QEMU messages, of which only the extended key event is known
+            255 if reader.read_u8().await? == 0 => {
+                let down = reader.read_u16().await? > 0;
+                let keysym = reader.read_u32().await?;
+                let keycode = reader.read_u32().await?;
+                Ok(ClientMsg::QemuKeyEvent(keysym, keycode, down))
+            }
+            253 => {
+                let endian_and_sub_type = reader.read_u8().await?;
+                let be = endian_and_sub_type & GII_BIG_ENDIAN > 0;
+                let len = read_eu16(reader, be).await?;
+                match endian_and_sub_type & !GII_BIG_ENDIAN {
+                    0 => {
+                        let mut events = Vec::new();
+                        let mut left = len as usize;
+                        while left > 0 {
+                            let (event, size) = read_gii_event(reader, be).await?;
+                            events.push(event);
+                            left = left.saturating_sub(size);
+                        }
+                        Ok(ClientMsg::GiiEvents(events))
+                    }
+                    1 => Ok(ClientMsg::GiiVersion(read_eu16(reader, be).await?)),
+                    2 => {
+                        let name = read_name(reader, 32).await?;
+                        let vendor_id = read_eu32(reader, be).await?;
+                        let product_id = read_eu32(reader, be).await?;
+                        let _event_mask = read_eu32(reader, be).await?;
+                        let _registers = read_eu32(reader, be).await?;
+                        let num = read_eu32(reader, be).await?;
+                        let buttons = read_eu32(reader, be).await?;
+                        let mut valuators = Vec::with_capacity(num.min(64) as usize);
+                        for _ in 0..num {
+                            valuators.push(read_valuator(reader, be).await?);
+                        }
+                        Ok(ClientMsg::GiiCreateDevice(GiiDevice {
+                            name,
+                            vendor_id,
+                            product_id,
+                            valuators,
+                            buttons,
+                        }))
+                    }
+                    3 => Ok(ClientMsg::GiiDestroyDevice(read_eu32(reader, be).await?)),
+                    _ => Err(VncError::WrongClientMessage.into()),
+                }
+            }
+            _ => Err(VncError::WrongClientMessage.into()),
+        }
+    }
+
+    /// Parse a message sent after the initialization from the head of `buf`
+    ///
+    /// Returns the message and the number of bytes consumed
+    ///
+    /// An [std::io::ErrorKind::UnexpectedEof] error is returned if `buf` doesn't contain a whole message
+    ///
+    pub fn from_bytes(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut cursor = buf;
+        let msg = now_or_never(Self::read(&mut cursor))?;
+        Ok((msg, buf.len() - cursor.len()))
+    }
+}
+
+/// Messages sent from the server to the client
+///
+/// Referring to [RFC6143, section-7.6](https://www.rfc-editor.org/rfc/rfc6143.html#section-7.6)
+///
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerMsg {
+    /// The number of rects following
+    ///
+    /// The rects are not part of the message, since their length depends on the encoding
+    ///
+    FramebufferUpdate(u16),
+    /// The first color and the `[red, green, blue]` of the colors
+    ///
+    SetColorMapEntries(u16, Vec<[u16; 3]>),
+    Bell,
+    ServerCutText(String),
+    /// Confirms the support of the [VncEncoding::ContinuousUpdatesPseudo],
+    ///
+    /// or that the continuous updates are disabled
+    ///
+    EndOfContinuousUpdates,
+    /// The maximum & the minimum gii versions supported by the server
+    ///
+    /// Confirms the support of the [VncEncoding::GiiPseudo]
+    ///
+    GiiVersion(u16, u16),
+    /// The origin of the device created, `0` on failure
+    ///
+    GiiDeviceCreated(u32),
+    /// The xvp version supported, confirms the support of the [VncEncoding::XvpPseudo]
+    ///
+    XvpInit(u8),
+    /// The xvp version, the latest action has failed
+    ///
+    XvpFail(u8),
+}
+
+impl ServerMsg {
+    /// The name of the message type, which is the name of the variant
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerMsg::FramebufferUpdate(..) => "FramebufferUpdate",
+            ServerMsg::SetColorMapEntries(..) => "SetColorMapEntries",
+            ServerMsg::Bell => "Bell",
+            ServerMsg::ServerCutText(..) => "ServerCutText",
+            ServerMsg::EndOfContinuousUpdates => "EndOfContinuousUpdates",
+            ServerMsg::GiiVersion(..) => "GiiVersion",
+            ServerMsg::GiiDeviceCreated(..) => "GiiDeviceCreated",
+            ServerMsg::XvpInit(..) => "XvpInit",
+            ServerMsg::XvpFail(..) => "XvpFail",
+        }
+    }
+
+    /// Serialize the message into its wire format
+    ///
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            ServerMsg::FramebufferUpdate(rects) => {
+                let mut payload = vec![0, 0];
+                payload.extend_from_slice(&rects.to_be_bytes());
+                payload
+            }
+            ServerMsg::SetColorMapEntries(first_color, colors) => {
+                let mut payload = vec![1, 0];
+                payload.extend_from_slice(&first_color.to_be_bytes());
+                payload.extend_from_slice(&(colors.len() as u16).to_be_bytes());
+                for color in colors.iter().flatten() {
+                    payload.extend_from_slice(&color.to_be_bytes());
+                }
+                payload
+            }
+            ServerMsg::Bell => vec![2],
+            ServerMsg::ServerCutText(text) => {
+                let mut payload = vec![3, 0, 0, 0];
+                payload.extend_from_slice(&(text.len() as u32).to_be_bytes());
+                payload.extend_from_slice(text.as_bytes());
+                payload
+            }
+            ServerMsg::EndOfContinuousUpdates => vec![150],
+            ServerMsg::XvpFail(version) => vec![250, 0, *version, 0],
+            ServerMsg::XvpInit(version) => vec![250, 0, *version, 1],
+            ServerMsg::GiiVersion(max, min) => {
+                let mut payload = vec![253, GII_BIG_ENDIAN | 1, 0, 4];
+                payload.extend_from_slice(&max.to_be_bytes());
+                payload.extend_from_slice(&min.to_be_bytes());
+                payload
+            }
+            ServerMsg::GiiDeviceCreated(origin) => {
+                let mut payload = vec![253, GII_BIG_ENDIAN | 2, 0, 4];
+                payload.extend_from_slice(&origin.to_be_bytes());
+                payload
+            }
+        }
+    }
+
+    /// Write the message to `writer`
+    ///
+    pub async fn write<S>(&self, writer: &mut S) -> Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        writer.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
+
+    /// Read a message from `reader`
+    ///
+    pub async fn read<S>(reader: &mut S) -> Result<Self>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let server_msg = reader.read_u8().await?;
+
+        match server_msg {
+            0 => {
+                // FramebufferUpdate
+                //   +--------------+--------------+----------------------+
+                //   | No. of bytes | Type [Value] | Description          |
+                //   +--------------+--------------+----------------------+
+                //   | 1            | U8 [0]       | message-type         |
+                //   | 1            |              | padding              |
+                //   | 2            | U16          | number-of-rectangles |
+                //   +--------------+--------------+----------------------+
+                let _padding = reader.read_u8().await?;
+                let rects = reader.read_u16().await?;
+                Ok(ServerMsg::FramebufferUpdate(rects))
+            }
+            1 => {
+                // SetColorMapEntries
+                // +--------------+--------------+------------------+
+                // | No. of bytes | Type [Value] | Description      |
+                // +--------------+--------------+------------------+
+                // | 1            | U8 [1]       | message-type     |
+                // | 1            |              | padding          |
+                // | 2            | U16          | first-color      |
+                // | 2            | U16          | number-of-colors |
+                // +--------------+--------------+------------------+
+
+                // This is followed by number-of-colors repetitions of the following:
+                // +--------------+--------------+-------------+
+                // | No. of bytes | Type [Value] | Description |
+                // +--------------+--------------+-------------+
+                // | 2            | U16          | red         |
+                // | 2            | U16          | green       |
+                // | 2            | U16          | blue        |
+                // +--------------+--------------+-------------+
+                let _padding = reader.read_u8().await?;
+                let first_color = reader.read_u16().await?;
+                let num = reader.read_u16().await?;
+                let mut colors = Vec::with_capacity(num as usize);
+                for _ in 0..num {
+                    colors.push([
+                        reader.read_u16().await?,
+                        reader.read_u16().await?,
+                        reader.read_u16().await?,
+                    ]);
+                }
+                Ok(ServerMsg::SetColorMapEntries(first_color, colors))
+            }
+            2 => {
+                // Bell
+                //   +--------------+--------------+--------------+
+                //   | No. of bytes | Type [Value] | Description  |
+                //   +--------------+--------------+--------------+
+                //   | 1            | U8 [2]       | message-type |
+                //   +--------------+--------------+--------------+
+                Ok(ServerMsg::Bell)
+            }
+            3 => {
+                // ServerCutText
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [3]       | message-type |
+                // | 3            |              | padding      |
+                // | 4            | U32          | length       |
+                // | length       | U8 array     | text         |
+                // +--------------+--------------+--------------+
+                let mut padding = [0; 3];
+                reader.read_exact(&mut padding).await?;
+                Ok(Self::ServerCutText(read_text(reader).await?))
+            }
+            150 => {
+                // EndOfContinuousUpdates
+                //   +--------------+--------------+--------------+
+                //   | No. of bytes | Type [Value] | Description  |
+                //   +--------------+--------------+--------------+
+                //   | 1            | U8 [150]     | message-type |
+                //   +--------------+--------------+--------------+
+                Ok(ServerMsg::EndOfContinuousUpdates)
+            }
+            250 => {
+                // xvp
+                // +--------------+--------------+--------------+
+                // | No. of bytes | Type [Value] | Description  |
+                // +--------------+--------------+--------------+
+                // | 1            | U8 [250]     | message-type |
+                // | 1            |              | padding      |
+                // | 1            | U8           | version      |
+                // | 1            | U8           | message-code |
+                // +--------------+--------------+--------------+
+                let _padding = reader.read_u8().await?;
+                let version = reader.read_u8().await?;
+                match reader.read_u8().await? {
+                    0 => Ok(ServerMsg::XvpFail(version)),
+                    1 => Ok(ServerMsg::XvpInit(version)),
+                    _ => Err(VncError::WrongServerMessage.into()),
+                }
+            }
+            253 => {
+                // gii
+                // +--------------+--------------+---------------------+
+                // | No. of bytes | Type [Value] | Description         |
+                // +--------------+--------------+---------------------+
+                // | 1            | U8 [253]     | message-type        |
+                // | 1            | U8           | endian-and-sub-type |
+                // | 2            | EU16 [4]     | length              |
+                // +--------------+--------------+---------------------+
+                //
+                // Followed by the maximum & the minimum versions (EU16) for the version sub type 1,
+                // the device origin (EU32) for the device creation sub type 2
+                let endian_and_sub_type = reader.read_u8().await?;
+                let be = endian_and_sub_type & GII_BIG_ENDIAN > 0;
+                let _len = read_eu16(reader, be).await?;
+                match endian_and_sub_type & !GII_BIG_ENDIAN {
+                    1 => Ok(ServerMsg::GiiVersion(
+                        read_eu16(reader, be).await?,
+                        read_eu16(reader, be).await?,
+                    )),
+                    2 => Ok(ServerMsg::GiiDeviceCreated(read_eu32(reader, be).await?)),
+                    _ => Err(VncError::WrongServerMessage.into()),
+                }
+            }
+            _ => Err(VncError::WrongServerMessage.into()),
+        }
+    }
+
+    /// Parse a message from the head of `buf`
+    ///
+    /// Returns the message and the number of bytes consumed
+    ///
+    /// An [std::io::ErrorKind::UnexpectedEof] error is returned if `buf` doesn't contain a whole message
+    ///
+    pub fn from_bytes(buf: &[u8]) -> Result<(Self, usize)> {
+        let mut cursor = buf;
+        let msg = now_or_never(Self::read(&mut cursor))?;
+        Ok((msg, buf.len() - cursor.len()))
+    }
+
+    /// Read a message from a buffered `reader`
+    ///
+    /// The message is parsed at once if it has been buffered, instead of awaiting field by field,
+    ///
+    /// the head of a message split across reads is moved to `pending` until the rest arrives
+    ///
+    /// Nothing is lost if the future is dropped before completion, e.g. in a `tokio::select!`,
+    ///
+    /// as long as the same `pending` is passed to the next call
+    ///
+    pub async fn read_buffered<S>(reader: &mut S, pending: &mut Vec<u8>) -> Result<Self>
+    where
+        S: AsyncBufRead + Unpin,
+    {
+        loop {
+            let buf = reader.fill_buf().await?;
+            if buf.is_empty() {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            let head = pending.len();
+            let parsed = if head == 0 {
+                Self::from_bytes(buf)
+            } else {
+                pending.extend_from_slice(buf);
+                let parsed = Self::from_bytes(pending);
+                pending.truncate(head);
+                parsed.map(|(msg, len)| (msg, len - head))
+            };
+            match parsed {
+                Ok((msg, len)) => {
+                    reader.consume(len);
+                    pending.clear();
+                    return Ok(msg);
+                }
+                Err(e) if is_incomplete(&e) => {
+                    let len = buf.len();
+                    pending.extend_from_slice(buf);
+                    reader.consume(len);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Whether parsing failed because the message is not complete yet
+pub(crate) fn is_incomplete(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+/// Read a SCREEN structure of the ExtendedDesktopSize extension
+///
+/// ```text
+/// +--------------+--------------+-------------+
+/// | No. of bytes | Type [Value] | Description |
+/// +--------------+--------------+-------------+
+/// | 4            | U32          | id          |
+/// | 2            | U16          | x-position  |
+/// | 2            | U16          | y-position  |
+/// | 2            | U16          | width       |
+/// | 2            | U16          | height      |
+/// | 4            | U32          | flags       |
+/// +--------------+--------------+-------------+
+/// ```
+pub(crate) async fn read_screen<S>(reader: &mut S) -> Result<ScreenLayout>
+where
+    S: AsyncRead + Unpin,
+{
+    Ok(ScreenLayout {
+        id: reader.read_u32().await?,
+        x: reader.read_u16().await?,
+        y: reader.read_u16().await?,
+        width: reader.read_u16().await?,
+        height: reader.read_u16().await?,
+        flags: reader.read_u32().await?,
+    })
+}
+
+fn write_screen(payload: &mut Vec<u8>, screen: &ScreenLayout) {
+    payload.extend_from_slice(&screen.id.to_be_bytes());
+    payload.extend_from_slice(&screen.x.to_be_bytes());
+    payload.extend_from_slice(&screen.y.to_be_bytes());
+    payload.extend_from_slice(&screen.width.to_be_bytes());
+    payload.extend_from_slice(&screen.height.to_be_bytes());
+    payload.extend_from_slice(&screen.flags.to_be_bytes());
+}
+
+/// The flag of the gii endian-and-sub-type
+const GII_BIG_ENDIAN: u8 = 0x80;
+/// evPtrButtonPress | evPtrButtonRelease | evValAbsolute
+const GII_EVENT_MASK: u32 = 0x400 | 0x800 | 0x2000;
+const GII_VALUATOR_LEN: usize = 116;
+const GII_BUTTON_PRESS: u8 = 10;
+const GII_BUTTON_RELEASE: u8 = 11;
+const GII_VALUATOR_ABSOLUTE: u8 = 13;
+
+/// Write a nul terminated string into a field of `size` bytes, truncated if too long
+fn write_name(payload: &mut Vec<u8>, name: &str, size: usize) {
+    let name = &name.as_bytes()[..name.len().min(size - 1)];
+    payload.extend_from_slice(name);
+    payload.resize(payload.len() + size - name.len(), 0);
+}
+
+async fn read_name<S>(reader: &mut S, size: usize) -> Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut name = vec![0; size];
+    reader.read_exact(&mut name).await?;
+    let len = name.iter().position(|b| *b == 0).unwrap_or(size);
+    Ok(String::from_utf8_lossy(&name[..len]).to_string())
+}
+
+/// Write a VALUATOR structure of the gii device creation
+///
+/// ```text
+/// +--------------+--------------+--------------+
+/// | No. of bytes | Type [Value] | Description  |
+/// +--------------+--------------+--------------+
+/// | 4            | EU32         | index        |
+/// | 75           | U8 array     | long-name    |
+/// | 5            | U8 array     | short-name   |
+/// | 4            | ES32         | range-min    |
+/// | 4            | ES32         | range-center |
+/// | 4            | ES32         | range-max    |
+/// | 4            | EU32         | SI-unit      |
+/// | 4            | ES32         | SI-add       |
+/// | 4            | ES32         | SI-mul       |
+/// | 4            | ES32         | SI-div       |
+/// | 4            | ES32         | SI-shift     |
+/// +--------------+--------------+--------------+
+/// ```
+fn write_valuator(payload: &mut Vec<u8>, index: u32, valuator: &GiiValuator) {
+    payload.extend_from_slice(&index.to_be_bytes());
+    write_name(payload, &valuator.long_name, 75);
+    write_name(payload, &valuator.short_name, 5);
+    for field in [
+        valuator.range_min,
+        valuator.range_center,
+        valuator.range_max,
+        valuator.si_unit as i32,
+        valuator.si_add,
+        valuator.si_mul,
+        valuator.si_div,
+        valuator.si_shift,
+    ] {
+        payload.extend_from_slice(&field.to_be_bytes());
+    }
+}
+
+async fn read_valuator<S>(reader: &mut S, be: bool) -> Result<GiiValuator>
+where
+    S: AsyncRead + Unpin,
+{
+    let _index = read_eu32(reader, be).await?;
+    Ok(GiiValuator {
+        long_name: read_name(reader, 75).await?,
+        short_name: read_name(reader, 5).await?,
+        range_min: read_eu32(reader, be).await? as i32,
+        range_center: read_eu32(reader, be).await? as i32,
+        range_max: read_eu32(reader, be).await? as i32,
+        si_unit: read_eu32(reader, be).await?,
+        si_add: read_eu32(reader, be).await? as i32,
+        si_mul: read_eu32(reader, be).await? as i32,
+        si_div: read_eu32(reader, be).await? as i32,
+        si_shift: read_eu32(reader, be).await? as i32,
+    })
+}
+
+/// Write a gii event
+///
+/// ```text
+/// +--------------+--------------+-----------------------------+
+/// | No. of bytes | Type [Value] | Description                 |
+/// +--------------+--------------+-----------------------------+
+/// | 1            | U8           | event-size                  |
+/// | 1            | U8           | event-type                  |
+/// | 2            | EU16         | padding                     |
+/// | 4            | EU32         | device-origin               |
+/// | 4            | EU32         | button-number / first       |
+/// +--------------+--------------+-----------------------------+
+/// ```
+///
+/// The valuator events are followed by the count (EU32) and the values (ES32)
+fn write_gii_event(payload: &mut Vec<u8>, event: &GiiEvent) {
+    match event {
+        GiiEvent::Button {
+            origin,
+            button,
+            down,
+        } => {
+            let event_type = if *down {
+                GII_BUTTON_PRESS
+            } else {
+                GII_BUTTON_RELEASE
+            };
+            payload.extend_from_slice(&[12, event_type, 0, 0]);
+            payload.extend_from_slice(&origin.to_be_bytes());
+            payload.extend_from_slice(&button.to_be_bytes());
+        }
+        GiiEvent::Valuators {
+            origin,
+            first,
+            values,
+        } => {
+            let size = 16 + values.len() * 4;
+            payload.extend_from_slice(&[size as u8, GII_VALUATOR_ABSOLUTE, 0, 0]);
+            payload.extend_from_slice(&origin.to_be_bytes());
+            payload.extend_from_slice(&first.to_be_bytes());
+            payload.extend_from_slice(&(values.len() as u32).to_be_bytes());
+            for value in values {
+                payload.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+    }
+}
+
+/// Read a gii event, returns the event and its size
+async fn read_gii_event<S>(reader: &mut S, be: bool) -> Result<(GiiEvent, usize)>
+where
+    S: AsyncRead + Unpin,
+{
+    let size = reader.read_u8().await? as usize;
+    let event_type = reader.read_u8().await?;
+    let _padding = reader.read_u16().await?;
+    let origin = read_eu32(reader, be).await?;
+    let event = match event_type {
+        GII_BUTTON_PRESS | GII_BUTTON_RELEASE => GiiEvent::Button {
+            origin,
+            button: read_eu32(reader, be).await?,
+            down: event_type == GII_BUTTON_PRESS,
+        },
+        GII_VALUATOR_ABSOLUTE => {
+            let first = read_eu32(reader, be).await?;
+            let count = read_eu32(reader, be).await?;
+            let mut values = Vec::with_capacity(count.min(64) as usize);
+            for _ in 0..count {
+                values.push(read_eu32(reader, be).await? as i32);
+            }
+            GiiEvent::Valuators {
+                origin,
+                first,
+                values,
+            }
+        }
+        _ => return Err(VncError::WrongClientMessage.into()),
+    };
+    Ok((event, size))
+}
+
+/// Read an EU16 of the gii messages, in the endianness of the message
+async fn read_eu16<S>(reader: &mut S, be: bool) -> Result<u16>
+where
+    S: AsyncRead + Unpin,
+{
+    let value = reader.read_u16().await?;
+    Ok(if be { value } else { value.swap_bytes() })
+}
+
+/// Read an EU32 of the gii messages, in the endianness of the message
+async fn read_eu32<S>(reader: &mut S, be: bool) -> Result<u32>
+where
+    S: AsyncRead + Unpin,
+{
+    let value = reader.read_u32().await?;
+    Ok(if be { value } else { value.swap_bytes() })
+}
+
+async fn read_text<S>(reader: &mut S) -> Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = reader.read_u32().await?;
+    let mut buffer_str = vec![0; len as usize];
+    reader.read_exact(&mut buffer_str).await?;
+    Ok(String::from_utf8_lossy(&buffer_str).to_string())
+}
+
+/// Poll a future reading from memory, which never waits
+fn now_or_never<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    match future
+        .as_mut()
+        .poll(&mut Context::from_waker(Waker::noop()))
+    {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("reading from memory never waits"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_msg_round_trip() {
+        let msgs = [
+            ClientMsg::SetPixelFormat(PixelFormat::rgba()),
+            ClientMsg::SetEncodings(vec![VncEncoding::Zrle, VncEncoding::CursorPseudo]),
+            ClientMsg::FramebufferUpdateRequest(
+                Rect {
+                    x: 1,
+                    y: 2,
+                    width: 3,
+                    height: 4,
+                },
+                1,
+            ),
+            ClientMsg::KeyEvent(0xff0d, true),
+            ClientMsg::QemuKeyEvent(0xfe03, 0xb8, true),
+            ClientMsg::GiiVersion(1),
+            ClientMsg::Xvp(1, XvpAction::Reboot),
+            ClientMsg::GiiCreateDevice(GiiDevice {
+                name: "pen".to_string(),
+                vendor_id: 0x056a,
+                product_id: 0x0357,
+                valuators: vec![
+                    GiiValuator::new("Pressure", "p", 0, 2047),
+                    GiiValuator::new("Tilt X", "tx", -64, 63),
+                ],
+                buttons: 3,
+            }),
+            ClientMsg::GiiDestroyDevice(7),
+            ClientMsg::GiiEvents(vec![
+                GiiEvent::Valuators {
+                    origin: 7,
+                    first: 0,
+                    values: vec![1024, -12],
+                },
+                GiiEvent::Button {
+                    origin: 7,
+                    button: 1,
+                    down: true,
+                },
+            ]),
+            ClientMsg::PointerEvent(300, 200, 1),
+            ClientMsg::ClientCutText("text".to_string()),
+            ClientMsg::EnableContinuousUpdates(
+                true,
+                Rect {
+                    x: 0,
+                    y: 0,
+                    width: 800,
+                    height: 600,
+                },
+            ),
+            ClientMsg::SetDesktopSize(
+                1920,
+                1080,
+                vec![ScreenLayout {
+                    id: 1,
+                    x: 0,
+                    y: 0,
+                    width: 1920,
+                    height: 1080,
+                    flags: 0,
+                }],
+            ),
+        ];
+        for msg in msgs {
+            let mut bytes = msg.to_bytes();
+            let len = bytes.len();
+            // followed by the next message
+            bytes.push(5);
+            assert_eq!(ClientMsg::from_bytes(&bytes).unwrap(), (msg, len));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_msg_round_trip() {
+        let msgs = [
+            ServerMsg::FramebufferUpdate(3),
+            ServerMsg::SetColorMapEntries(1, vec![[0, 0, 0], [65535, 0, 32768]]),
+            ServerMsg::Bell,
+            ServerMsg::ServerCutText("text".to_string()),
+            ServerMsg::EndOfContinuousUpdates,
+            ServerMsg::XvpInit(1),
+            ServerMsg::XvpFail(1),
+            ServerMsg::GiiVersion(1, 1),
+            ServerMsg::GiiDeviceCreated(7),
+        ];
+        for msg in msgs {
+            let mut bytes = vec![];
+            msg.write(&mut bytes).await.unwrap();
+            assert_eq!(ServerMsg::read(&mut &bytes[..]).await.unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_without_runtime() {
+        // the core is used by the other drivers outside of any tokio runtime
+        assert!(tokio::runtime::Handle::try_current().is_err());
+        let msgs = [
+            ServerMsg::FramebufferUpdate(3),
+            ServerMsg::ServerCutText("text".to_string()),
+            ServerMsg::GiiDeviceCreated(7),
+        ];
+        let bytes = msgs
+            .iter()
+            .flat_map(ServerMsg::to_bytes)
+            .collect::<Vec<_>>();
+        let mut buf = &bytes[..];
+        for msg in msgs {
+            let (parsed, len) = ServerMsg::from_bytes(buf).unwrap();
+            assert_eq!(parsed, msg);
+            buf = &buf[len..];
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_buffered() {
+        let mut bytes = vec![];
+        ServerMsg::Bell.write(&mut bytes).await.unwrap();
+        ServerMsg::ServerCutText("text".to_string())
+            .write(&mut bytes)
+            .await
+            .unwrap();
+        ServerMsg::FramebufferUpdate(3)
+            .write(&mut bytes)
+            .await
+            .unwrap();
+        // the text is split across two fills
+        let mut reader = tokio::io::BufReader::with_capacity(6, &bytes[..]);
+        let mut pending = Vec::new();
+        assert_eq!(
+            ServerMsg::read_buffered(&mut reader, &mut pending)
+                .await
+                .unwrap(),
+            ServerMsg::Bell
+        );
+        assert_eq!(
+            ServerMsg::read_buffered(&mut reader, &mut pending)
+                .await
+                .unwrap(),
+            ServerMsg::ServerCutText("text".to_string())
+        );
+        assert_eq!(
+            ServerMsg::read_buffered(&mut reader, &mut pending)
+                .await
+                .unwrap(),
+            ServerMsg::FramebufferUpdate(3)
+        );
+        assert!(pending.is_empty());
+        assert!(ServerMsg::read_buffered(&mut reader, &mut pending)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_buffered_cancelled() {
+        let bytes = ServerMsg::ServerCutText("text".to_string()).to_bytes();
+        let (mut server, client) = tokio::io::duplex(64);
+        let mut reader = tokio::io::BufReader::new(client);
+        let mut pending = Vec::new();
+        server.write_all(&bytes[..6]).await.unwrap();
+        {
+            // the read is dropped while waiting for the rest
+            let read = pin!(ServerMsg::read_buffered(&mut reader, &mut pending));
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(read.poll(&mut cx).is_pending());
+        }
+        assert_eq!(pending, bytes[..6]);
+        server.write_all(&bytes[6..]).await.unwrap();
+        assert_eq!(
+            ServerMsg::read_buffered(&mut reader, &mut pending)
+                .await
+                .unwrap(),
+            ServerMsg::ServerCutText("text".to_string())
+        );
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        // incomplete
+        let bytes = ClientMsg::PointerEvent(1, 2, 0).to_bytes();
+        let e = ClientMsg::from_bytes(&bytes[..5]).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<std::io::Error>().unwrap().kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+        // unknown message type
+        assert!(ServerMsg::from_bytes(&[9]).is_err());
+        // unknown encodings are skipped
+        let bytes = [2, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 0];
+        assert_eq!(
+            ClientMsg::from_bytes(&bytes).unwrap().0,
+            ClientMsg::SetEncodings(vec![VncEncoding::Raw])
+        );
+    }
+}
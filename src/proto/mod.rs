@@ -0,0 +1,6 @@
+//! The wire format of the RFB protocol
+//!
+//! Shared by the client engine, and reusable by proxies, recorders and test tools
+//!
+
+pub mod messages;
@@ -0,0 +1,161 @@
+use crate::{PixelFormat, Rect};
+
+const RAW: u8 = 1;
+const BACKGROUND_SPECIFIED: u8 = 2;
+const FOREGROUND_SPECIFIED: u8 = 4;
+const ANY_SUBRECTS: u8 = 8;
+
+/// Encode the RGBA pixels of `rect` in 16x16 tiles
+///
+/// A tile of a single color is sent as the background, one of two colors as the subrects of
+/// the foreground row by row, and the others as they are
+pub(super) fn encode(format: &PixelFormat, rect: &Rect, rgba: &[u8]) -> Vec<u8> {
+    let bpp = format.bits_per_pixel as usize / 8;
+    let pixels = format.pack(rgba);
+    let stride = rect.width as usize * bpp;
+    let mut encoded = Vec::new();
+    // the colors are carried over from the previous tile, except after a raw one
+    let mut background: Option<&[u8]> = None;
+    let mut foreground: Option<&[u8]> = None;
+
+    for y in (0..rect.height as usize).step_by(16) {
+        let height = (rect.height as usize - y).min(16);
+        for x in (0..rect.width as usize).step_by(16) {
+            let width = (rect.width as usize - x).min(16);
+            let rows = (y..y + height)
+                .map(|row| &pixels[row * stride + x * bpp..row * stride + (x + width) * bpp])
+                .collect::<Vec<_>>();
+            let bg = &rows[0][..bpp];
+            let fg = rows
+                .iter()
+                .flat_map(|row| row.chunks_exact(bpp))
+                .find(|pixel| *pixel != bg);
+
+            let Some(fg) = fg else {
+                if background == Some(bg) {
+                    encoded.push(0);
+                } else {
+                    encoded.push(BACKGROUND_SPECIFIED);
+                    encoded.extend_from_slice(bg);
+                    background = Some(bg);
+                }
+                continue;
+            };
+
+            let subrects = two_colors(&rows, bpp, bg, fg);
+            match subrects {
+                // encoded as large as the raw tile is not worth it
+                Some(subrects) if subrects.len() * 2 + 2 * bpp + 2 < width * height * bpp => {
+                    let mut subencoding = ANY_SUBRECTS;
+                    if background != Some(bg) {
+                        subencoding |= BACKGROUND_SPECIFIED;
+                    }
+                    if foreground != Some(fg) {
+                        subencoding |= FOREGROUND_SPECIFIED;
+                    }
+                    encoded.push(subencoding);
+                    if background != Some(bg) {
+                        encoded.extend_from_slice(bg);
+                    }
+                    if foreground != Some(fg) {
+                        encoded.extend_from_slice(fg);
+                    }
+                    encoded.push(subrects.len() as u8);
+                    for (x, y, width) in subrects {
+                        encoded.push(x << 4 | y);
+                        encoded.push((width - 1) << 4);
+                    }
+                    background = Some(bg);
+                    foreground = Some(fg);
+                }
+                _ => {
+                    encoded.push(RAW);
+                    rows.iter().for_each(|row| encoded.extend_from_slice(row));
+                    background = None;
+                    foreground = None;
+                }
+            }
+        }
+    }
+    encoded
+}
+
+/// The runs of `fg` in each row as (x, y, width), if the tile is of only `bg` & `fg`
+fn two_colors(rows: &[&[u8]], bpp: usize, bg: &[u8], fg: &[u8]) -> Option<Vec<(u8, u8, u8)>> {
+    let mut subrects = Vec::new();
+    for (y, row) in rows.iter().enumerate() {
+        let mut run: Option<(u8, u8)> = None;
+        for (x, pixel) in row.chunks_exact(bpp).enumerate() {
+            if pixel == fg {
+                run = match run {
+                    Some((start, width)) => Some((start, width + 1)),
+                    None => Some((x as u8, 1)),
+                };
+            } else if pixel == bg {
+                if let Some((start, width)) = run.take() {
+                    subrects.push((start, y as u8, width));
+                }
+            } else {
+                return None;
+            }
+        }
+        if let Some((start, width)) = run {
+            subrects.push((start, y as u8, width));
+        }
+    }
+    Some(subrects)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: [u8; 4] = [1, 2, 3, 255];
+    const B: [u8; 4] = [4, 5, 6, 255];
+    const C: [u8; 4] = [7, 8, 9, 255];
+
+    fn rect(width: u16, height: u16) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn test_hextile() {
+        let format = PixelFormat::bgra();
+        let a = format.pack(&A);
+        let b = format.pack(&B);
+
+        // the background of the second tile is the same
+        let encoded = encode(&format, &rect(18, 1), &A.repeat(18));
+        assert_eq!(encoded, [&[2][..], &a, &[0]].concat());
+
+        // the runs of the foreground
+        let rgba = [A, B, B, A, A, A, A, A, B].concat();
+        let encoded = encode(&format, &rect(3, 3), &rgba);
+        assert_eq!(
+            encoded,
+            [
+                &[2 | 4 | 8][..],
+                &a,
+                &b,
+                &[2, 1 << 4, 1 << 4, 2 << 4 | 2, 0]
+            ]
+            .concat()
+        );
+
+        // too many colors, then the background is specified again
+        let rgba = [A, B, C, A].concat();
+        let encoded = encode(&format, &rect(4, 1), &rgba);
+        assert_eq!(encoded, [&[1][..], &format.pack(&rgba)].concat());
+        let rgba = [[A, B, C].concat(), A.repeat(13), A.repeat(16)].concat();
+        let encoded = encode(&format, &rect(32, 1), &rgba);
+        assert_eq!(
+            encoded,
+            [&[1][..], &format.pack(&rgba[..64]), &[2], &a].concat()
+        );
+    }
+}
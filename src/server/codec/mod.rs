@@ -0,0 +1,138 @@
+use anyhow::Result;
+use flate2::{Compress, Compression, FlushCompress};
+
+use crate::{PixelFormat, Rect, VncEncoding};
+
+mod hextile;
+mod tight;
+mod zrle;
+
+/// The encodings of the pixels which can be served
+const ENCODINGS: [VncEncoding; 4] = [
+    VncEncoding::Raw,
+    VncEncoding::Hextile,
+    VncEncoding::Zrle,
+    VncEncoding::Tight,
+];
+
+/// The encoding of the pixels, the first one of the client's preference which can be served
+///
+/// Raw if none of them can, which every client must support
+pub(super) fn preferred(encodings: &[VncEncoding]) -> VncEncoding {
+    encodings
+        .iter()
+        .find(|encoding| ENCODINGS.contains(encoding))
+        .copied()
+        .unwrap_or(VncEncoding::Raw)
+}
+
+/// The payload of a CopyRect from `src`
+pub(super) fn copy_rect(src: (u16, u16)) -> Vec<u8> {
+    let mut payload = src.0.to_be_bytes().to_vec();
+    payload.extend_from_slice(&src.1.to_be_bytes());
+    payload
+}
+
+/// The RGBA pixels of `rect` cropped from the `width` pixels wide rows of `rgba`
+fn crop(rgba: &[u8], width: u16, rect: &Rect) -> Vec<u8> {
+    let stride = width as usize * 4;
+    let line = rect.width as usize * 4;
+    let mut cropped = Vec::with_capacity(line * rect.height as usize);
+    for y in rect.y as usize..rect.y as usize + rect.height as usize {
+        let start = y * stride + rect.x as usize * 4;
+        cropped.extend_from_slice(&rgba[start..start + line]);
+    }
+    cropped
+}
+
+/// Deflate `data` and flush it, so that the client can inflate all of it at once
+///
+/// The stream is kept for the next rects, as the client keeps its decompressor
+fn deflate(compressor: &mut Compress, data: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len() / 2 + 64);
+    let mut input = data;
+    loop {
+        if output.capacity() - output.len() < 64 {
+            output.reserve(output.capacity());
+        }
+        let before = compressor.total_in();
+        compressor.compress_vec(input, &mut output, FlushCompress::Sync)?;
+        input = &input[(compressor.total_in() - before) as usize..];
+        // the flush is done once the output is no longer filled up
+        if input.is_empty() && output.len() < output.capacity() {
+            return Ok(output);
+        }
+    }
+}
+
+/// Encodes the pixels of the rects for a client, keeping the zlib streams of the ZRLE & Tight
+pub(super) struct Encoder {
+    zrle: Compress,
+    tight: Compress,
+}
+
+impl Encoder {
+    pub(super) fn new() -> Self {
+        Self {
+            zrle: Compress::new(Compression::default(), true),
+            tight: Compress::new(Compression::default(), true),
+        }
+    }
+
+    /// Encode the RGBA pixels of `rect` in `encoding` and `format`
+    ///
+    /// A rect may be split into several, so the rects are returned along with their payloads
+    pub(super) fn encode(
+        &mut self,
+        encoding: VncEncoding,
+        format: &PixelFormat,
+        rect: Rect,
+        rgba: &[u8],
+    ) -> Result<Vec<(Rect, Vec<u8>)>> {
+        let payload = match encoding {
+            VncEncoding::Hextile => hextile::encode(format, &rect, rgba),
+            VncEncoding::Zrle => zrle::encode(&mut self.zrle, format, &rect, rgba)?,
+            VncEncoding::Tight => return tight::encode(&mut self.tight, format, rect, rgba),
+            _ => format.pack(rgba),
+        };
+        Ok(vec![(rect, payload)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferred() {
+        assert_eq!(preferred(&[]), VncEncoding::Raw);
+        assert_eq!(
+            preferred(&[
+                VncEncoding::CopyRect,
+                VncEncoding::Zrle,
+                VncEncoding::Tight,
+                VncEncoding::Raw
+            ]),
+            VncEncoding::Zrle
+        );
+        // Trle is not served
+        assert_eq!(
+            preferred(&[VncEncoding::Trle, VncEncoding::Hextile]),
+            VncEncoding::Hextile
+        );
+    }
+
+    #[test]
+    fn test_deflate() {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut decompressor = flate2::Decompress::new(true);
+        for data in [vec![7; 100_000], (0..=255).collect()] {
+            let compressed = deflate(&mut compressor, &data).unwrap();
+            let mut inflated = Vec::with_capacity(data.len());
+            decompressor
+                .decompress_vec(&compressed, &mut inflated, flate2::FlushDecompress::Sync)
+                .unwrap();
+            assert_eq!(inflated, data);
+        }
+    }
+}
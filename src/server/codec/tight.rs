@@ -0,0 +1,181 @@
+use anyhow::Result;
+use flate2::Compress;
+
+use crate::{PixelFormat, Rect};
+
+use super::{crop, deflate};
+
+/// The widest rect of the basic compression
+const MAX_WIDTH: u16 = 2048;
+/// The most pixels of a rect of the basic compression
+const MAX_PIXELS: usize = 65536;
+/// The data shorter than it is sent without the zlib
+const MIN_TO_COMPRESS: usize = 12;
+
+const FILL: u8 = 0x80;
+// the basic compression with the copy filter through the zlib stream 0
+const BASIC: u8 = 0x00;
+
+/// The TPIXELs of the RGBA pixels in `format`
+///
+/// The 32 bits pixels of 8 bits channels are sent as 3 bytes of RGB, the others as they are
+fn tpixels(format: &PixelFormat, rgba: &[u8]) -> (Vec<u8>, usize) {
+    if format.bits_per_pixel == 32
+        && format.depth == 24
+        && [format.red_max, format.green_max, format.blue_max] == [255; 3]
+    {
+        let rgb = rgba.chunks_exact(4).flat_map(|pixel| &pixel[..3]);
+        (rgb.copied().collect(), 3)
+    } else {
+        (format.pack(rgba), format.bits_per_pixel as usize / 8)
+    }
+}
+
+/// Append the compact representation of a data length
+fn push_len(encoded: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else if len < 0x4000 {
+        encoded.extend_from_slice(&[len as u8 | 0x80, (len >> 7) as u8]);
+    } else {
+        encoded.extend_from_slice(&[len as u8 | 0x80, (len >> 7) as u8 | 0x80, (len >> 14) as u8]);
+    }
+}
+
+/// Encode the RGBA pixels of `rect`, split into the rects small enough for the basic compression
+///
+/// A rect of a single color is filled, the others are deflated by the zlib stream 0 of the client
+pub(super) fn encode(
+    compressor: &mut Compress,
+    format: &PixelFormat,
+    rect: Rect,
+    rgba: &[u8],
+) -> Result<Vec<(Rect, Vec<u8>)>> {
+    let mut encoded = Vec::new();
+    for x in (0..rect.width).step_by(MAX_WIDTH as usize) {
+        let width = (rect.width - x).min(MAX_WIDTH);
+        let rows = (MAX_PIXELS / width as usize) as u16;
+        for y in (0..rect.height).step_by(rows as usize) {
+            let part = Rect {
+                x,
+                y,
+                width,
+                height: (rect.height - y).min(rows),
+            };
+            let (pixels, len) = tpixels(format, &crop(rgba, rect.width, &part));
+            let mut payload = Vec::new();
+            if pixels
+                .chunks_exact(len)
+                .all(|pixel| pixel == &pixels[..len])
+            {
+                payload.push(FILL);
+                payload.extend_from_slice(&pixels[..len]);
+            } else if pixels.len() < MIN_TO_COMPRESS {
+                payload.push(BASIC);
+                payload.extend_from_slice(&pixels);
+            } else {
+                let compressed = deflate(compressor, &pixels)?;
+                payload.push(BASIC);
+                push_len(&mut payload, compressed.len());
+                payload.extend_from_slice(&compressed);
+            }
+            let part = Rect {
+                x: rect.x + x,
+                y: rect.y + y,
+                ..part
+            };
+            encoded.push((part, payload));
+        }
+    }
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::TightDecoder, JpegPolicy, VncEvent};
+    use flate2::Compression;
+
+    #[test]
+    fn test_push_len() {
+        for (len, expected) in [
+            (10, vec![10]),
+            (0x80, vec![0x80, 1]),
+            (0x4000, vec![0x80, 0x80, 1]),
+        ] {
+            let mut encoded = Vec::new();
+            push_len(&mut encoded, len);
+            assert_eq!(encoded, expected);
+        }
+    }
+
+    async fn check(format: &PixelFormat, rect: Rect, rgba: &[u8]) -> Vec<Rect> {
+        let mut compressor = Compress::new(Compression::default(), true);
+        let mut decoder = TightDecoder::new(JpegPolicy::Emit, 0, Default::default());
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let bpp = format.bits_per_pixel as usize / 8;
+        // the decoder sets the unused bits of the 32 bits pixels
+        let mask = format.pixel_mask().to_le_bytes();
+        let mut parts = Vec::new();
+        for (part, payload) in encode(&mut compressor, format, rect, rgba).unwrap() {
+            decoder
+                .decode(format, &part, &mut payload.as_slice(), &tx)
+                .await
+                .unwrap();
+            let Some(VncEvent::RawImage(decoded, pixels)) = rx.recv().await else {
+                panic!("No image");
+            };
+            assert_eq!(decoded, part);
+            let pixels = pixels
+                .chunks_exact(bpp)
+                .flat_map(|pixel| pixel.iter().zip(mask).map(|(byte, mask)| byte & mask))
+                .collect::<Vec<_>>();
+            let relative = Rect {
+                x: part.x - rect.x,
+                y: part.y - rect.y,
+                ..part
+            };
+            assert_eq!(pixels, format.pack(&crop(rgba, rect.width, &relative)));
+            parts.push(part);
+        }
+        parts
+    }
+
+    #[tokio::test]
+    async fn test_tight() {
+        let rect = Rect {
+            x: 3,
+            y: 5,
+            width: 2100,
+            height: 40,
+        };
+        // the pixels on the right of the widest rect are filled
+        let rgba = (0..40)
+            .flat_map(|y: usize| {
+                (0..2100).flat_map(move |x: usize| match x {
+                    ..=2047 => [x as u8, y as u8, 7, 0],
+                    _ => [9, 9, 9, 0],
+                })
+            })
+            .collect::<Vec<_>>();
+        let small = Rect {
+            width: 2,
+            height: 1,
+            ..rect
+        };
+        for format in [PixelFormat::bgra(), PixelFormat::rgb565()] {
+            // too short to be compressed
+            check(&format, small, &[1, 2, 3, 0, 4, 5, 6, 0]).await;
+
+            let parts = check(&format, rect, &rgba).await;
+            let sizes = parts
+                .iter()
+                .map(|part| (part.x, part.y, part.width, part.height))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                sizes,
+                [(3, 5, 2048, 32), (3, 37, 2048, 8), (2051, 5, 52, 40)]
+            );
+        }
+    }
+}
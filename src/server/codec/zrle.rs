@@ -0,0 +1,180 @@
+use anyhow::Result;
+use flate2::Compress;
+
+use crate::{PixelFormat, Rect};
+
+use super::deflate;
+
+const TILE: usize = 64;
+const MAX_PALETTE: usize = 16;
+
+/// The bytes of a pixel kept in a CPIXEL, as (offset, len)
+///
+/// The 32 bits true color pixels whose channels fit in 3 bytes leave out the unused one
+fn cpixel(format: &PixelFormat) -> (usize, usize) {
+    let bpp = format.bits_per_pixel as usize / 8;
+    if format.bits_per_pixel != 32 || format.true_color_flag == 0 || format.depth > 24 {
+        return (0, bpp);
+    }
+    let big_endian = format.big_endian_flag > 0;
+    let pixel_mask = format.pixel_mask();
+    if pixel_mask & 0x000000ff == 0 {
+        // the least significant byte is unused, which comes first in little endian
+        (!big_endian as usize, 3)
+    } else if pixel_mask & 0xff000000 == 0 {
+        (big_endian as usize, 3)
+    } else {
+        (0, 4)
+    }
+}
+
+/// Encode the RGBA pixels of `rect` in 64x64 tiles, deflated by the zlib stream of the client
+///
+/// A tile is sent as a single color, a packed palette of up to 16 colors, or raw CPIXELs
+pub(super) fn encode(
+    compressor: &mut Compress,
+    format: &PixelFormat,
+    rect: &Rect,
+    rgba: &[u8],
+) -> Result<Vec<u8>> {
+    let bpp = format.bits_per_pixel as usize / 8;
+    let (offset, len) = cpixel(format);
+    let cpixels = format
+        .pack(rgba)
+        .chunks_exact(bpp)
+        .flat_map(|pixel| pixel[offset..offset + len].to_vec())
+        .collect::<Vec<_>>();
+    let stride = rect.width as usize * len;
+
+    let mut tiles = Vec::new();
+    for y in (0..rect.height as usize).step_by(TILE) {
+        let height = (rect.height as usize - y).min(TILE);
+        for x in (0..rect.width as usize).step_by(TILE) {
+            let width = (rect.width as usize - x).min(TILE);
+            let rows = (y..y + height)
+                .map(|row| &cpixels[row * stride + x * len..row * stride + (x + width) * len])
+                .collect::<Vec<_>>();
+            encode_tile(&mut tiles, &rows, len);
+        }
+    }
+
+    let compressed = deflate(compressor, &tiles)?;
+    let mut encoded = (compressed.len() as u32).to_be_bytes().to_vec();
+    encoded.extend_from_slice(&compressed);
+    Ok(encoded)
+}
+
+fn encode_tile(tiles: &mut Vec<u8>, rows: &[&[u8]], len: usize) {
+    let mut palette: Vec<&[u8]> = Vec::with_capacity(MAX_PALETTE);
+    for pixel in rows.iter().flat_map(|row| row.chunks_exact(len)) {
+        if !palette.contains(&pixel) {
+            if palette.len() == MAX_PALETTE {
+                // raw
+                tiles.push(0);
+                rows.iter().for_each(|row| tiles.extend_from_slice(row));
+                return;
+            }
+            palette.push(pixel);
+        }
+    }
+
+    tiles.push(palette.len() as u8);
+    palette
+        .iter()
+        .for_each(|color| tiles.extend_from_slice(color));
+    let bits = match palette.len() {
+        1 => return,
+        2 => 1,
+        3..=4 => 2,
+        _ => 4,
+    };
+    for row in rows {
+        // every row starts at a byte boundary
+        let mut packed = 0;
+        let mut shift = 8;
+        for pixel in row.chunks_exact(len) {
+            let index = palette.iter().position(|color| *color == pixel).unwrap() as u8;
+            shift -= bits;
+            packed |= index << shift;
+            if shift == 0 {
+                tiles.push(packed);
+                packed = 0;
+                shift = 8;
+            }
+        }
+        if shift < 8 {
+            tiles.push(packed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec::ZrleDecoder, VncEvent};
+    use flate2::Compression;
+
+    /// The decoded tiles put together, in the wire format of 4 bytes pixels
+    fn assemble(rect: &Rect, rx: &mut tokio::sync::mpsc::Receiver<VncEvent>) -> Vec<u8> {
+        let stride = rect.width as usize * 4;
+        let mut image = vec![0; stride * rect.height as usize];
+        while let Ok(VncEvent::RawImage(tile, pixels)) = rx.try_recv() {
+            let line = tile.width as usize * 4;
+            for (row, pixels) in pixels.chunks_exact(line).enumerate() {
+                let start = (tile.y as usize + row) * stride + tile.x as usize * 4;
+                image[start..start + line].copy_from_slice(pixels);
+            }
+        }
+        image
+    }
+
+    #[tokio::test]
+    async fn test_zrle() {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 70,
+            height: 66,
+        };
+        // a raw tile, a palette tile & two solid tiles
+        let rgba = (0..66)
+            .flat_map(|y: usize| {
+                (0..70).flat_map(move |x: usize| match (x < 64, y < 64) {
+                    (true, true) => [x as u8, y as u8, 0, 0],
+                    (false, true) => [(y % 5) as u8, 0, 0, 0],
+                    _ => [9, 9, 9, 0],
+                })
+            })
+            .collect::<Vec<_>>();
+        for format in [PixelFormat::bgra(), PixelFormat::rgba()] {
+            let mut big_endian = format;
+            big_endian.big_endian_flag = 1;
+            for format in [format, big_endian] {
+                let mut compressor = Compress::new(Compression::default(), true);
+                let mut decoder = ZrleDecoder::new(Default::default());
+                let (tx, mut rx) = tokio::sync::mpsc::channel(8);
+                // twice through the same zlib streams
+                for _ in 0..2 {
+                    let encoded = encode(&mut compressor, &format, &rect, &rgba).unwrap();
+                    decoder
+                        .decode(&format, &rect, &mut encoded.as_slice(), &tx)
+                        .await
+                        .unwrap();
+                    // the unused byte of the 3 bytes CPIXELs is filled up by the decoder
+                    let decoded = assemble(&rect, &mut rx)
+                        .chunks_exact(4)
+                        .flat_map(|pixel| {
+                            let pixel: [u8; 4] = pixel.try_into().unwrap();
+                            let mask = format.pixel_mask();
+                            match format.big_endian_flag {
+                                0 => (u32::from_le_bytes(pixel) & mask).to_le_bytes(),
+                                _ => (u32::from_be_bytes(pixel) & mask).to_be_bytes(),
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    assert_eq!(decoded, format.pack(&rgba));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{info, trace, warn};
+
+use crate::{client::security, SecurityType, VncError, VncVersion};
+
+use super::service::ServerConfig;
+
+/// A client which has been authenticated, given to the access policy of [super::VncServer]
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Unique among the clients of the same server
+    ///
+    pub id: u64,
+    /// The address of the client, if served over a socket
+    ///
+    pub peer: Option<SocketAddr>,
+    /// The version spoken by the client
+    ///
+    pub version: VncVersion,
+    /// The shared flag of the ClientInit
+    ///
+    /// A client asking for the exclusive access disconnects the others once accepted
+    ///
+    pub shared: bool,
+}
+
+/// Go through the handshake as the server, up to the ServerInit of `screen`
+///
+pub(super) async fn handshake<S>(
+    stream: &mut S,
+    config: &ServerConfig,
+    id: u64,
+    peer: Option<SocketAddr>,
+    screen: (u16, u16),
+) -> Result<ClientInfo>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    VncVersion::RFB38.write(stream).await?;
+    stream.flush().await?;
+    let version = VncVersion::from(VncVersion::read(stream).await?);
+    trace!("Client {} speaks {:?}", id, version);
+
+    let security_type = match config.password {
+        Some(_) => SecurityType::VncAuth,
+        None => SecurityType::None,
+    };
+    if version == VncVersion::RFB33 {
+        // the server decides the security type
+        stream.write_u32(security_type as u32).await?;
+    } else {
+        stream.write_all(&[1, security_type as u8]).await?;
+        stream.flush().await?;
+        let chosen = stream.read_u8().await?;
+        if chosen != security_type as u8 {
+            let reason = format!("Security type {} is not offered", chosen);
+            fail(stream, version, &reason).await?;
+            return Err(VncError::Custom(reason).into());
+        }
+    }
+
+    match config.password.as_ref() {
+        Some(password) => {
+            let mut challenge = [0; 16];
+            getrandom::getrandom(&mut challenge).map_err(|_| {
+                VncError::Custom("No random source for the VncAuth challenge".to_string())
+            })?;
+            stream.write_all(&challenge).await?;
+            stream.flush().await?;
+            let mut response = [0; 16];
+            stream.read_exact(&mut response).await?;
+            let accepted = password(peer).is_some_and(|password| {
+                security::vnc_auth_response(&challenge, password.as_bytes()) == response
+            });
+            if !accepted {
+                warn!("Client {} failed the VncAuth", id);
+                fail(stream, version, "Authentication failed").await?;
+                return Err(VncError::WrongPassword.into());
+            }
+            stream.write_u32(0).await?;
+        }
+        // only 3.8 confirms the None security type
+        None if version == VncVersion::RFB38 => stream.write_u32(0).await?,
+        None => (),
+    }
+    stream.flush().await?;
+
+    let client = ClientInfo {
+        id,
+        peer,
+        version,
+        shared: stream.read_u8().await? > 0,
+    };
+    if !config
+        .access_policy
+        .as_ref()
+        .is_none_or(|policy| policy(&client))
+    {
+        // there is no way to tell the reason after the ClientInit
+        info!("Client {} is rejected by the access policy", id);
+        return Err(VncError::Custom("Rejected by the access policy".to_string()).into());
+    }
+
+    // +--------------+--------------+------------------------------+
+    // | No. of bytes | Type [Value] | Description                  |
+    // +--------------+--------------+------------------------------+
+    // | 2            | U16          | framebuffer-width in pixels  |
+    // | 2            | U16          | framebuffer-height in pixels |
+    // | 16           | PIXEL_FORMAT | server-pixel-format          |
+    // | 4            | U32          | name-length                  |
+    // | name-length  | U8 array     | name-string                  |
+    // +--------------+--------------+------------------------------+
+    let mut server_init = Vec::with_capacity(24 + config.name.len());
+    server_init.extend_from_slice(&screen.0.to_be_bytes());
+    server_init.extend_from_slice(&screen.1.to_be_bytes());
+    server_init.extend_from_slice(&Vec::<u8>::from(config.pixel_format));
+    server_init.extend_from_slice(&(config.name.len() as u32).to_be_bytes());
+    server_init.extend_from_slice(config.name.as_bytes());
+    stream.write_all(&server_init).await?;
+    stream.flush().await?;
+    info!("Client {} connected from {:?}", id, peer);
+    Ok(client)
+}
+
+/// Tell the client that the security handshake failed, with the reason if 3.8 is spoken
+///
+async fn fail<S>(stream: &mut S, version: VncVersion, reason: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u32(1).await?;
+    if version == VncVersion::RFB38 {
+        stream.write_u32(reason.len() as u32).await?;
+        stream.write_all(reason.as_bytes()).await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use tokio::sync::mpsc;
+use tracing::trace;
+
+use crate::{ClientKeyEvent, ClientMouseEvent};
+
+/// The inputs queued for each subscriber, the clients wait for a slow one
+pub(super) const INPUT_QUEUE: usize = 64;
+
+/// An input sent by a served client
+///
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub enum InputEvent {
+    /// Key down/up
+    ///
+    KeyEvent(ClientKeyEvent),
+    /// Mouse move/up/down/scroll
+    ///
+    PointerEvent(ClientMouseEvent),
+    /// The text cut by the client, in Latin-1
+    ///
+    CutText(String),
+}
+
+/// An input along with the id of the client, see [super::VncServer::subscribe_inputs]
+///
+#[derive(Debug, Clone)]
+pub struct ClientInput {
+    /// The id in the [super::ClientInfo] of the client
+    ///
+    pub client: u64,
+    pub event: InputEvent,
+}
+
+pub(super) type InputSubscribers = Mutex<Vec<mpsc::Sender<ClientInput>>>;
+
+/// Delivers the inputs of a client to the subscribers, unless the client is view only
+pub(super) struct InputSink<'a> {
+    pub(super) client: u64,
+    pub(super) view_only: Arc<AtomicBool>,
+    pub(super) subscribers: &'a InputSubscribers,
+}
+
+impl InputSink<'_> {
+    pub(super) async fn deliver(&self, event: InputEvent) {
+        if self.view_only.load(Ordering::Relaxed) {
+            trace!("Input of the view only client {} ignored", self.client);
+            return;
+        }
+        let subscribers = self.subscribers.lock().unwrap().clone();
+        let mut unsubscribed = false;
+        for subscriber in subscribers {
+            let input = ClientInput {
+                client: self.client,
+                event: event.clone(),
+            };
+            unsubscribed |= subscriber.send(input).await.is_err();
+        }
+        if unsubscribed {
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain(|subscriber| !subscriber.is_closed());
+        }
+    }
+}
@@ -0,0 +1,16 @@
+//! The server side, serving a surface rendered by the application to the vnc clients
+//!
+//! The surface is provided by a [FrameSource], and served by a [VncServer]
+//!
+
+mod codec;
+mod handshake;
+mod input;
+mod service;
+mod session;
+mod source;
+
+pub use handshake::ClientInfo;
+pub use input::{ClientInput, InputEvent};
+pub use service::VncServer;
+pub use source::{ChannelSource, Damage, DamageSender, FrameSource, MemorySource};
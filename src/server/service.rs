@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::{mpsc, watch},
+};
+use tracing::info;
+
+use crate::PixelFormat;
+
+use super::{
+    handshake::{self, ClientInfo},
+    input::{ClientInput, InputSink, InputSubscribers, INPUT_QUEUE},
+    session, FrameSource,
+};
+
+/// Returns the password of the VncAuth for the client at the address, `None` rejects the client
+type PasswordCallback = Box<dyn Fn(Option<SocketAddr>) -> Option<String> + Send + Sync>;
+
+/// Returns whether the authenticated client is accepted, or is view only
+type AccessPolicy = Box<dyn Fn(&ClientInfo) -> bool + Send + Sync>;
+
+pub(super) struct ServerConfig {
+    pub(super) name: String,
+    // announced in the ServerInit
+    pub(super) pixel_format: PixelFormat,
+    // the VncAuth security type is required if set, otherwise None
+    pub(super) password: Option<PasswordCallback>,
+    pub(super) access_policy: Option<AccessPolicy>,
+    pub(super) view_only_policy: Option<AccessPolicy>,
+    // advertise the session by mDNS/DNS-SD while listening
+    #[cfg(feature = "mdns")]
+    pub(super) mdns: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            name: "vnc-rs".to_string(),
+            pixel_format: PixelFormat::bgra(),
+            password: None,
+            access_policy: None,
+            view_only_policy: None,
+            #[cfg(feature = "mdns")]
+            mdns: false,
+        }
+    }
+}
+
+/// A client being served, which can be told to stop
+struct Connected {
+    stop: watch::Sender<bool>,
+    // the inputs of the client are ignored if set
+    view_only: Arc<AtomicBool>,
+}
+
+/// The clients being served and the subscribers of their inputs
+#[derive(Default)]
+struct Clients {
+    next_id: AtomicU64,
+    connected: Mutex<HashMap<u64, Connected>>,
+    inputs: InputSubscribers,
+}
+
+/// A vnc server serving the surface of a [FrameSource] to the clients
+///
+/// Without a password the clients connect with the None security type,
+///
+/// and every client authenticated is accepted unless an access policy says otherwise
+///
+/// ```no_run
+/// use vnc::server::{MemorySource, VncServer};
+/// use tokio::{self, net::TcpListener};
+/// use anyhow::Result;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<()> {
+///     let source = MemorySource::new(800, 600);
+///     let listener = TcpListener::bind("127.0.0.1:5900").await?;
+///     VncServer::new(source.clone())
+///         .set_name("desktop")
+///         .set_password(|_| Some("password".to_string()))
+///         .set_access_policy(|client| client.peer.is_some_and(|peer| peer.ip().is_loopback()))
+///         .listen(listener)
+///         .await
+/// }
+/// ```
+///
+pub struct VncServer<F> {
+    source: F,
+    config: ServerConfig,
+    clients: Clients,
+}
+
+impl<F> VncServer<F>
+where
+    F: FrameSource,
+{
+    pub fn new(source: F) -> Self {
+        Self {
+            source,
+            config: ServerConfig::default(),
+            clients: Clients::default(),
+        }
+    }
+
+    /// The desktop name told in the ServerInit, default to `vnc-rs`
+    ///
+    pub fn set_name(mut self, name: &str) -> Self {
+        self.config.name = name.to_string();
+        self
+    }
+
+    /// Require the VncAuth security type, with the password returned by `password`
+    ///
+    /// for the address of the client if known, `None` rejects the client
+    ///
+    /// Only the first 8 bytes of the password are used by the VncAuth
+    ///
+    pub fn set_password<P>(mut self, password: P) -> Self
+    where
+        P: Fn(Option<SocketAddr>) -> Option<String> + Send + Sync + 'static,
+    {
+        self.config.password = Some(Box::new(password));
+        self
+    }
+
+    /// Decide whether to serve each client once it is authenticated,
+    ///
+    /// by the address & the shared flag in the [ClientInfo]
+    ///
+    /// The rejected clients are disconnected before the ServerInit
+    ///
+    pub fn set_access_policy<P>(mut self, policy: P) -> Self
+    where
+        P: Fn(&ClientInfo) -> bool + Send + Sync + 'static,
+    {
+        self.config.access_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Decide whether each client accepted is view only, whose inputs are ignored
+    ///
+    /// Which can be changed by [VncServer::set_view_only] later, default to none of them
+    ///
+    pub fn set_view_only_policy<P>(mut self, policy: P) -> Self
+    where
+        P: Fn(&ClientInfo) -> bool + Send + Sync + 'static,
+    {
+        self.config.view_only_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Advertise the session as `_rfb._tcp` by mDNS/DNS-SD while [VncServer::listen] is serving,
+    ///
+    /// with the name of [VncServer::set_name] and the port of the listener, so that the clients on the LAN discover it
+    ///
+    /// The advertisement is withdrawn once `listen` returns or is dropped
+    ///
+    #[cfg(feature = "mdns")]
+    pub fn set_mdns(mut self, enable: bool) -> Self {
+        self.config.mdns = enable;
+        self
+    }
+
+    /// Make the client of `id` view only or not, returns whether the client is being served
+    ///
+    pub fn set_view_only(&self, id: u64, view_only: bool) -> bool {
+        match self.clients.connected.lock().unwrap().get(&id) {
+            Some(client) => {
+                client.view_only.store(view_only, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Subscribe to the key, pointer & cut text inputs of the clients from now on
+    ///
+    /// Each input is delivered to every subscriber, and the clients wait for a subscriber which lags
+    ///
+    /// so the inputs should be applied promptly, e.g. to the UI or the virtual machine
+    ///
+    /// ```no_run
+    /// use vnc::server::{InputEvent, MemorySource, VncServer};
+    /// use tokio::{self, net::TcpListener};
+    /// use anyhow::Result;
+    ///
+    /// #[tokio::main]
+    /// async fn main() -> Result<()> {
+    ///     let server = VncServer::new(MemorySource::new(800, 600));
+    ///     let mut inputs = server.subscribe_inputs();
+    ///     tokio::spawn(server.listen(TcpListener::bind("127.0.0.1:5900").await?));
+    ///     while let Some(input) = inputs.recv().await {
+    ///         if let InputEvent::KeyEvent(key) = input.event {
+    ///             println!("Client {} pressed {}: {}", input.client, key.keycode, key.down);
+    ///         }
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    pub fn subscribe_inputs(&self) -> mpsc::Receiver<ClientInput> {
+        let (sender, inputs) = mpsc::channel(INPUT_QUEUE);
+        self.clients.inputs.lock().unwrap().push(sender);
+        inputs
+    }
+
+    /// Serve a client over `stream` until it disconnects, `peer` is the address of it if any
+    ///
+    /// A client asking for the exclusive access disconnects the others being served
+    ///
+    pub async fn serve<S>(&self, mut stream: S, peer: Option<SocketAddr>) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let id = self.clients.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        // subscribed ahead, so that no damage is missed after the ServerInit
+        let damage = self.source.damage();
+        let client =
+            handshake::handshake(&mut stream, &self.config, id, peer, self.source.size()).await?;
+        let (stop_sender, stop) = watch::channel(false);
+        let view_only = self
+            .config
+            .view_only_policy
+            .as_ref()
+            .is_some_and(|policy| policy(&client));
+        let view_only = Arc::new(AtomicBool::new(view_only));
+        {
+            let mut connected = self.clients.connected.lock().unwrap();
+            if !client.shared {
+                for (other, connected) in connected.drain() {
+                    info!(
+                        "Disconnect client {} for the exclusive client {}",
+                        other, id
+                    );
+                    let _ = connected.stop.send(true);
+                }
+            }
+            connected.insert(
+                id,
+                Connected {
+                    stop: stop_sender,
+                    view_only: view_only.clone(),
+                },
+            );
+        }
+        let inputs = InputSink {
+            client: id,
+            view_only,
+            subscribers: &self.clients.inputs,
+        };
+        let result = session::run(stream, &self.source, damage, &self.config, inputs, stop).await;
+        self.clients.connected.lock().unwrap().remove(&id);
+        info!("Client {} disconnected", id);
+        result
+    }
+
+    /// Serve the clients accepted by `listener`, each in a task of its own
+    ///
+    /// Only fails if the listener does, a failure of the mDNS advertisement is only logged
+    ///
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn listen(self, listener: tokio::net::TcpListener) -> Result<()> {
+        #[cfg(feature = "mdns")]
+        let _advertisement = if self.config.mdns {
+            crate::mdns::Advertisement::new(&self.config.name, listener.local_addr()?)
+                .inspect_err(|e| tracing::warn!("Failed to advertise the session: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        let server = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.serve(stream, Some(peer)).await {
+                    info!("Stop serving {}: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{InputEvent, MemorySource},
+        Rect, VncClient, VncConnector, VncEncoding, VncError, VncEvent, X11Event,
+    };
+    use tokio::io::{duplex, DuplexStream};
+
+    async fn connect(
+        stream: DuplexStream,
+        password: &'static str,
+        shared: bool,
+    ) -> Result<VncClient> {
+        connect_with(stream, password, shared, &[VncEncoding::Raw]).await
+    }
+
+    async fn connect_with(
+        stream: DuplexStream,
+        password: &'static str,
+        shared: bool,
+        encodings: &[VncEncoding],
+    ) -> Result<VncClient> {
+        encodings
+            .iter()
+            .fold(VncConnector::new(stream), |connector, encoding| {
+                connector.add_encoding(*encoding)
+            })
+            .set_auth_method(move |_| async move { Ok(password.into()) })
+            .allow_shared(shared)
+            .set_pixel_format(PixelFormat::bgra())
+            .build()?
+            .try_start()
+            .await?
+            .finish()
+    }
+
+    async fn next_image(vnc: &VncClient) -> (Rect, Vec<u8>) {
+        loop {
+            if let VncEvent::RawImage(rect, pixels) = vnc.recv_event().await.unwrap() {
+                return (rect, pixels);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let source = MemorySource::new(2, 1);
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        source.write(whole, &[1, 2, 3, 255, 4, 5, 6, 255]).unwrap();
+        let server = Arc::new(
+            VncServer::new(source.clone())
+                .set_name("test")
+                .set_password(|_| Some("secret".to_string())),
+        );
+        let (client, stream) = duplex(4096);
+        let serving = {
+            let server = server.clone();
+            tokio::spawn(async move { server.serve(stream, None).await })
+        };
+        let vnc = connect(client, "secret", true).await.unwrap();
+        assert_eq!(vnc.session_info().name, "test");
+        assert_eq!(
+            next_image(&vnc).await,
+            (whole, vec![3, 2, 1, 0, 6, 5, 4, 0])
+        );
+
+        // only the damage is updated upon the incremental requests
+        let damaged = Rect {
+            x: 1,
+            width: 1,
+            ..whole
+        };
+        source.write(damaged, &[7, 8, 9, 255]).unwrap();
+        vnc.input(X11Event::Refresh).await.unwrap();
+        assert_eq!(next_image(&vnc).await, (damaged, vec![9, 8, 7, 0]));
+        serving.abort();
+    }
+
+    #[tokio::test]
+    async fn test_encodings() {
+        let source = MemorySource::new(4, 2);
+        let whole = Rect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 2,
+        };
+        let rgba = (0..32).collect::<Vec<u8>>();
+        source.write(whole, &rgba).unwrap();
+        let server = VncServer::new(source.clone());
+        let (client, stream) = duplex(4096);
+        let serving = tokio::spawn(async move { server.serve(stream, None).await });
+        let encodings = [VncEncoding::CopyRect, VncEncoding::Zrle, VncEncoding::Raw];
+        let vnc = connect_with(client, "", true, &encodings).await.unwrap();
+        // through the ZRLE
+        let (rect, pixels) = next_image(&vnc).await;
+        assert_eq!(rect, whole);
+        let expected = PixelFormat::bgra().pack(&rgba);
+        for (pixel, expected) in pixels.chunks_exact(4).zip(expected.chunks_exact(4)) {
+            assert_eq!(pixel[..3], expected[..3]);
+        }
+
+        // scrolled up by a row
+        let dst = Rect { height: 1, ..whole };
+        source.copy(dst, (0, 1)).unwrap();
+        vnc.input(X11Event::Refresh).await.unwrap();
+        loop {
+            match vnc.recv_event().await.unwrap() {
+                VncEvent::Copy(copied, src) => {
+                    assert_eq!(copied, dst);
+                    assert_eq!(src, Rect { y: 1, ..dst });
+                    break;
+                }
+                VncEvent::RawImage(..) => panic!("Copied as an image"),
+                _ => (),
+            }
+        }
+        serving.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_inputs() {
+        let server = Arc::new(
+            VncServer::new(MemorySource::new(1, 1)).set_view_only_policy(|client| client.id == 2),
+        );
+        let mut inputs = server.subscribe_inputs();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (client, stream) = duplex(4096);
+            let server = server.clone();
+            tokio::spawn(async move { server.serve(stream, None).await });
+            clients.push(connect(client, "", true).await.unwrap());
+        }
+        let key = |keysym: u32| X11Event::KeyEvent((keysym, true).into());
+
+        // the view only client is ignored
+        clients[1].input(key(0x62)).await.unwrap();
+        clients[1].flush_input().await.unwrap();
+        clients[0]
+            .input(X11Event::PointerEvent((3, 4, 1).into()))
+            .await
+            .unwrap();
+        let input = inputs.recv().await.unwrap();
+        assert_eq!(input.client, 1);
+        assert!(matches!(
+            input.event,
+            InputEvent::PointerEvent(pointer) if (pointer.position_x, pointer.position_y, pointer.bottons) == (3, 4, 1)
+        ));
+        // the paused clock only advances once the sessions are idle, i.e. the key has been ignored
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        assert!(server.set_view_only(2, false));
+        assert!(!server.set_view_only(3, false));
+        clients[1].input(key(0x63)).await.unwrap();
+        clients[1]
+            .input(X11Event::CopyText("text".to_string()))
+            .await
+            .unwrap();
+        let input = inputs.recv().await.unwrap();
+        assert_eq!(input.client, 2);
+        assert!(matches!(
+            input.event,
+            InputEvent::KeyEvent(key) if key.keycode == 0x63 && key.down
+        ));
+        assert!(matches!(
+            inputs.recv().await.unwrap().event,
+            InputEvent::CutText(text) if text == "text"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_security() {
+        let server = VncServer::new(MemorySource::new(1, 1))
+            .set_password(|_| Some("secret".to_string()))
+            .set_access_policy(|client| client.shared);
+
+        let (client, stream) = duplex(4096);
+        let (vnc, served) =
+            tokio::join!(connect(client, "wrong", true), server.serve(stream, None));
+        assert!(vnc.is_err());
+        assert!(matches!(
+            served.unwrap_err().downcast_ref::<VncError>(),
+            Some(VncError::WrongPassword)
+        ));
+
+        // authenticated but rejected by the policy
+        let (client, stream) = duplex(4096);
+        let (vnc, served) =
+            tokio::join!(connect(client, "secret", false), server.serve(stream, None));
+        assert!(vnc.is_err());
+        assert!(served.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exclusive_client() {
+        let server = Arc::new(VncServer::new(MemorySource::new(1, 1)));
+        let mut clients = Vec::new();
+        let mut served = Vec::new();
+        for shared in [true, true, false] {
+            let (client, stream) = duplex(4096);
+            let server = server.clone();
+            served.push(tokio::spawn(
+                async move { server.serve(stream, None).await },
+            ));
+            clients.push(connect(client, "", shared).await.unwrap());
+        }
+        // the shared ones are disconnected by the exclusive one
+        for served in served.drain(..2) {
+            served.await.unwrap().unwrap();
+        }
+        assert!(!served[0].is_finished());
+    }
+}
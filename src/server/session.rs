@@ -0,0 +1,347 @@
+use std::io;
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{broadcast, mpsc, watch},
+};
+use tracing::{error, trace};
+
+use crate::{
+    proto::messages::{ClientMsg, ServerMsg},
+    ClientKeyEvent, ClientMouseEvent, PixelFormat, Rect, VncEncoding, VncError,
+};
+
+use super::{
+    codec::{self, Encoder},
+    input::{InputEvent, InputSink},
+    service::ServerConfig,
+    Damage, FrameSource,
+};
+
+/// The client messages read ahead while an update is being written
+const MESSAGE_QUEUE: usize = 64;
+
+fn full(width: u16, height: u16) -> Rect {
+    Rect {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    }
+}
+
+fn is_empty(rect: &Rect) -> bool {
+    rect.width == 0 || rect.height == 0
+}
+
+/// The bounding box of both
+fn union(a: Option<Rect>, b: Rect) -> Option<Rect> {
+    if is_empty(&b) {
+        return a;
+    }
+    let Some(a) = a else {
+        return Some(b);
+    };
+    let x = a.x.min(b.x);
+    let y = a.y.min(b.y);
+    let right = (a.x as u32 + a.width as u32).max(b.x as u32 + b.width as u32);
+    let bottom = (a.y as u32 + a.height as u32).max(b.y as u32 + b.height as u32);
+    Some(Rect {
+        x,
+        y,
+        width: (right - x as u32) as u16,
+        height: (bottom - y as u32) as u16,
+    })
+}
+
+fn intersect(a: &Rect, b: &Rect) -> Option<Rect> {
+    let x = a.x.max(b.x);
+    let y = a.y.max(b.y);
+    let right = (a.x as u32 + a.width as u32).min(b.x as u32 + b.width as u32);
+    let bottom = (a.y as u32 + a.height as u32).min(b.y as u32 + b.height as u32);
+    (right > x as u32 && bottom > y as u32).then(|| Rect {
+        x,
+        y,
+        width: (right - x as u32) as u16,
+        height: (bottom - y as u32) as u16,
+    })
+}
+
+fn contains(outer: &Rect, inner: &Rect) -> bool {
+    intersect(outer, inner).as_ref() == Some(inner)
+}
+
+/// The state of a client being served
+struct Session<'a, F> {
+    source: &'a F,
+    pixel_format: PixelFormat,
+    encodings: Vec<VncEncoding>,
+    // the encoding of the pixels chosen from the encodings
+    encoding: VncEncoding,
+    encoder: Encoder,
+    // the size known by the client
+    screen: (u16, u16),
+    // the region requested but not updated yet
+    requested: Option<Rect>,
+    // the bounding box of the damage since the last update
+    dirty: Option<Rect>,
+    // the copies since the last update as (dst, src), which are sent before the dirty region
+    copies: Vec<(Rect, (u16, u16))>,
+    // the client is to be told about the new size
+    resized: bool,
+    // a request of no area to be answered with an empty update, e.g. a keepalive probe
+    probed: bool,
+}
+
+impl<'a, F> Session<'a, F>
+where
+    F: FrameSource,
+{
+    fn new(source: &'a F, pixel_format: PixelFormat) -> Self {
+        Self {
+            source,
+            pixel_format,
+            encodings: Vec::new(),
+            encoding: VncEncoding::Raw,
+            encoder: Encoder::new(),
+            screen: source.size(),
+            requested: None,
+            dirty: None,
+            copies: Vec::new(),
+            resized: false,
+            probed: false,
+        }
+    }
+
+    /// Returns the input to deliver if any
+    fn handle(&mut self, message: ClientMsg) -> Result<Option<InputEvent>> {
+        trace!("Client message got: {:?}", message.name());
+        match message {
+            ClientMsg::SetPixelFormat(pixel_format) => {
+                // the colour maps are not served
+                if pixel_format.true_color_flag == 0 {
+                    return Err(VncError::WrongPixelFormat.into());
+                }
+                self.pixel_format = pixel_format;
+            }
+            ClientMsg::SetEncodings(encodings) => {
+                self.encoding = codec::preferred(&encodings);
+                self.encodings = encodings;
+            }
+            ClientMsg::FramebufferUpdateRequest(rect, incremental) => {
+                let Some(rect) = intersect(&rect, &full(self.screen.0, self.screen.1)) else {
+                    self.probed |= incremental == 0;
+                    return Ok(None);
+                };
+                if incremental == 0 {
+                    self.dirty = union(self.dirty, rect);
+                }
+                self.requested = union(self.requested, rect);
+            }
+            ClientMsg::KeyEvent(keysym, down) => {
+                return Ok(Some(InputEvent::KeyEvent(ClientKeyEvent {
+                    keycode: keysym,
+                    down,
+                })))
+            }
+            ClientMsg::PointerEvent(x, y, buttons) => {
+                return Ok(Some(InputEvent::PointerEvent(ClientMouseEvent {
+                    position_x: x,
+                    position_y: y,
+                    bottons: buttons,
+                })))
+            }
+            ClientMsg::ClientCutText(text) => return Ok(Some(InputEvent::CutText(text))),
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    fn damage(&mut self, damage: Damage) {
+        let rect = match damage {
+            Damage::Rect(rect) => rect,
+            Damage::Copy { dst, src } => {
+                let src_rect = Rect {
+                    x: src.0,
+                    y: src.1,
+                    ..dst
+                };
+                // the client has to hold the pixels to copy
+                let copied = self.encodings.contains(&VncEncoding::CopyRect)
+                    && self
+                        .dirty
+                        .is_none_or(|dirty| intersect(&dirty, &src_rect).is_none());
+                if copied {
+                    self.copies.push((dst, src));
+                    return;
+                }
+                dst
+            }
+        };
+        if self.source.size() != self.screen
+            && self.encodings.contains(&VncEncoding::DesktopSizePseudo)
+        {
+            self.resized = true;
+        }
+        self.dirty = union(self.dirty, rect);
+    }
+
+    /// The FramebufferUpdate of the dirty region requested, if any
+    fn update(&mut self) -> Result<Option<Vec<u8>>> {
+        let rects = match self.requested {
+            Some(requested) => self.rects(requested)?,
+            None => Vec::new(),
+        };
+        if !rects.is_empty() {
+            self.requested = None;
+        } else if !self.probed {
+            return Ok(None);
+        }
+        self.probed = false;
+
+        let mut update = ServerMsg::FramebufferUpdate(rects.len() as u16).to_bytes();
+        for (rect, encoding, payload) in rects {
+            for value in [rect.x, rect.y, rect.width, rect.height] {
+                update.extend_from_slice(&value.to_be_bytes());
+            }
+            update.extend_from_slice(&(encoding as i32).to_be_bytes());
+            update.extend_from_slice(&payload);
+        }
+        Ok(Some(update))
+    }
+
+    /// The rects of the copies & the dirty region within `requested`,
+    /// along with their encodings & payloads
+    fn rects(&mut self, requested: Rect) -> Result<Vec<(Rect, VncEncoding, Vec<u8>)>> {
+        let size = self.source.size();
+        let mut rects = Vec::new();
+        let region = if self.resized {
+            self.resized = false;
+            self.screen = size;
+            // the whole surface is sent anyway
+            self.copies.clear();
+            rects.push((
+                full(size.0, size.1),
+                VncEncoding::DesktopSizePseudo,
+                Vec::new(),
+            ));
+            Some(full(size.0, size.1))
+        } else {
+            // the client may not know that the surface has shrunk
+            let visible = full(size.0.min(self.screen.0), size.1.min(self.screen.1));
+            let within = |rect: &Rect| contains(&requested, rect) && contains(&visible, rect);
+            let copies = std::mem::take(&mut self.copies);
+            // the copies depend on each other, so either all of them are sent or none
+            if copies.iter().all(|(dst, src)| {
+                within(dst)
+                    && contains(
+                        &visible,
+                        &Rect {
+                            x: src.0,
+                            y: src.1,
+                            ..*dst
+                        },
+                    )
+            }) {
+                for (dst, src) in copies {
+                    rects.push((dst, VncEncoding::CopyRect, codec::copy_rect(src)));
+                }
+            } else {
+                for (dst, _) in copies {
+                    self.dirty = union(self.dirty, dst);
+                }
+            }
+            self.dirty
+                .and_then(|dirty| intersect(&dirty, &requested))
+                .and_then(|region| intersect(&region, &visible))
+        };
+        if let Some(region) = region {
+            let rgba = self.source.pixels(region);
+            if rgba.len() != region.width as usize * region.height as usize * 4 {
+                error!("{} bytes of pixels for {:?}", rgba.len(), region);
+                return Err(VncError::InvalidImageData.into());
+            }
+            let encoded = self
+                .encoder
+                .encode(self.encoding, &self.pixel_format, region, &rgba)?;
+            for (rect, payload) in encoded {
+                rects.push((rect, self.encoding, payload));
+            }
+            if self.dirty.is_some_and(|dirty| contains(&region, &dirty)) {
+                self.dirty = None;
+            }
+        }
+        Ok(rects)
+    }
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+/// Serve the client after the ServerInit, until it disconnects or `stop` is told
+///
+pub(super) async fn run<S, F>(
+    stream: S,
+    source: &F,
+    mut damage: broadcast::Receiver<Damage>,
+    config: &ServerConfig,
+    inputs: InputSink<'_>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FrameSource,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (sender, mut messages) = mpsc::channel(MESSAGE_QUEUE);
+    // ClientMsg::read is not cancel safe, so the messages are read apart from the updates
+    let reading = async move {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let message = ClientMsg::read(&mut reader).await;
+            let failed = message.is_err();
+            if sender.send(message).await.is_err() || failed {
+                break;
+            }
+        }
+        // the messages left are still to be handled
+        std::future::pending::<()>().await;
+    };
+    let mut session = Session::new(source, config.pixel_format);
+    let serving = async {
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Some(Ok(message)) => {
+                        if let Some(input) = session.handle(message)? {
+                            inputs.deliver(input).await;
+                        }
+                    }
+                    Some(Err(e)) if is_eof(&e) => return Ok(()),
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                },
+                damaged = damage.recv() => match damaged {
+                    Ok(damaged) => session.damage(damaged),
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        let (width, height) = source.size();
+                        session.damage(Damage::Rect(full(width, height)));
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                _ = stop.changed() => return Ok(()),
+            }
+            if let Some(update) = session.update()? {
+                writer.write_all(&update).await?;
+                writer.flush().await?;
+            }
+        }
+    };
+    tokio::select! {
+        result = serving => result,
+        _ = reading => unreachable!(),
+    }
+}
@@ -0,0 +1,356 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use anyhow::Result;
+use tokio::sync::broadcast;
+
+use crate::{Rect, VncError};
+
+/// Damages buffered for each subscriber, a lagging one refreshes the whole surface instead
+const DAMAGE_BUFFER: usize = 256;
+
+/// A change of the surface, told by [FrameSource::damage]
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Damage {
+    /// The pixels of the rect have changed
+    ///
+    Rect(Rect),
+    /// The pixels of the same size as `dst` at `src` have been copied to `dst`, e.g. scrolled
+    ///
+    /// Which is sent as a CopyRect to the clients supporting it
+    ///
+    Copy { dst: Rect, src: (u16, u16) },
+}
+
+/// A surface served to the clients
+///
+/// The pixels are RGBA, 4 bytes per pixel row by row, and converted to the pixel format of each client
+///
+/// [MemorySource] keeps the pixels in memory, while [ChannelSource] reads them from the renderer of the application
+///
+pub trait FrameSource: Send + Sync + 'static {
+    /// The current size of the surface
+    ///
+    fn size(&self) -> (u16, u16);
+
+    /// The pixels of `rect`, which was within [FrameSource::size] when asked
+    ///
+    /// The surface may have been resized since, so the pixels out of it are to be returned black
+    ///
+    fn pixels(&self, rect: Rect) -> Vec<u8>;
+
+    /// Subscribe to the damages from now on
+    ///
+    /// A resize is told by the damage of the whole surface in the new size,
+    ///
+    /// and a [broadcast::error::RecvError::Lagged] means that anything may have been damaged
+    ///
+    fn damage(&self) -> broadcast::Receiver<Damage>;
+}
+
+fn full(width: u16, height: u16) -> Rect {
+    Rect {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    }
+}
+
+struct Frame {
+    width: u16,
+    height: u16,
+    rgba: Vec<u8>,
+}
+
+/// An RGBA surface kept in memory, which is drawn by [MemorySource::write]
+///
+/// The clones share the same surface, so one can be given to the server while the application draws on another
+///
+#[derive(Clone)]
+pub struct MemorySource {
+    frame: Arc<RwLock<Frame>>,
+    damage: broadcast::Sender<Damage>,
+}
+
+impl MemorySource {
+    /// A black surface of `width` x `height`
+    ///
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            frame: Arc::new(RwLock::new(Frame {
+                width,
+                height,
+                rgba: vec![0; width as usize * height as usize * 4],
+            })),
+            damage: broadcast::channel(DAMAGE_BUFFER).0,
+        }
+    }
+
+    /// Copy the RGBA pixels of `rect` onto the surface, which is damaged
+    ///
+    pub fn write(&self, rect: Rect, rgba: &[u8]) -> Result<()> {
+        {
+            let mut frame = self.frame.write().unwrap();
+            if rect.x as usize + rect.width as usize > frame.width as usize
+                || rect.y as usize + rect.height as usize > frame.height as usize
+            {
+                return Err(VncError::Custom(format!("{:?} is out of the surface", rect)).into());
+            }
+            let line = rect.width as usize * 4;
+            if rgba.len() != line * rect.height as usize {
+                return Err(VncError::InvalidImageData.into());
+            }
+            let stride = frame.width as usize * 4;
+            for (row, pixels) in rgba.chunks_exact(line).enumerate() {
+                let start = (rect.y as usize + row) * stride + rect.x as usize * 4;
+                frame.rgba[start..start + line].copy_from_slice(pixels);
+            }
+        }
+        // nobody may be subscribed
+        let _ = self.damage.send(Damage::Rect(rect));
+        Ok(())
+    }
+
+    /// Copy the pixels of the same size as `dst` at `src` to `dst`, e.g. to scroll
+    ///
+    pub fn copy(&self, dst: Rect, src: (u16, u16)) -> Result<()> {
+        {
+            let mut frame = self.frame.write().unwrap();
+            let fits = |x: u16, y: u16| {
+                x as usize + dst.width as usize <= frame.width as usize
+                    && y as usize + dst.height as usize <= frame.height as usize
+            };
+            if !fits(dst.x, dst.y) || !fits(src.0, src.1) {
+                return Err(VncError::Custom(format!(
+                    "Copy {:?} from {:?} is out of the surface",
+                    dst, src
+                ))
+                .into());
+            }
+            let stride = frame.width as usize * 4;
+            let line = dst.width as usize * 4;
+            // copied row by row in the order that the overlapping rows are read before written
+            let rows: Box<dyn Iterator<Item = usize>> = if dst.y > src.1 {
+                Box::new((0..dst.height as usize).rev())
+            } else {
+                Box::new(0..dst.height as usize)
+            };
+            for row in rows {
+                let from = (src.1 as usize + row) * stride + src.0 as usize * 4;
+                let to = (dst.y as usize + row) * stride + dst.x as usize * 4;
+                frame.rgba.copy_within(from..from + line, to);
+            }
+        }
+        let _ = self.damage.send(Damage::Copy { dst, src });
+        Ok(())
+    }
+
+    /// Resize the surface, which is cleared to black
+    ///
+    pub fn resize(&self, width: u16, height: u16) {
+        *self.frame.write().unwrap() = Frame {
+            width,
+            height,
+            rgba: vec![0; width as usize * height as usize * 4],
+        };
+        let _ = self.damage.send(Damage::Rect(full(width, height)));
+    }
+}
+
+impl FrameSource for MemorySource {
+    fn size(&self) -> (u16, u16) {
+        let frame = self.frame.read().unwrap();
+        (frame.width, frame.height)
+    }
+
+    fn pixels(&self, rect: Rect) -> Vec<u8> {
+        let frame = self.frame.read().unwrap();
+        let stride = frame.width as usize * 4;
+        let line = rect.width as usize * 4;
+        let mut rgba = vec![0; line * rect.height as usize];
+        // the part out of a shrunk surface is left black
+        let width = frame.width.saturating_sub(rect.x).min(rect.width) as usize * 4;
+        let height = frame.height.saturating_sub(rect.y).min(rect.height) as usize;
+        if width > 0 {
+            for (row, pixels) in rgba.chunks_exact_mut(line).take(height).enumerate() {
+                let start = (rect.y as usize + row) * stride + rect.x as usize * 4;
+                pixels[..width].copy_from_slice(&frame.rgba[start..start + width]);
+            }
+        }
+        rgba
+    }
+
+    fn damage(&self) -> broadcast::Receiver<Damage> {
+        self.damage.subscribe()
+    }
+}
+
+struct Rendered {
+    size: Mutex<(u16, u16)>,
+    damage: broadcast::Sender<Damage>,
+}
+
+/// A surface rendered by the application, whose pixels are read by a callback
+///
+/// The damaged rects are sent through the [DamageSender] returned along with it
+///
+/// ```no_run
+/// use vnc::{server::{ChannelSource, FrameSource}, Rect};
+///
+/// let (source, damage) = ChannelSource::new(800, 600, |rect: Rect| {
+///     // read the RGBA pixels of the rect from the renderer
+///     vec![0; rect.width as usize * rect.height as usize * 4]
+/// });
+/// damage.send(Rect { x: 0, y: 0, width: 100, height: 100 });
+/// ```
+///
+pub struct ChannelSource<P> {
+    pixels: P,
+    shared: Arc<Rendered>,
+}
+
+impl<P> ChannelSource<P>
+where
+    P: Fn(Rect) -> Vec<u8> + Send + Sync + 'static,
+{
+    /// A surface of `width` x `height`, whose pixels are returned by `pixels` in RGBA
+    ///
+    pub fn new(width: u16, height: u16, pixels: P) -> (Self, DamageSender) {
+        let shared = Arc::new(Rendered {
+            size: Mutex::new((width, height)),
+            damage: broadcast::channel(DAMAGE_BUFFER).0,
+        });
+        (
+            Self {
+                pixels,
+                shared: shared.clone(),
+            },
+            DamageSender { shared },
+        )
+    }
+}
+
+impl<P> FrameSource for ChannelSource<P>
+where
+    P: Fn(Rect) -> Vec<u8> + Send + Sync + 'static,
+{
+    fn size(&self) -> (u16, u16) {
+        *self.shared.size.lock().unwrap()
+    }
+
+    fn pixels(&self, rect: Rect) -> Vec<u8> {
+        (self.pixels)(rect)
+    }
+
+    fn damage(&self) -> broadcast::Receiver<Damage> {
+        self.shared.damage.subscribe()
+    }
+}
+
+/// The sending side of the damaged rects of a [ChannelSource]
+///
+#[derive(Clone)]
+pub struct DamageSender {
+    shared: Arc<Rendered>,
+}
+
+impl DamageSender {
+    /// Tell that `rect` has been rendered again
+    ///
+    pub fn send(&self, rect: Rect) {
+        let _ = self.shared.damage.send(Damage::Rect(rect));
+    }
+
+    /// Tell that the pixels at `src` have been copied to `dst` by the renderer, see [Damage::Copy]
+    ///
+    pub fn copy(&self, dst: Rect, src: (u16, u16)) {
+        let _ = self.shared.damage.send(Damage::Copy { dst, src });
+    }
+
+    /// Tell that the surface has been resized, the whole of which is damaged
+    ///
+    pub fn resize(&self, width: u16, height: u16) {
+        *self.shared.size.lock().unwrap() = (width, height);
+        let _ = self.shared.damage.send(Damage::Rect(full(width, height)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_source() {
+        let source = MemorySource::new(4, 3);
+        let mut damage = source.clone().damage();
+        let rect = Rect {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 2,
+        };
+        let rgba = (0..16).collect::<Vec<u8>>();
+        source.write(rect, &rgba).unwrap();
+        assert_eq!(damage.try_recv().unwrap(), Damage::Rect(rect));
+        assert_eq!(source.pixels(rect), rgba);
+        let row = source.pixels(Rect {
+            x: 0,
+            y: 1,
+            width: 4,
+            height: 1,
+        });
+        assert_eq!(row[..4], [0; 4]);
+        assert_eq!(row[4..12], rgba[..8]);
+
+        // out of the surface or not as many pixels as the rect
+        assert!(source.write(Rect { x: 3, ..rect }, &rgba).is_err());
+        assert!(source.write(rect, &rgba[..12]).is_err());
+
+        // scrolled up by a row
+        let dst = Rect { y: 0, ..rect };
+        source.copy(dst, (1, 1)).unwrap();
+        assert_eq!(
+            damage.try_recv().unwrap(),
+            Damage::Copy { dst, src: (1, 1) }
+        );
+        assert_eq!(source.pixels(dst), rgba);
+        assert!(source.copy(dst, (3, 0)).is_err());
+
+        source.resize(2, 2);
+        assert_eq!(source.size(), (2, 2));
+        assert_eq!(damage.try_recv().unwrap(), Damage::Rect(full(2, 2)));
+        assert_eq!(source.pixels(full(2, 2)), [0; 16]);
+
+        // a rect clipped before the surface shrunk
+        source.write(full(2, 2), &[1; 16]).unwrap();
+        let rgba = source.pixels(full(3, 3));
+        assert_eq!(rgba.len(), 36);
+        assert_eq!(rgba[..8], [1; 8]);
+        assert_eq!(rgba[8..12], [0; 4]);
+        assert_eq!(rgba[12..20], [1; 8]);
+        assert_eq!(rgba[20..], [0; 16]);
+        assert_eq!(source.pixels(Rect { x: 2, ..rect }), [0; 16]);
+    }
+
+    #[test]
+    fn test_channel_source() {
+        let (source, sender) = ChannelSource::new(8, 8, |rect: Rect| {
+            vec![rect.x as u8; rect.width as usize * rect.height as usize * 4]
+        });
+        let mut damage = source.damage();
+        let rect = Rect {
+            x: 2,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        sender.send(rect);
+        assert_eq!(damage.try_recv().unwrap(), Damage::Rect(rect));
+        assert_eq!(source.pixels(rect), [2; 4]);
+
+        sender.resize(16, 4);
+        assert_eq!(source.size(), (16, 4));
+        assert_eq!(damage.try_recv().unwrap(), Damage::Rect(full(16, 4)));
+    }
+}
@@ -0,0 +1,7 @@
+//! Adapters turning the transports which aren't byte streams into the `AsyncRead + AsyncWrite`
+//!
+//! taken by [crate::VncConnector::new]
+//!
+
+#[cfg(feature = "ws")]
+pub mod ws;
@@ -0,0 +1,246 @@
+//! The VNC servers behind a websockify/noVNC proxy, over [tokio-tungstenite](https://crates.io/crates/tokio-tungstenite)
+//!
+//! ```no_run
+//! # async fn connect() -> anyhow::Result<()> {
+//! use tokio::net::TcpStream;
+//! use vnc::transport::ws::{tokio_tungstenite, WsStream};
+//!
+//! let tcp = TcpStream::connect("127.0.0.1:6080").await?;
+//! let (ws, _) = tokio_tungstenite::client_async("ws://127.0.0.1:6080/websockify", tcp).await?;
+//! let connector = vnc::VncConnector::new(WsStream::new(ws))
+//!     .set_auth_method(|_| async move { Ok("password".into()) });
+//! # Ok(())
+//! # }
+//! ```
+//!
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{protocol::frame::coding::CloseCode, Bytes, Error as WsError, Message},
+    WebSocketStream,
+};
+
+pub use tokio_tungstenite;
+
+fn into_io(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// A WebSocket as a byte stream
+///
+/// The bytes written are sent as binary messages, and the binary messages received are read
+///
+/// as a continuous stream regardless of their boundaries
+///
+/// The text messages, i.e. the legacy `base64` subprotocol of websockify, fail the reads with [io::ErrorKind::InvalidData]
+///
+/// A close frame of the server ends the stream, unless it carries a code other than `1000`
+///
+/// which fails the reads with [io::ErrorKind::ConnectionAborted] and e.g. `close 4001: token expired`
+///
+pub struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    // the rest of the binary message being read
+    read: Bytes,
+    // a message is queued by the writes but not flushed yet
+    unflushed: bool,
+}
+
+impl<S> WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wrap a WebSocket whose handshake is done
+    ///
+    pub fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            read: Bytes::new(),
+            unflushed: false,
+        }
+    }
+
+    pub fn into_inner(self) -> WebSocketStream<S> {
+        self.ws
+    }
+
+    /// Flush the queued message without blocking on it
+    ///
+    /// tungstenite holds the messages until flushed, while the handshake of the connector
+    ///
+    /// writes without flushing and waits for the reply
+    ///
+    fn poll_unflushed(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if self.unflushed {
+            if let Poll::Ready(result) = Pin::new(&mut self.ws).poll_flush(cx) {
+                self.unflushed = false;
+                result.map_err(into_io)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S> AsyncRead for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_unflushed(cx)?;
+        while this.read.is_empty() {
+            match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => this.read = data,
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Text message on the WebSocket, the binary subprotocol is expected",
+                    )))
+                }
+                Some(Ok(Message::Close(Some(frame)))) if frame.code != CloseCode::Normal => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("close {}: {}", u16::from(frame.code), frame.reason),
+                    )))
+                }
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                // the pings are answered by tungstenite
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Poll::Ready(Err(into_io(e))),
+            }
+        }
+        let data = this.read.split_to(this.read.len().min(buf.remaining()));
+        buf.put_slice(&data);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for WsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        ready!(Pin::new(&mut this.ws).poll_ready(cx)).map_err(into_io)?;
+        Pin::new(&mut this.ws)
+            .start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+            .map_err(into_io)?;
+        this.unflushed = true;
+        this.poll_unflushed(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.ws).poll_flush(cx)).map_err(into_io)?;
+        this.unflushed = false;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().ws)
+            .poll_close(cx)
+            .map_err(into_io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
+
+    async fn pair() -> (WsStream<DuplexStream>, WebSocketStream<DuplexStream>) {
+        let (client, server) = duplex(4096);
+        (
+            WsStream::new(WebSocketStream::from_raw_socket(client, Role::Client, None).await),
+            WebSocketStream::from_raw_socket(server, Role::Server, None).await,
+        )
+    }
+
+    async fn send(ws: &mut WebSocketStream<DuplexStream>, message: Message) {
+        poll_fn(|cx| Pin::new(&mut *ws).poll_ready(cx))
+            .await
+            .unwrap();
+        Pin::new(&mut *ws).start_send(message).unwrap();
+        poll_fn(|cx| Pin::new(&mut *ws).poll_flush(cx))
+            .await
+            .unwrap();
+    }
+
+    async fn recv(ws: &mut WebSocketStream<DuplexStream>) -> Message {
+        poll_fn(|cx| Pin::new(&mut *ws).poll_next(cx))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let (mut client, mut server) = pair().await;
+
+        // the reads cross the message boundaries
+        send(&mut server, Message::Binary(vec![1, 2, 3].into())).await;
+        send(&mut server, Message::Ping(Bytes::new())).await;
+        send(&mut server, Message::Binary(Bytes::new())).await;
+        send(&mut server, Message::Binary(vec![4, 5].into())).await;
+        let mut data = [0; 2];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [1, 2]);
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [3, 4]);
+        assert_eq!(client.read_u8().await.unwrap(), 5);
+
+        // delivered without an explicit flush
+        client.write_all(&[6, 7]).await.unwrap();
+        assert!(matches!(recv(&mut server).await, Message::Pong(_)));
+        assert_eq!(recv(&mut server).await, Message::Binary(vec![6, 7].into()));
+
+        send(&mut server, Message::Text("AQI=".into())).await;
+        let e = client.read_u8().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let (mut client, mut server) = pair().await;
+        server.close(None).await.unwrap();
+        let mut data = Vec::new();
+        assert_eq!(client.read_to_end(&mut data).await.unwrap(), 0);
+
+        let (mut client, mut server) = pair().await;
+        server
+            .close(Some(CloseFrame {
+                code: CloseCode::from(4001),
+                reason: "token expired".into(),
+            }))
+            .await
+            .unwrap();
+        let e = client.read_u8().await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(e.to_string(), "close 4001: token expired");
+    }
+}